@@ -1,13 +1,27 @@
+use crate::poll::JobUpdate;
 use crate::Error;
 use crate::Job;
-use serenity::all::{CreateActionRow, CreateButton, CreateEmbed};
+use crate::JobState;
+use crate::Statuses;
+use limits::embed_limits::{
+    EMBED_DESCRIPTION_LIMIT, EMBED_FIELDS_MAX_COUNT, EMBED_FIELD_NAME_LIMIT,
+    EMBED_FIELD_VALUE_LIMIT, EMBED_TITLE_LIMIT, EMBED_TOTAL_LIMIT,
+};
+use limits::validation::{sanitize_mentions, truncate, AllowedMentionsPolicy};
+use serenity::all::{CreateActionRow, CreateButton, CreateEmbed, CreateEmbedFooter};
 
+/// Returns the icon of a job state given as its string form, see ``get_icon_of_job_state``
 pub fn get_icon_of_state(state: &str) -> String {
+    get_icon_of_job_state(&JobState::from(state.to_string()))
+}
+
+pub fn get_icon_of_job_state(state: &JobState) -> String {
     match state {
-        "pending" => ":hourglass:",
-        "running" => ":hourglass_flowing_sand:",
-        "completed" => ":white_check_mark:",
-        "failed" => ":x:",
+        JobState::Pending => ":hourglass:",
+        JobState::Running => ":hourglass_flowing_sand:",
+        JobState::Completed => ":white_check_mark:",
+        JobState::Failed => ":x:",
+        JobState::Lost => ":ghost:",
         _ => ":question:",
     }
     .to_string()
@@ -45,68 +59,33 @@ impl<'a> EmbedResponse<'a> {
     }
 }
 
+/// Renders the status embed of a job after ``pre_embeds``
+///
+/// Statuses are rendered like ``render_job_embed`` renders them, keeping the most recent ones that fit in the description
 pub fn embed<'a>(
     base_api_url: &str,
     job: &Job,
     pre_embeds: Vec<serenity::all::CreateEmbed<'a>>,
     show_status: bool,
 ) -> Result<EmbedResponse<'a>, Error> {
-    let mut job_statuses: Vec<String> = Vec::new();
-    let mut job_statuses_length = 0;
     let mut components = Vec::new();
 
     let job_state = &job.state;
 
-    if show_status {
-        for status in &job.statuses {
-            if job_statuses_length > 2500 {
-                // Keep removing elements from start of array until we are under 2500 characters
-                while job_statuses_length > 2500 {
-                    let removed = job_statuses.remove(0);
-                    job_statuses_length -= removed.len();
-                }
-            }
-
-            let mut add = format!("`{}` {}", status.level, status.msg);
-
-            let mut vs = Vec::new();
-
-            let bdi = status.bot_display_ignore.clone().unwrap_or_default();
-
-            for (k, v) in status.extra_info.iter() {
-                if bdi.contains(k) {
-                    continue;
-                }
-
-                vs.push(format!("{}={}", k, serde_json::to_string(v)?));
-            }
-
-            if !vs.is_empty() {
-                add += &format!(" {}", vs.join(", "));
-            }
-
-            add = add.chars().take(500).collect::<String>()
-                + if add.len() > 500 { "..." } else { "" };
-
-            add += &format!(" | <t:{}:R>", status.ts.round());
-
-            job_statuses_length += if add.len() > 500 { 500 } else { add.len() };
-            job_statuses.push(add);
-        }
-    }
-
-    let mut description = format!(
-        "{} Job state: {}\nJob ID: {}\n\n{}",
-        get_icon_of_state(job_state.as_str()),
+    let header = format!(
+        "{}{} Job state: {}\nJob ID: {}\n\n",
+        dry_run_notice(job).map(|n| n + "\n").unwrap_or_default(),
+        get_icon_of_job_state(job_state),
         job_state,
         job.id,
-        job_statuses.join("\n")
     );
 
-    if job.state == "completed" {
+    let mut download = String::new();
+
+    if job.state == JobState::Completed {
         if let Some(ref output) = job.output {
             let furl = format!("{}/jobs/{}/ioauth/download-link", base_api_url, job.id);
-            description += &format!("\n\n:link: [Download {}]({})", output.filename, &furl);
+            download = format!("\n\n:link: [Download {}]({})", output.filename, &furl);
 
             components.push(CreateActionRow::Buttons(
                 vec![CreateButton::new_link(furl).label("Download").emoji('📥')].into(),
@@ -114,9 +93,26 @@ pub fn embed<'a>(
         }
     }
 
+    let statuses = if show_status {
+        let max_status_length = EmbedRenderOptions::default().max_status_length;
+
+        let lines = job
+            .statuses
+            .iter()
+            .map(|s| render_status(s, max_status_length))
+            .collect::<Vec<_>>();
+
+        let budget = EMBED_DESCRIPTION_LIMIT
+            .saturating_sub(header.chars().count() + download.chars().count());
+
+        join_recent_lines(&lines, budget)
+    } else {
+        String::new()
+    };
+
     let embed = CreateEmbed::default()
         .title("Status")
-        .description(description)
+        .description(format!("{}{}{}", header, statuses, download))
         .color(serenity::all::Colour::DARK_GREEN);
 
     let mut msg = EmbedResponse::new();
@@ -129,3 +125,350 @@ pub fn embed<'a>(
 
     Ok(msg)
 }
+
+pub struct EmbedRenderOptions {
+    /// The base API URL used to build the output download link. No link is rendered if unset
+    pub base_api_url: Option<String>,
+
+    /// The number of most recent statuses to render
+    pub max_statuses: usize,
+
+    /// The maximum length of a single rendered status line
+    pub max_status_length: usize,
+
+    /// Whether to render the job's fields as embed fields
+    pub show_fields: bool,
+}
+
+impl Default for EmbedRenderOptions {
+    fn default() -> Self {
+        EmbedRenderOptions {
+            base_api_url: None,
+            max_statuses: 10,
+            max_status_length: 500,
+            show_fields: true,
+        }
+    }
+}
+
+/// Returns a notice to show before everything else if the job is a dry run, see ``prune::is_dry_run``
+fn dry_run_notice(job: &Job) -> Option<String> {
+    if !crate::prune::is_dry_run(job) {
+        return None;
+    }
+
+    Some(":warning: **DRY RUN**: no messages will be deleted".to_string())
+}
+
+/// Renders a single status line, skipping ``bot_display_ignore`` keys
+///
+/// Status messages may contain user-controlled text, so mentions are escaped
+fn render_status(status: &Statuses, max_length: usize) -> String {
+    let mut line = format!(
+        "`{}` {}",
+        status.level,
+        sanitize_mentions(&status.msg, &AllowedMentionsPolicy::default())
+    );
+
+    let bdi = status.bot_display_ignore.clone().unwrap_or_default();
+
+    let vs = status
+        .extra_info
+        .iter()
+        .filter(|(k, _)| !bdi.contains(k))
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>();
+
+    if !vs.is_empty() {
+        line += &format!(" {}", vs.join(", "));
+    }
+
+    let ts = format!(" | <t:{}:R>", status.ts.round());
+
+    truncate(&line, max_length.saturating_sub(ts.chars().count())) + &ts
+}
+
+/// Joins the most recent lines that fit within ``budget`` characters, dropping the oldest first
+fn join_recent_lines(lines: &[String], budget: usize) -> String {
+    let mut used = 0;
+    let mut start = lines.len();
+
+    for (i, line) in lines.iter().enumerate().rev() {
+        let len = line.chars().count() + 1; // + newline
+
+        if used + len > budget {
+            break;
+        }
+
+        used += len;
+        start = i;
+    }
+
+    lines[start..].join("\n")
+}
+
+/// Returns the download URL of the job's output if it has completed with one
+fn download_url(job: &Job, opts: &EmbedRenderOptions) -> Option<String> {
+    let base_api_url = opts.base_api_url.as_ref()?;
+
+    if job.state != JobState::Completed || job.output.is_none() {
+        return None;
+    }
+
+    Some(format!(
+        "{}/jobs/{}/ioauth/download-link",
+        base_api_url, job.id
+    ))
+}
+
+/// Renders a job into a single embed that stays within Discord's embed limits
+pub fn render_job_embed(job: &Job, opts: &EmbedRenderOptions) -> CreateEmbed<'static> {
+    let title = truncate(
+        &format!("{} {}", get_icon_of_job_state(&job.state), job.name),
+        EMBED_TITLE_LIMIT,
+    );
+
+    let mut header = format!(
+        "**State:** {}\n**Job ID:** {}\n**Created:** <t:{}:R>",
+        job.state,
+        job.id,
+        job.created_at.timestamp()
+    );
+
+    if let Some(notice) = dry_run_notice(job) {
+        header = format!("{}\n\n{}", notice, header);
+    }
+
+    if let (Some(url), Some(output)) = (download_url(job, opts), &job.output) {
+        header += &format!("\n\n:link: [Download {}]({})", output.filename, url);
+    }
+
+    let statuses = job
+        .statuses
+        .iter()
+        .skip(job.statuses.len().saturating_sub(opts.max_statuses))
+        .map(|s| render_status(s, opts.max_status_length))
+        .collect::<Vec<_>>();
+
+    let header_len = header.chars().count() + 2;
+    let description = if statuses.is_empty() {
+        header
+    } else {
+        let budget = EMBED_DESCRIPTION_LIMIT
+            .min(EMBED_TOTAL_LIMIT.saturating_sub(title.chars().count()))
+            .saturating_sub(header_len);
+
+        format!("{}\n\n{}", header, join_recent_lines(&statuses, budget))
+    };
+
+    let mut total = title.chars().count() + description.chars().count();
+
+    let mut embed = CreateEmbed::default()
+        .title(title)
+        .description(description)
+        .color(serenity::all::Colour::DARK_GREEN);
+
+    if opts.show_fields {
+        for (k, v) in job.fields.iter().take(EMBED_FIELDS_MAX_COUNT) {
+            let name = truncate(k, EMBED_FIELD_NAME_LIMIT);
+            let value = truncate(&v.to_string(), EMBED_FIELD_VALUE_LIMIT);
+
+            let len = name.chars().count() + value.chars().count();
+
+            if total + len > EMBED_TOTAL_LIMIT {
+                break;
+            }
+
+            total += len;
+            embed = embed.field(name, value, true);
+        }
+    }
+
+    embed
+}
+
+/// Applies an incremental update (see ``poll::reactive_incremental``) to a local copy of the job and renders it
+pub fn render_job_update(
+    job: &mut Job,
+    update: JobUpdate,
+    opts: &EmbedRenderOptions,
+) -> CreateEmbed<'static> {
+    update.apply(job);
+    render_job_embed(job, opts)
+}
+
+/// Renders all statuses of a job into as many embeds as needed to stay within Discord's embed limits
+pub fn render_job_statuses_paginated(
+    job: &Job,
+    opts: &EmbedRenderOptions,
+) -> Vec<CreateEmbed<'static>> {
+    let mut pages: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for status in job.statuses.iter() {
+        let line = render_status(status, opts.max_status_length);
+
+        if !current.is_empty()
+            && current.chars().count() + line.chars().count() + 1 > EMBED_DESCRIPTION_LIMIT
+        {
+            pages.push(std::mem::take(&mut current));
+        }
+
+        if !current.is_empty() {
+            current.push('\n');
+        }
+
+        current += &line;
+    }
+
+    if !current.is_empty() || pages.is_empty() {
+        pages.push(current);
+    }
+
+    let page_count = pages.len();
+
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, page)| {
+            CreateEmbed::default()
+                .title(truncate(
+                    &format!("Statuses of {}", job.name),
+                    EMBED_TITLE_LIMIT,
+                ))
+                .description(page)
+                .footer(CreateEmbedFooter::new(format!(
+                    "Page {}/{}",
+                    i + 1,
+                    page_count
+                )))
+                .color(serenity::all::Colour::DARK_GREEN)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+
+    fn status(i: usize, msg_len: usize) -> Statuses {
+        Statuses {
+            level: "info".to_string(),
+            msg: format!("status {} {}", i, "x".repeat(msg_len)),
+            ts: i as f64,
+            bot_display_ignore: None,
+            extra_info: IndexMap::new(),
+        }
+    }
+
+    fn job(statuses: Vec<Statuses>) -> Job {
+        Job {
+            id: uuid::Uuid::new_v4(),
+            name: "guild_create_backup".to_string(),
+            output: Some(crate::Output {
+                filename: "backup.arbackup".to_string(),
+                perguild: None,
+            }),
+            fields: IndexMap::new(),
+            statuses,
+            guild_id: serenity::all::GuildId::new(1),
+            expiry: None,
+            state: JobState::Completed,
+            resumable: false,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn description(embed: &CreateEmbed<'_>) -> String {
+        serde_json::to_value(embed).unwrap()["description"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn legacy_embed_keeps_oversized_statuses_within_limits() {
+        let job = job((0..200).map(|i| status(i, 3000)).collect());
+
+        let resp = embed("https://api.example", &job, Vec::new(), true).unwrap();
+        assert_eq!(resp.embeds.len(), 1);
+        assert_eq!(resp.components.len(), 1);
+
+        let description = description(&resp.embeds[0]);
+        assert!(description.chars().count() <= EMBED_DESCRIPTION_LIMIT);
+
+        // The most recent statuses are kept, the download link is never cut off
+        assert!(description.contains("status 199 "));
+        assert!(!description.contains("status 0 "));
+        assert!(description.ends_with(&format!(
+            "[Download backup.arbackup](https://api.example/jobs/{}/ioauth/download-link)",
+            job.id
+        )));
+
+        let max_status_length = EmbedRenderOptions::default().max_status_length;
+        for line in description.lines().filter(|l| l.starts_with("`info`")) {
+            assert!(line.chars().count() <= max_status_length);
+        }
+    }
+
+    #[test]
+    fn legacy_embed_truncates_multibyte_statuses_on_char_boundaries() {
+        let mut statuses = (0..50).map(|i| status(i, 10)).collect::<Vec<_>>();
+        statuses[49].msg = "é".repeat(5000);
+
+        let description = description(
+            &embed("https://api.example", &job(statuses), Vec::new(), true)
+                .unwrap()
+                .embeds[0],
+        );
+
+        assert!(description.chars().count() <= EMBED_DESCRIPTION_LIMIT);
+        assert!(description.contains("éé..."));
+    }
+
+    #[test]
+    fn legacy_embed_without_statuses() {
+        let job = job((0..5).map(|i| status(i, 10)).collect());
+
+        let description = description(&embed("", &job, Vec::new(), false).unwrap().embeds[0]);
+        assert!(!description.contains("status"));
+        assert!(description.contains(&format!("Job ID: {}", job.id)));
+    }
+
+    #[test]
+    fn render_job_embed_keeps_oversized_statuses_within_limits() {
+        let job = job((0..200).map(|i| status(i, 3000)).collect());
+
+        let opts = EmbedRenderOptions {
+            max_statuses: 200,
+            max_status_length: 5000,
+            ..Default::default()
+        };
+
+        let embed = serde_json::to_value(render_job_embed(&job, &opts)).unwrap();
+        let title = embed["title"].as_str().unwrap().chars().count();
+        let description = embed["description"].as_str().unwrap();
+
+        assert!(description.chars().count() <= EMBED_DESCRIPTION_LIMIT);
+        assert!(title + description.chars().count() <= EMBED_TOTAL_LIMIT);
+        assert!(description.contains("status 199 "));
+    }
+
+    #[test]
+    fn paginated_statuses_stay_within_limits() {
+        let job = job((0..200).map(|i| status(i, 3000)).collect());
+
+        let pages = render_job_statuses_paginated(&job, &EmbedRenderOptions::default());
+        assert!(pages.len() > 1);
+
+        let mut seen = 0;
+        for page in pages.iter() {
+            let description = description(page);
+            assert!(description.chars().count() <= EMBED_DESCRIPTION_LIMIT);
+            seen += description.lines().count();
+        }
+
+        assert_eq!(seen, 200);
+    }
+}
@@ -0,0 +1,364 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+use crate::poll::PollTaskOptions;
+use crate::{Error, Job, JobState, Spawn};
+
+/// How long to wait for the job row to appear after spawning
+const ROW_WAIT: Duration = Duration::from_secs(5);
+const ROW_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum JobHandleError {
+    /// The jobserver rejected or failed to spawn the job
+    Spawn(Error),
+    /// The job row did not show up in the database in time after spawning
+    RowMissing(Uuid),
+    /// Polling the job failed
+    Poll(Error),
+    /// The job did not reach a terminal state in time
+    Timeout(Duration),
+    /// The job stream ended before the job reached a terminal state
+    StreamEnded,
+    /// Cancelling the job failed
+    Cancel(Error),
+}
+
+impl std::fmt::Display for JobHandleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobHandleError::Spawn(e) => write!(f, "Failed to spawn job: {}", e),
+            JobHandleError::RowMissing(id) => {
+                write!(
+                    f,
+                    "Job {} was spawned but never appeared in the database",
+                    id
+                )
+            }
+            JobHandleError::Poll(e) => write!(f, "Failed to poll job: {}", e),
+            JobHandleError::Timeout(timeout) => {
+                write!(f, "Job did not complete within {:?}", timeout)
+            }
+            JobHandleError::StreamEnded => {
+                write!(f, "Job stream ended before the job completed")
+            }
+            JobHandleError::Cancel(e) => write!(f, "Failed to cancel job: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for JobHandleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JobHandleError::Spawn(e) | JobHandleError::Poll(e) | JobHandleError::Cancel(e) => {
+                Some(e.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Drives ``stream`` until it yields a job in a terminal state
+async fn wait_for_terminal(
+    stream: impl futures_util::Stream<Item = Result<Option<std::sync::Arc<Job>>, Error>>,
+) -> Result<(), JobHandleError> {
+    let mut stream = std::pin::pin!(stream);
+
+    while let Some(job) = stream.next().await {
+        let Some(job) = job.map_err(JobHandleError::Poll)? else {
+            continue;
+        };
+
+        if job.state.is_terminal() {
+            return Ok(());
+        }
+    }
+
+    Err(JobHandleError::StreamEnded)
+}
+
+/// A spawned job, combining spawning, polling and cancellation
+pub struct JobHandle {
+    id: Uuid,
+    reqwest: reqwest::Client,
+    pool: sqlx::PgPool,
+    jobserver_addr: String,
+    jobserver_port: u16,
+    poll_opts: PollTaskOptions,
+}
+
+impl JobHandle {
+    /// Spawns the job and waits (for a bounded amount of time) for its row to exist
+    pub async fn create(
+        reqwest: &reqwest::Client,
+        pool: &sqlx::PgPool,
+        spawn: Spawn,
+        jobserver_addr: &str,
+        jobserver_port: u16,
+        poll_opts: PollTaskOptions,
+    ) -> Result<Self, JobHandleError> {
+        let resp = crate::spawn::spawn_task(reqwest, &spawn, jobserver_addr, jobserver_port)
+            .await
+            .map_err(JobHandleError::Spawn)?;
+
+        let id = Uuid::parse_str(&resp.id).map_err(|e| JobHandleError::Spawn(e.into()))?;
+
+        let start = tokio::time::Instant::now();
+
+        loop {
+            let exists: bool =
+                sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM jobs WHERE id = $1)")
+                    .bind(id)
+                    .fetch_one(pool)
+                    .await
+                    .map_err(|e| JobHandleError::Poll(e.into()))?;
+
+            if exists {
+                break;
+            }
+
+            if start.elapsed() > ROW_WAIT {
+                return Err(JobHandleError::RowMissing(id));
+            }
+
+            tokio::time::sleep(ROW_WAIT_INTERVAL).await;
+        }
+
+        Ok(Self {
+            id,
+            reqwest: reqwest.clone(),
+            pool: pool.clone(),
+            jobserver_addr: jobserver_addr.to_string(),
+            jobserver_port,
+            poll_opts,
+        })
+    }
+
+    /// Returns the ID of the job
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Returns a reactive stream of job updates, see ``poll::reactive``
+    pub fn stream(
+        &self,
+    ) -> Result<
+        impl futures_util::Stream<Item = Result<Option<std::sync::Arc<Job>>, Error>>,
+        JobHandleError,
+    > {
+        crate::poll::reactive(&self.pool, &self.id.to_string(), self.poll_opts)
+            .map_err(JobHandleError::Poll)
+    }
+
+    /// Drives the job stream until the job reaches a terminal state, returning the final job
+    ///
+    /// Note that a failed job is returned as ``Ok``, check ``Job::state`` to distinguish it
+    pub async fn wait_for_completion(&self, timeout: Duration) -> Result<Job, JobHandleError> {
+        tokio::time::timeout(timeout, wait_for_terminal(self.stream()?))
+            .await
+            .map_err(|_| JobHandleError::Timeout(timeout))??;
+
+        Job::from_id(self.id, &self.pool)
+            .await
+            .map_err(JobHandleError::Poll)
+    }
+
+    /// Asks the jobserver to cancel the job and marks the job row as cancelled through ``Job::set_state``
+    ///
+    /// Jobs which already reached a terminal state are left as they are
+    pub async fn cancel(&self) -> Result<(), JobHandleError> {
+        let resp = self
+            .reqwest
+            .post(format!(
+                "{}:{}/jobs/{}/cancel",
+                self.jobserver_addr, self.jobserver_port, self.id
+            ))
+            .send()
+            .await
+            .map_err(|e| JobHandleError::Cancel(e.into()))?;
+
+        if !resp.status().is_success() {
+            let err_text = resp
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+
+            return Err(JobHandleError::Cancel(err_text.into()));
+        }
+
+        let mut job = Job::from_id(self.id, &self.pool)
+            .await
+            .map_err(JobHandleError::Cancel)?;
+
+        if job.state.is_terminal() {
+            return Ok(());
+        }
+
+        if let Err(e) = job.set_state(&self.pool, JobState::Cancelled).await {
+            // The job may have finished concurrently, which is fine
+            let job = Job::from_id(self.id, &self.pool)
+                .await
+                .map_err(JobHandleError::Cancel)?;
+
+            if !job.state.is_terminal() {
+                return Err(JobHandleError::Cancel(e));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+    use crate::test_schema::{create_tables, JOBS};
+    use axum::http::StatusCode;
+
+    const ADDR: &str = "http://127.0.0.1";
+
+    fn spawn() -> Spawn {
+        Spawn {
+            name: "backup".to_string(),
+            data: serde_json::json!({}),
+            create: true,
+            execute: true,
+            id: None,
+            guild_id: "1".to_string(),
+        }
+    }
+
+    async fn insert_job(pool: &sqlx::PgPool, state: &str) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id, state) VALUES ('backup', '1', $1) RETURNING id",
+        )
+        .bind(state)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    /// A jobserver which spawns ``id`` and accepts cancellations
+    async fn jobserver(id: Uuid) -> MockServer {
+        MockServer::start(move |_, _| MockResponse::ok(serde_json::json!({ "id": id.to_string() })))
+            .await
+    }
+
+    async fn handle(pool: &sqlx::PgPool, server: &MockServer) -> JobHandle {
+        JobHandle::create(
+            &reqwest::Client::new(),
+            pool,
+            spawn(),
+            ADDR,
+            server.port(),
+            PollTaskOptions {
+                interval: 1,
+                timeout_nostatuschange: 0,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn waits_until_the_job_completes(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id = insert_job(&pool, "running").await;
+        let server = jobserver(id).await;
+        let handle = handle(&pool, &server).await;
+
+        assert_eq!(handle.id(), id);
+        assert_eq!(server.requests()[0].path, "/spawn");
+
+        let completer = pool.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            sqlx::query("UPDATE jobs SET state = 'completed' WHERE id = $1")
+                .bind(id)
+                .execute(&completer)
+                .await
+                .unwrap();
+        });
+
+        let job = handle
+            .wait_for_completion(Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(job.state, JobState::Completed);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn times_out_on_a_stuck_job(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id = insert_job(&pool, "running").await;
+        let server = jobserver(id).await;
+        let handle = handle(&pool, &server).await;
+
+        assert!(matches!(
+            handle
+                .wait_for_completion(Duration::from_millis(1500))
+                .await,
+            Err(JobHandleError::Timeout(_))
+        ));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn stream_ending_early_is_an_error(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id = insert_job(&pool, "running").await;
+        let running = std::sync::Arc::new(Job::from_id(id, &pool).await.unwrap());
+
+        let stream = futures_util::stream::iter(vec![Ok(None), Ok(Some(running))]);
+
+        assert!(matches!(
+            wait_for_terminal(stream).await,
+            Err(JobHandleError::StreamEnded)
+        ));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn spawn_failures_are_reported(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let server =
+            MockServer::start(|_, _| MockResponse::status(StatusCode::INTERNAL_SERVER_ERROR)).await;
+
+        let res = JobHandle::create(
+            &reqwest::Client::new(),
+            &pool,
+            spawn(),
+            ADDR,
+            server.port(),
+            PollTaskOptions::default(),
+        )
+        .await;
+
+        assert!(matches!(res, Err(JobHandleError::Spawn(_))));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn cancel_marks_the_job_cancelled(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id = insert_job(&pool, "running").await;
+        let server = jobserver(id).await;
+        let handle = handle(&pool, &server).await;
+
+        handle.cancel().await.unwrap();
+
+        let request = server.requests().pop().unwrap();
+        assert_eq!(request.method, axum::http::Method::POST);
+        assert_eq!(request.path, format!("/jobs/{}/cancel", id));
+        assert_eq!(
+            Job::from_id(id, &pool).await.unwrap().state,
+            JobState::Cancelled
+        );
+    }
+}
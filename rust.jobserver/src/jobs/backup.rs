@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+
+use serenity::all::{ChannelId, GuildId, UserId};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{Error, Job, Spawn, SpawnResponse};
+
+/// Name of the job creating a guild backup
+pub const CREATE_BACKUP_JOB: &str = "guild_create_backup";
+
+/// Name of the job restoring a guild backup
+pub const RESTORE_BACKUP_JOB: &str = "guild_restore_backup";
+
+/// Maximum number of messages to back up per channel (without special allocations)
+pub const MAX_MESSAGES_PER_CHANNEL: u32 = 500;
+
+/// Maximum number of messages to back up in total
+pub const MAX_TOTAL_MESSAGES: u32 = 10000;
+
+/// Parses special allocations of form ``channel_id=count,channel_id=count``
+pub fn parse_special_allocations(s: &str) -> Result<HashMap<ChannelId, u32>, Error> {
+    let mut allocations = HashMap::new();
+
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((channel_id, count)) = entry.split_once('=') else {
+            return Err(format!(
+                "Special allocation {:?} must be of form channel_id=count",
+                entry
+            )
+            .into());
+        };
+
+        let channel_id: ChannelId = channel_id.trim().parse().map_err(|e| {
+            format!(
+                "Invalid channel id in special allocation {:?}: {}",
+                entry, e
+            )
+        })?;
+
+        let count: u32 = count
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid count in special allocation {:?}: {}", entry, e))?;
+
+        if allocations.insert(channel_id, count).is_some() {
+            return Err(format!("Channel {} has multiple special allocations", channel_id).into());
+        }
+    }
+
+    Ok(allocations)
+}
+
+/// Options for a guild backup
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GuildBackupOptions {
+    /// Maximum number of messages to back up in total
+    pub max_messages: u32,
+    /// Number of messages to back up per channel
+    pub per_channel: u32,
+    /// Channels which should back up a different number of messages than ``per_channel``
+    pub special_allocations: HashMap<ChannelId, u32>,
+    pub include_attachments: bool,
+    /// Whether messages left over from channels with fewer messages than their allocation
+    /// should be given to the remaining channels
+    pub rollover_leftovers: bool,
+}
+
+impl Default for GuildBackupOptions {
+    fn default() -> Self {
+        GuildBackupOptions {
+            max_messages: 500,
+            per_channel: 100,
+            special_allocations: HashMap::new(),
+            include_attachments: false,
+            rollover_leftovers: true,
+        }
+    }
+}
+
+impl GuildBackupOptions {
+    /// Sets the special allocations from a string, see ``parse_special_allocations``
+    pub fn with_special_allocations_str(mut self, s: &str) -> Result<Self, Error> {
+        self.special_allocations = parse_special_allocations(s)?;
+        Ok(self)
+    }
+
+    /// Validates the options against the backup limits
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_messages > MAX_TOTAL_MESSAGES {
+            return Err(format!(
+                "Cannot back up more than {} messages in total",
+                MAX_TOTAL_MESSAGES
+            )
+            .into());
+        }
+
+        if self.per_channel > MAX_MESSAGES_PER_CHANNEL {
+            return Err(format!(
+                "Cannot back up more than {} messages per channel",
+                MAX_MESSAGES_PER_CHANNEL
+            )
+            .into());
+        }
+
+        if self.per_channel > self.max_messages {
+            return Err(
+                "Messages per channel cannot be greater than the total number of messages".into(),
+            );
+        }
+
+        let mut special_total: u32 = 0;
+
+        for (channel_id, count) in self.special_allocations.iter() {
+            if *count > MAX_MESSAGES_PER_CHANNEL {
+                return Err(format!(
+                    "Special allocation of channel {} ({}) is greater than the maximum of {} messages per channel",
+                    channel_id, count, MAX_MESSAGES_PER_CHANNEL
+                )
+                .into());
+            }
+
+            if *count > self.max_messages {
+                return Err(format!(
+                    "Special allocation of channel {} ({}) is greater than the total number of messages",
+                    channel_id, count
+                )
+                .into());
+            }
+
+            special_total = special_total.saturating_add(*count);
+        }
+
+        if special_total > self.max_messages {
+            return Err(format!(
+                "Special allocations add up to {} messages which is greater than the total of {}",
+                special_total, self.max_messages
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Validates the options and converts them to a ``Spawn`` for the backup job
+    pub fn to_spawn(&self, guild_id: GuildId, user_id: UserId) -> Result<Spawn, Error> {
+        self.validate()?;
+
+        let special_allocations = self
+            .special_allocations
+            .iter()
+            .map(|(channel_id, count)| (channel_id.to_string(), *count))
+            .collect::<HashMap<_, _>>();
+
+        Ok(Spawn {
+            name: CREATE_BACKUP_JOB.to_string(),
+            data: serde_json::json!({
+                "Options": {
+                    "MaxMessages": self.max_messages,
+                    "PerChannel": self.per_channel,
+                    "SpecialAllocs": special_allocations,
+                    "BackupAttachments": self.include_attachments,
+                    "RolloverLeftovers": self.rollover_leftovers,
+                },
+                "UserID": user_id.to_string(),
+            }),
+            create: true,
+            execute: true,
+            id: None,
+            guild_id: guild_id.to_string(),
+        })
+    }
+}
+
+/// Validates the options and spawns a backup job, returning its id
+pub async fn create_backup_job(
+    reqwest: &reqwest::Client,
+    opts: &GuildBackupOptions,
+    guild_id: GuildId,
+    user_id: UserId,
+    jobserver_addr: &str,
+    jobserver_port: u16,
+) -> Result<SpawnResponse, Error> {
+    let spawn = opts.to_spawn(guild_id, user_id)?;
+
+    crate::spawn::spawn_task(reqwest, &spawn, jobserver_addr, jobserver_port).await
+}
+
+/// Options for restoring a guild from the output of a backup job
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GuildRestoreOptions {
+    /// The backup job to restore from
+    pub backup_job_id: Uuid,
+    /// Whether to continue restoring if parts of the restore fail
+    pub ignore_restore_errors: bool,
+}
+
+impl GuildRestoreOptions {
+    /// Checks that the backup job exists, belongs to ``guild_id`` and has an output file
+    pub async fn validate(&self, pool: &PgPool, guild_id: GuildId) -> Result<Job, Error> {
+        let job = match Job::from_id(self.backup_job_id, pool).await {
+            Ok(job) => job,
+            Err(e) if matches!(e.downcast_ref(), Some(sqlx::Error::RowNotFound)) => {
+                return Err(format!("Backup job {} not found", self.backup_job_id).into());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if job.guild_id != guild_id {
+            return Err(format!(
+                "Backup job {} does not belong to this guild",
+                self.backup_job_id
+            )
+            .into());
+        }
+
+        if job.name != CREATE_BACKUP_JOB {
+            return Err(format!("Job {} is not a backup job", self.backup_job_id).into());
+        }
+
+        if job.output.is_none() {
+            return Err(format!("Backup job {} has no output file", self.backup_job_id).into());
+        }
+
+        Ok(job)
+    }
+
+    /// Validates the options and converts them to a ``Spawn`` for the restore job
+    pub async fn to_spawn(
+        &self,
+        pool: &PgPool,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Spawn, Error> {
+        let job = self.validate(pool, guild_id).await?;
+
+        let Some(path) = job.get_file_path() else {
+            return Err(format!("Backup job {} has no output file", self.backup_job_id).into());
+        };
+
+        Ok(Spawn {
+            name: RESTORE_BACKUP_JOB.to_string(),
+            data: serde_json::json!({
+                "BackupSource": path,
+                "Options": {
+                    "IgnoreRestoreErrors": self.ignore_restore_errors,
+                },
+                "UserID": user_id.to_string(),
+            }),
+            create: true,
+            execute: true,
+            id: None,
+            guild_id: guild_id.to_string(),
+        })
+    }
+}
+
+/// Validates the options and spawns a restore job, returning its id
+pub async fn create_restore_job(
+    reqwest: &reqwest::Client,
+    pool: &PgPool,
+    opts: &GuildRestoreOptions,
+    guild_id: GuildId,
+    user_id: UserId,
+    jobserver_addr: &str,
+    jobserver_port: u16,
+) -> Result<SpawnResponse, Error> {
+    let spawn = opts.to_spawn(pool, guild_id, user_id).await?;
+
+    crate::spawn::spawn_task(reqwest, &spawn, jobserver_addr, jobserver_port).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, JOBS};
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    fn assert_invalid(opts: GuildBackupOptions, needle: &str) {
+        let err = opts.validate().unwrap_err().to_string();
+        assert!(
+            err.contains(needle),
+            "{:?} does not contain {:?}",
+            err,
+            needle
+        );
+    }
+
+    #[test]
+    fn parses_special_allocations() {
+        let allocations = parse_special_allocations(" 1=10, 2 = 20 ,").unwrap();
+        assert_eq!(allocations.len(), 2);
+        assert_eq!(allocations[&ChannelId::new(1)], 10);
+        assert_eq!(allocations[&ChannelId::new(2)], 20);
+
+        assert!(parse_special_allocations("").unwrap().is_empty());
+
+        for invalid in ["1", "a=1", "1=a", "1=-1", "1=1,1=2"] {
+            assert!(parse_special_allocations(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn validation_failures() {
+        assert!(GuildBackupOptions::default().validate().is_ok());
+
+        assert_invalid(
+            GuildBackupOptions {
+                max_messages: MAX_TOTAL_MESSAGES + 1,
+                ..Default::default()
+            },
+            "in total",
+        );
+
+        assert_invalid(
+            GuildBackupOptions {
+                max_messages: MAX_TOTAL_MESSAGES,
+                per_channel: MAX_MESSAGES_PER_CHANNEL + 1,
+                ..Default::default()
+            },
+            "per channel",
+        );
+
+        assert_invalid(
+            GuildBackupOptions {
+                max_messages: 50,
+                per_channel: 100,
+                ..Default::default()
+            },
+            "greater than the total",
+        );
+
+        assert_invalid(
+            GuildBackupOptions {
+                max_messages: MAX_TOTAL_MESSAGES,
+                special_allocations: HashMap::from([(
+                    ChannelId::new(1),
+                    MAX_MESSAGES_PER_CHANNEL + 1,
+                )]),
+                ..Default::default()
+            },
+            "maximum of",
+        );
+
+        assert_invalid(
+            GuildBackupOptions {
+                special_allocations: HashMap::from([(ChannelId::new(1), 501)]),
+                max_messages: 500,
+                ..Default::default()
+            },
+            "Special allocation of channel 1",
+        );
+
+        assert_invalid(
+            GuildBackupOptions {
+                special_allocations: HashMap::from([
+                    (ChannelId::new(1), 300),
+                    (ChannelId::new(2), 300),
+                ]),
+                ..Default::default()
+            },
+            "add up to 600",
+        );
+
+        // Allocations at the per channel limit are fine
+        assert!(GuildBackupOptions {
+            max_messages: MAX_TOTAL_MESSAGES,
+            special_allocations: HashMap::from([(ChannelId::new(1), MAX_MESSAGES_PER_CHANNEL)]),
+            ..Default::default()
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn backup_spawn_payload() {
+        let spawn = GuildBackupOptions::default()
+            .with_special_allocations_str("3=40")
+            .unwrap()
+            .to_spawn(GUILD, UserId::new(2))
+            .unwrap();
+
+        assert_eq!(spawn.name, CREATE_BACKUP_JOB);
+        assert_eq!(spawn.guild_id, "1");
+        assert_eq!(spawn.data["UserID"], "2");
+        assert_eq!(spawn.data["Options"]["SpecialAllocs"]["3"], 40);
+        assert_eq!(spawn.data["Options"]["MaxMessages"], 500);
+
+        assert!(GuildBackupOptions {
+            per_channel: MAX_MESSAGES_PER_CHANNEL + 1,
+            ..Default::default()
+        }
+        .to_spawn(GUILD, UserId::new(2))
+        .is_err());
+    }
+
+    async fn insert_job(pool: &PgPool, name: &str, guild_id: &str, output: bool) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id, output) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(name)
+        .bind(guild_id)
+        .bind(output.then(|| serde_json::json!({ "filename": "backup.arbackup" })))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn restore_err(pool: &PgPool, backup_job_id: Uuid) -> String {
+        let opts = GuildRestoreOptions {
+            backup_job_id,
+            ignore_restore_errors: false,
+        };
+
+        match opts.validate(pool, GUILD).await {
+            Ok(_) => panic!("restore of {} should fail validation", backup_job_id),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn restore_validation(pool: PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        assert!(restore_err(&pool, Uuid::new_v4())
+            .await
+            .contains("not found"));
+
+        let other_guild = insert_job(&pool, CREATE_BACKUP_JOB, "2", true).await;
+        assert!(restore_err(&pool, other_guild)
+            .await
+            .contains("does not belong"));
+
+        let not_backup = insert_job(&pool, "message_prune", "1", true).await;
+        assert!(restore_err(&pool, not_backup)
+            .await
+            .contains("not a backup job"));
+
+        let no_output = insert_job(&pool, CREATE_BACKUP_JOB, "1", false).await;
+        assert!(restore_err(&pool, no_output)
+            .await
+            .contains("no output file"));
+
+        let backup = insert_job(&pool, CREATE_BACKUP_JOB, "1", true).await;
+        let spawn = GuildRestoreOptions {
+            backup_job_id: backup,
+            ignore_restore_errors: true,
+        }
+        .to_spawn(&pool, GUILD, UserId::new(2))
+        .await
+        .unwrap();
+
+        assert_eq!(spawn.name, RESTORE_BACKUP_JOB);
+        assert_eq!(
+            spawn.data["BackupSource"],
+            format!("jobs/{}/backup.arbackup", backup)
+        );
+        assert_eq!(spawn.data["Options"]["IgnoreRestoreErrors"], true);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn restore_database_errors_are_not_reported_as_missing(pool: PgPool) {
+        // No jobs table, so the lookup fails with a database error
+        let err = restore_err(&pool, Uuid::new_v4()).await;
+        assert!(!err.contains("not found"), "{}", err);
+    }
+}
@@ -1,17 +1,36 @@
 pub mod embed;
+pub mod handle;
+pub mod jobs;
+#[cfg(test)]
+mod mock_server;
 pub mod poll;
+pub mod prune;
+pub mod queue;
+pub mod reaper;
+pub mod resume;
 pub mod spawn;
+pub mod state;
+#[cfg(test)]
+mod test_schema;
+
+pub use handle::{JobHandle, JobHandleError};
+pub use state::{InvalidStateTransition, JobState};
 
 use chrono::Utc;
 use indexmap::IndexMap;
-use silverpelt::objectstore::{guild_bucket, ObjectStore};
+use silverpelt::decode::{decode_lossy, parse_snowflake_column, DecodeFailure};
+use silverpelt::objectstore::{guild_bucket, ByteRange, GetObject, ObjectStore};
+use silverpelt::pginterval::try_pg_interval_to_chrono_duration;
 use sqlx::postgres::types::PgInterval;
 use sqlx::PgPool;
 use uuid::Uuid;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>; // This is constant and should be copy pasted
 
-#[derive(serde::Deserialize, serde::Serialize)]
+/// Extra time added on top of a job's stored expiry, kept from the original conversion
+const EXPIRY_GRACE_SECS: i64 = 60;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct SpawnResponse {
     pub id: String,
 }
@@ -46,7 +65,7 @@ pub struct Job {
     pub statuses: Vec<Statuses>,
     pub guild_id: serenity::all::GuildId,
     pub expiry: Option<chrono::Duration>,
-    pub state: String,
+    pub state: JobState,
     pub resumable: bool,
     pub created_at: chrono::DateTime<Utc>,
 }
@@ -90,19 +109,15 @@ impl Job {
                 .transpose()?,
             fields: serde_json::from_value::<IndexMap<String, serde_json::Value>>(rec.fields)?,
             statuses,
-            guild_id: rec.guild_id.parse()?,
-            expiry: {
-                if let Some(expiry) = rec.expiry {
-                    let t = expiry.microseconds
-                        + 60 * 1_000_000
-                        + (expiry.days as i64) * 24 * 60 * 60 * 1_000_000
-                        + (expiry.months as i64) * 30 * 24 * 60 * 60 * 1_000_000;
-                    Some(chrono::Duration::microseconds(t))
-                } else {
-                    None
-                }
-            },
-            state: rec.state,
+            guild_id: parse_snowflake_column(&rec.guild_id, "guild_id", rec.id)?,
+            expiry: rec
+                .expiry
+                .map(|expiry| {
+                    try_pg_interval_to_chrono_duration(&expiry, true)
+                        .map(|d| d + chrono::Duration::seconds(EXPIRY_GRACE_SECS))
+                })
+                .transpose()?,
+            state: rec.state.into(),
             created_at: rec.created_at,
             resumable: rec.resumable,
         };
@@ -122,13 +137,51 @@ impl Job {
         Self::from_pgrow(rec)
     }
 
+    /// Returns the statuses of a job after the first ``offset`` statuses
+    pub async fn statuses_since(
+        pool: &PgPool,
+        id: Uuid,
+        offset: usize,
+    ) -> Result<Vec<Statuses>, Error> {
+        let offset: i32 = offset.try_into()?;
+
+        // Postgres arrays are 1-indexed and slicing past the end returns an empty array
+        let statuses: Vec<serde_json::Value> = sqlx::query_scalar(
+            "SELECT COALESCE(statuses[($2 + 1):], '{}') FROM jobs WHERE id = $1",
+        )
+        .bind(id)
+        .bind(offset)
+        .fetch_one(pool)
+        .await?;
+
+        let mut parsed = Vec::with_capacity(statuses.len());
+
+        for status in statuses {
+            parsed.push(serde_json::from_value::<Statuses>(status)?);
+        }
+
+        Ok(parsed)
+    }
+
+    /// Returns the number of statuses of a job without fetching them
+    pub async fn status_count(pool: &PgPool, id: Uuid) -> Result<usize, Error> {
+        let count: i32 = sqlx::query_scalar(
+            "SELECT COALESCE(array_length(statuses, 1), 0) FROM jobs WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(count.try_into()?)
+    }
+
     /// Fetches all jobs of a guild given guild id
     pub async fn from_guild(
         guild_id: serenity::all::GuildId,
         pool: &sqlx::PgPool,
     ) -> Result<Vec<Self>, Error> {
         let recs = sqlx::query_as(
-            "SELECT id, name, output, statuses, expiry, state, created_at, fields, resumable FROM jobs WHERE guild_id = $1",
+            "SELECT id, name, output, statuses, guild_id, expiry, state, created_at, fields, resumable FROM jobs WHERE guild_id = $1",
         )
         .bind(guild_id.to_string())
         .fetch_all(pool)
@@ -166,6 +219,120 @@ impl Job {
         Ok(jobs)
     }
 
+    /// Like ``from_guild`` but skips rows which fail to decode, returning them separately
+    pub async fn from_guild_lossy(
+        guild_id: serenity::all::GuildId,
+        pool: &sqlx::PgPool,
+    ) -> Result<(Vec<Self>, Vec<DecodeFailure>), Error> {
+        let recs: Vec<JobRow> = sqlx::query_as(
+            "SELECT id, name, output, statuses, guild_id, expiry, state, created_at, fields, resumable FROM jobs WHERE guild_id = $1",
+        )
+        .bind(guild_id.to_string())
+        .fetch_all(pool)
+        .await?;
+
+        Ok(decode_lossy(
+            recs,
+            "job",
+            |rec| rec.id.to_string(),
+            Self::from_pgrow,
+        ))
+    }
+
+    /// Like ``from_guild_and_name`` but skips rows which fail to decode, returning them separately
+    pub async fn from_guild_and_name_lossy(
+        guild_id: serenity::all::GuildId,
+        name: &str,
+        pool: &sqlx::PgPool,
+    ) -> Result<(Vec<Self>, Vec<DecodeFailure>), Error> {
+        let recs: Vec<JobRow> = sqlx::query_as(
+            "SELECT id, name, output, statuses, guild_id, expiry, state, created_at, fields, resumable FROM jobs WHERE guild_id = $1 AND name = $2",
+        )
+        .bind(guild_id.to_string())
+        .bind(name)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(decode_lossy(
+            recs,
+            "job",
+            |rec| rec.id.to_string(),
+            Self::from_pgrow,
+        ))
+    }
+
+    /// Returns the number of jobs of a guild which are not in a terminal state, optionally only those whose name starts with ``name_prefix``
+    pub async fn count_active(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        name_prefix: Option<&str>,
+    ) -> Result<i64, Error> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM jobs WHERE guild_id = $1 AND state != ALL($2) AND ($3::text IS NULL OR starts_with(name, $3))",
+        )
+        .bind(guild_id.to_string())
+        .bind(JobState::terminal_state_strings())
+        .bind(name_prefix)
+        .fetch_one(db)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Returns resumable jobs which are still in a non-terminal state and were created more than ``max_age`` ago
+    pub async fn find_resumable(
+        pool: &sqlx::PgPool,
+        max_age: std::time::Duration,
+    ) -> Result<Vec<Self>, Error> {
+        let recs = sqlx::query_as(
+            "SELECT id, name, output, statuses, guild_id, expiry, state, created_at, fields, resumable FROM jobs WHERE resumable = true AND state != ALL($1) AND created_at < NOW() - make_interval(secs => $2) ORDER BY created_at ASC",
+        )
+        .bind(JobState::terminal_state_strings())
+        .bind(max_age.as_secs_f64())
+        .fetch_all(pool)
+        .await?;
+
+        let mut jobs = Vec::new();
+
+        for rec in recs {
+            jobs.push(Self::from_pgrow(rec)?);
+        }
+
+        Ok(jobs)
+    }
+
+    /// Moves the job to ``new_state``, erroring with ``InvalidStateTransition`` if the transition is not allowed
+    ///
+    /// The update only applies if the state in the database still matches ``self.state``, otherwise the job was
+    /// changed concurrently and an error is returned
+    pub async fn set_state(&mut self, pool: &PgPool, new_state: JobState) -> Result<(), Error> {
+        if !self.state.can_transition_to(&new_state) {
+            return Err(Box::new(InvalidStateTransition {
+                from: self.state.clone(),
+                to: new_state,
+            }));
+        }
+
+        let updated = sqlx::query("UPDATE jobs SET state = $1 WHERE id = $2 AND state = $3")
+            .bind(new_state.to_string())
+            .bind(self.id)
+            .bind(self.state.to_string())
+            .execute(pool)
+            .await?;
+
+        if updated.rows_affected() == 0 {
+            return Err(format!(
+                "Job {} is no longer in state {}, it was changed concurrently",
+                self.id, self.state
+            )
+            .into());
+        }
+
+        self.state = new_state;
+
+        Ok(())
+    }
+
     pub fn get_path(&self) -> String {
         format!("jobs/{}", self.id)
     }
@@ -178,6 +345,56 @@ impl Job {
             .map(|output| format!("{}/{}", path, output.filename))
     }
 
+    /// Streams the job output to the object storage and records it on the job
+    pub async fn upload_output(
+        &mut self,
+        pool: &PgPool,
+        object_store: &ObjectStore,
+        filename: String,
+        reader: impl tokio::io::AsyncRead + Unpin + Send,
+    ) -> Result<(), Error> {
+        object_store
+            .put_streaming(
+                &guild_bucket(self.guild_id),
+                &format!("{}/{}", self.get_path(), filename),
+                reader,
+                None,
+            )
+            .await?;
+
+        let output = Output {
+            filename,
+            perguild: Some(true),
+        };
+
+        sqlx::query("UPDATE jobs SET output = $1 WHERE id = $2")
+            .bind(serde_json::to_value(&output)?)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        self.output = Some(output);
+
+        Ok(())
+    }
+
+    /// Streams the job output (or with ``range``, part of it) from the object storage
+    ///
+    /// Returns None if the job has no output or it is missing from the object storage
+    pub async fn get_output(
+        &self,
+        object_store: &ObjectStore,
+        range: Option<ByteRange>,
+    ) -> Result<Option<GetObject>, Error> {
+        let Some(path) = self.get_file_path() else {
+            return Ok(None);
+        };
+
+        object_store
+            .get(&guild_bucket(self.guild_id), &path, range)
+            .await
+    }
+
     /// Deletes the job from the object storage
     async fn delete_from_storage(&self, object_store: &ObjectStore) -> Result<(), Error> {
         // Check if the job has an output
@@ -210,7 +427,6 @@ impl Job {
     /// Deletes the job entirely, this includes deleting it from the object storage and the
     ///
     /// This also consumes the job dropping it from memory
-    #[allow(dead_code)] // Will be used in the near future
     pub async fn delete(self, pool: &PgPool, object_store: &ObjectStore) -> Result<(), Error> {
         self.delete_from_storage(object_store).await?;
         self.delete_from_db(pool).await?;
@@ -218,3 +434,201 @@ impl Job {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, JOBS};
+
+    #[test]
+    fn expiry_includes_grace_period() {
+        let row = JobRow {
+            id: Uuid::new_v4(),
+            name: "backup".to_string(),
+            output: None,
+            fields: serde_json::json!({}),
+            statuses: vec![],
+            guild_id: "1".to_string(),
+            expiry: Some(PgInterval {
+                months: 0,
+                days: 1,
+                microseconds: 0,
+            }),
+            state: "pending".to_string(),
+            created_at: Utc::now(),
+            resumable: false,
+        };
+
+        let job = Job::from_pgrow(row).unwrap();
+
+        assert_eq!(
+            job.expiry,
+            Some(chrono::Duration::days(1) + chrono::Duration::seconds(EXPIRY_GRACE_SECS))
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn from_guild_lossy_skips_bad_rows(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        sqlx::query(
+            "INSERT INTO jobs (name, guild_id) SELECT 'backup', '1' FROM generate_series(1, 3)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // A status missing its required fields
+        let bad: Uuid = sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id, statuses) VALUES ('backup', '1', ARRAY['{}'::jsonb]) RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let guild = serenity::all::GuildId::new(1);
+
+        assert!(Job::from_guild(guild, &pool).await.is_err());
+
+        let (jobs, failures) = Job::from_guild_lossy(guild, &pool).await.unwrap();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].id, bad.to_string());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn upload_output_streams_and_persists_output(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id: Uuid = sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id) VALUES ('backup', '1') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let object_store = ObjectStore::new_memory();
+        let mut job = Job::from_id(id, &pool).await.unwrap();
+
+        job.upload_output(
+            &pool,
+            &object_store,
+            "backup.tar".to_string(),
+            &b"backup data"[..],
+        )
+        .await
+        .unwrap();
+
+        let path = format!("jobs/{}/backup.tar", id);
+        assert_eq!(job.get_file_path().as_deref(), Some(path.as_str()));
+        assert_eq!(
+            object_store
+                .download_file(&guild_bucket(job.guild_id), &path)
+                .await
+                .unwrap(),
+            b"backup data"
+        );
+
+        // The output column is persisted, not just set in memory
+        let stored = Job::from_id(id, &pool).await.unwrap();
+        let output = stored.output.unwrap();
+        assert_eq!(output.filename, "backup.tar");
+        assert_eq!(output.perguild, Some(true));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn get_output_streams_ranges_and_handles_missing_output(pool: sqlx::PgPool) {
+        use tokio::io::AsyncReadExt;
+
+        create_tables(&pool, &[JOBS]).await;
+
+        let id: Uuid = sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id) VALUES ('backup', '1') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let object_store = ObjectStore::new_memory();
+        let mut job = Job::from_id(id, &pool).await.unwrap();
+
+        // No output yet
+        assert!(job.get_output(&object_store, None).await.unwrap().is_none());
+
+        job.upload_output(
+            &pool,
+            &object_store,
+            "backup.tar".to_string(),
+            &b"0123456789"[..],
+        )
+        .await
+        .unwrap();
+
+        let mut part = job
+            .get_output(&object_store, Some(ByteRange::Suffix(4)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(part.content_range().as_deref(), Some("bytes 6-9/10"));
+
+        let mut data = vec![];
+        part.reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"6789");
+
+        // The output is recorded but the object is gone
+        object_store
+            .delete(&guild_bucket(job.guild_id), &job.get_file_path().unwrap())
+            .await
+            .unwrap();
+        assert!(job.get_output(&object_store, None).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn statuses_since_slices_the_statuses_array(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id: Uuid = sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id, statuses) SELECT 'restore', '1', ARRAY(SELECT jsonb_build_object('level', 'info', 'msg', 'status ' || g, 'ts', g) FROM generate_series(1, 1200) g) RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(Job::status_count(&pool, id).await.unwrap(), 1200);
+        assert_eq!(Job::statuses_since(&pool, id, 0).await.unwrap().len(), 1200);
+
+        let tail = Job::statuses_since(&pool, id, 1195).await.unwrap();
+        assert_eq!(
+            tail.iter().map(|s| s.msg.as_str()).collect::<Vec<_>>(),
+            [
+                "status 1196",
+                "status 1197",
+                "status 1198",
+                "status 1199",
+                "status 1200"
+            ]
+        );
+
+        assert!(Job::statuses_since(&pool, id, 1200)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(Job::statuses_since(&pool, id, 5000)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let empty: Uuid = sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id) VALUES ('backup', '1') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(Job::status_count(&pool, empty).await.unwrap(), 0);
+        assert!(Job::statuses_since(&pool, empty, 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,8 +1,10 @@
 use crate::Error;
 use crate::Job;
+use crate::JobState;
 use futures_util::Stream;
 use std::sync::Arc;
 
+#[derive(Clone, Copy)]
 pub struct PollTaskOptions {
     /// The interval at which to update/poll at in seconds
     pub interval: u64,
@@ -46,7 +48,7 @@ pub fn reactive(
             let mut state = state;
 
             if let Some(ref prev_job) = state.prev_job {
-                if prev_job.state == "completed" {
+                if prev_job.state == JobState::Completed {
                     if state.at_end {
                         return None;
                     } else {
@@ -101,3 +103,249 @@ pub struct JobserverStreamState {
     last_statuschange: tokio::time::Instant,
     at_end: bool,
 }
+
+/// An incremental update of a job, see ``reactive_incremental``
+pub enum JobUpdate {
+    /// The state of the job changed
+    StateChanged(JobState),
+    /// New statuses were appended to the job
+    NewStatuses(Vec<super::Statuses>),
+    /// The job reached a terminal state (``JobState::is_terminal``), this is always the last update
+    Completed(Box<Job>),
+}
+
+impl JobUpdate {
+    /// Applies the update to a local copy of the job
+    pub fn apply(self, job: &mut Job) {
+        match self {
+            JobUpdate::StateChanged(state) => job.state = state,
+            JobUpdate::NewStatuses(statuses) => job.statuses.extend(statuses),
+            JobUpdate::Completed(completed) => *job = *completed,
+        }
+    }
+}
+
+/// Like ``reactive`` but only fetches the job state and newly appended statuses on every tick
+///
+/// ``offset`` is the number of statuses already known to the caller
+pub fn reactive_incremental(
+    pool: &sqlx::PgPool,
+    id: &str,
+    offset: usize,
+    to: PollTaskOptions,
+) -> Result<impl Stream<Item = Result<JobUpdate, Error>>, Error> {
+    let interval = tokio::time::interval(std::time::Duration::from_secs(to.interval));
+    let id = sqlx::types::uuid::Uuid::parse_str(id)?;
+
+    Ok(futures_util::stream::unfold(
+        IncrementalStreamState {
+            pool: pool.clone(),
+            id,
+            timeout_nostatuschange: to.timeout_nostatuschange,
+            last_state: None,
+            last_count: offset,
+            pending: std::collections::VecDeque::new(),
+            interval,
+            last_statuschange: tokio::time::Instant::now(),
+            at_end: false,
+        },
+        |mut state| async move {
+            loop {
+                if let Some(update) = state.pending.pop_front() {
+                    return Some((Ok(update), state));
+                }
+
+                if state.at_end {
+                    return None;
+                }
+
+                state.interval.tick().await;
+
+                if state.timeout_nostatuschange > 0
+                    && tokio::time::Instant::now() - state.last_statuschange
+                        > tokio::time::Duration::from_secs(state.timeout_nostatuschange)
+                {
+                    return Some((
+                        Err(format!(
+                            "Job poll timeout of {} seconds reached without status change",
+                            state.timeout_nostatuschange
+                        )
+                        .into()),
+                        state,
+                    ));
+                }
+
+                let (job_state, count) = match poll_state(&state.pool, state.id).await {
+                    Ok(res) => res,
+                    Err(e) => return Some((Err(e), state)),
+                };
+
+                if count > state.last_count {
+                    match super::Job::statuses_since(&state.pool, state.id, state.last_count).await
+                    {
+                        Ok(statuses) => {
+                            state.last_count += statuses.len();
+                            state.pending.push_back(JobUpdate::NewStatuses(statuses));
+                        }
+                        Err(e) => return Some((Err(e), state)),
+                    }
+                }
+
+                if state.last_state.as_ref() != Some(&job_state) {
+                    state.last_state = Some(job_state.clone());
+                    state
+                        .pending
+                        .push_back(JobUpdate::StateChanged(job_state.clone()));
+                }
+
+                if job_state.is_terminal() {
+                    match super::Job::from_id(state.id, &state.pool).await {
+                        Ok(job) => state.pending.push_back(JobUpdate::Completed(Box::new(job))),
+                        Err(e) => return Some((Err(e), state)),
+                    }
+
+                    state.at_end = true;
+                }
+
+                if !state.pending.is_empty() {
+                    state.last_statuschange = tokio::time::Instant::now();
+                }
+            }
+        },
+    ))
+}
+
+/// Returns the state and status count of a job
+async fn poll_state(
+    pool: &sqlx::PgPool,
+    id: sqlx::types::Uuid,
+) -> Result<(JobState, usize), Error> {
+    let (state, count): (String, i32) = sqlx::query_as(
+        "SELECT state, COALESCE(array_length(statuses, 1), 0) FROM jobs WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok((state.into(), count.try_into()?))
+}
+
+pub struct IncrementalStreamState {
+    pool: sqlx::PgPool,
+    id: sqlx::types::Uuid,
+    timeout_nostatuschange: u64,
+    last_state: Option<JobState>,
+    last_count: usize,
+    pending: std::collections::VecDeque<JobUpdate>,
+    interval: tokio::time::Interval,
+    last_statuschange: tokio::time::Instant,
+    at_end: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, JOBS};
+    use futures_util::StreamExt;
+
+    /// Appends statuses ``from..=to`` to a job, setting its state
+    async fn append_statuses(
+        pool: &sqlx::PgPool,
+        id: sqlx::types::Uuid,
+        from: i32,
+        to: i32,
+        state: &str,
+    ) {
+        sqlx::query(
+            "UPDATE jobs SET state = $4, statuses = statuses || ARRAY(SELECT jsonb_build_object('level', 'info', 'msg', 'status ' || g, 'ts', g) FROM generate_series($2::int, $3::int) g) WHERE id = $1",
+        )
+        .bind(id)
+        .bind(from)
+        .bind(to)
+        .bind(state)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    /// Number of bytes the statuses take up when serialized, a proxy for how much is transferred
+    fn transfer_size(statuses: &[crate::Statuses]) -> usize {
+        serde_json::to_vec(statuses).unwrap().len()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn incremental_updates_only_transfer_new_statuses(pool: sqlx::PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let id: sqlx::types::Uuid = sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id) VALUES ('restore', '1') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        append_statuses(&pool, id, 1, 1500, "running").await;
+
+        let stream = reactive_incremental(
+            &pool,
+            &id.to_string(),
+            0,
+            PollTaskOptions {
+                interval: 1,
+                timeout_nostatuschange: 0,
+            },
+        )
+        .unwrap();
+        let mut stream = std::pin::pin!(stream);
+
+        let mut job = Job::from_id(id, &pool).await.unwrap();
+        let full_size = transfer_size(&job.statuses);
+        job.statuses.clear();
+
+        // The first tick catches up on everything
+        let JobUpdate::NewStatuses(initial) = stream.next().await.unwrap().unwrap() else {
+            panic!("expected the initial statuses first");
+        };
+        assert_eq!(initial.len(), 1500);
+        assert_eq!(transfer_size(&initial), full_size);
+        JobUpdate::NewStatuses(initial).apply(&mut job);
+
+        assert!(matches!(
+            stream.next().await.unwrap().unwrap(),
+            JobUpdate::StateChanged(JobState::Running)
+        ));
+
+        append_statuses(&pool, id, 1501, 1505, "completed").await;
+
+        // Later ticks only fetch what was appended, not the whole array again
+        let JobUpdate::NewStatuses(new) = stream.next().await.unwrap().unwrap() else {
+            panic!("expected the appended statuses");
+        };
+        assert_eq!(
+            new.iter().map(|s| s.msg.as_str()).collect::<Vec<_>>(),
+            [
+                "status 1501",
+                "status 1502",
+                "status 1503",
+                "status 1504",
+                "status 1505"
+            ]
+        );
+        assert!(transfer_size(&new) * 100 < full_size);
+        JobUpdate::NewStatuses(new).apply(&mut job);
+
+        assert!(matches!(
+            stream.next().await.unwrap().unwrap(),
+            JobUpdate::StateChanged(JobState::Completed)
+        ));
+
+        let JobUpdate::Completed(completed) = stream.next().await.unwrap().unwrap() else {
+            panic!("expected the completed job");
+        };
+        assert!(stream.next().await.is_none());
+
+        // The statuses built up incrementally match the final job
+        assert_eq!(completed.statuses.len(), 1505);
+        assert!(job.statuses == completed.statuses);
+    }
+}
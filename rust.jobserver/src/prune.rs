@@ -0,0 +1,382 @@
+use indexmap::IndexMap;
+use serenity::all::{ChannelId, GuildId, MessageId, UserId};
+
+use crate::{Error, Job, Spawn, SpawnResponse, Statuses};
+
+/// Name of the job deleting messages in bulk
+pub const PRUNE_MESSAGES_JOB: &str = "message_prune";
+
+/// Maximum number of messages a single prune job may delete
+pub const MAX_PRUNE_MESSAGES: u32 = 1000;
+
+/// Key of the job field recording whether the prune is a dry run
+pub const DRY_RUN_FIELD: &str = "dry_run";
+
+/// Keys of the status ``extra_info`` the prune job reports per channel counts with
+const STATUS_CHANNEL_ID_KEY: &str = "channel_id";
+const STATUS_COUNT_KEY: &str = "count";
+
+/// A bound of the range of messages to prune
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneBound {
+    Message(MessageId),
+    Timestamp(chrono::DateTime<chrono::Utc>),
+}
+
+impl PruneBound {
+    fn to_json(self) -> serde_json::Value {
+        match self {
+            PruneBound::Message(message_id) => serde_json::json!({
+                "MessageID": message_id.to_string(),
+            }),
+            PruneBound::Timestamp(ts) => serde_json::json!({
+                "Timestamp": ts,
+            }),
+        }
+    }
+}
+
+/// Options for pruning messages
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct PruneOptions {
+    pub channels: Vec<ChannelId>,
+    /// Only prune messages of this user
+    pub user_filter: Option<UserId>,
+    /// Only prune messages sent before this bound
+    pub before: Option<PruneBound>,
+    /// Only prune messages sent after this bound
+    pub after: Option<PruneBound>,
+    /// Maximum number of messages to prune across all channels
+    pub max_messages: u32,
+    /// Only count the messages which would be pruned without deleting them
+    pub dry_run: bool,
+}
+
+impl Default for PruneOptions {
+    fn default() -> Self {
+        PruneOptions {
+            channels: Vec::new(),
+            user_filter: None,
+            before: None,
+            after: None,
+            max_messages: 100,
+            dry_run: true,
+        }
+    }
+}
+
+impl PruneOptions {
+    /// Validates the options against the prune limits
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.channels.is_empty() {
+            return Err("At least one channel must be selected".into());
+        }
+
+        for (i, channel_id) in self.channels.iter().enumerate() {
+            if self.channels[..i].contains(channel_id) {
+                return Err(format!("Channel {} is selected more than once", channel_id).into());
+            }
+        }
+
+        if self.max_messages == 0 {
+            return Err("Maximum number of messages must be greater than 0".into());
+        }
+
+        if self.max_messages > MAX_PRUNE_MESSAGES {
+            return Err(format!(
+                "Cannot prune more than {} messages at once",
+                MAX_PRUNE_MESSAGES
+            )
+            .into());
+        }
+
+        if self.before.is_none() && self.after.is_none() {
+            return Err("At least one of before or after must be set".into());
+        }
+
+        let empty_range = match (self.before, self.after) {
+            (Some(PruneBound::Message(before)), Some(PruneBound::Message(after))) => {
+                after >= before
+            }
+            (Some(PruneBound::Timestamp(before)), Some(PruneBound::Timestamp(after))) => {
+                after >= before
+            }
+            _ => false,
+        };
+
+        if empty_range {
+            return Err("The after bound must be earlier than the before bound".into());
+        }
+
+        Ok(())
+    }
+
+    /// Validates the options and converts them to a ``Spawn`` for the prune job
+    pub fn to_spawn(&self, guild_id: GuildId, user_id: UserId) -> Result<Spawn, Error> {
+        self.validate()?;
+
+        Ok(Spawn {
+            name: PRUNE_MESSAGES_JOB.to_string(),
+            data: serde_json::json!({
+                "Options": {
+                    "Channels": self.channels.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+                    "UserFilter": self.user_filter.map(|u| u.to_string()),
+                    "Before": self.before.map(PruneBound::to_json),
+                    "After": self.after.map(PruneBound::to_json),
+                    "MaxMessages": self.max_messages,
+                    "DryRun": self.dry_run,
+                },
+                "UserID": user_id.to_string(),
+                DRY_RUN_FIELD: self.dry_run,
+            }),
+            create: true,
+            execute: true,
+            id: None,
+            guild_id: guild_id.to_string(),
+        })
+    }
+}
+
+/// Validates the options and spawns a prune job, returning its id
+pub async fn create_prune_job(
+    reqwest: &reqwest::Client,
+    opts: &PruneOptions,
+    guild_id: GuildId,
+    user_id: UserId,
+    jobserver_addr: &str,
+    jobserver_port: u16,
+) -> Result<SpawnResponse, Error> {
+    let spawn = opts.to_spawn(guild_id, user_id)?;
+
+    crate::spawn::spawn_task(reqwest, &spawn, jobserver_addr, jobserver_port).await
+}
+
+/// Returns true if the job is a prune job which was spawned as a dry run
+///
+/// The top level ``dry_run`` field is checked first, falling back to ``Options.DryRun`` for jobs spawned before it
+/// was written. Prune jobs missing both are treated as dry runs, so a job is never shown as having deleted messages
+/// unless it says so
+pub fn is_dry_run(job: &Job) -> bool {
+    if job.name != PRUNE_MESSAGES_JOB {
+        return false;
+    }
+
+    job.fields
+        .get(DRY_RUN_FIELD)
+        .and_then(|v| v.as_bool())
+        .or_else(|| {
+            job.fields
+                .get("Options")
+                .and_then(|o| o.get("DryRun"))
+                .and_then(|v| v.as_bool())
+        })
+        .unwrap_or(true)
+}
+
+/// Number of messages pruned (or with a dry run, which would be pruned) per channel
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub per_channel: IndexMap<ChannelId, u64>,
+}
+
+impl PruneReport {
+    /// Parses the report from the statuses of a prune job
+    ///
+    /// The job reports a running count per channel through the ``channel_id`` and ``count`` keys of a status'
+    /// extra info, so the last count of each channel wins. Statuses without both keys are ignored
+    pub fn from_statuses(statuses: &[Statuses], dry_run: bool) -> Result<Self, Error> {
+        let mut per_channel = IndexMap::new();
+
+        for status in statuses {
+            let (Some(channel_id), Some(count)) = (
+                status.extra_info.get(STATUS_CHANNEL_ID_KEY),
+                status.extra_info.get(STATUS_COUNT_KEY),
+            ) else {
+                continue;
+            };
+
+            let channel_id: ChannelId = match channel_id {
+                serde_json::Value::String(s) => s.parse()?,
+                serde_json::Value::Number(n) => n.to_string().parse()?,
+                _ => {
+                    return Err(
+                        format!("Invalid channel id in prune status: {}", channel_id).into(),
+                    )
+                }
+            };
+
+            let count = count
+                .as_u64()
+                .ok_or_else(|| format!("Invalid count in prune status: {}", count))?;
+
+            per_channel.insert(channel_id, count);
+        }
+
+        Ok(PruneReport {
+            dry_run,
+            per_channel,
+        })
+    }
+
+    /// Parses the report of a prune job
+    pub fn from_job(job: &Job) -> Result<Self, Error> {
+        if job.name != PRUNE_MESSAGES_JOB {
+            return Err(format!("Job {} is not a prune job", job.id).into());
+        }
+
+        Self::from_statuses(&job.statuses, is_dry_run(job))
+    }
+
+    /// Total number of messages across all channels
+    pub fn total(&self) -> u64 {
+        self.per_channel.values().sum()
+    }
+}
+
+impl std::fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = if self.dry_run {
+            "would be deleted"
+        } else {
+            "were deleted"
+        };
+
+        write!(f, "{} messages {}", self.total(), verb)?;
+
+        for (channel_id, count) in self.per_channel.iter() {
+            write!(f, "\n<#{}>: {}", channel_id, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JobState;
+
+    fn job_from_spawn(spawn: &Spawn, statuses: Vec<Statuses>) -> Job {
+        Job {
+            id: uuid::Uuid::new_v4(),
+            name: spawn.name.clone(),
+            output: None,
+            fields: serde_json::from_value(spawn.data.clone()).unwrap(),
+            statuses,
+            guild_id: spawn.guild_id.parse().unwrap(),
+            expiry: None,
+            state: JobState::Completed,
+            resumable: false,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn count_status(channel_id: ChannelId, count: u64) -> Statuses {
+        let mut extra_info = IndexMap::new();
+        extra_info.insert(
+            STATUS_CHANNEL_ID_KEY.to_string(),
+            serde_json::Value::String(channel_id.to_string()),
+        );
+        extra_info.insert(STATUS_COUNT_KEY.to_string(), serde_json::json!(count));
+
+        Statuses {
+            level: "info".to_string(),
+            msg: "Pruned messages".to_string(),
+            ts: 0.0,
+            bot_display_ignore: None,
+            extra_info,
+        }
+    }
+
+    fn opts(dry_run: bool) -> PruneOptions {
+        PruneOptions {
+            channels: vec![ChannelId::new(10), ChannelId::new(20)],
+            before: Some(PruneBound::Message(MessageId::new(1000))),
+            dry_run,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn real_prune_is_not_reported_as_dry_run() {
+        let spawn = opts(false)
+            .to_spawn(GuildId::new(1), UserId::new(2))
+            .unwrap();
+
+        let job = job_from_spawn(
+            &spawn,
+            vec![
+                count_status(ChannelId::new(10), 3),
+                count_status(ChannelId::new(10), 5),
+                count_status(ChannelId::new(20), 2),
+            ],
+        );
+
+        assert!(!is_dry_run(&job));
+
+        let report = PruneReport::from_job(&job).unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(report.per_channel.get(&ChannelId::new(10)), Some(&5));
+        assert_eq!(report.total(), 7);
+        assert!(report.to_string().starts_with("7 messages were deleted"));
+    }
+
+    #[test]
+    fn dry_run_prune_is_reported_as_dry_run() {
+        let spawn = opts(true)
+            .to_spawn(GuildId::new(1), UserId::new(2))
+            .unwrap();
+
+        let job = job_from_spawn(&spawn, vec![count_status(ChannelId::new(20), 4)]);
+
+        assert!(is_dry_run(&job));
+
+        let report = PruneReport::from_job(&job).unwrap();
+        assert!(report.dry_run);
+        assert!(report
+            .to_string()
+            .starts_with("4 messages would be deleted"));
+    }
+
+    #[test]
+    fn falls_back_to_options_dry_run() {
+        let spawn = opts(false)
+            .to_spawn(GuildId::new(1), UserId::new(2))
+            .unwrap();
+
+        let mut job = job_from_spawn(&spawn, Vec::new());
+        job.fields.shift_remove(DRY_RUN_FIELD);
+
+        assert!(!is_dry_run(&job));
+
+        job.fields.shift_remove("Options");
+
+        assert!(is_dry_run(&job));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_options() {
+        assert!(opts(true).validate().is_ok());
+
+        let mut no_channels = opts(true);
+        no_channels.channels.clear();
+        assert!(no_channels.validate().is_err());
+
+        let mut duplicate = opts(true);
+        duplicate.channels.push(ChannelId::new(10));
+        assert!(duplicate.validate().is_err());
+
+        let mut too_many = opts(true);
+        too_many.max_messages = MAX_PRUNE_MESSAGES + 1;
+        assert!(too_many.validate().is_err());
+
+        let mut unbounded = opts(true);
+        unbounded.before = None;
+        assert!(unbounded.validate().is_err());
+
+        let mut empty_range = opts(true);
+        empty_range.after = Some(PruneBound::Message(MessageId::new(1000)));
+        assert!(empty_range.validate().is_err());
+    }
+}
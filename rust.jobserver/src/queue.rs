@@ -0,0 +1,619 @@
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::spawn::{check_limits, spawn_task, SpawnDenied, SpawnLimits};
+use crate::{Error, Spawn};
+
+/// How long a claimed entry is reserved for its spawn before it counts as abandoned and may be claimed again
+const CLAIM_LEASE_SECS: f64 = 300.0;
+
+/// A job waiting for its guild to have capacity
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct QueuedJob {
+    pub id: Uuid,
+    pub guild_id: String,
+    pub name: String,
+    /// 1-based position in the guild's queue
+    pub position: i64,
+    /// Number of failed attempts to spawn this job
+    pub attempts: i32,
+    /// The error of the last failed attempt
+    pub last_error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct QueuedJobRow {
+    id: Uuid,
+    guild_id: String,
+    name: String,
+    spawn: serde_json::Value,
+    attempts: i32,
+    last_error: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Matches entries waiting to be spawned, i.e. not claimed by a spawn in flight (or whose claim was abandoned)
+fn waiting(alias: &str) -> String {
+    format!(
+        "({alias}.claimed_at IS NULL OR {alias}.claimed_at < NOW() - make_interval(secs => {}))",
+        CLAIM_LEASE_SECS,
+    )
+}
+
+/// Matches entries claimed by a spawn in flight
+fn in_flight() -> String {
+    format!(
+        "(claimed_at IS NOT NULL AND claimed_at >= NOW() - make_interval(secs => {}))",
+        CLAIM_LEASE_SECS
+    )
+}
+
+/// Takes the queue lock of a guild until the end of the transaction
+///
+/// ``spawn_task_guarded`` and ``drain_queue`` check the limits and claim capacity under this lock, so two spawns can
+/// never both see the same free capacity and a guild's queue is only ever drained by one caller at a time
+pub async fn lock_guild(tx: &mut Transaction<'_, Postgres>, guild_id: &str) -> Result<(), Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended('job_queue/' || $1, 0))")
+        .bind(guild_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Queues a spawn, returning the id of the queue entry
+pub async fn enqueue(db: impl sqlx::PgExecutor<'_>, spawn: &Spawn) -> Result<Uuid, Error> {
+    let id: Uuid = sqlx::query_scalar(
+        "INSERT INTO job_queue (guild_id, name, spawn) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(&spawn.guild_id)
+    .bind(&spawn.name)
+    .bind(serde_json::to_value(spawn)?)
+    .fetch_one(db)
+    .await?;
+
+    Ok(id)
+}
+
+/// Inserts an entry that is already claimed, reserving capacity for a spawn in flight until ``release`` is called
+pub(crate) async fn reserve(db: impl sqlx::PgExecutor<'_>, spawn: &Spawn) -> Result<Uuid, Error> {
+    let id: Uuid = sqlx::query_scalar(
+        "INSERT INTO job_queue (guild_id, name, spawn, claimed_at) VALUES ($1, $2, $3, NOW()) RETURNING id",
+    )
+    .bind(&spawn.guild_id)
+    .bind(&spawn.name)
+    .bind(serde_json::to_value(spawn)?)
+    .fetch_one(db)
+    .await?;
+
+    Ok(id)
+}
+
+/// Removes an entry once its spawn is done
+pub(crate) async fn release(db: impl sqlx::PgExecutor<'_>, id: Uuid) -> Result<(), Error> {
+    sqlx::query("DELETE FROM job_queue WHERE id = $1")
+        .bind(id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Puts a claimed entry back in the queue after a failed spawn, counting the attempt
+async fn record_failure(db: impl sqlx::PgExecutor<'_>, id: Uuid, error: &str) -> Result<(), Error> {
+    sqlx::query(
+        "UPDATE job_queue SET claimed_at = NULL, attempts = attempts + 1, last_error = $2 WHERE id = $1",
+    )
+    .bind(id)
+    .bind(error)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns whether a guild has jobs waiting in the queue
+pub async fn has_queued(db: impl sqlx::PgExecutor<'_>, guild_id: &str) -> Result<bool, Error> {
+    let exists: bool = sqlx::query_scalar(&format!(
+        "SELECT EXISTS(SELECT 1 FROM job_queue q WHERE q.guild_id = $1 AND {})",
+        waiting("q")
+    ))
+    .bind(guild_id)
+    .fetch_one(db)
+    .await?;
+
+    Ok(exists)
+}
+
+/// Returns the number of spawns of a guild in flight, optionally only those whose name starts with ``name_prefix``
+///
+/// These are counted by ``check_limits`` as the jobs they create may not exist yet
+pub async fn count_in_flight(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: &str,
+    name_prefix: Option<&str>,
+) -> Result<i64, Error> {
+    let count: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM job_queue WHERE guild_id = $1 AND {} AND ($2::text IS NULL OR starts_with(name, $2))",
+        in_flight()
+    ))
+    .bind(guild_id)
+    .bind(name_prefix)
+    .fetch_one(db)
+    .await?;
+
+    Ok(count)
+}
+
+/// Returns the 1-based position of a queue entry within its guild's queue, None if it is no longer waiting
+pub async fn position(db: impl sqlx::PgExecutor<'_>, id: Uuid) -> Result<Option<i64>, Error> {
+    let position: Option<i64> = sqlx::query_scalar(&format!(
+        "SELECT (SELECT COUNT(*) FROM job_queue q WHERE q.guild_id = e.guild_id AND {} AND (q.created_at, q.id) <= (e.created_at, e.id)) FROM job_queue e WHERE e.id = $1 AND {}",
+        waiting("q"),
+        waiting("e")
+    ))
+    .bind(id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(position)
+}
+
+/// Returns the queued jobs of a guild in the order they will be spawned
+pub async fn list_guild(
+    pool: &PgPool,
+    guild_id: serenity::all::GuildId,
+) -> Result<Vec<QueuedJob>, Error> {
+    let recs: Vec<QueuedJobRow> = sqlx::query_as(&format!(
+        "SELECT id, guild_id, name, spawn, attempts, last_error, created_at FROM job_queue q WHERE guild_id = $1 AND {} ORDER BY created_at ASC, id ASC",
+        waiting("q")
+    ))
+    .bind(guild_id.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    Ok(recs
+        .into_iter()
+        .enumerate()
+        .map(|(i, rec)| QueuedJob {
+            id: rec.id,
+            guild_id: rec.guild_id,
+            name: rec.name,
+            position: i as i64 + 1,
+            attempts: rec.attempts,
+            last_error: rec.last_error,
+            created_at: rec.created_at,
+        })
+        .collect())
+}
+
+pub struct DrainOptions {
+    pub jobserver_addr: String,
+    pub jobserver_port: u16,
+    pub limits: SpawnLimits,
+    /// Entries which failed to spawn this many times are dropped from the queue, None retries them forever
+    pub max_attempts: Option<i32>,
+}
+
+impl DrainOptions {
+    pub fn new(jobserver_addr: String, jobserver_port: u16, limits: SpawnLimits) -> Self {
+        Self {
+            jobserver_addr,
+            jobserver_port,
+            limits,
+            max_attempts: Some(10),
+        }
+    }
+}
+
+/// Statistics for a single ``drain_queue`` run
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct DrainStats {
+    pub spawned: usize,
+    pub failed: usize,
+    /// Entries dropped after reaching ``DrainOptions::max_attempts`` or whose spawn could not be decoded
+    pub dropped: usize,
+}
+
+/// Spawns queued jobs of every guild as capacity frees up, in FIFO order per guild
+///
+/// A guild's queue is not skipped ahead of: once its oldest entry is still at capacity, the rest of that guild
+/// waits for the next run. Each entry is claimed under the guild's queue lock and the HTTP spawn happens after the
+/// lock is released. Entries which fail to spawn stay queued with their attempt counted, and the guild is retried on
+/// the next run
+pub async fn drain_queue(
+    pool: &PgPool,
+    reqwest: &reqwest::Client,
+    opts: &DrainOptions,
+) -> Result<DrainStats, Error> {
+    let guild_ids: Vec<String> = sqlx::query_scalar(&format!(
+        "SELECT DISTINCT guild_id FROM job_queue q WHERE {}",
+        waiting("q")
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    let mut stats = DrainStats::default();
+
+    for guild_id in guild_ids {
+        loop {
+            let mut tx = pool.begin().await?;
+
+            lock_guild(&mut tx, &guild_id).await?;
+
+            let rec: Option<QueuedJobRow> = sqlx::query_as(&format!(
+                "SELECT id, guild_id, name, spawn, attempts, last_error, created_at FROM job_queue q WHERE guild_id = $1 AND {} ORDER BY created_at ASC, id ASC LIMIT 1",
+                waiting("q")
+            ))
+            .bind(&guild_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let Some(rec) = rec else {
+                break;
+            };
+
+            if opts.max_attempts.is_some_and(|max| rec.attempts >= max) {
+                log::error!(
+                    "Dropping queued job {} ({}) for guild {} after {} failed attempts, last error: {}",
+                    rec.id,
+                    rec.name,
+                    guild_id,
+                    rec.attempts,
+                    rec.last_error.as_deref().unwrap_or("none")
+                );
+
+                release(&mut *tx, rec.id).await?;
+                tx.commit().await?;
+                stats.dropped += 1;
+                continue;
+            }
+
+            let spawn: Spawn = match serde_json::from_value(rec.spawn) {
+                Ok(spawn) => spawn,
+                Err(e) => {
+                    log::error!(
+                        "Dropping queued job {} for guild {} as it could not be decoded: {}",
+                        rec.id,
+                        guild_id,
+                        e
+                    );
+
+                    release(&mut *tx, rec.id).await?;
+                    tx.commit().await?;
+                    stats.dropped += 1;
+                    continue;
+                }
+            };
+
+            match check_limits(&mut tx, &spawn, &opts.limits).await {
+                Ok(()) => {}
+                Err(SpawnDenied::TooManyActive { .. }) => break,
+                Err(SpawnDenied::Error(e)) => return Err(e),
+            }
+
+            sqlx::query("UPDATE job_queue SET claimed_at = NOW() WHERE id = $1")
+                .bind(rec.id)
+                .execute(&mut *tx)
+                .await?;
+
+            tx.commit().await?;
+
+            match spawn_task(reqwest, &spawn, &opts.jobserver_addr, opts.jobserver_port).await {
+                Ok(_) => {
+                    release(pool, rec.id).await?;
+                    stats.spawned += 1;
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to spawn queued job {} ({}) for guild {} (attempt {}): {}",
+                        rec.id,
+                        spawn.name,
+                        guild_id,
+                        rec.attempts + 1,
+                        e
+                    );
+
+                    record_failure(pool, rec.id, &e.to_string()).await?;
+                    stats.failed += 1;
+
+                    // Retrying now would hit the same entry again, leave the guild for the next run
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+    use crate::spawn::{spawn_task_guarded, GuardedSpawn};
+    use crate::test_schema::{create_tables, JOBS, JOB_QUEUE};
+    use axum::http::StatusCode;
+    use serenity::all::GuildId;
+    use std::time::Duration;
+
+    const GUILD: &str = "1";
+    const ADDR: &str = "http://127.0.0.1";
+
+    fn spawn(name: &str) -> Spawn {
+        Spawn {
+            name: name.to_string(),
+            data: serde_json::json!({}),
+            create: true,
+            execute: true,
+            id: None,
+            guild_id: GUILD.to_string(),
+        }
+    }
+
+    fn limits(max_per_guild: i64, queue: bool) -> SpawnLimits {
+        SpawnLimits {
+            max_per_guild: Some(max_per_guild),
+            max_per_name: Default::default(),
+            queue,
+        }
+    }
+
+    fn drain_opts(server: &MockServer, max_per_guild: i64) -> DrainOptions {
+        DrainOptions::new(ADDR.to_string(), server.port(), limits(max_per_guild, true))
+    }
+
+    async fn jobserver(fail_first: usize) -> MockServer {
+        MockServer::start(move |_, i| {
+            if i < fail_first {
+                MockResponse::status(StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                MockResponse::ok(serde_json::json!({ "id": i.to_string() }))
+            }
+        })
+        .await
+    }
+
+    fn spawned_names(server: &MockServer) -> Vec<String> {
+        server
+            .requests()
+            .iter()
+            .map(|r| r.body["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    async fn queued_names(pool: &PgPool) -> Vec<String> {
+        list_guild(pool, GuildId::new(1))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|q| q.name)
+            .collect()
+    }
+
+    async fn insert_active_job(pool: &PgPool) {
+        sqlx::query("INSERT INTO jobs (name, guild_id, state) VALUES ('backup', $1, 'running')")
+            .bind(GUILD)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn drains_in_fifo_order_once_capacity_frees_up(pool: PgPool) {
+        create_tables(&pool, &[JOBS, JOB_QUEUE]).await;
+
+        let server = jobserver(0).await;
+        let client = reqwest::Client::new();
+
+        insert_active_job(&pool).await;
+
+        for name in ["a", "b", "c"] {
+            enqueue(&pool, &spawn(name)).await.unwrap();
+        }
+
+        let stats = drain_queue(&pool, &client, &drain_opts(&server, 1))
+            .await
+            .unwrap();
+        assert_eq!(stats.spawned, 0);
+        assert!(server.requests().is_empty());
+
+        sqlx::query("DELETE FROM jobs")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let stats = drain_queue(&pool, &client, &drain_opts(&server, 3))
+            .await
+            .unwrap();
+        assert_eq!(stats.spawned, 3);
+        assert_eq!(spawned_names(&server), ["a", "b", "c"]);
+        assert!(queued_names(&pool).await.is_empty());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn failed_spawns_stay_queued(pool: PgPool) {
+        create_tables(&pool, &[JOBS, JOB_QUEUE]).await;
+
+        let server = jobserver(1).await;
+        let client = reqwest::Client::new();
+
+        for name in ["a", "b"] {
+            enqueue(&pool, &spawn(name)).await.unwrap();
+        }
+
+        let stats = drain_queue(&pool, &client, &drain_opts(&server, 5))
+            .await
+            .unwrap();
+        assert_eq!((stats.spawned, stats.failed), (0, 1));
+
+        let queued = list_guild(&pool, GuildId::new(1)).await.unwrap();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].name, "a");
+        assert_eq!(queued[0].attempts, 1);
+        assert!(queued[0].last_error.is_some());
+        assert_eq!(queued[1].attempts, 0);
+
+        let stats = drain_queue(&pool, &client, &drain_opts(&server, 5))
+            .await
+            .unwrap();
+        assert_eq!((stats.spawned, stats.failed), (2, 0));
+        assert_eq!(spawned_names(&server), ["a", "a", "b"]);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn entries_are_dropped_after_max_attempts(pool: PgPool) {
+        create_tables(&pool, &[JOBS, JOB_QUEUE]).await;
+
+        let server = jobserver(usize::MAX).await;
+        let client = reqwest::Client::new();
+
+        for name in ["a", "b"] {
+            enqueue(&pool, &spawn(name)).await.unwrap();
+        }
+
+        let mut opts = drain_opts(&server, 5);
+        opts.max_attempts = Some(2);
+
+        for _ in 0..2 {
+            let stats = drain_queue(&pool, &client, &opts).await.unwrap();
+            assert_eq!((stats.failed, stats.dropped), (1, 0));
+        }
+
+        let stats = drain_queue(&pool, &client, &opts).await.unwrap();
+        assert_eq!((stats.failed, stats.dropped), (1, 1));
+        assert_eq!(queued_names(&pool).await, ["b"]);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn in_flight_spawns_count_towards_limits(pool: PgPool) {
+        create_tables(&pool, &[JOBS, JOB_QUEUE]).await;
+
+        let reservation = reserve(&pool, &spawn("a")).await.unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+
+        assert!(matches!(
+            check_limits(&mut conn, &spawn("b"), &limits(1, false)).await,
+            Err(SpawnDenied::TooManyActive {
+                active: 1,
+                limit: 1
+            })
+        ));
+
+        // A spawn in flight is not waiting in the queue
+        assert!(!has_queued(&pool, GUILD).await.unwrap());
+        assert!(queued_names(&pool).await.is_empty());
+
+        release(&pool, reservation).await.unwrap();
+
+        assert!(check_limits(&mut conn, &spawn("b"), &limits(1, false))
+            .await
+            .is_ok());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn guarded_spawns_queue_behind_waiting_entries(pool: PgPool) {
+        create_tables(&pool, &[JOBS, JOB_QUEUE]).await;
+
+        let server = jobserver(0).await;
+        let client = reqwest::Client::new();
+
+        insert_active_job(&pool).await;
+
+        for (name, expected) in [("a", 1), ("b", 2)] {
+            let res = spawn_task_guarded(
+                &client,
+                &pool,
+                &spawn(name),
+                ADDR,
+                server.port(),
+                &limits(1, true),
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(res, GuardedSpawn::Queued { position, .. } if position == expected));
+        }
+
+        sqlx::query("DELETE FROM jobs")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // There is capacity again but a and b are still waiting, c must not skip ahead of them
+        let res = spawn_task_guarded(
+            &client,
+            &pool,
+            &spawn("c"),
+            ADDR,
+            server.port(),
+            &limits(3, true),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(res, GuardedSpawn::Queued { position: 3, .. }));
+
+        drain_queue(&pool, &client, &drain_opts(&server, 3))
+            .await
+            .unwrap();
+        assert_eq!(spawned_names(&server), ["a", "b", "c"]);
+
+        let res = spawn_task_guarded(
+            &client,
+            &pool,
+            &spawn("d"),
+            ADDR,
+            server.port(),
+            &limits(3, true),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(res, GuardedSpawn::Spawned(_)));
+        assert_eq!(count_in_flight(&pool, GUILD, None).await.unwrap(), 0);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn concurrent_guarded_spawns_respect_the_limit(pool: PgPool) {
+        create_tables(&pool, &[JOBS, JOB_QUEUE]).await;
+
+        let server = MockServer::start(|_, i| {
+            MockResponse::ok(serde_json::json!({ "id": i.to_string() }))
+                .delayed(Duration::from_millis(200))
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let spawns = (0..5).map(|i| {
+            let (pool, client) = (pool.clone(), client.clone());
+            let port = server.port();
+
+            async move {
+                spawn_task_guarded(
+                    &client,
+                    &pool,
+                    &spawn(&i.to_string()),
+                    ADDR,
+                    port,
+                    &limits(2, false),
+                )
+                .await
+            }
+        });
+
+        let results = futures_util::future::join_all(spawns).await;
+
+        let spawned = results
+            .iter()
+            .filter(|r| matches!(r, Ok(GuardedSpawn::Spawned(_))))
+            .count();
+        let denied = results
+            .iter()
+            .filter(|r| matches!(r, Err(SpawnDenied::TooManyActive { .. })))
+            .count();
+
+        assert_eq!((spawned, denied), (2, 3));
+        assert_eq!(server.requests().len(), 2);
+    }
+}
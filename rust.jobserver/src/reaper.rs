@@ -0,0 +1,243 @@
+use crate::Error;
+use crate::Job;
+use silverpelt::objectstore::{guild_bucket, ObjectStore};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct ReaperOptions {
+    /// The interval at which to look for expired jobs in seconds
+    pub interval: u64,
+
+    /// The maximum number of expired jobs to fetch at once
+    pub batch_size: i64,
+}
+
+impl Default for ReaperOptions {
+    fn default() -> Self {
+        ReaperOptions {
+            interval: 300,
+            batch_size: 100,
+        }
+    }
+}
+
+impl ReaperOptions {
+    /// Validates the options, ``run_reaper`` refuses to start with invalid options
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.interval == 0 {
+            return Err("Reaper interval must be greater than 0 seconds".into());
+        }
+
+        if self.batch_size < 1 {
+            return Err("Reaper batch size must be greater than 0".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Statistics for a single reaper iteration
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct ReaperStats {
+    /// Number of jobs that were successfully deleted
+    pub reaped: usize,
+    /// Number of jobs that could not be deleted
+    pub failed: usize,
+}
+
+/// Returns up to ``limit`` jobs whose expiry has passed, oldest first, skipping the jobs in ``exclude``
+pub async fn get_expired(pool: &PgPool, limit: i64, exclude: &[Uuid]) -> Result<Vec<Job>, Error> {
+    let recs = sqlx::query_as(
+        "SELECT id, name, output, statuses, guild_id, expiry, state, created_at, fields, resumable FROM jobs WHERE expiry IS NOT NULL AND (created_at + expiry) < NOW() AND id <> ALL($2) ORDER BY created_at ASC LIMIT $1",
+    )
+    .bind(limit)
+    .bind(exclude)
+    .fetch_all(pool)
+    .await?;
+
+    let mut jobs = Vec::new();
+
+    for rec in recs {
+        jobs.push(Job::from_pgrow(rec)?);
+    }
+
+    Ok(jobs)
+}
+
+/// Deletes a single expired job, treating an already missing output file as deleted
+async fn reap_job(job: Job, pool: &PgPool, object_store: &ObjectStore) -> Result<(), Error> {
+    if let Some(path) = job.get_file_path() {
+        if object_store
+            .exists(&guild_bucket(job.guild_id), &path)
+            .await?
+        {
+            return job.delete(pool, object_store).await;
+        }
+
+        log::info!(
+            "Output of expired job {} is already gone, deleting database row only",
+            job.id
+        );
+    }
+
+    job.delete_from_db(pool).await
+}
+
+/// Runs a single reaper iteration, deleting expired jobs ``opts.batch_size`` at a time until none are left
+///
+/// A failure to delete one job does not stop the rest from being reaped, failed jobs are skipped for the rest of the
+/// iteration so they cannot hold up the jobs behind them and are retried on the next iteration
+pub async fn reap_once(
+    pool: &PgPool,
+    object_store: &ObjectStore,
+    opts: &ReaperOptions,
+) -> Result<ReaperStats, Error> {
+    let mut stats = ReaperStats::default();
+    let mut failed = Vec::new();
+
+    loop {
+        let jobs = get_expired(pool, opts.batch_size, &failed).await?;
+        let fetched = jobs.len();
+
+        for job in jobs {
+            let id = job.id;
+            let guild_id = job.guild_id;
+
+            match reap_job(job, pool, object_store).await {
+                Ok(()) => {
+                    log::info!("Reaped expired job {} (guild {})", id, guild_id);
+                    stats.reaped += 1;
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to reap expired job {} (guild {}): {}",
+                        id,
+                        guild_id,
+                        e
+                    );
+                    stats.failed += 1;
+                    failed.push(id);
+                }
+            }
+        }
+
+        if (fetched as i64) < opts.batch_size {
+            return Ok(stats);
+        }
+    }
+}
+
+/// Runs the reaper forever, deleting expired jobs every ``opts.interval`` seconds
+///
+/// Only returns (with an error) if ``opts`` is invalid, see ``ReaperOptions::validate``
+pub async fn run_reaper(
+    pool: PgPool,
+    object_store: &ObjectStore,
+    opts: ReaperOptions,
+) -> Result<std::convert::Infallible, Error> {
+    opts.validate()?;
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(opts.interval));
+
+    loop {
+        interval.tick().await;
+
+        match reap_once(&pool, object_store, &opts).await {
+            Ok(stats) => {
+                if stats.reaped > 0 || stats.failed > 0 {
+                    log::info!(
+                        "Job reaper iteration done: reaped={}, failed={}",
+                        stats.reaped,
+                        stats.failed
+                    );
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to fetch expired jobs: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, JOBS};
+
+    async fn insert_expired_job(pool: &PgPool, age_secs: i64) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id, state, expiry, created_at) VALUES ('backup', '1', 'completed', interval '1 second', NOW() - make_interval(secs => $1)) RETURNING id",
+        )
+        .bind(age_secs as f64)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_rejects_zero_interval_and_batch_size() {
+        assert!(ReaperOptions::default().validate().is_ok());
+
+        let zero_interval = ReaperOptions {
+            interval: 0,
+            ..Default::default()
+        };
+        assert!(zero_interval.validate().is_err());
+
+        let zero_batch = ReaperOptions {
+            batch_size: 0,
+            ..Default::default()
+        };
+        assert!(zero_batch.validate().is_err());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn failed_jobs_do_not_block_the_jobs_behind_them(pool: PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        // The oldest jobs fill a whole batch and cannot be deleted
+        let mut broken = Vec::new();
+        for i in 0..2 {
+            broken.push(insert_expired_job(&pool, 1000 - i).await);
+        }
+
+        let mut expired = Vec::new();
+        for i in 0..3 {
+            expired.push(insert_expired_job(&pool, 100 - i).await);
+        }
+
+        sqlx::raw_sql(&format!(
+            "CREATE FUNCTION reject_delete() RETURNS trigger AS $$ BEGIN RAISE EXCEPTION 'rejected'; END $$ LANGUAGE plpgsql;
+            CREATE TRIGGER reject_delete BEFORE DELETE ON jobs FOR EACH ROW WHEN (OLD.id IN ('{}', '{}')) EXECUTE FUNCTION reject_delete();",
+            broken[0], broken[1]
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let opts = ReaperOptions {
+            batch_size: 2,
+            ..Default::default()
+        };
+        let stats = reap_once(&pool, &ObjectStore::new_memory(), &opts)
+            .await
+            .unwrap();
+        assert_eq!(stats.reaped, 3);
+        assert_eq!(stats.failed, 2);
+
+        let mut left: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM jobs")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        left.sort();
+        broken.sort();
+        assert_eq!(left, broken);
+
+        // Failed jobs are retried on the next iteration
+        let stats = reap_once(&pool, &ObjectStore::new_memory(), &opts)
+            .await
+            .unwrap();
+        assert_eq!(stats.reaped, 0);
+        assert_eq!(stats.failed, 2);
+    }
+}
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use crate::spawn::{resume_task, ResumeStatus};
+use crate::{Error, Job, JobState, Statuses};
+use indexmap::IndexMap;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct ResumeOptions {
+    /// Only jobs created longer than this ago are considered stale
+    pub max_age: Duration,
+    pub jobserver_addr: String,
+    pub jobserver_port: u16,
+}
+
+/// Result of a ``resume_all_stale`` run
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ResumeReport {
+    /// Jobs the jobserver accepted for resumption
+    pub resumed: Vec<Uuid>,
+    /// Jobs the jobserver did not know about, these are marked as ``lost``
+    pub lost: Vec<Uuid>,
+    /// Jobs for which the resume request failed, these will be retried on the next run
+    pub failed: Vec<Uuid>,
+}
+
+/// Appends a status entry to a job
+async fn append_status(pool: &PgPool, id: Uuid, level: &str, msg: String) -> Result<(), Error> {
+    let status = Statuses {
+        level: level.to_string(),
+        msg,
+        ts: chrono::Utc::now().timestamp_millis() as f64 / 1000.0,
+        bot_display_ignore: None,
+        extra_info: IndexMap::new(),
+    };
+
+    sqlx::query("UPDATE jobs SET statuses = array_append(statuses, $1) WHERE id = $2")
+        .bind(serde_json::to_value(&status)?)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Attempts to resume all stale resumable jobs, see ``Job::find_resumable``
+///
+/// Jobs unknown to the jobserver are transitioned to the ``lost`` state instead of being retried forever
+pub async fn resume_all_stale(
+    pool: &PgPool,
+    reqwest: &reqwest::Client,
+    opts: &ResumeOptions,
+) -> Result<ResumeReport, Error> {
+    let jobs = Job::find_resumable(pool, opts.max_age).await?;
+
+    let mut report = ResumeReport::default();
+
+    for mut job in jobs {
+        match resume_task(reqwest, job.id, &opts.jobserver_addr, opts.jobserver_port).await {
+            Ok(ResumeStatus::Resumed) => {
+                log::info!("Resumed stale job {} (guild {})", job.id, job.guild_id);
+
+                if let Err(e) = append_status(
+                    pool,
+                    job.id,
+                    "info",
+                    "Resumed after jobserver restart".to_string(),
+                )
+                .await
+                {
+                    log::error!("Failed to record resume of job {}: {}", job.id, e);
+                }
+
+                report.resumed.push(job.id);
+            }
+            Ok(ResumeStatus::Unknown) => {
+                log::warn!(
+                    "Stale job {} (guild {}) is unknown to the jobserver, marking as lost",
+                    job.id,
+                    job.guild_id
+                );
+
+                if let Err(e) = job.set_state(pool, JobState::Lost).await {
+                    log::error!("Failed to mark job {} as lost: {}", job.id, e);
+                    report.failed.push(job.id);
+                    continue;
+                }
+
+                if let Err(e) = append_status(
+                    pool,
+                    job.id,
+                    "error",
+                    "Job was lost and could not be resumed".to_string(),
+                )
+                .await
+                {
+                    log::error!("Failed to record loss of job {}: {}", job.id, e);
+                }
+
+                report.lost.push(job.id);
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to resume stale job {} (guild {}): {}",
+                    job.id,
+                    job.guild_id,
+                    e
+                );
+
+                if let Err(e) = append_status(
+                    pool,
+                    job.id,
+                    "warn",
+                    format!("Resume attempt failed: {}", e),
+                )
+                .await
+                {
+                    log::error!("Failed to record resume failure of job {}: {}", job.id, e);
+                }
+
+                report.failed.push(job.id);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+    use crate::test_schema::{create_tables, JOBS};
+    use axum::http::StatusCode;
+
+    async fn insert_stale_job(pool: &PgPool) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO jobs (name, guild_id, state, resumable, created_at) VALUES ('backup', '1', 'running', true, NOW() - interval '1 hour') RETURNING id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn failing_to_mark_a_job_lost_does_not_abort_the_run(pool: PgPool) {
+        create_tables(&pool, &[JOBS]).await;
+
+        let broken = insert_stale_job(&pool).await;
+        let lost = insert_stale_job(&pool).await;
+
+        // Rejects any update of the first job so marking it as lost fails
+        sqlx::raw_sql(&format!(
+            "CREATE FUNCTION reject_update() RETURNS trigger AS $$ BEGIN RAISE EXCEPTION 'rejected'; END $$ LANGUAGE plpgsql;
+            CREATE TRIGGER reject_update BEFORE UPDATE ON jobs FOR EACH ROW WHEN (OLD.id = '{}') EXECUTE FUNCTION reject_update();",
+            broken
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let server = MockServer::start(|_, _| MockResponse::status(StatusCode::NOT_FOUND)).await;
+
+        let report = resume_all_stale(
+            &pool,
+            &reqwest::Client::new(),
+            &ResumeOptions {
+                max_age: Duration::from_secs(60),
+                jobserver_addr: "http://127.0.0.1".to_string(),
+                jobserver_port: server.port(),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.failed, vec![broken]);
+        assert_eq!(report.lost, vec![lost]);
+        assert!(report.resumed.is_empty());
+
+        let state: String = sqlx::query_scalar("SELECT state FROM jobs WHERE id = $1")
+            .bind(lost)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(state, "lost");
+    }
+}
@@ -21,3 +21,217 @@ pub async fn spawn_task(
         Err(format!("Failed to initiate task: {}", err_text).into())
     }
 }
+
+/// Outcome of asking the jobserver to resume a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeStatus {
+    /// The jobserver accepted the job for resumption
+    Resumed,
+    /// The jobserver does not know about the job (e.g. it was never persisted before a restart)
+    Unknown,
+}
+
+pub async fn resume_task(
+    reqwest_client: &reqwest::Client,
+    job_id: uuid::Uuid,
+    jobserver_addr: &str,
+    jobserver_port: u16,
+) -> Result<ResumeStatus, Error> {
+    let resp = reqwest_client
+        .post(format!(
+            "{}:{}/resume/{}",
+            jobserver_addr, jobserver_port, job_id
+        ))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to resume task: {}", e))?;
+
+    if resp.status().is_success() {
+        Ok(ResumeStatus::Resumed)
+    } else if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        Ok(ResumeStatus::Unknown)
+    } else {
+        let err_text = resp.text().await?;
+
+        Err(format!("Failed to resume task: {}", err_text).into())
+    }
+}
+
+/// Per-guild concurrency caps checked by ``spawn_task_guarded``
+#[derive(Clone, Debug, Default)]
+pub struct SpawnLimits {
+    /// Maximum number of active jobs per guild
+    pub max_per_guild: Option<i64>,
+    /// Maximum number of active jobs per guild and job name
+    pub max_per_name: std::collections::HashMap<String, i64>,
+    /// Queue denied spawns in ``job_queue`` instead of rejecting them
+    pub queue: bool,
+}
+
+#[derive(Debug)]
+pub enum SpawnDenied {
+    /// The guild already has ``limit`` or more active jobs
+    TooManyActive { active: i64, limit: i64 },
+    /// Checking the limits or spawning the job failed
+    Error(Error),
+}
+
+impl std::fmt::Display for SpawnDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnDenied::TooManyActive { active, limit } => write!(
+                f,
+                "Too many active jobs ({} active, limit is {}), please wait for some to finish",
+                active, limit
+            ),
+            SpawnDenied::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SpawnDenied {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpawnDenied::Error(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Result of a guarded spawn
+#[derive(Debug)]
+pub enum GuardedSpawn {
+    Spawned(super::SpawnResponse),
+    /// The job was queued as the guild is at capacity, see ``queue::drain_queue``
+    Queued {
+        queue_id: uuid::Uuid,
+        position: i64,
+    },
+}
+
+/// Returns the number of active jobs of a guild plus its spawns in flight, whose jobs may not exist yet
+async fn count_active_or_in_flight(
+    conn: &mut sqlx::PgConnection,
+    guild_id: serenity::all::GuildId,
+    name_prefix: Option<&str>,
+) -> Result<i64, SpawnDenied> {
+    let active = super::Job::count_active(&mut *conn, guild_id, name_prefix)
+        .await
+        .map_err(SpawnDenied::Error)?;
+
+    let in_flight = crate::queue::count_in_flight(&mut *conn, &guild_id.to_string(), name_prefix)
+        .await
+        .map_err(SpawnDenied::Error)?;
+
+    Ok(active + in_flight)
+}
+
+/// Returns ``SpawnDenied::TooManyActive`` if spawning ``spawn`` would exceed the limits
+///
+/// Callers must hold the guild's ``queue::lock_guild`` lock until they have claimed the capacity, otherwise
+/// concurrent callers can pass the check together. ``conn`` should be the connection holding the lock
+pub async fn check_limits(
+    conn: &mut sqlx::PgConnection,
+    spawn: &super::Spawn,
+    limits: &SpawnLimits,
+) -> Result<(), SpawnDenied> {
+    let guild_id: serenity::all::GuildId = spawn
+        .guild_id
+        .parse()
+        .map_err(|e| SpawnDenied::Error(format!("Invalid guild id: {}", e).into()))?;
+
+    if let Some(limit) = limits.max_per_guild {
+        let active = count_active_or_in_flight(conn, guild_id, None).await?;
+
+        if active >= limit {
+            return Err(SpawnDenied::TooManyActive { active, limit });
+        }
+    }
+
+    if let Some(limit) = limits.max_per_name.get(&spawn.name) {
+        let active = count_active_or_in_flight(conn, guild_id, Some(&spawn.name)).await?;
+
+        if active >= *limit {
+            return Err(SpawnDenied::TooManyActive {
+                active,
+                limit: *limit,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Like ``spawn_task`` but checks the concurrency limits first, queueing the job if denied and ``limits.queue`` is set
+///
+/// The check and the reservation of capacity happen under the guild's queue lock, the spawn itself after it is
+/// released
+pub async fn spawn_task_guarded(
+    reqwest_client: &reqwest::Client,
+    pool: &sqlx::PgPool,
+    spawn: &super::Spawn,
+    jobserver_addr: &str,
+    jobserver_port: u16,
+    limits: &SpawnLimits,
+) -> Result<GuardedSpawn, SpawnDenied> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| SpawnDenied::Error(e.into()))?;
+
+    crate::queue::lock_guild(&mut tx, &spawn.guild_id)
+        .await
+        .map_err(SpawnDenied::Error)?;
+
+    // Jobs already queued for the guild go first to keep FIFO order
+    let mut queue = limits.queue
+        && crate::queue::has_queued(&mut *tx, &spawn.guild_id)
+            .await
+            .map_err(SpawnDenied::Error)?;
+
+    if !queue {
+        match check_limits(&mut tx, spawn, limits).await {
+            Ok(()) => {}
+            Err(SpawnDenied::TooManyActive { .. }) if limits.queue => queue = true,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if queue {
+        let queue_id = crate::queue::enqueue(&mut *tx, spawn)
+            .await
+            .map_err(SpawnDenied::Error)?;
+
+        let position = crate::queue::position(&mut *tx, queue_id)
+            .await
+            .map_err(SpawnDenied::Error)?
+            .unwrap_or_default();
+
+        tx.commit()
+            .await
+            .map_err(|e| SpawnDenied::Error(e.into()))?;
+
+        return Ok(GuardedSpawn::Queued { queue_id, position });
+    }
+
+    let reservation = crate::queue::reserve(&mut *tx, spawn)
+        .await
+        .map_err(SpawnDenied::Error)?;
+
+    tx.commit()
+        .await
+        .map_err(|e| SpawnDenied::Error(e.into()))?;
+
+    let res = spawn_task(reqwest_client, spawn, jobserver_addr, jobserver_port).await;
+
+    if let Err(e) = crate::queue::release(pool, reservation).await {
+        log::error!(
+            "Failed to release spawn reservation {} for guild {}: {}",
+            reservation,
+            spawn.guild_id,
+            e
+        );
+    }
+
+    res.map(GuardedSpawn::Spawned).map_err(SpawnDenied::Error)
+}
@@ -0,0 +1,260 @@
+use std::str::FromStr;
+
+/// The state of a job as stored in the ``state`` column of ``jobs``
+///
+/// States not known to this build are kept as ``Unknown`` with the original string so newer jobserver states
+/// round-trip unchanged
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum JobState {
+    Pending,
+    Claimed,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+    Lost,
+    Unknown(String),
+}
+
+/// States after which a job will not change anymore
+const TERMINAL_STATES: &[JobState] = &[
+    JobState::Completed,
+    JobState::Failed,
+    JobState::Cancelled,
+    JobState::Lost,
+];
+
+const PENDING_TRANSITIONS: &[JobState] = &[
+    JobState::Claimed,
+    JobState::Running,
+    JobState::Failed,
+    JobState::Cancelled,
+    JobState::Lost,
+];
+
+const CLAIMED_TRANSITIONS: &[JobState] = &[
+    JobState::Pending,
+    JobState::Running,
+    JobState::Failed,
+    JobState::Cancelled,
+    JobState::Lost,
+];
+
+const RUNNING_TRANSITIONS: &[JobState] = &[
+    JobState::Completed,
+    JobState::Failed,
+    JobState::Cancelled,
+    JobState::Lost,
+];
+
+impl JobState {
+    /// Returns true if the job will not change anymore
+    pub fn is_terminal(&self) -> bool {
+        TERMINAL_STATES.contains(self)
+    }
+
+    /// Returns the ``state`` column values of all terminal states, for use in queries
+    pub(crate) fn terminal_state_strings() -> Vec<String> {
+        TERMINAL_STATES.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Returns the states a job in this state may move to
+    ///
+    /// Terminal and unknown states have no known transitions, see ``can_transition_to`` for how unknown states are handled
+    pub fn allowed_transitions(&self) -> &'static [JobState] {
+        match self {
+            JobState::Pending => PENDING_TRANSITIONS,
+            JobState::Claimed => CLAIMED_TRANSITIONS,
+            JobState::Running => RUNNING_TRANSITIONS,
+            _ => &[],
+        }
+    }
+
+    /// Returns true if a job in this state may move to ``next``
+    ///
+    /// A job in a state unknown to this build may move to any known non-pending state, as newer jobservers may
+    /// add states before an older bot build knows about them. Moving to an unknown state is never allowed
+    pub fn can_transition_to(&self, next: &JobState) -> bool {
+        match (self, next) {
+            (_, JobState::Unknown(_)) => false,
+            (JobState::Unknown(_), next) => *next != JobState::Pending,
+            (current, next) => current.allowed_transitions().contains(next),
+        }
+    }
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobState::Pending => write!(f, "pending"),
+            JobState::Claimed => write!(f, "claimed"),
+            JobState::Running => write!(f, "running"),
+            JobState::Completed => write!(f, "completed"),
+            JobState::Failed => write!(f, "failed"),
+            JobState::Cancelled => write!(f, "cancelled"),
+            JobState::Lost => write!(f, "lost"),
+            JobState::Unknown(state) => write!(f, "{}", state),
+        }
+    }
+}
+
+impl FromStr for JobState {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "pending" => JobState::Pending,
+            "claimed" => JobState::Claimed,
+            "running" => JobState::Running,
+            "completed" => JobState::Completed,
+            "failed" => JobState::Failed,
+            "cancelled" => JobState::Cancelled,
+            "lost" => JobState::Lost,
+            _ => JobState::Unknown(s.to_string()),
+        })
+    }
+}
+
+impl From<String> for JobState {
+    fn from(s: String) -> Self {
+        match JobState::from_str(&s) {
+            Ok(JobState::Unknown(_)) => JobState::Unknown(s),
+            Ok(state) => state,
+            Err(never) => match never {},
+        }
+    }
+}
+
+impl From<JobState> for String {
+    fn from(state: JobState) -> Self {
+        match state {
+            JobState::Unknown(state) => state,
+            state => state.to_string(),
+        }
+    }
+}
+
+/// Returned by ``Job::set_state`` when the transition is not allowed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidStateTransition {
+    pub from: JobState,
+    pub to: JobState,
+}
+
+impl std::fmt::Display for InvalidStateTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Job cannot move from state {} to state {}",
+            self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for InvalidStateTransition {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KNOWN: &[JobState] = &[
+        JobState::Pending,
+        JobState::Claimed,
+        JobState::Running,
+        JobState::Completed,
+        JobState::Failed,
+        JobState::Cancelled,
+        JobState::Lost,
+    ];
+
+    #[test]
+    fn transition_table() {
+        use JobState::*;
+
+        // (from, to) pairs which are allowed, every other pair of known states is not
+        let allowed = [
+            (Pending, Claimed),
+            (Pending, Running),
+            (Pending, Failed),
+            (Pending, Cancelled),
+            (Pending, Lost),
+            (Claimed, Pending),
+            (Claimed, Running),
+            (Claimed, Failed),
+            (Claimed, Cancelled),
+            (Claimed, Lost),
+            (Running, Completed),
+            (Running, Failed),
+            (Running, Cancelled),
+            (Running, Lost),
+        ];
+
+        for from in KNOWN {
+            for to in KNOWN {
+                let expected = allowed.contains(&(from.clone(), to.clone()));
+
+                assert_eq!(from.can_transition_to(to), expected, "{} -> {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn terminal_states_have_no_transitions() {
+        for state in KNOWN {
+            assert_eq!(
+                state.is_terminal(),
+                state.allowed_transitions().is_empty(),
+                "{}",
+                state
+            );
+        }
+
+        assert_eq!(
+            JobState::terminal_state_strings(),
+            ["completed", "failed", "cancelled", "lost"]
+        );
+    }
+
+    #[test]
+    fn unknown_states() {
+        let unknown = JobState::Unknown("paused".to_string());
+
+        assert!(!unknown.is_terminal());
+        assert!(unknown.can_transition_to(&JobState::Running));
+        assert!(unknown.can_transition_to(&JobState::Cancelled));
+        assert!(!unknown.can_transition_to(&JobState::Pending));
+
+        for state in KNOWN {
+            assert!(!state.can_transition_to(&unknown), "{}", state);
+        }
+    }
+
+    #[test]
+    fn string_round_trip() {
+        for state in KNOWN {
+            assert_eq!(JobState::from(state.to_string()), *state);
+            assert_eq!(String::from(state.clone()), state.to_string());
+        }
+
+        let unknown = JobState::from("paused".to_string());
+        assert_eq!(unknown, JobState::Unknown("paused".to_string()));
+        assert_eq!(String::from(unknown), "paused");
+
+        // Typos are not silently treated as known states
+        assert_eq!(
+            JobState::from("complete".to_string()),
+            JobState::Unknown("complete".to_string())
+        );
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let value = serde_json::to_value(JobState::Cancelled).unwrap();
+        assert_eq!(value, "cancelled");
+
+        let state: JobState = serde_json::from_value(serde_json::json!("paused")).unwrap();
+        assert_eq!(state, JobState::Unknown("paused".to_string()));
+        assert_eq!(serde_json::to_value(state).unwrap(), "paused");
+    }
+}
@@ -0,0 +1,43 @@
+//! Minimal versions of the tables used by the database tests
+//!
+//! The real schema is managed outside this crate, these only contain the columns the jobserver client reads and
+//! writes. Tests use ``#[sqlx::test(migrations = false)]`` (which needs ``DATABASE_URL``) and create the tables they
+//! need
+
+pub const JOBS: &str = r#"
+CREATE TABLE jobs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    output JSONB,
+    fields JSONB NOT NULL DEFAULT '{}',
+    statuses JSONB[] NOT NULL DEFAULT '{}',
+    guild_id TEXT NOT NULL,
+    expiry INTERVAL,
+    state TEXT NOT NULL DEFAULT 'pending',
+    resumable BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+"#;
+
+pub const JOB_QUEUE: &str = r#"
+CREATE TABLE job_queue (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    spawn JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    claimed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+);
+"#;
+
+/// Creates the given tables
+pub async fn create_tables(pool: &sqlx::PgPool, tables: &[&str]) {
+    for table in tables {
+        sqlx::raw_sql(table)
+            .execute(pool)
+            .await
+            .expect("failed to create test table");
+    }
+}
@@ -0,0 +1,309 @@
+use crate::command_name_limits::RESERVED_COMMAND_NAMES;
+
+/// Maximum length of a single command name segment
+pub const COMMAND_NAME_SEGMENT_MAX_LENGTH: usize = 32;
+
+/// Maximum nesting depth of a command name (command group subcommand)
+pub const COMMAND_NAME_MAX_DEPTH: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandNameError {
+    Empty,
+    TooDeep {
+        depth: usize,
+        max: usize,
+    },
+    SegmentTooLong {
+        segment: String,
+        max: usize,
+    },
+    Uppercase {
+        segment: String,
+    },
+    InvalidCharacter {
+        segment: String,
+        character: char,
+    },
+    Reserved {
+        name: String,
+        reserved: &'static str,
+    },
+}
+
+impl std::fmt::Display for CommandNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandNameError::Empty => write!(f, "Command name cannot be empty"),
+            CommandNameError::TooDeep { depth, max } => {
+                write!(f, "Command name is nested too deeply: {} > {}", depth, max)
+            }
+            CommandNameError::SegmentTooLong { segment, max } => {
+                write!(
+                    f,
+                    "Command name segment ``{}`` is longer than {} characters",
+                    segment, max
+                )
+            }
+            CommandNameError::Uppercase { segment } => {
+                write!(f, "Command name segment ``{}`` must be lowercase", segment)
+            }
+            CommandNameError::InvalidCharacter { segment, character } => write!(
+                f,
+                "Command name segment ``{}`` contains invalid character {:?}",
+                segment, character
+            ),
+            CommandNameError::Reserved { name, reserved } => {
+                write!(
+                    f,
+                    "Command name ``{}`` uses reserved name ``{}``",
+                    name, reserved
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandNameError {}
+
+/// Splits a command name such as ``backups create`` into its segments
+pub fn segments(name: &str) -> Vec<&str> {
+    name.split_whitespace().collect()
+}
+
+/// Validates a single segment against Discord's slash command naming rules
+///
+/// Segments may contain letters and numbers from any script as well as ``-`` and ``_``, and letters must be lowercase
+fn validate_segment(segment: &str) -> Result<(), CommandNameError> {
+    if segment.chars().count() > COMMAND_NAME_SEGMENT_MAX_LENGTH {
+        return Err(CommandNameError::SegmentTooLong {
+            segment: segment.to_string(),
+            max: COMMAND_NAME_SEGMENT_MAX_LENGTH,
+        });
+    }
+
+    for c in segment.chars() {
+        if c.is_uppercase() {
+            return Err(CommandNameError::Uppercase {
+                segment: segment.to_string(),
+            });
+        }
+
+        if !(c.is_alphanumeric() || c == '-' || c == '_') {
+            return Err(CommandNameError::InvalidCharacter {
+                segment: segment.to_string(),
+                character: c,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates a (possibly nested) command name, checking naming rules, nesting depth and reserved names
+pub fn validate_command_name(name: &str) -> Result<(), CommandNameError> {
+    let segments = segments(name);
+
+    if segments.is_empty() {
+        return Err(CommandNameError::Empty);
+    }
+
+    if segments.len() > COMMAND_NAME_MAX_DEPTH {
+        return Err(CommandNameError::TooDeep {
+            depth: segments.len(),
+            max: COMMAND_NAME_MAX_DEPTH,
+        });
+    }
+
+    for segment in segments.iter() {
+        validate_segment(segment)?;
+    }
+
+    // Reserved names cover the root command and thus all of its subcommands
+    if let Some(reserved) = RESERVED_COMMAND_NAMES.iter().find(|r| **r == segments[0]) {
+        return Err(CommandNameError::Reserved {
+            name: name.to_string(),
+            reserved,
+        });
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandConflict {
+    /// The proposed name
+    pub name: String,
+    /// The existing (or other proposed) name it conflicts with
+    pub conflicts_with: String,
+}
+
+/// Returns true if ``a`` and ``b`` overlap, that is, one is the other or a subcommand of it
+///
+/// Matching is done per segment so ``backup`` does not conflict with ``backups``
+fn overlaps(a: &[&str], b: &[&str]) -> bool {
+    let len = a.len().min(b.len());
+    len > 0 && a[..len] == b[..len]
+}
+
+/// Checks proposed command names against a list of existing command names (including subcommand forms) and against each other
+pub fn find_conflicts(names: &[String], existing: &[String]) -> Vec<CommandConflict> {
+    let mut conflicts = Vec::new();
+
+    for (i, name) in names.iter().enumerate() {
+        let name_segments = segments(name);
+
+        let others = existing.iter().chain(names[..i].iter());
+
+        for other in others {
+            if overlaps(&name_segments, &segments(other)) {
+                conflicts.push(CommandConflict {
+                    name: name.clone(),
+                    conflicts_with: other.clone(),
+                });
+            }
+        }
+    }
+
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_valid_names() {
+        for name in ["raid-alerts", "my_cmd", "welcome setup", "a b c", "cmd2"] {
+            assert_eq!(validate_command_name(name), Ok(()), "{name}");
+        }
+    }
+
+    #[test]
+    fn accepts_lowercase_unicode() {
+        for name in ["привет", "café", "日本語", "ñandú set"] {
+            assert_eq!(validate_command_name(name), Ok(()), "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_uppercase() {
+        assert_eq!(
+            validate_command_name("Welcome"),
+            Err(CommandNameError::Uppercase {
+                segment: "Welcome".to_string()
+            })
+        );
+
+        // Uppercase in other scripts is rejected too
+        assert_eq!(
+            validate_command_name("welcome Ñandú"),
+            Err(CommandNameError::Uppercase {
+                segment: "Ñandú".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_characters() {
+        assert_eq!(
+            validate_command_name("hi!"),
+            Err(CommandNameError::InvalidCharacter {
+                segment: "hi!".to_string(),
+                character: '!'
+            })
+        );
+        assert_eq!(
+            validate_command_name("wave👋"),
+            Err(CommandNameError::InvalidCharacter {
+                segment: "wave👋".to_string(),
+                character: '👋'
+            })
+        );
+    }
+
+    #[test]
+    fn segment_length_counts_characters() {
+        let at_limit = "é".repeat(COMMAND_NAME_SEGMENT_MAX_LENGTH);
+        assert_eq!(validate_command_name(&at_limit), Ok(()));
+
+        let over = "é".repeat(COMMAND_NAME_SEGMENT_MAX_LENGTH + 1);
+        assert_eq!(
+            validate_command_name(&over),
+            Err(CommandNameError::SegmentTooLong {
+                segment: over.clone(),
+                max: COMMAND_NAME_SEGMENT_MAX_LENGTH
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_deep_names() {
+        assert_eq!(validate_command_name("   "), Err(CommandNameError::Empty));
+        assert_eq!(
+            validate_command_name("a b c d"),
+            Err(CommandNameError::TooDeep {
+                depth: 4,
+                max: COMMAND_NAME_MAX_DEPTH
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_reserved_roots_and_subcommands() {
+        assert_eq!(
+            validate_command_name("help"),
+            Err(CommandNameError::Reserved {
+                name: "help".to_string(),
+                reserved: "help"
+            })
+        );
+        assert_eq!(
+            validate_command_name("lockdowns custom"),
+            Err(CommandNameError::Reserved {
+                name: "lockdowns custom".to_string(),
+                reserved: "lockdowns"
+            })
+        );
+
+        // Only whole segments are reserved
+        assert_eq!(validate_command_name("helpme"), Ok(()));
+        assert_eq!(validate_command_name("my help"), Ok(()));
+    }
+
+    #[test]
+    fn prefix_is_not_a_conflict() {
+        assert!(find_conflicts(&names(&["backup"]), &names(&["backups"])).is_empty());
+        assert!(find_conflicts(&names(&["backups"]), &names(&["backup create"])).is_empty());
+    }
+
+    #[test]
+    fn subcommands_conflict_with_their_root() {
+        assert_eq!(
+            find_conflicts(&names(&["backups"]), &names(&["backups create"])),
+            vec![CommandConflict {
+                name: "backups".to_string(),
+                conflicts_with: "backups create".to_string()
+            }]
+        );
+        assert_eq!(
+            find_conflicts(&names(&["backups create"]), &names(&["backups"])).len(),
+            1
+        );
+        assert!(find_conflicts(&names(&["backups list"]), &names(&["backups create"])).is_empty());
+    }
+
+    #[test]
+    fn proposed_names_conflict_with_each_other() {
+        assert_eq!(
+            find_conflicts(&names(&["welcome", "welcome setup", "goodbye"]), &[]),
+            vec![CommandConflict {
+                name: "welcome setup".to_string(),
+                conflicts_with: "welcome".to_string()
+            }]
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod command_names;
+pub mod validation;
+
 pub mod embed_limits {
     pub const EMBED_TITLE_LIMIT: usize = 256;
     pub const EMBED_DESCRIPTION_LIMIT: usize = 4096;
@@ -0,0 +1,526 @@
+use crate::embed_limits::{
+    EMBED_AUTHOR_NAME_LIMIT, EMBED_DESCRIPTION_LIMIT, EMBED_FIELDS_MAX_COUNT,
+    EMBED_FIELD_NAME_LIMIT, EMBED_FIELD_VALUE_LIMIT, EMBED_FOOTER_TEXT_LIMIT, EMBED_MAX_COUNT,
+    EMBED_TITLE_LIMIT, EMBED_TOTAL_LIMIT,
+};
+use crate::message_limits::MESSAGE_CONTENT_LIMIT;
+
+/// A Discord limit that was exceeded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitViolation {
+    /// The name of the exceeded limit, e.g. ``EMBED_TITLE_LIMIT``
+    pub limit: &'static str,
+    /// The maximum allowed by the limit
+    pub max: usize,
+    /// The observed value
+    pub actual: usize,
+}
+
+impl std::fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} exceeded: {} > {} (over by {})",
+            self.limit,
+            self.actual,
+            self.max,
+            self.actual - self.max
+        )
+    }
+}
+
+impl std::error::Error for LimitViolation {}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct CanonicalEmbedField {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct CanonicalEmbedFooter {
+    pub text: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct CanonicalEmbedAuthor {
+    pub name: String,
+}
+
+/// The parts of an embed that count towards Discord's limits
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct CanonicalEmbed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<CanonicalEmbedField>,
+    pub footer: Option<CanonicalEmbedFooter>,
+    pub author: Option<CanonicalEmbedAuthor>,
+}
+
+/// A structured message payload with content and embeds
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct CanonicalMessage {
+    pub content: Option<String>,
+    #[serde(default)]
+    pub embeds: Vec<CanonicalEmbed>,
+}
+
+/// Discord counts characters, not bytes
+fn char_len(s: &str) -> usize {
+    s.chars().count()
+}
+
+/// Truncates a string to at most ``max`` characters, marking truncation with an ellipsis
+pub fn truncate(s: &str, max: usize) -> String {
+    if char_len(s) <= max {
+        return s.to_string();
+    }
+
+    s.chars().take(max.saturating_sub(3)).collect::<String>() + "..."
+}
+
+fn check(limit: &'static str, max: usize, actual: usize) -> Result<(), LimitViolation> {
+    if actual > max {
+        return Err(LimitViolation { limit, max, actual });
+    }
+
+    Ok(())
+}
+
+/// Validates a single embed, returning the number of characters it counts towards ``EMBED_TOTAL_LIMIT``
+fn embed_length(embed: &CanonicalEmbed) -> Result<usize, LimitViolation> {
+    let mut total = 0;
+
+    if let Some(ref title) = embed.title {
+        let len = char_len(title);
+        check("EMBED_TITLE_LIMIT", EMBED_TITLE_LIMIT, len)?;
+        total += len;
+    }
+
+    if let Some(ref description) = embed.description {
+        let len = char_len(description);
+        check("EMBED_DESCRIPTION_LIMIT", EMBED_DESCRIPTION_LIMIT, len)?;
+        total += len;
+    }
+
+    check(
+        "EMBED_FIELDS_MAX_COUNT",
+        EMBED_FIELDS_MAX_COUNT,
+        embed.fields.len(),
+    )?;
+
+    for field in embed.fields.iter() {
+        let name_len = char_len(&field.name);
+        check("EMBED_FIELD_NAME_LIMIT", EMBED_FIELD_NAME_LIMIT, name_len)?;
+
+        let value_len = char_len(&field.value);
+        check(
+            "EMBED_FIELD_VALUE_LIMIT",
+            EMBED_FIELD_VALUE_LIMIT,
+            value_len,
+        )?;
+
+        total += name_len + value_len;
+    }
+
+    if let Some(ref footer) = embed.footer {
+        let len = char_len(&footer.text);
+        check("EMBED_FOOTER_TEXT_LIMIT", EMBED_FOOTER_TEXT_LIMIT, len)?;
+        total += len;
+    }
+
+    if let Some(ref author) = embed.author {
+        let len = char_len(&author.name);
+        check("EMBED_AUTHOR_NAME_LIMIT", EMBED_AUTHOR_NAME_LIMIT, len)?;
+        total += len;
+    }
+
+    Ok(total)
+}
+
+/// Validates message content against ``MESSAGE_CONTENT_LIMIT``
+pub fn validate_content(content: &str) -> Result<(), LimitViolation> {
+    check(
+        "MESSAGE_CONTENT_LIMIT",
+        MESSAGE_CONTENT_LIMIT,
+        char_len(content),
+    )
+}
+
+/// Validates a single embed, including ``EMBED_TOTAL_LIMIT`` for the embed on its own
+pub fn validate_embed(embed: &CanonicalEmbed) -> Result<(), LimitViolation> {
+    check("EMBED_TOTAL_LIMIT", EMBED_TOTAL_LIMIT, embed_length(embed)?)
+}
+
+/// Validates a structured message against Discord's content and embed limits
+///
+/// ``EMBED_TOTAL_LIMIT`` is enforced cumulatively across all embeds of the message
+pub fn validate_message(message: &CanonicalMessage) -> Result<(), LimitViolation> {
+    if let Some(ref content) = message.content {
+        validate_content(content)?;
+    }
+
+    check("EMBED_MAX_COUNT", EMBED_MAX_COUNT, message.embeds.len())?;
+
+    let mut total = 0;
+    for embed in message.embeds.iter() {
+        total += embed_length(embed)?;
+    }
+
+    check("EMBED_TOTAL_LIMIT", EMBED_TOTAL_LIMIT, total)
+}
+
+/// Validates a message payload which is either plain content or a JSON-encoded ``CanonicalMessage``
+pub fn validate_message_payload(payload: &str) -> Result<(), LimitViolation> {
+    if payload.trim_start().starts_with('{') {
+        if let Ok(message) = serde_json::from_str::<CanonicalMessage>(payload) {
+            return validate_message(&message);
+        }
+    }
+
+    validate_content(payload)
+}
+
+/// Which mentions ``sanitize_mentions`` leaves intact
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct AllowedMentionsPolicy {
+    /// Whether @everyone and @here may ping
+    #[serde(default)]
+    pub everyone: bool,
+    /// Role IDs which may be mentioned
+    #[serde(default)]
+    pub roles: Vec<u64>,
+}
+
+/// Zero width space, used to break mentions without visibly changing the text
+const ZWSP: char = '\u{200B}';
+
+/// Escapes @everyone, @here and role mentions in ``content`` unless allowed by ``policy``
+pub fn sanitize_mentions(content: &str, policy: &AllowedMentionsPolicy) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(pos) = rest.find(['@', '<']) {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if rest.starts_with('@') {
+            if !policy.everyone && (rest.starts_with("@everyone") || rest.starts_with("@here")) {
+                out.push('@');
+                out.push(ZWSP);
+            } else {
+                out.push('@');
+            }
+
+            rest = &rest[1..];
+            continue;
+        }
+
+        // Role mentions are of the form <@&id>
+        if let Some(after) = rest.strip_prefix("<@&") {
+            let digits = after
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after.len());
+
+            if digits > 0 && after[digits..].starts_with('>') {
+                let allowed = after[..digits]
+                    .parse::<u64>()
+                    .map(|id| policy.roles.contains(&id))
+                    .unwrap_or(false);
+
+                out.push_str("<@&");
+                if !allowed {
+                    out.push(ZWSP);
+                }
+                out.push_str(&after[..=digits]);
+
+                rest = &after[digits + 1..];
+                continue;
+            }
+        }
+
+        out.push('<');
+        rest = &rest[1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, value: &str) -> CanonicalEmbedField {
+        CanonicalEmbedField {
+            name: name.to_string(),
+            value: value.to_string(),
+            inline: false,
+        }
+    }
+
+    fn limit_of<T>(res: Result<T, LimitViolation>) -> &'static str {
+        res.err().expect("expected a limit violation").limit
+    }
+
+    #[test]
+    fn content_limit() {
+        assert!(validate_content("hello").is_ok());
+        assert_eq!(
+            validate_content(&"a".repeat(MESSAGE_CONTENT_LIMIT + 5)),
+            Err(LimitViolation {
+                limit: "MESSAGE_CONTENT_LIMIT",
+                max: MESSAGE_CONTENT_LIMIT,
+                actual: MESSAGE_CONTENT_LIMIT + 5,
+            })
+        );
+    }
+
+    #[test]
+    fn embed_part_limits() {
+        let embed = CanonicalEmbed {
+            title: Some("a".repeat(EMBED_TITLE_LIMIT + 1)),
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_TITLE_LIMIT");
+
+        let embed = CanonicalEmbed {
+            description: Some("a".repeat(EMBED_DESCRIPTION_LIMIT + 1)),
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_DESCRIPTION_LIMIT");
+
+        let embed = CanonicalEmbed {
+            fields: vec![field("a", "b"); EMBED_FIELDS_MAX_COUNT + 1],
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_FIELDS_MAX_COUNT");
+
+        let embed = CanonicalEmbed {
+            fields: vec![field(&"a".repeat(EMBED_FIELD_NAME_LIMIT + 1), "b")],
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_FIELD_NAME_LIMIT");
+
+        let embed = CanonicalEmbed {
+            fields: vec![field("a", &"b".repeat(EMBED_FIELD_VALUE_LIMIT + 1))],
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_FIELD_VALUE_LIMIT");
+
+        let embed = CanonicalEmbed {
+            footer: Some(CanonicalEmbedFooter {
+                text: "a".repeat(EMBED_FOOTER_TEXT_LIMIT + 1),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_FOOTER_TEXT_LIMIT");
+
+        let embed = CanonicalEmbed {
+            author: Some(CanonicalEmbedAuthor {
+                name: "a".repeat(EMBED_AUTHOR_NAME_LIMIT + 1),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_AUTHOR_NAME_LIMIT");
+    }
+
+    #[test]
+    fn embed_total_limit() {
+        // Every part is within its own limit but together they exceed the total
+        let embed = CanonicalEmbed {
+            description: Some("a".repeat(EMBED_DESCRIPTION_LIMIT)),
+            fields: vec![field("a", &"b".repeat(EMBED_FIELD_VALUE_LIMIT)); 2],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            validate_embed(&embed),
+            Err(LimitViolation {
+                limit: "EMBED_TOTAL_LIMIT",
+                max: EMBED_TOTAL_LIMIT,
+                actual: EMBED_DESCRIPTION_LIMIT + 2 * (1 + EMBED_FIELD_VALUE_LIMIT),
+            })
+        );
+    }
+
+    #[test]
+    fn message_limits() {
+        let embed = CanonicalEmbed {
+            description: Some("a".repeat(EMBED_DESCRIPTION_LIMIT)),
+            ..Default::default()
+        };
+
+        // Each embed is valid on its own, but the total is cumulative across the message
+        assert!(validate_embed(&embed).is_ok());
+
+        let message = CanonicalMessage {
+            content: Some("hi".to_string()),
+            embeds: vec![embed.clone(), embed],
+        };
+        assert_eq!(limit_of(validate_message(&message)), "EMBED_TOTAL_LIMIT");
+
+        let message = CanonicalMessage {
+            content: None,
+            embeds: vec![CanonicalEmbed::default(); EMBED_MAX_COUNT + 1],
+        };
+        assert_eq!(limit_of(validate_message(&message)), "EMBED_MAX_COUNT");
+
+        let message = CanonicalMessage {
+            content: Some("a".repeat(MESSAGE_CONTENT_LIMIT + 1)),
+            embeds: vec![],
+        };
+        assert_eq!(
+            limit_of(validate_message(&message)),
+            "MESSAGE_CONTENT_LIMIT"
+        );
+    }
+
+    #[test]
+    fn message_payload() {
+        assert!(validate_message_payload("hello").is_ok());
+        assert_eq!(
+            limit_of(validate_message_payload(
+                &"a".repeat(MESSAGE_CONTENT_LIMIT + 1)
+            )),
+            "MESSAGE_CONTENT_LIMIT"
+        );
+
+        let payload = serde_json::json!({
+            "content": "hi",
+            "embeds": [{ "title": "a".repeat(EMBED_TITLE_LIMIT + 1) }]
+        })
+        .to_string();
+        assert_eq!(
+            limit_of(validate_message_payload(&payload)),
+            "EMBED_TITLE_LIMIT"
+        );
+
+        // Content that merely looks like JSON is validated as plain content
+        assert!(validate_message_payload("{not json}").is_ok());
+    }
+
+    #[test]
+    fn violation_display_names_limit() {
+        let err = validate_content(&"a".repeat(MESSAGE_CONTENT_LIMIT + 3)).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "MESSAGE_CONTENT_LIMIT exceeded: {} > {} (over by 3)",
+                MESSAGE_CONTENT_LIMIT + 3,
+                MESSAGE_CONTENT_LIMIT
+            )
+        );
+    }
+    #[test]
+    fn content_boundary_counts_characters() {
+        // 'é' is 2 bytes and '😀' is 4 bytes, but each is one character
+        for c in ['a', 'é', '😀'] {
+            let at_limit = c.to_string().repeat(MESSAGE_CONTENT_LIMIT);
+            assert!(validate_content(&at_limit).is_ok(), "{c} at limit");
+
+            let over = c.to_string().repeat(MESSAGE_CONTENT_LIMIT + 1);
+            assert_eq!(
+                validate_content(&over),
+                Err(LimitViolation {
+                    limit: "MESSAGE_CONTENT_LIMIT",
+                    max: MESSAGE_CONTENT_LIMIT,
+                    actual: MESSAGE_CONTENT_LIMIT + 1,
+                }),
+                "{c} over limit"
+            );
+        }
+    }
+
+    #[test]
+    fn embed_boundary_counts_characters() {
+        let embed = CanonicalEmbed {
+            title: Some("😀".repeat(EMBED_TITLE_LIMIT)),
+            ..Default::default()
+        };
+        assert!(validate_embed(&embed).is_ok());
+
+        let embed = CanonicalEmbed {
+            title: Some("😀".repeat(EMBED_TITLE_LIMIT + 1)),
+            ..Default::default()
+        };
+        assert_eq!(limit_of(validate_embed(&embed)), "EMBED_TITLE_LIMIT");
+
+        // Exactly at the total limit is allowed, one more character is not
+        let mut embed = CanonicalEmbed {
+            description: Some("é".repeat(EMBED_DESCRIPTION_LIMIT)),
+            fields: vec![field("é", &"é".repeat(EMBED_FIELD_VALUE_LIMIT - 1))],
+            footer: Some(CanonicalEmbedFooter {
+                text: "é"
+                    .repeat(EMBED_TOTAL_LIMIT - EMBED_DESCRIPTION_LIMIT - EMBED_FIELD_VALUE_LIMIT),
+            }),
+            ..Default::default()
+        };
+        assert!(validate_embed(&embed).is_ok());
+
+        embed.footer.as_mut().unwrap().text.push('é');
+        assert_eq!(
+            validate_embed(&embed),
+            Err(LimitViolation {
+                limit: "EMBED_TOTAL_LIMIT",
+                max: EMBED_TOTAL_LIMIT,
+                actual: EMBED_TOTAL_LIMIT + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn truncate_counts_characters() {
+        assert_eq!(truncate("😀😀😀", 3), "😀😀😀");
+        assert_eq!(truncate("😀😀😀😀", 3), "...");
+        assert_eq!(truncate("😀😀😀😀😀", 4), "😀...");
+        assert_eq!(truncate(&"é".repeat(10), 5).chars().count(), 5);
+    }
+
+    #[test]
+    fn sanitizes_everyone_and_here() {
+        let policy = AllowedMentionsPolicy::default();
+
+        assert_eq!(
+            sanitize_mentions("hi @everyone and @here", &policy),
+            "hi @\u{200B}everyone and @\u{200B}here"
+        );
+        assert_eq!(
+            sanitize_mentions("mail@example.com", &policy),
+            "mail@example.com"
+        );
+
+        let policy = AllowedMentionsPolicy {
+            everyone: true,
+            roles: vec![],
+        };
+        assert_eq!(sanitize_mentions("@everyone", &policy), "@everyone");
+    }
+
+    #[test]
+    fn sanitizes_role_mentions_unless_allowed() {
+        let policy = AllowedMentionsPolicy {
+            everyone: false,
+            roles: vec![123],
+        };
+
+        assert_eq!(
+            sanitize_mentions("<@&123> <@&456> <@789> <@&abc> <@&", &policy),
+            "<@&123> <@&\u{200B}456> <@789> <@&abc> <@&"
+        );
+    }
+
+    #[test]
+    fn sanitize_keeps_multibyte_text() {
+        let policy = AllowedMentionsPolicy::default();
+
+        assert_eq!(
+            sanitize_mentions("😀@here é<@&1>ü", &policy),
+            "😀@\u{200B}here é<@&\u{200B}1>ü"
+        );
+    }
+}
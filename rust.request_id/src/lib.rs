@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Header used to propagate the request id, both on requests and responses
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Incoming request ids longer than this are replaced with a generated one
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of a request, stored in the request extensions by the RPC server
+///
+/// Handlers can extract it with ``axum::Extension<RequestId>`` to forward it to other services
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Generates a new request id which is unique within this process
+    pub fn generate() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Self(format!(
+            "{:016x}-{:08x}-{:08x}",
+            nanos,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed) as u32
+        ))
+    }
+
+    /// Returns the request id if it is a valid id to propagate
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.is_empty()
+            || value.len() > MAX_REQUEST_ID_LEN
+            || !value.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return None;
+        }
+
+        Some(Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_ids_only() {
+        assert_eq!(
+            RequestId::parse("abc-123"),
+            Some(RequestId("abc-123".to_string()))
+        );
+        assert_eq!(RequestId::parse(""), None);
+        assert_eq!(RequestId::parse("has space"), None);
+        assert_eq!(RequestId::parse("ünicode"), None);
+        assert!(RequestId::parse(&"a".repeat(MAX_REQUEST_ID_LEN)).is_some());
+        assert_eq!(RequestId::parse(&"a".repeat(MAX_REQUEST_ID_LEN + 1)), None);
+    }
+
+    #[test]
+    fn generates_unique_ids() {
+        let a = RequestId::generate();
+        let b = RequestId::generate();
+
+        assert_ne!(a, b);
+        assert!(RequestId::parse(a.as_str()).is_some());
+    }
+}
@@ -0,0 +1,341 @@
+use axum::{
+    body::Body,
+    http::{header, Request, Response, StatusCode},
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::request_id::RequestId;
+
+/// A token accepted by the RPC server along with the scopes it grants
+#[derive(Debug, Clone)]
+pub struct RpcAuthToken {
+    pub token: String,
+    pub scopes: Vec<String>,
+}
+
+/// Shared-secret authentication for the RPC server
+///
+/// Every request must carry an ``Authorization: Bearer <token>`` header matching one of ``tokens``.
+/// If the request path is under one of the prefixes in ``route_scopes``, the token must also
+/// grant the scope of the longest matching prefix. Prefixes match whole path segments, so
+/// ``/settings-operation`` covers ``/settings-operation/x`` but not ``/settings-operationx``
+#[derive(Debug, Clone, Default)]
+pub struct RpcAuthConfig {
+    pub tokens: Vec<RpcAuthToken>,
+    /// Route prefix -> required scope
+    pub route_scopes: Vec<(String, String)>,
+}
+
+impl RpcAuthConfig {
+    /// Returns the scope required for a path, if any
+    pub fn required_scope(&self, path: &str) -> Option<&str> {
+        self.route_scopes
+            .iter()
+            .filter(|(prefix, _)| path_has_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, scope)| scope.as_str())
+    }
+
+    /// Checks the request against the configured tokens and scopes
+    pub fn authorize<B>(&self, request: &Request<B>) -> Result<(), RpcAuthError> {
+        let Some(header) = request.headers().get(header::AUTHORIZATION) else {
+            return Err(RpcAuthError::MissingHeader);
+        };
+
+        let Some(provided) = header.to_str().ok().and_then(|h| h.strip_prefix("Bearer ")) else {
+            return Err(RpcAuthError::MalformedHeader);
+        };
+
+        // Check every token so the time taken does not depend on which token matched
+        let mut matched = None;
+        for token in self.tokens.iter() {
+            if constant_time_eq(token.token.as_bytes(), provided.as_bytes()) {
+                matched = Some(token);
+            }
+        }
+
+        let Some(token) = matched else {
+            return Err(RpcAuthError::InvalidToken);
+        };
+
+        if let Some(scope) = self.required_scope(request.uri().path()) {
+            if !token.scopes.iter().any(|s| s == scope) {
+                return Err(RpcAuthError::MissingScope(scope.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether ``path`` is ``prefix`` or lies below it
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let Some(rest) = path.strip_prefix(prefix) else {
+        return false;
+    };
+
+    rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+}
+
+/// Compares two byte strings in time independent of their contents
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+
+    std::hint::black_box(diff) == 0
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcAuthError {
+    MissingHeader,
+    MalformedHeader,
+    InvalidToken,
+    MissingScope(String),
+}
+
+impl std::fmt::Display for RpcAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcAuthError::MissingHeader => write!(f, "Missing Authorization header"),
+            RpcAuthError::MalformedHeader => {
+                write!(f, "Authorization header must be a Bearer token")
+            }
+            RpcAuthError::InvalidToken => write!(f, "Invalid token"),
+            RpcAuthError::MissingScope(scope) => {
+                write!(f, "Token is missing required scope: {}", scope)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RpcAuthError {}
+
+impl RpcAuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RpcAuthError::MissingScope(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// Converts the error into a JSON error response
+    pub fn into_response(self) -> Response<Body> {
+        let body = serde_json::json!({ "message": self.to_string() }).to_string();
+
+        Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("Failed to build auth error response")
+    }
+}
+
+/// Tower layer rejecting requests which fail ``RpcAuthConfig::authorize`` with a 401/403 JSON error
+#[derive(Debug, Clone)]
+pub struct RpcAuthLayer {
+    config: Arc<RpcAuthConfig>,
+}
+
+impl RpcAuthLayer {
+    pub fn new(config: Arc<RpcAuthConfig>) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for RpcAuthLayer {
+    type Service = RpcAuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcAuthService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Service created by ``RpcAuthLayer``
+#[derive(Debug, Clone)]
+pub struct RpcAuthService<S> {
+    inner: S,
+    config: Arc<RpcAuthConfig>,
+}
+
+impl<S, B> Service<Request<B>> for RpcAuthService<S>
+where
+    S: Service<Request<B>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        if let Err(err) = self.config.authorize(&request) {
+            log::warn!(
+                "rejecting request {} {} (request {}): {}",
+                request.method(),
+                request.uri().path(),
+                request
+                    .extensions()
+                    .get::<RequestId>()
+                    .map(|r| r.as_str())
+                    .unwrap_or("-"),
+                err
+            );
+
+            return Box::pin(std::future::ready(Ok(err.into_response())));
+        }
+
+        Box::pin(self.inner.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    fn config() -> RpcAuthConfig {
+        RpcAuthConfig {
+            tokens: vec![
+                RpcAuthToken {
+                    token: "reader".to_string(),
+                    scopes: vec!["read".to_string()],
+                },
+                RpcAuthToken {
+                    token: "writer".to_string(),
+                    scopes: vec!["read".to_string(), "write".to_string()],
+                },
+            ],
+            route_scopes: vec![
+                ("/settings-operation".to_string(), "write".to_string()),
+                ("/modules".to_string(), "read".to_string()),
+                ("/modules/admin/".to_string(), "write".to_string()),
+            ],
+        }
+    }
+
+    async fn call(path: &str, authorization: Option<&str>) -> StatusCode {
+        let service = RpcAuthLayer::new(Arc::new(config())).layer(tower::service_fn(
+            |_: Request<Body>| async { Ok::<_, Infallible>(Response::new(Body::empty())) },
+        ));
+
+        let mut request = Request::builder().uri(path);
+
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        service
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_or_malformed_header() {
+        assert_eq!(call("/guilds-exist", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            call("/guilds-exist", Some("Basic writer")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_token() {
+        assert_eq!(
+            call("/guilds-exist", Some("Bearer wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call("/guilds-exist", Some("Bearer writerx")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_insufficient_scope() {
+        assert_eq!(
+            call("/settings-operation", Some("Bearer reader")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call("/settings-operation/123", Some("Bearer reader")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call("/settings-operation", Some("Bearer writer")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call("/modules", Some("Bearer reader")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn error_response_is_json() {
+        let service = RpcAuthLayer::new(Arc::new(config())).layer(tower::service_fn(
+            |_: Request<Body>| async { Ok::<_, Infallible>(Response::new(Body::empty())) },
+        ));
+
+        let response = service
+            .oneshot(
+                Request::builder()
+                    .uri("/modules")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["message"], "Missing Authorization header");
+    }
+
+    #[test]
+    fn scopes_match_whole_segments() {
+        let config = config();
+
+        assert_eq!(config.required_scope("/settings-operation"), Some("write"));
+        assert_eq!(
+            config.required_scope("/settings-operation/x"),
+            Some("write")
+        );
+        assert_eq!(config.required_scope("/settings-operationX"), None);
+        assert_eq!(config.required_scope("/settings-operation-x"), None);
+        assert_eq!(config.required_scope("/modulesx"), None);
+        assert_eq!(config.required_scope("/"), None);
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let config = config();
+
+        assert_eq!(config.required_scope("/modules/123"), Some("read"));
+        assert_eq!(config.required_scope("/modules/admin/x"), Some("write"));
+        // A prefix ending in ``/`` only covers paths below it
+        assert_eq!(config.required_scope("/modules/admin"), Some("read"));
+    }
+}
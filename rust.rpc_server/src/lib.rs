@@ -1,13 +1,41 @@
-use axum::{http::Request, Router};
+pub mod auth;
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod request_id;
+
+use auth::{RpcAuthConfig, RpcAuthLayer};
+use axum::{
+    body::Body,
+    http::{Request, Response, StatusCode},
+    Router,
+};
 use hyper::body::Incoming;
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server,
 };
-use std::{convert::Infallible, path::PathBuf};
+use logging::RpcLoggingConfig;
+use request_id::{RequestId, RequestIdLayer};
+use std::{
+    convert::Infallible,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::UnixListener;
+use tokio_util::task::TaskTracker;
+use tower_layer::Layer;
 use tower_service::Service;
 
+pub use tokio_util::sync::CancellationToken;
+
+/// The default permissions of the unix socket file (owner only)
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o700;
+
 #[derive(Debug, Clone)]
 pub enum CreateRpcServerBind {
     /// Bind to a specific address
@@ -17,16 +45,147 @@ pub enum CreateRpcServerBind {
     UnixSocket(String),
 }
 
+/// Peer credentials allowed to connect to a unix socket RPC server
+///
+/// A peer is accepted if its uid is in ``allowed_uids`` or its gid is in ``allowed_gids``
+#[derive(Debug, Clone, Default)]
+pub struct UnixPeerCredentials {
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+}
+
+impl UnixPeerCredentials {
+    /// Returns whether a peer with the given uid and gid may connect
+    pub fn is_allowed(&self, uid: u32, gid: u32) -> bool {
+        self.allowed_uids.contains(&uid) || self.allowed_gids.contains(&gid)
+    }
+}
+
+/// Construct with ``CreateRpcServerOptions::new`` and the ``with_*`` methods, new options may be added at any time
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct CreateRpcServerOptions {
     /// The bind address for the RPC server
     pub bind: CreateRpcServerBind,
+    /// If set, unix socket peers are checked against these credentials (via SO_PEERCRED) before routing
+    pub unix_peer_credentials: Option<UnixPeerCredentials>,
+    /// The permissions to set on the unix socket file, defaults to ``DEFAULT_UNIX_SOCKET_MODE``
+    pub unix_socket_mode: Option<u32>,
+    /// If set, requests must be authenticated with a bearer token before routing
+    pub auth: Option<RpcAuthConfig>,
+    /// If set, every request is logged along with its (redacted) JSON bodies
+    pub logging: Option<RpcLoggingConfig>,
+}
+
+impl CreateRpcServerOptions {
+    pub fn new(bind: CreateRpcServerBind) -> Self {
+        Self {
+            bind,
+            unix_peer_credentials: None,
+            unix_socket_mode: None,
+            auth: None,
+            logging: None,
+        }
+    }
+
+    /// Checks unix socket peers against ``credentials`` before routing
+    pub fn with_unix_peer_credentials(mut self, credentials: UnixPeerCredentials) -> Self {
+        self.unix_peer_credentials = Some(credentials);
+        self
+    }
+
+    /// Sets the permissions of the unix socket file instead of ``DEFAULT_UNIX_SOCKET_MODE``
+    pub fn with_unix_socket_mode(mut self, mode: u32) -> Self {
+        self.unix_socket_mode = Some(mode);
+        self
+    }
+
+    /// Requires requests to be authenticated with a bearer token
+    pub fn with_auth(mut self, auth: RpcAuthConfig) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Logs every request along with its (redacted) JSON bodies
+    pub fn with_logging(mut self, logging: RpcLoggingConfig) -> Self {
+        self.logging = Some(logging);
+        self
+    }
 }
 
+/// Binds a unix socket at ``path`` whose file has the permissions ``mode`` before anyone can connect to it
+///
+/// The socket is bound within a private (``0700``) directory next to ``path``, its permissions are set and it is then
+/// renamed into place, so there is no window in which the socket is reachable with the default (umask) permissions
+#[cfg(unix)]
+fn bind_unix_socket(path: &std::path::Path, mode: u32) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let parent = path.parent().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "unix socket path has no parent directory",
+        )
+    })?;
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "unix socket path has no file name",
+        )
+    })?;
+
+    let mut private_name = std::ffi::OsString::from(".");
+    private_name.push(file_name);
+    private_name.push(format!(".{}.bind", std::process::id()));
+    let private_dir = parent.join(private_name);
+
+    // Left over from a previous run with the same pid
+    let _ = std::fs::remove_dir_all(&private_dir);
+
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+
+    let bound = (|| {
+        let private_path = private_dir.join(file_name);
+
+        let listener = UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private_path, path)?;
+
+        Ok(listener)
+    })();
+
+    let _ = std::fs::remove_dir_all(&private_dir);
+
+    bound
+}
+
+/// Starts the RPC server, never returning
+///
+/// Use ``start_rpc_server_with_shutdown`` if the server needs to be shut down gracefully
 pub async fn start_rpc_server(
     opts: CreateRpcServerOptions,
-    mut make_service: axum::routing::IntoMakeService<Router>,
+    make_service: axum::routing::IntoMakeService<Router>,
 ) -> ! {
+    start_rpc_server_with_shutdown(opts, make_service, None).await;
+
+    unreachable!("rpc server returned without a shutdown being requested")
+}
+
+/// Starts the RPC server, returning once ``shutdown`` is cancelled and all in-flight connections have been drained
+///
+/// When bound to a unix socket, the socket file is removed on shutdown
+pub async fn start_rpc_server_with_shutdown(
+    opts: CreateRpcServerOptions,
+    mut make_service: axum::routing::IntoMakeService<Router>,
+    shutdown: Option<CancellationToken>,
+) {
+    let shutdown = shutdown.unwrap_or_default();
+    let tracker = TaskTracker::new();
+    let auth = opts.auth.map(Arc::new);
+    let logging = opts.logging.map(Arc::new);
+
     match opts.bind {
         CreateRpcServerBind::Address(addr) => {
             let listener = match tokio::net::TcpListener::bind(addr).await {
@@ -49,31 +208,27 @@ pub async fn start_rpc_server(
             );
 
             loop {
-                let (socket, _remote_addr) = match listener.accept().await {
-                    Ok(ok) => ok,
-                    Err(err) => {
-                        log::error!("failed to accept connection: {err:#}");
-                        continue;
-                    }
+                let (socket, _remote_addr) = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(ok) => ok,
+                        Err(err) => {
+                            log::error!("failed to accept connection: {err:#}");
+                            continue;
+                        }
+                    },
+                    _ = shutdown.cancelled() => break,
                 };
 
                 let tower_service = unwrap_infallible(make_service.call(&socket).await);
 
-                tokio::spawn(async move {
-                    let socket = TokioIo::new(socket);
-
-                    let hyper_service =
-                        hyper::service::service_fn(move |request: Request<Incoming>| {
-                            tower_service.clone().call(request)
-                        });
-
-                    if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
-                        .serve_connection_with_upgrades(socket, hyper_service)
-                        .await
-                    {
-                        log::error!("failed to serve connection: {err:#}");
-                    }
-                });
+                spawn_connection(
+                    &tracker,
+                    &shutdown,
+                    auth.clone(),
+                    logging.clone(),
+                    socket,
+                    tower_service,
+                );
             }
         }
         #[cfg(unix)]
@@ -95,7 +250,9 @@ pub async fn start_rpc_server(
                 }
             }
 
-            let uds = match UnixListener::bind(path.clone()) {
+            let mode = opts.unix_socket_mode.unwrap_or(DEFAULT_UNIX_SOCKET_MODE);
+
+            let uds = match bind_unix_socket(&path, mode) {
                 Ok(ok) => ok,
                 Err(err) => {
                     log::error!("failed to bind to unix socket: {err:#}");
@@ -104,36 +261,247 @@ pub async fn start_rpc_server(
             };
 
             loop {
-                let (socket, _remote_addr) = match uds.accept().await {
-                    Ok(ok) => ok,
-                    Err(err) => {
-                        log::error!("failed to accept connection: {err:#}");
-                        continue;
-                    }
+                let (socket, _remote_addr) = tokio::select! {
+                    res = uds.accept() => match res {
+                        Ok(ok) => ok,
+                        Err(err) => {
+                            log::error!("failed to accept connection: {err:#}");
+                            continue;
+                        }
+                    },
+                    _ = shutdown.cancelled() => break,
                 };
 
+                if let Some(ref allowed) = opts.unix_peer_credentials {
+                    match socket.peer_cred() {
+                        Ok(cred) if allowed.is_allowed(cred.uid(), cred.gid()) => {}
+                        Ok(cred) => {
+                            log::warn!(
+                                "rejecting unix socket peer (uid={}, gid={}, pid={:?})",
+                                cred.uid(),
+                                cred.gid(),
+                                cred.pid()
+                            );
+                            continue;
+                        }
+                        Err(err) => {
+                            log::error!("failed to get unix socket peer credentials: {err:#}");
+                            continue;
+                        }
+                    }
+                }
+
                 let tower_service = unwrap_infallible(make_service.call(&socket).await);
 
-                tokio::spawn(async move {
-                    let socket = TokioIo::new(socket);
+                spawn_connection(
+                    &tracker,
+                    &shutdown,
+                    auth.clone(),
+                    logging.clone(),
+                    socket,
+                    tower_service,
+                );
+            }
 
-                    let hyper_service =
-                        hyper::service::service_fn(move |request: Request<Incoming>| {
-                            tower_service.clone().call(request)
-                        });
+            drain_connections(&tracker).await;
 
-                    if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
-                        .serve_connection_with_upgrades(socket, hyper_service)
-                        .await
-                    {
-                        log::error!("failed to serve connection: {err:#}");
-                    }
-                });
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                log::error!("failed to remove unix socket file: {err:#}");
+            }
+
+            return;
+        }
+    }
+
+    drain_connections(&tracker).await;
+}
+
+/// Serves a single connection, gracefully shutting it down once ``shutdown`` is cancelled
+///
+/// Requests pass through ``RequestIdLayer``, then ``RpcAuthLayer`` (if enabled), then logging (if enabled), so bodies
+/// of rejected requests are never buffered for logging
+fn spawn_connection<I>(
+    tracker: &TaskTracker,
+    shutdown: &CancellationToken,
+    auth: Option<Arc<RpcAuthConfig>>,
+    logging: Option<Arc<RpcLoggingConfig>>,
+    socket: I,
+    tower_service: Router,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let shutdown = shutdown.clone();
+
+    let service = RpcService {
+        inner: tower_service,
+        logging,
+    };
+
+    match auth {
+        Some(auth) => serve_connection(
+            tracker,
+            shutdown,
+            socket,
+            RpcAuthLayer::new(auth).layer(service),
+        ),
+        None => serve_connection(tracker, shutdown, socket, service),
+    }
+}
+
+/// Spawns the task serving a connection with the given service stack
+fn serve_connection<I, S>(tracker: &TaskTracker, shutdown: CancellationToken, socket: I, service: S)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    tracker.spawn(async move {
+        let socket = TokioIo::new(socket);
+
+        let service = RequestIdLayer.layer(service);
+
+        let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+            let mut service = service.clone();
+            service.call(request.map(Body::new))
+        });
+
+        let builder = server::conn::auto::Builder::new(TokioExecutor::new());
+        let conn = builder.serve_connection_with_upgrades(socket, hyper_service);
+        tokio::pin!(conn);
+
+        let res = tokio::select! {
+            res = conn.as_mut() => res,
+            _ = shutdown.cancelled() => {
+                conn.as_mut().graceful_shutdown();
+                conn.as_mut().await
             }
+        };
+
+        if let Err(err) = res {
+            log::error!("failed to serve connection: {err:#}");
         }
+    });
+}
+
+/// Logs a request handled by ``inner``, expecting ``RequestIdLayer`` to have assigned its request id
+#[derive(Clone)]
+struct RpcService<S> {
+    inner: S,
+    logging: Option<Arc<RpcLoggingConfig>>,
+}
+
+impl<S> Service<Request<Body>> for RpcService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        // The clone may not be ready, so call the service that was polled and keep the clone for the next request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let logging = self.logging.clone();
+
+        Box::pin(async move {
+            let start = std::time::Instant::now();
+            let request_id = request
+                .extensions()
+                .get::<RequestId>()
+                .cloned()
+                .unwrap_or_else(|| request_id::assign_request_id(&mut request));
+
+            let method = request.method().clone();
+            let path = request.uri().path().to_string();
+
+            let mut request_body = None;
+
+            let request = match logging {
+                Some(ref logging) => {
+                    let (parts, body) = request.into_parts();
+
+                    match logging::capture_body(
+                        logging,
+                        &parts.headers,
+                        body,
+                        StatusCode::BAD_REQUEST,
+                    )
+                    .await
+                    {
+                        Ok((body, captured)) => {
+                            request_body = captured;
+                            Ok(Request::from_parts(parts, body))
+                        }
+                        Err(response) => Err(response),
+                    }
+                }
+                None => Ok(request),
+            };
+
+            let response = match request {
+                Ok(request) => inner.call(request).await?,
+                Err(response) => response,
+            };
+
+            let Some(logging) = logging else {
+                return Ok(response);
+            };
+
+            let (parts, body) = response.into_parts();
+            let (parts, body, response_body) = match logging::capture_body(
+                &logging,
+                &parts.headers,
+                body,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .await
+            {
+                Ok((body, captured)) => (parts, body, captured),
+                Err(response) => {
+                    let (parts, body) = response.into_parts();
+                    (parts, body, None)
+                }
+            };
+
+            logging::log_exchange(
+                &logging,
+                logging::Exchange {
+                    request_id: &request_id,
+                    method: &method,
+                    path: &path,
+                    status: parts.status,
+                    latency: start.elapsed(),
+                    request_body,
+                    response_body,
+                },
+            );
+
+            Ok(Response::from_parts(parts, body))
+        })
     }
 }
 
+/// Waits for all in-flight connections to finish
+async fn drain_connections(tracker: &TaskTracker) {
+    tracker.close();
+
+    log::info!("Draining {} in-flight connections", tracker.len());
+
+    tracker.wait().await;
+}
+
 fn unwrap_infallible<T>(result: Result<T, Infallible>) -> T {
     match result {
         Ok(value) => value,
@@ -141,3 +509,150 @@ fn unwrap_infallible<T>(result: Result<T, Infallible>) -> T {
         Err(never) => match never {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory for a test, unique within the test run
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rpc-server-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Only accepts a call on an instance that ``poll_ready`` returned ready for, clones start out not ready
+    struct ReadyOnce {
+        open: Arc<std::sync::atomic::AtomicBool>,
+        ready: bool,
+    }
+
+    impl Clone for ReadyOnce {
+        fn clone(&self) -> Self {
+            Self {
+                open: self.open.clone(),
+                ready: false,
+            }
+        }
+    }
+
+    impl Service<Request<Body>> for ReadyOnce {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            if !self.open.load(std::sync::atomic::Ordering::Relaxed) {
+                return Poll::Pending;
+            }
+
+            self.ready = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Request<Body>) -> Self::Future {
+            assert!(self.ready, "called before poll_ready");
+            self.ready = false;
+
+            std::future::ready(Ok(Response::new(Body::empty())))
+        }
+    }
+
+    #[tokio::test]
+    async fn rpc_service_waits_for_the_inner_service() {
+        let open = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let mut service = RpcService {
+            inner: ReadyOnce {
+                open: open.clone(),
+                ready: false,
+            },
+            logging: None,
+        };
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+
+        assert!(service.poll_ready(&mut cx).is_pending());
+
+        open.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for _ in 0..2 {
+            assert!(service.poll_ready(&mut cx).is_ready());
+
+            let response = service.call(Request::new(Body::empty())).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn options_builder_sets_fields() {
+        let opts =
+            CreateRpcServerOptions::new(CreateRpcServerBind::Address("127.0.0.1:0".to_string()))
+                .with_unix_socket_mode(0o770)
+                .with_unix_peer_credentials(UnixPeerCredentials {
+                    allowed_uids: vec![1000],
+                    allowed_gids: Vec::new(),
+                })
+                .with_logging(RpcLoggingConfig::default());
+
+        assert_eq!(opts.unix_socket_mode, Some(0o770));
+        assert!(opts
+            .unix_peer_credentials
+            .as_ref()
+            .is_some_and(|c| c.is_allowed(1000, 0) && !c.is_allowed(1001, 0)));
+        assert!(opts.logging.is_some());
+        assert!(opts.auth.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_unix_socket_with_mode_in_place() {
+        use std::os::unix::fs::PermissionsExt;
+
+        for mode in [DEFAULT_UNIX_SOCKET_MODE, 0o660] {
+            let dir = test_dir(&format!("bind-{:o}", mode));
+            let path = dir.join("rpc.sock");
+
+            // A stale socket file is replaced
+            std::fs::write(&path, "stale").unwrap();
+
+            let listener = bind_unix_socket(&path, mode).unwrap();
+
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, mode);
+
+            // Only the socket is left behind, the private bind directory is removed
+            let entries = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert_eq!(entries, vec![std::ffi::OsString::from("rpc.sock")]);
+
+            let (client, accepted) =
+                tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+            client.unwrap();
+            accepted.unwrap();
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_errors_clean_up_the_private_directory() {
+        let dir = test_dir("bind-error");
+
+        // The final path is a non-empty directory, so renaming the socket onto it fails
+        let path = dir.join("rpc.sock");
+        std::fs::create_dir_all(path.join("occupied")).unwrap();
+
+        assert!(bind_unix_socket(&path, DEFAULT_UNIX_SOCKET_MODE).is_err());
+
+        let entries = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![std::ffi::OsString::from("rpc.sock")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Method, Response, StatusCode},
+};
+use hyper::body::Body as _;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::request_id::RequestId;
+
+/// Replacement for redacted values
+pub const REDACTED: &str = "[redacted]";
+
+/// Route whose ``fields`` maps are additionally redacted using ``RpcLoggingConfig::settings``
+pub const SETTINGS_OPERATION_PATH: &str = "/settings-operation";
+
+/// Looks up which columns of a setting are secret
+///
+/// The bot implements this over its setting definitions (the columns with ``secret`` set), any
+/// ``Fn(&str) -> Option<Vec<String>>`` closure also works
+pub trait SettingsSecretColumns: Send + Sync {
+    /// Returns the ids of the columns of ``setting`` marked as secret, None if the setting is not known
+    fn secret_columns(&self, setting: &str) -> Option<Vec<String>>;
+}
+
+impl<F> SettingsSecretColumns for F
+where
+    F: Fn(&str) -> Option<Vec<String>> + Send + Sync,
+{
+    fn secret_columns(&self, setting: &str) -> Option<Vec<String>> {
+        self(setting)
+    }
+}
+
+/// Opt-in request/response logging
+///
+/// JSON bodies of at most ``max_body_size`` bytes are logged after redaction, larger or non-JSON bodies are not
+/// buffered at all
+#[derive(Clone)]
+pub struct RpcLoggingConfig {
+    /// Bodies larger than this (or of unknown size) are not logged
+    pub max_body_size: usize,
+    /// Values of JSON keys containing any of these (case insensitive) are redacted, at any depth
+    pub redact_keys: Vec<String>,
+    /// The setting definitions whose secret columns are redacted within ``fields`` of settings operations
+    ///
+    /// If unset, or if the setting is not known, every column of ``fields`` is redacted
+    pub settings: Option<Arc<dyn SettingsSecretColumns>>,
+}
+
+impl std::fmt::Debug for RpcLoggingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcLoggingConfig")
+            .field("max_body_size", &self.max_body_size)
+            .field("redact_keys", &self.redact_keys)
+            .field(
+                "settings",
+                &self.settings.as_ref().map(|_| "SettingsSecretColumns"),
+            )
+            .finish()
+    }
+}
+
+impl Default for RpcLoggingConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: 16 * 1024,
+            redact_keys: vec![
+                "token".to_string(),
+                "secret".to_string(),
+                "webhook".to_string(),
+                "password".to_string(),
+            ],
+            settings: None,
+        }
+    }
+}
+
+impl RpcLoggingConfig {
+    /// Returns whether the value of ``key`` should be redacted
+    pub fn should_redact_key(&self, key: &str) -> bool {
+        let key = key.to_lowercase();
+
+        self.redact_keys
+            .iter()
+            .any(|pattern| key.contains(&pattern.to_lowercase()))
+    }
+
+    /// Redacts the values of matching keys, recursing into objects and arrays
+    pub fn redact(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.should_redact_key(key) {
+                        *value = serde_json::Value::String(REDACTED.to_string());
+                    } else {
+                        self.redact(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values.iter_mut() {
+                    self.redact(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Redacts the secret columns of ``setting`` within every ``fields`` map (or array of maps) of ``value``
+    ///
+    /// All columns are redacted if the setting's secret columns are not known
+    pub fn redact_settings_fields(&self, setting: Option<&str>, value: &mut serde_json::Value) {
+        let secret_columns = setting.and_then(|setting| {
+            self.settings
+                .as_ref()
+                .and_then(|settings| settings.secret_columns(setting))
+        });
+
+        redact_fields(secret_columns.as_deref(), value);
+    }
+}
+
+fn redact_fields(secret_columns: Option<&[String]>, value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if key == "fields" {
+                    redact_columns(secret_columns, value);
+                }
+
+                redact_fields(secret_columns, value);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values.iter_mut() {
+                redact_fields(secret_columns, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_columns(secret_columns: Option<&[String]>, fields: &mut serde_json::Value) {
+    match fields {
+        serde_json::Value::Object(map) => {
+            for (column, value) in map.iter_mut() {
+                let secret = secret_columns.is_none_or(|columns| columns.contains(column));
+
+                if secret && !value.is_null() {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                }
+            }
+        }
+        serde_json::Value::Array(rows) => {
+            for row in rows.iter_mut() {
+                redact_columns(secret_columns, row);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"))
+}
+
+/// Buffers a JSON body of known size within ``max_body_size`` so it can be logged, returning the body to pass on
+///
+/// If the body fails to be read, it has been consumed and cannot be passed on, so ``status`` is returned with a JSON
+/// error response instead
+pub(crate) async fn capture_body(
+    config: &RpcLoggingConfig,
+    headers: &HeaderMap,
+    body: Body,
+    status: StatusCode,
+) -> Result<(Body, Option<serde_json::Value>), Response<Body>> {
+    if !is_json(headers) {
+        return Ok((body, None));
+    }
+
+    if body
+        .size_hint()
+        .upper()
+        .is_none_or(|size| size > config.max_body_size as u64)
+    {
+        return Ok((body, None));
+    }
+
+    match axum::body::to_bytes(body, config.max_body_size).await {
+        Ok(bytes) => {
+            let parsed = serde_json::from_slice(&bytes).ok();
+            Ok((Body::from(bytes), parsed))
+        }
+        Err(err) => {
+            log::warn!("Failed to buffer body for logging: {}", err);
+
+            let body = serde_json::json!({ "message": format!("Failed to read body: {}", err) });
+
+            Err(Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .expect("Failed to build body error response"))
+        }
+    }
+}
+
+/// A request/response pair to log
+pub(crate) struct Exchange<'a> {
+    pub request_id: &'a RequestId,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub status: StatusCode,
+    pub latency: Duration,
+    pub request_body: Option<serde_json::Value>,
+    pub response_body: Option<serde_json::Value>,
+}
+
+/// Redacts and logs an exchange
+pub(crate) fn log_exchange(config: &RpcLoggingConfig, exchange: Exchange<'_>) {
+    let Exchange {
+        request_id,
+        method,
+        path,
+        status,
+        latency,
+        mut request_body,
+        mut response_body,
+    } = exchange;
+
+    if path.ends_with(SETTINGS_OPERATION_PATH) {
+        let setting = request_body
+            .as_ref()
+            .and_then(|b| b.get("setting"))
+            .and_then(|s| s.as_str())
+            .map(|s| s.to_string());
+
+        for body in [&mut request_body, &mut response_body]
+            .into_iter()
+            .flatten()
+        {
+            config.redact_settings_fields(setting.as_deref(), body);
+        }
+    }
+
+    for body in [&mut request_body, &mut response_body]
+        .into_iter()
+        .flatten()
+    {
+        config.redact(body);
+    }
+
+    let fmt_body = |body: Option<serde_json::Value>| match body {
+        Some(body) => body.to_string(),
+        None => "-".to_string(),
+    };
+
+    log::info!(
+        "rpc {} {} -> {} in {}ms (request {}) request_body={} response_body={}",
+        method,
+        path,
+        status.as_u16(),
+        latency.as_millis(),
+        request_id,
+        fmt_body(request_body),
+        fmt_body(response_body)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use hyper::body::{Frame, SizeHint};
+    use serde_json::json;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    fn json_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers
+    }
+
+    /// Claims a small exact size but fails when read
+    struct FailingBody;
+
+    impl hyper::body::Body for FailingBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(Some(Err(std::io::Error::other("connection reset"))))
+        }
+
+        fn size_hint(&self) -> SizeHint {
+            SizeHint::with_exact(10)
+        }
+    }
+
+    #[test]
+    fn redacts_nested_keys_case_insensitively() {
+        let config = RpcLoggingConfig::default();
+
+        let mut value = json!({
+            "guild_id": "123",
+            "Token": "abc",
+            "nested": {
+                "client_secret": "def",
+                "items": [{ "webhook_url": "https://example.com", "name": "kept" }]
+            }
+        });
+
+        config.redact(&mut value);
+
+        assert_eq!(
+            value,
+            json!({
+                "guild_id": "123",
+                "Token": REDACTED,
+                "nested": {
+                    "client_secret": REDACTED,
+                    "items": [{ "webhook_url": REDACTED, "name": "kept" }]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn redacts_secret_setting_columns() {
+        let config = RpcLoggingConfig {
+            settings: Some(Arc::new(|setting: &str| match setting {
+                "lockdown_settings" => Some(vec!["api_key".to_string()]),
+                _ => None,
+            })),
+            ..Default::default()
+        };
+
+        let mut value = json!({
+            "setting": "lockdown_settings",
+            "fields": { "api_key": "abc", "channel": "123", "unset": null },
+            "rows": [{ "fields": { "api_key": "def", "channel": "456" } }]
+        });
+
+        config.redact_settings_fields(Some("lockdown_settings"), &mut value);
+
+        assert_eq!(
+            value,
+            json!({
+                "setting": "lockdown_settings",
+                "fields": { "api_key": REDACTED, "channel": "123", "unset": null },
+                "rows": [{ "fields": { "api_key": REDACTED, "channel": "456" } }]
+            })
+        );
+    }
+
+    #[test]
+    fn redacts_all_columns_of_unknown_settings() {
+        let config = RpcLoggingConfig {
+            settings: Some(Arc::new(|_: &str| None)),
+            ..Default::default()
+        };
+
+        for setting in [Some("unknown"), None] {
+            let mut value =
+                json!({ "fields": { "api_key": "abc", "channel": "123", "unset": null } });
+
+            config.redact_settings_fields(setting, &mut value);
+
+            assert_eq!(
+                value,
+                json!({ "fields": { "api_key": REDACTED, "channel": REDACTED, "unset": null } })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn captures_json_bodies_within_threshold() {
+        let config = RpcLoggingConfig {
+            max_body_size: 32,
+            ..Default::default()
+        };
+
+        let (body, captured) = capture_body(
+            &config,
+            &json_headers(),
+            Body::from(r#"{"a":1}"#),
+            StatusCode::BAD_REQUEST,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(captured, Some(json!({ "a": 1 })));
+        assert_eq!(
+            axum::body::to_bytes(body, usize::MAX).await.unwrap(),
+            r#"{"a":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_bodies_over_threshold_or_not_json() {
+        let config = RpcLoggingConfig {
+            max_body_size: 32,
+            ..Default::default()
+        };
+
+        let large = format!(r#"{{"a":"{}"}}"#, "x".repeat(64));
+
+        let (body, captured) = capture_body(
+            &config,
+            &json_headers(),
+            Body::from(large.clone()),
+            StatusCode::BAD_REQUEST,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(captured, None);
+        assert_eq!(axum::body::to_bytes(body, usize::MAX).await.unwrap(), large);
+
+        let (_, captured) = capture_body(
+            &config,
+            &HeaderMap::new(),
+            Body::from(r#"{"a":1}"#),
+            StatusCode::BAD_REQUEST,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(captured, None);
+    }
+
+    #[tokio::test]
+    async fn failed_body_read_returns_error_response() {
+        let config = RpcLoggingConfig::default();
+
+        let response = capture_body(
+            &config,
+            &json_headers(),
+            Body::new(FailingBody),
+            StatusCode::BAD_REQUEST,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("Failed to read body"));
+    }
+}
@@ -0,0 +1,257 @@
+use axum::extract::MatchedPath;
+use axum::http::{header, Method, Request, Response};
+use axum::routing::get;
+use axum::Router;
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Path of the route rendering all metrics in the Prometheus text format, see ``metrics_router``
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Histogram of request durations (in seconds), labelled by ``route`` and ``method``
+pub const REQUEST_DURATION_METRIC: &str = "antiraid_rpc_request_duration_seconds";
+
+/// Counter of requests, labelled by ``route``, ``method`` and ``status``
+pub const REQUESTS_METRIC: &str = "antiraid_rpc_requests_total";
+
+/// ``route`` label of requests which did not match any route, so unknown paths never create new series
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Installs a Prometheus recorder as the global metrics recorder, returning the handle to pass to ``metrics_router``
+///
+/// Everything recorded through the ``metrics`` crate (including silverpelt's metrics) is then rendered on ``/metrics``
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new().install_recorder()
+}
+
+/// Returns a router serving ``GET /metrics``, to be merged into the RPC router
+pub fn metrics_router<S>(handle: PrometheusHandle) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route(
+        METRICS_PATH,
+        get(move || {
+            let handle = handle.clone();
+
+            async move {
+                (
+                    [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    handle.render(),
+                )
+            }
+        }),
+    )
+}
+
+/// Returns the ``method`` label of a request, non-standard methods share a single label
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+/// Tower layer recording the duration and status of every request
+///
+/// Must be added with ``Router::layer`` so the matched route is known. The route template (e.g. ``/guilds/:guild_id``)
+/// is used as the ``route`` label rather than the path, keeping label cardinality bounded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsLayer;
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService { inner }
+    }
+}
+
+/// Service created by ``MetricsLayer``
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for MetricsService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|p| p.as_str().to_string())
+            .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+        let method = method_label(request.method());
+
+        let start = Instant::now();
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = future.await?;
+
+            metrics::histogram!(
+                REQUEST_DURATION_METRIC,
+                "route" => route.clone(),
+                "method" => method,
+            )
+            .record(start.elapsed());
+
+            metrics::counter!(
+                REQUESTS_METRIC,
+                "route" => route,
+                "method" => method,
+                "status" => response.status().as_u16().to_string(),
+            )
+            .increment(1);
+
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    async fn call(router: &Router, method: Method, uri: &str) -> Response<Body> {
+        router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    /// Runs ``f`` on a current thread runtime with ``recorder`` installed for this thread only
+    fn with_recorder<F: Future>(
+        recorder: &metrics_exporter_prometheus::PrometheusRecorder,
+        f: F,
+    ) -> F::Output {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        metrics::with_local_recorder(recorder, || rt.block_on(f))
+    }
+
+    #[test]
+    fn records_requests_by_route_template() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        let router = Router::new()
+            .route(
+                "/guilds/:guild_id",
+                get(|| async { "ok" }).post(|| async { StatusCode::BAD_REQUEST }),
+            )
+            .layer(MetricsLayer);
+
+        with_recorder(&recorder, async {
+            for uri in ["/guilds/1", "/guilds/2"] {
+                assert_eq!(
+                    call(&router, Method::GET, uri).await.status(),
+                    StatusCode::OK
+                );
+            }
+
+            assert_eq!(
+                call(&router, Method::POST, "/guilds/3").await.status(),
+                StatusCode::BAD_REQUEST
+            );
+            assert_eq!(
+                call(&router, Method::GET, "/unknown/4").await.status(),
+                StatusCode::NOT_FOUND
+            );
+        });
+
+        let rendered = handle.render();
+
+        assert!(rendered.contains(
+            r#"antiraid_rpc_requests_total{route="/guilds/:guild_id",method="GET",status="200"} 2"#
+        ));
+        assert!(rendered.contains(
+            r#"antiraid_rpc_requests_total{route="/guilds/:guild_id",method="POST",status="400"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"antiraid_rpc_requests_total{route="unmatched",method="GET",status="404"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"antiraid_rpc_request_duration_seconds_count{route="/guilds/:guild_id",method="GET"} 2"#
+        ));
+
+        // Concrete ids never end up in labels
+        for id in ["/guilds/1", "/guilds/2", "/unknown/4"] {
+            assert!(!rendered.contains(id));
+        }
+    }
+
+    #[test]
+    fn metrics_route_renders_prometheus_text() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        let router = Router::new()
+            .route("/ping", get(|| async { "pong" }))
+            .merge(metrics_router(handle))
+            .layer(MetricsLayer);
+
+        let (content_type, body) = with_recorder(&recorder, async {
+            call(&router, Method::GET, "/ping").await;
+
+            let response = call(&router, Method::GET, METRICS_PATH).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let content_type = response.headers()[header::CONTENT_TYPE].clone();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+
+            (content_type, String::from_utf8(body.to_vec()).unwrap())
+        });
+
+        assert_eq!(content_type, "text/plain; version=0.0.4");
+        assert!(body.contains("# TYPE antiraid_rpc_requests_total counter"));
+        assert!(body
+            .contains(r#"antiraid_rpc_requests_total{route="/ping",method="GET",status="200"} 1"#));
+    }
+
+    #[test]
+    fn non_standard_methods_share_a_label() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PURGE").unwrap()),
+            "other"
+        );
+    }
+}
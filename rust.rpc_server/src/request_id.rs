@@ -0,0 +1,141 @@
+use axum::http::{HeaderValue, Request, Response};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::Instrument;
+
+pub use ::request_id::{RequestId, MAX_REQUEST_ID_LEN, REQUEST_ID_HEADER};
+
+/// Reads the request id from the ``x-request-id`` header, generating one if it is missing or invalid
+///
+/// The id is stored in the request extensions and the header is set to it
+pub fn assign_request_id<B>(request: &mut Request<B>) -> RequestId {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_else(RequestId::generate);
+
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        request.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    request.extensions_mut().insert(request_id.clone());
+
+    request_id
+}
+
+/// Sets the ``x-request-id`` header of a response
+pub fn set_response_header<B>(response: &mut Response<B>, request_id: &RequestId) {
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+/// Tower layer assigning each request a ``RequestId`` (see ``assign_request_id``) and echoing it on the response
+///
+/// The inner service runs within an ``rpc_request`` tracing span carrying the request id, method and path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+/// Service created by ``RequestIdLayer``
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for RequestIdService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let request_id = assign_request_id(&mut request);
+
+        let span = tracing::info_span!(
+            "rpc_request",
+            request_id = %request_id,
+            method = %request.method(),
+            path = request.uri().path(),
+        );
+
+        let future = span.in_scope(|| self.inner.call(request));
+
+        Box::pin(
+            async move {
+                let mut response = future.await?;
+                set_response_header(&mut response, &request_id);
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    /// Calls a service behind ``RequestIdLayer`` which responds with the request id it saw
+    async fn echo(request: Request<()>) -> Response<String> {
+        let service = RequestIdLayer.layer(tower::service_fn(|request: Request<()>| async move {
+            let request_id = request.extensions().get::<RequestId>().cloned().unwrap();
+            Ok::<_, Infallible>(Response::new(request_id.0))
+        }));
+
+        service.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn layer_propagates_incoming_id() {
+        let request = Request::builder()
+            .header(REQUEST_ID_HEADER, "incoming-id")
+            .body(())
+            .unwrap();
+
+        let response = echo(request).await;
+
+        assert_eq!(response.body(), "incoming-id");
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "incoming-id");
+    }
+
+    #[tokio::test]
+    async fn layer_replaces_missing_or_invalid_id() {
+        for header in [None, Some("not valid")] {
+            let mut request = Request::builder();
+
+            if let Some(header) = header {
+                request = request.header(REQUEST_ID_HEADER, header);
+            }
+
+            let response = echo(request.body(()).unwrap()).await;
+
+            let assigned = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+
+            assert_eq!(response.body(), assigned);
+            assert_ne!(Some(assigned), header);
+            assert!(RequestId::parse(assigned).is_some());
+        }
+    }
+}
@@ -1,4 +1,13 @@
+pub mod batch;
+pub mod custom_events;
+pub mod event_sinks;
+
+pub use batch::{BatchOptions, BatchedDispatcher};
+pub use custom_events::CustomEventBuilder;
+
 use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
 
 use crate::data::Data;
 use antiraid_types::ar_event::AntiraidEvent;
@@ -23,11 +32,240 @@ pub trait AntiraidEventOperations {
     ) -> Result<AntiraidEventResultHandle, crate::Error>;
 }
 
+/// Where events are dispatched
+///
+/// How they are dispatched (retries, webhook sinks and batching) is configured per process with ``DispatchOptions``
 pub struct DispatchEventData {
     pub template_worker_addr: &'static str,
     pub template_worker_port: u16,
 }
 
+impl DispatchEventData {
+    pub fn new(template_worker_addr: &'static str, template_worker_port: u16) -> Self {
+        Self {
+            template_worker_addr,
+            template_worker_port,
+        }
+    }
+}
+
+/// Controls how events are dispatched, registered on ``Data`` with ``insert_extension``
+///
+/// If none is registered, events are dispatched directly with the default retry policy and without sinks, see
+/// ``Data::dispatch_options``
+#[derive(Clone, Default)]
+pub struct DispatchOptions {
+    pub retry_policy: DispatchRetryPolicy,
+    /// If set, events are also fanned out to the guild's webhook sinks in the background, whatever the result of the
+    /// template worker call
+    pub sinks: Option<event_sinks::SinkDispatchOptions>,
+    /// If set, ``dispatch_to_template_worker_and_nowait`` queues events in the batcher instead of POSTing them directly
+    pub batcher: Option<std::sync::Arc<batch::BatchedDispatcher>>,
+}
+
+impl DispatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: DispatchRetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_sinks(mut self, sinks: event_sinks::SinkDispatchOptions) -> Self {
+        self.sinks = Some(sinks);
+        self
+    }
+
+    pub fn with_batcher(mut self, batcher: std::sync::Arc<batch::BatchedDispatcher>) -> Self {
+        self.batcher = Some(batcher);
+        self
+    }
+}
+
+/// Controls how dispatches to the template worker are retried
+///
+/// Connection failures, timeouts and 5xx responses are retried, everything else fails immediately
+#[derive(Clone, Copy, Debug)]
+pub struct DispatchRetryPolicy {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled on every further retry
+    pub initial_backoff: Duration,
+    /// Upper bound on the backoff between two attempts
+    pub max_backoff: Duration,
+    /// No retry is started if it would end after this much time has passed since the first attempt
+    pub deadline: Duration,
+}
+
+impl Default for DispatchRetryPolicy {
+    fn default() -> Self {
+        DispatchRetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(1),
+            deadline: Duration::from_secs(2),
+        }
+    }
+}
+
+impl DispatchRetryPolicy {
+    /// Returns the backoff to wait after the given (1-indexed) failed attempt, with jitter applied
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        // Equal jitter: half the backoff plus a random share of the other half, so retries never bunch up at zero
+        let half = exp / 2;
+        let jitter_range = (exp - half).as_millis() as u64;
+
+        if jitter_range == 0 {
+            return exp;
+        }
+
+        let random = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+
+        half + Duration::from_millis(random % (jitter_range + 1))
+    }
+}
+
+/// Returns the name of the event variant (e.g. ``StingCreate``) for logging
+fn event_name(event: &AntiraidEvent) -> String {
+    match serde_json::to_value(event) {
+        Ok(serde_json::Value::Object(map)) => map
+            .keys()
+            .next()
+            .cloned()
+            .unwrap_or_else(|| "Unknown".to_string()),
+        Ok(serde_json::Value::String(s)) => s,
+        _ => "Unknown".to_string(),
+    }
+}
+
+/// Fans the event out to the guild's webhook sinks in the background if enabled
+///
+/// Sink deliveries never fail or delay the dispatch itself
+fn fan_out_to_sinks(
+    event: &AntiraidEvent,
+    data: &Data,
+    guild_id: serenity::all::GuildId,
+    dispatch_options: &DispatchOptions,
+) {
+    let Some(opts) = dispatch_options.sinks else {
+        return;
+    };
+
+    let event = event.clone();
+    let data = data.clone();
+
+    tokio::spawn(async move {
+        if let Err(e) = event_sinks::dispatch_to_sinks(&event, guild_id, &data, &opts).await {
+            log::error!(
+                "Failed to dispatch {} to webhook sinks for guild {}: {}",
+                event_name(&event),
+                guild_id,
+                e
+            );
+        }
+    });
+}
+
+/// POSTs the event to the template worker, retrying retryable failures according to the retry policy
+async fn send_with_retry<T: serde::Serialize + ?Sized>(
+    event: &T,
+    event_name: &(dyn Fn() -> String + Sync),
+    reqwest: &reqwest::Client,
+    url: &str,
+    guild_id: serenity::all::GuildId,
+    policy: &DispatchRetryPolicy,
+    request_id: Option<&str>,
+) -> Result<reqwest::Response, crate::Error> {
+    let start = Instant::now();
+    let res = send_with_retry_impl(
+        event, event_name, reqwest, url, guild_id, policy, request_id,
+    )
+    .await;
+
+    crate::metrics::record_duration(
+        "antiraid_template_worker_dispatch_duration_seconds",
+        &[("outcome", if res.is_ok() { "ok" } else { "error" })],
+        start.elapsed(),
+    );
+
+    res
+}
+
+async fn send_with_retry_impl<T: serde::Serialize + ?Sized>(
+    event: &T,
+    event_name: &(dyn Fn() -> String + Sync),
+    reqwest: &reqwest::Client,
+    url: &str,
+    guild_id: serenity::all::GuildId,
+    policy: &DispatchRetryPolicy,
+    request_id: Option<&str>,
+) -> Result<reqwest::Response, crate::Error> {
+    let start = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let mut req = reqwest.post(url).json(event);
+
+        if let Some(request_id) = request_id {
+            req = req.header(crate::request_context::REQUEST_ID_HEADER, request_id);
+        }
+
+        let err: crate::Error = match req.send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(resp),
+            Ok(resp) => {
+                let status = resp.status();
+                let err_text = resp
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown error".to_string());
+
+                if !status.is_server_error() {
+                    return Err(err_text.into());
+                }
+
+                err_text.into()
+            }
+            Err(e) => {
+                if !e.is_connect() && !e.is_timeout() {
+                    return Err(e.into());
+                }
+
+                e.into()
+            }
+        };
+
+        let backoff = policy.backoff(attempt);
+
+        if attempt >= policy.max_attempts || start.elapsed() + backoff > policy.deadline {
+            return Err(err);
+        }
+
+        log::warn!(
+            "Dispatch of {} to template worker failed for guild {} (attempt {}/{}, request {}), retrying in {:?}: {}",
+            event_name(),
+            guild_id,
+            attempt,
+            policy.max_attempts,
+            request_id.unwrap_or("none"),
+            backoff,
+            err
+        );
+
+        tokio::time::sleep(backoff).await;
+    }
+}
+
 impl AntiraidEventOperations for AntiraidEvent {
     /// Dispatch the event to the template worker process
     async fn dispatch_to_template_worker_and_nowait(
@@ -36,6 +274,16 @@ impl AntiraidEventOperations for AntiraidEvent {
         guild_id: serenity::all::GuildId,
         dispatch_event_data: &DispatchEventData,
     ) -> Result<(), crate::Error> {
+        let dispatch_options = data.dispatch_options();
+
+        fan_out_to_sinks(self, data, guild_id, &dispatch_options);
+
+        if let Some(batcher) = &dispatch_options.batcher {
+            batcher.enqueue(self.clone(), guild_id).await?;
+
+            return Ok(());
+        }
+
         let url = format!(
             "http://{}:{}/dispatch-event/{}",
             &dispatch_event_data.template_worker_addr,
@@ -43,18 +291,20 @@ impl AntiraidEventOperations for AntiraidEvent {
             guild_id
         );
 
-        let resp = data.reqwest.post(&url).json(&self).send().await?;
+        let request_context = crate::request_context::RequestContext::current();
 
-        if resp.status().is_success() {
-            Ok(())
-        } else {
-            let err_text = resp
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
+        send_with_retry(
+            self,
+            &|| event_name(self),
+            &data.reqwest,
+            &url,
+            guild_id,
+            &dispatch_options.retry_policy,
+            request_context.as_ref().map(|ctx| ctx.request_id.as_str()),
+        )
+        .await?;
 
-            Err(err_text.into())
-        }
+        Ok(())
     }
 
     /// Dispatch the event to the template worker process
@@ -65,6 +315,10 @@ impl AntiraidEventOperations for AntiraidEvent {
         dispatch_event_data: &DispatchEventData,
         wait_timeout: std::time::Duration,
     ) -> Result<AntiraidEventResultHandle, crate::Error> {
+        let dispatch_options = data.dispatch_options();
+
+        fan_out_to_sinks(self, data, guild_id, &dispatch_options);
+
         let url = format!(
             "http://{}:{}/dispatch-event/{}/@wait?wait_timeout={}",
             &dispatch_event_data.template_worker_addr,
@@ -73,35 +327,98 @@ impl AntiraidEventOperations for AntiraidEvent {
             wait_timeout.as_millis()
         );
 
-        let resp = data.reqwest.post(&url).json(&self).send().await?;
+        let request_context = crate::request_context::RequestContext::current();
 
-        if resp.status().is_success() {
-            let json = resp.json::<HashMap<String, serde_json::Value>>().await?;
+        let resp = send_with_retry(
+            self,
+            &|| event_name(self),
+            &data.reqwest,
+            &url,
+            guild_id,
+            &dispatch_options.retry_policy,
+            request_context.as_ref().map(|ctx| ctx.request_id.as_str()),
+        )
+        .await?;
 
-            // Check for DispatchStop
-            for result in json.values() {
-                if let Some(value) = result.get("DispatchStop") {
-                    match value {
-                        serde_json::Value::String(s) => return Err(s.clone().into()),
-                        value => return Err(value.to_string().into()),
-                    }
-                }
-            }
+        let json = resp.json::<HashMap<String, serde_json::Value>>().await?;
+
+        let handle = AntiraidEventResultHandle::new(json);
+
+        if let Some((template, reason)) = handle.first_dispatch_stop() {
+            return Err(Box::new(DispatchStopError {
+                template: template.to_string(),
+                reason: reason.to_string(),
+            }));
+        }
+
+        Ok(handle)
+    }
+}
+
+/// Returned when a template stops the dispatch of an event
+///
+/// The error message is the reason given by the template, the template name is kept for callers that downcast to this
+#[derive(Debug, Clone)]
+pub struct DispatchStopError {
+    pub template: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for DispatchStopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for DispatchStopError {}
+
+/// The result of a single template for a dispatched event
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateResult {
+    Ok(serde_json::Value),
+    /// The template stopped the dispatch (``{"DispatchStop": reason}``)
+    DispatchStop {
+        reason: String,
+    },
+    /// The template errored (``{"error": message}``)
+    Error {
+        message: String,
+    },
+}
+
+impl TemplateResult {
+    pub fn from_value(value: &serde_json::Value) -> Self {
+        let stringify = |value: &serde_json::Value| match value {
+            serde_json::Value::String(s) => s.clone(),
+            value => value.to_string(),
+        };
 
-            Ok(AntiraidEventResultHandle { results: json })
-        } else {
-            let err_text = resp
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
+        if let serde_json::Value::Object(map) = value {
+            if let Some(reason) = map.get("DispatchStop") {
+                return TemplateResult::DispatchStop {
+                    reason: stringify(reason),
+                };
+            }
 
-            Err(err_text.into())
+            if map.len() == 1 {
+                if let Some(message) = map.get("error") {
+                    return TemplateResult::Error {
+                        message: stringify(message),
+                    };
+                }
+            }
         }
+
+        TemplateResult::Ok(value.clone())
     }
 }
 
+/// The results of a dispatched event by template name
+///
+/// The raw results are read-only so they always match ``results_typed``
 pub struct AntiraidEventResultHandle {
-    pub results: HashMap<String, serde_json::Value>,
+    results: HashMap<String, serde_json::Value>,
+    typed: HashMap<String, TemplateResult>,
 }
 
 impl std::ops::Deref for AntiraidEventResultHandle {
@@ -113,6 +430,99 @@ impl std::ops::Deref for AntiraidEventResultHandle {
 }
 
 impl AntiraidEventResultHandle {
+    pub fn new(results: HashMap<String, serde_json::Value>) -> Self {
+        let typed = results
+            .iter()
+            .map(|(template, value)| (template.clone(), TemplateResult::from_value(value)))
+            .collect();
+
+        Self { results, typed }
+    }
+
+    /// Returns the raw result of every template
+    pub fn results(&self) -> &HashMap<String, serde_json::Value> {
+        &self.results
+    }
+
+    /// Consumes the handle, returning the raw result of every template
+    pub fn into_results(self) -> HashMap<String, serde_json::Value> {
+        self.results
+    }
+
+    /// Returns the parsed result of every template
+    pub fn results_typed(&self) -> &HashMap<String, TemplateResult> {
+        &self.typed
+    }
+
+    /// Returns the template name and reason of the first (by template name) template that stopped the dispatch
+    pub fn first_dispatch_stop(&self) -> Option<(&str, &str)> {
+        self.typed
+            .iter()
+            .filter_map(|(template, result)| match result {
+                TemplateResult::DispatchStop { reason } => {
+                    Some((template.as_str(), reason.as_str()))
+                }
+                _ => None,
+            })
+            .min_by_key(|(template, _)| *template)
+    }
+
+    /// Deserializes the result of a template, returning None if the template has no result
+    ///
+    /// Templates that stopped the dispatch or errored return an error
+    pub fn get_as<T: serde::de::DeserializeOwned>(
+        &self,
+        template_name: &str,
+    ) -> Result<Option<T>, crate::Error> {
+        match self.typed.get(template_name) {
+            None => Ok(None),
+            Some(TemplateResult::Ok(value)) => Ok(Some(serde_json::from_value(value.clone())?)),
+            Some(TemplateResult::DispatchStop { reason }) => {
+                Err(format!("Template {} stopped dispatch: {}", template_name, reason).into())
+            }
+            Some(TemplateResult::Error { message }) => {
+                Err(format!("Template {} errored: {}", template_name, message).into())
+            }
+        }
+    }
+
+    /// Deep merges all successful object results into one object
+    ///
+    /// Templates are merged in order of name, so on conflicting non-object values the template with the greatest name wins
+    pub fn merged_object(&self) -> serde_json::Map<String, serde_json::Value> {
+        fn merge(
+            into: &mut serde_json::Map<String, serde_json::Value>,
+            from: &serde_json::Map<String, serde_json::Value>,
+        ) {
+            for (key, value) in from {
+                match (into.get_mut(key), value) {
+                    (
+                        Some(serde_json::Value::Object(existing)),
+                        serde_json::Value::Object(value),
+                    ) => merge(existing, value),
+                    _ => {
+                        into.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+
+        let mut templates = self.typed.keys().collect::<Vec<_>>();
+        templates.sort();
+
+        let mut merged = serde_json::Map::new();
+
+        for template in templates {
+            if let Some(TemplateResult::Ok(serde_json::Value::Object(obj))) =
+                self.typed.get(template)
+            {
+                merge(&mut merged, obj);
+            }
+        }
+
+        merged
+    }
+
     /// Returns if at least one template has a "allow_exec" set to true
     ///
     /// This means the template explicitly allows for execution to occur without falling back to default checks
@@ -130,3 +540,218 @@ impl AntiraidEventResultHandle {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+    use axum::http::StatusCode;
+
+    fn policy(max_attempts: u32) -> DispatchRetryPolicy {
+        DispatchRetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            deadline: Duration::from_secs(5),
+        }
+    }
+
+    async fn send(
+        server: &MockServer,
+        reqwest: &reqwest::Client,
+        policy: &DispatchRetryPolicy,
+        request_id: Option<&str>,
+    ) -> Result<reqwest::Response, crate::Error> {
+        send_with_retry_impl(
+            &serde_json::json!({ "event": "test" }),
+            &|| "Test".to_string(),
+            reqwest,
+            &server.url("/dispatch-event/1"),
+            serenity::all::GuildId::new(1),
+            policy,
+            request_id,
+        )
+        .await
+    }
+
+    #[test]
+    fn result_handle_views_agree() {
+        let results = HashMap::from([
+            ("a".to_string(), serde_json::json!({ "allow_exec": true })),
+            (
+                "b".to_string(),
+                serde_json::json!({ "DispatchStop": "spam" }),
+            ),
+            ("c".to_string(), serde_json::json!({ "error": "oops" })),
+        ]);
+
+        let handle = AntiraidEventResultHandle::new(results.clone());
+
+        assert_eq!(handle.results(), &results);
+        assert_eq!(handle.len(), 3);
+        assert_eq!(
+            handle
+                .results_typed()
+                .keys()
+                .collect::<std::collections::BTreeSet<_>>(),
+            results.keys().collect()
+        );
+        assert_eq!(handle.first_dispatch_stop(), Some(("b", "spam")));
+        assert!(handle.can_execute());
+        assert!(handle.get_as::<serde_json::Value>("c").is_err());
+        assert_eq!(handle.into_results(), results);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let server = MockServer::start(|_, idx| match idx {
+            0 => MockResponse::status(StatusCode::SERVICE_UNAVAILABLE),
+            _ => MockResponse::ok(serde_json::json!({})),
+        })
+        .await;
+
+        send(&server, &reqwest::Client::new(), &policy(3), None)
+            .await
+            .unwrap();
+
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_attempts() {
+        let server =
+            MockServer::start(|_, _| MockResponse::status(StatusCode::INTERNAL_SERVER_ERROR)).await;
+
+        assert!(send(&server, &reqwest::Client::new(), &policy(3), None)
+            .await
+            .is_err());
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let server = MockServer::start(|_, _| MockResponse::status(StatusCode::BAD_REQUEST)).await;
+
+        assert!(send(&server, &reqwest::Client::new(), &policy(3), None)
+            .await
+            .is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        let server = MockServer::start(|_, idx| match idx {
+            0 => MockResponse::ok(serde_json::json!({})).delayed(Duration::from_millis(500)),
+            _ => MockResponse::ok(serde_json::json!({})),
+        })
+        .await;
+
+        let reqwest = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        send(&server, &reqwest, &policy(3), None).await.unwrap();
+
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_timeouts_after_max_attempts() {
+        let server = MockServer::start(|_, _| {
+            MockResponse::ok(serde_json::json!({})).delayed(Duration::from_millis(500))
+        })
+        .await;
+
+        let reqwest = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let err = send(&server, &reqwest, &policy(3), None).await.unwrap_err();
+
+        assert!(err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout()));
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_at_deadline() {
+        let server = MockServer::start(|_, _| MockResponse::status(StatusCode::BAD_GATEWAY)).await;
+
+        // No retry fits within a zero deadline
+        let policy = DispatchRetryPolicy {
+            deadline: Duration::ZERO,
+            ..policy(10)
+        };
+
+        assert!(send(&server, &reqwest::Client::new(), &policy, None)
+            .await
+            .is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn forwards_request_id() {
+        let server = MockServer::ok().await;
+        let reqwest = reqwest::Client::new();
+
+        send(&server, &reqwest, &policy(1), Some("abc"))
+            .await
+            .unwrap();
+        send(&server, &reqwest, &policy(1), None).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].headers[crate::request_context::REQUEST_ID_HEADER],
+            "abc"
+        );
+        assert!(requests[1]
+            .headers
+            .get(crate::request_context::REQUEST_ID_HEADER)
+            .is_none());
+    }
+
+    #[test]
+    fn backoff_uses_equal_jitter() {
+        let policy = DispatchRetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            deadline: Duration::from_secs(10),
+        };
+
+        for (attempt, exp) in [(1, 100), (2, 200), (3, 300), (4, 300)] {
+            for _ in 0..50 {
+                let backoff = policy.backoff(attempt).as_millis();
+                assert!(
+                    (exp / 2..=exp).contains(&backoff),
+                    "attempt {attempt}: {backoff}ms outside {}..={exp}ms",
+                    exp / 2
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn new_matches_struct_literal() {
+        let data = DispatchEventData::new("localhost", 60000);
+        let literal = DispatchEventData {
+            template_worker_addr: "localhost",
+            template_worker_port: 60000,
+        };
+
+        assert_eq!(data.template_worker_addr, literal.template_worker_addr);
+        assert_eq!(data.template_worker_port, literal.template_worker_port);
+    }
+
+    #[test]
+    fn options_default_to_direct_dispatch() {
+        let opts = DispatchOptions::new();
+
+        assert_eq!(opts.retry_policy.max_attempts, 3);
+        assert!(opts.sinks.is_none());
+        assert!(opts.batcher.is_none());
+    }
+}
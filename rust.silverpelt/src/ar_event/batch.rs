@@ -0,0 +1,539 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use antiraid_types::ar_event::AntiraidEvent;
+use dashmap::DashMap;
+use serenity::all::GuildId;
+use tokio::sync::mpsc;
+use tokio_util::task::TaskTracker;
+
+use super::{event_name, send_with_retry, DispatchRetryPolicy};
+
+/// Controls how events are batched per guild
+#[derive(Clone, Copy, Debug)]
+pub struct BatchOptions {
+    /// A batch is flushed once it holds this many events
+    pub max_batch_size: usize,
+    /// A batch is flushed this long after its first event was queued, even if it is not full
+    pub linger: Duration,
+    /// Maximum number of queued events per guild, events are dispatched directly once this is reached
+    pub queue_capacity: usize,
+    /// A guild's flush task exits after being idle this long, it is restarted on the next event
+    pub idle_timeout: Duration,
+    pub retry_policy: DispatchRetryPolicy,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            max_batch_size: 50,
+            linger: Duration::from_millis(100),
+            queue_capacity: 1000,
+            idle_timeout: Duration::from_secs(60),
+            retry_policy: DispatchRetryPolicy::default(),
+        }
+    }
+}
+
+/// The queue of a guild along with the generation of the flush task draining it
+struct GuildQueue {
+    sender: mpsc::Sender<AntiraidEvent>,
+    generation: u64,
+}
+
+/// Queues events per guild and flushes them to the template worker in batches
+///
+/// Events of a guild are flushed in order by a single task per guild. A flush task only exits (on idle or
+/// shutdown) once its queue is empty and it has been removed from the map under the map entry lock, so there is
+/// never more than one flush task per guild. When a guild's queue is full the event is dispatched directly
+/// instead of being dropped, such events may overtake queued ones
+pub struct BatchedDispatcher {
+    reqwest: reqwest::Client,
+    template_worker_addr: &'static str,
+    template_worker_port: u16,
+    opts: BatchOptions,
+    queues: DashMap<GuildId, GuildQueue>,
+    next_generation: AtomicU64,
+    tracker: TaskTracker,
+    closed: AtomicBool,
+}
+
+/// What happened when trying to queue an event
+enum QueueResult {
+    Queued,
+    /// The event must be dispatched directly, because the queue is full or the dispatcher is shut down
+    Direct(AntiraidEvent),
+}
+
+impl BatchedDispatcher {
+    pub fn new(
+        reqwest: reqwest::Client,
+        template_worker_addr: &'static str,
+        template_worker_port: u16,
+        opts: BatchOptions,
+    ) -> Self {
+        Self {
+            reqwest,
+            template_worker_addr,
+            template_worker_port,
+            opts,
+            queues: DashMap::new(),
+            next_generation: AtomicU64::new(0),
+            tracker: TaskTracker::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn spawn_queue(self: &Arc<Self>, guild_id: GuildId) -> GuildQueue {
+        let (sender, rx) = mpsc::channel(self.opts.queue_capacity.max(1));
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+
+        let this = self.clone();
+        self.tracker
+            .spawn(async move { this.run_queue(guild_id, generation, rx).await });
+
+        GuildQueue { sender, generation }
+    }
+
+    /// Sends the event to the guild's queue, (re)starting its flush task if there is none
+    ///
+    /// Everything happens under the map entry lock, which flush tasks also take before exiting, so an event is
+    /// never sent to a task which is about to exit
+    fn try_queue(self: &Arc<Self>, event: AntiraidEvent, guild_id: GuildId) -> QueueResult {
+        let entry = self.queues.entry(guild_id);
+
+        // Checked under the entry lock, ``shutdown`` sets this before clearing the map
+        if self.closed.load(Ordering::Acquire) {
+            return QueueResult::Direct(event);
+        }
+
+        let queue = entry.or_insert_with(|| self.spawn_queue(guild_id));
+
+        match queue.sender.try_send(event) {
+            Ok(()) => QueueResult::Queued,
+            Err(mpsc::error::TrySendError::Full(ev)) => {
+                log::warn!(
+                    "Dispatch queue of guild {} is full, dispatching {} directly",
+                    guild_id,
+                    event_name(&ev)
+                );
+
+                QueueResult::Direct(ev)
+            }
+            // Flush tasks only close their queue after removing it from the map, so this cannot happen
+            Err(mpsc::error::TrySendError::Closed(ev)) => QueueResult::Direct(ev),
+        }
+    }
+
+    /// Queues an event, dispatching it directly if the guild's queue is full or the dispatcher is shut down
+    pub async fn enqueue(
+        self: &Arc<Self>,
+        event: AntiraidEvent,
+        guild_id: GuildId,
+    ) -> Result<(), crate::Error> {
+        match self.try_queue(event, guild_id) {
+            QueueResult::Queued => Ok(()),
+            QueueResult::Direct(event) => self.dispatch_direct(&event, guild_id).await,
+        }
+    }
+
+    /// Removes the queue of this flush task from the map if no events are pending, under the map entry lock
+    ///
+    /// Returns false (and pushes the pending event onto ``batch``) if an event was queued in the meantime
+    fn try_retire(
+        &self,
+        guild_id: GuildId,
+        generation: u64,
+        rx: &mut mpsc::Receiver<AntiraidEvent>,
+        batch: &mut Vec<AntiraidEvent>,
+    ) -> bool {
+        let mut pending = None;
+
+        let removed = self.queues.remove_if(&guild_id, |_, queue| {
+            if queue.generation != generation {
+                return false;
+            }
+
+            match rx.try_recv() {
+                Ok(event) => {
+                    pending = Some(event);
+                    false
+                }
+                Err(_) => true,
+            }
+        });
+
+        if let Some(event) = pending {
+            batch.push(event);
+            return false;
+        }
+
+        // Our queue is not in the map if it was removed by ``shutdown``, nothing can be sent to it anymore
+        removed.is_some() || !self.is_current(guild_id, generation)
+    }
+
+    fn is_current(&self, guild_id: GuildId, generation: u64) -> bool {
+        self.queues
+            .get(&guild_id)
+            .is_some_and(|queue| queue.generation == generation)
+    }
+
+    async fn dispatch_direct(
+        &self,
+        event: &AntiraidEvent,
+        guild_id: GuildId,
+    ) -> Result<(), crate::Error> {
+        let url = format!(
+            "http://{}:{}/dispatch-event/{}",
+            self.template_worker_addr, self.template_worker_port, guild_id
+        );
+
+        send_with_retry(
+            event,
+            &|| event_name(event),
+            &self.reqwest,
+            &url,
+            guild_id,
+            &self.opts.retry_policy,
+            None,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn flush(&self, guild_id: GuildId, batch: &mut Vec<AntiraidEvent>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        let url = format!(
+            "http://{}:{}/dispatch-events-batch/{}",
+            self.template_worker_addr, self.template_worker_port, guild_id
+        );
+
+        let len = batch.len();
+
+        if let Err(e) = send_with_retry(
+            batch.as_slice(),
+            &|| format!("batch of {} events", len),
+            &self.reqwest,
+            &url,
+            guild_id,
+            &self.opts.retry_policy,
+            None,
+        )
+        .await
+        {
+            log::error!(
+                "Failed to dispatch batch of {} events for guild {}: {}",
+                len,
+                guild_id,
+                e
+            );
+        }
+
+        batch.clear();
+    }
+
+    /// Flushes a guild's queue until it is idle for ``idle_timeout`` or the dispatcher is shut down
+    async fn run_queue(
+        &self,
+        guild_id: GuildId,
+        generation: u64,
+        mut rx: mpsc::Receiver<AntiraidEvent>,
+    ) {
+        let max_batch_size = self.opts.max_batch_size.max(1);
+        let mut batch = Vec::with_capacity(max_batch_size);
+
+        loop {
+            if batch.is_empty() {
+                match tokio::time::timeout(self.opts.idle_timeout, rx.recv()).await {
+                    Ok(Some(event)) => batch.push(event),
+                    // All senders are gone, only happens once ``shutdown`` removed the queue
+                    Ok(None) => break,
+                    Err(_) => {
+                        if self.try_retire(guild_id, generation, &mut rx, &mut batch) {
+                            break;
+                        }
+
+                        continue;
+                    }
+                }
+            }
+
+            let deadline = tokio::time::Instant::now() + self.opts.linger;
+
+            while batch.len() < max_batch_size {
+                match tokio::time::timeout_at(deadline, rx.recv()).await {
+                    Ok(Some(event)) => batch.push(event),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            self.flush(guild_id, &mut batch).await;
+        }
+
+        // Nothing can be sent anymore, drain whatever is left
+        rx.close();
+
+        while let Some(event) = rx.recv().await {
+            batch.push(event);
+
+            if batch.len() >= max_batch_size {
+                self.flush(guild_id, &mut batch).await;
+            }
+        }
+
+        self.flush(guild_id, &mut batch).await;
+    }
+
+    /// Stops queueing new events and waits until all pending events have been flushed
+    ///
+    /// Events enqueued after this are dispatched directly
+    pub async fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+
+        // Dropping the senders lets the flush tasks drain their queues and exit
+        self.queues.clear();
+
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ar_event::CustomEventBuilder;
+    use crate::mock_server::MockServer;
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    fn event(seq: usize) -> AntiraidEvent {
+        CustomEventBuilder::new("custom/test", "Test")
+            .unwrap()
+            .field("seq", &seq)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    /// Collects the ``seq`` fields of all events, in order
+    fn seqs(value: &serde_json::Value, out: &mut Vec<usize>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(seq) = map.get("seq").and_then(|s| s.as_u64()) {
+                    out.push(seq as usize);
+                }
+
+                for value in map.values() {
+                    seqs(value, out);
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    seqs(value, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn received(server: &MockServer) -> Vec<usize> {
+        let mut out = Vec::new();
+
+        for request in server.requests() {
+            seqs(&request.body, &mut out);
+        }
+
+        out
+    }
+
+    fn dispatcher(server: &MockServer, opts: BatchOptions) -> Arc<BatchedDispatcher> {
+        Arc::new(BatchedDispatcher::new(
+            reqwest::Client::new(),
+            "127.0.0.1",
+            server.port(),
+            opts,
+        ))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn keeps_order_across_idle_restarts() {
+        let server = MockServer::ok().await;
+        let dispatcher = dispatcher(
+            &server,
+            BatchOptions {
+                max_batch_size: 3,
+                linger: Duration::from_millis(1),
+                idle_timeout: Duration::from_millis(5),
+                ..Default::default()
+            },
+        );
+
+        for seq in 0..200 {
+            dispatcher.enqueue(event(seq), GUILD).await.unwrap();
+
+            // Give the flush task time to go idle and retire, so the next event starts a new one
+            if seq % 20 == 19 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        dispatcher.shutdown().await;
+
+        assert_eq!(received(&server), (0..200).collect::<Vec<_>>());
+        assert!(dispatcher.queues.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn batches_events() {
+        let server = MockServer::ok().await;
+        let dispatcher = dispatcher(
+            &server,
+            BatchOptions {
+                max_batch_size: 10,
+                linger: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+
+        for seq in 0..25 {
+            dispatcher.enqueue(event(seq), GUILD).await.unwrap();
+        }
+
+        dispatcher.shutdown().await;
+
+        let requests = server.requests();
+
+        assert!(requests
+            .iter()
+            .all(|r| r.path == format!("/dispatch-events-batch/{}", GUILD)));
+        assert!(requests.len() <= 5, "{} requests", requests.len());
+        assert_eq!(received(&server), (0..25).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn flushes_partial_batch_after_linger() {
+        let server = MockServer::ok().await;
+        let dispatcher = dispatcher(
+            &server,
+            BatchOptions {
+                max_batch_size: 10,
+                linger: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+
+        for seq in 0..3 {
+            dispatcher.enqueue(event(seq), GUILD).await.unwrap();
+        }
+
+        // The idle timeout is far away, so only the linger can flush the batch
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(received(&server), vec![0, 1, 2]);
+
+        dispatcher.shutdown().await;
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_enqueue_and_shutdown_loses_nothing() {
+        let server = MockServer::ok().await;
+        let dispatcher = dispatcher(
+            &server,
+            BatchOptions {
+                max_batch_size: 5,
+                linger: Duration::from_millis(1),
+                idle_timeout: Duration::from_millis(2),
+                ..Default::default()
+            },
+        );
+
+        let mut handles = Vec::new();
+
+        for worker in 0..4 {
+            let dispatcher = dispatcher.clone();
+
+            handles.push(tokio::spawn(async move {
+                for i in 0..50 {
+                    dispatcher
+                        .enqueue(event(worker * 50 + i), GUILD)
+                        .await
+                        .unwrap();
+                    tokio::task::yield_now().await;
+                }
+            }));
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        dispatcher.shutdown().await;
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let mut received = received(&server);
+        received.sort();
+
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn per_worker_order_is_kept() {
+        let server = MockServer::ok().await;
+        let dispatcher = dispatcher(
+            &server,
+            BatchOptions {
+                max_batch_size: 4,
+                linger: Duration::from_millis(1),
+                idle_timeout: Duration::from_millis(1),
+                ..Default::default()
+            },
+        );
+
+        let mut handles = Vec::new();
+
+        for worker in 0..4 {
+            let dispatcher = dispatcher.clone();
+
+            handles.push(tokio::spawn(async move {
+                for i in 0..50 {
+                    dispatcher
+                        .enqueue(event(worker * 1000 + i), GUILD)
+                        .await
+                        .unwrap();
+
+                    if i % 7 == 0 {
+                        tokio::time::sleep(Duration::from_millis(2)).await;
+                    }
+                }
+            }));
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        dispatcher.shutdown().await;
+
+        let received = received(&server);
+
+        for worker in 0..4 {
+            let events = received
+                .iter()
+                .copied()
+                .filter(|seq| seq / 1000 == worker)
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                events,
+                (0..50).map(|i| worker * 1000 + i).collect::<Vec<_>>()
+            );
+        }
+    }
+}
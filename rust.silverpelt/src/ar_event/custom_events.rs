@@ -0,0 +1,498 @@
+use antiraid_types::ar_event::{AntiraidEvent, CustomEvent};
+
+/// Prefix required for event names which are not known Anti-Raid events
+pub const CUSTOM_EVENT_PREFIX: &str = "custom/";
+
+/// Prefix reserved for Anti-Raid's own events
+pub const AR_EVENT_PREFIX: &str = "AR/";
+
+/// The expected JSON type of a custom event field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Number,
+    Bool,
+    Array,
+    Object,
+    /// Any value including null
+    Any,
+}
+
+impl FieldType {
+    pub fn matches(&self, value: &serde_json::Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Number => value.is_number(),
+            FieldType::Bool => value.is_boolean(),
+            FieldType::Array => value.is_array(),
+            FieldType::Object => value.is_object(),
+            FieldType::Any => true,
+        }
+    }
+}
+
+/// A known Anti-Raid custom event along with its declared fields
+pub struct KnownEvent {
+    pub name: &'static str,
+    pub fields: &'static [(&'static str, FieldType)],
+    /// Declared fields which may be omitted from the event
+    pub optional_fields: &'static [(&'static str, FieldType)],
+}
+
+pub const CHECK_COMMAND_EVENT: &str = "AR/CheckCommand";
+pub const CHECK_KITTYCAT_PERMISSIONS_EVENT: &str = "AR/CheckKittycatPermissions";
+pub const ROLE_PERMS_CHANGED_EVENT: &str = "AR/RolePermsChanged";
+pub const RETENTION_APPLIED_EVENT: &str = "AR/RetentionApplied";
+pub const ROOT_OVERRIDE_USED_EVENT: &str = "AR/RootOverrideUsed";
+pub const STING_CREATE_EVENT: &str = "AR/StingCreate";
+pub const STING_UPDATE_EVENT: &str = "AR/StingUpdate";
+pub const STING_DELETE_EVENT: &str = "AR/StingDelete";
+pub const STING_CREATE_MANY_EVENT: &str = "AR/StingCreateMany";
+pub const STING_EXPIRE_EVENT: &str = "AR/StingExpire";
+pub const PUNISHMENT_EXPIRE_EVENT: &str = "AR/PunishmentExpire";
+pub const BROKEN_REFERENCES_EVENT: &str = "AR/BrokenReferences";
+
+/// All known Anti-Raid custom events
+pub const KNOWN_EVENTS: &[KnownEvent] = &[
+    KnownEvent {
+        name: CHECK_COMMAND_EVENT,
+        fields: &[
+            ("command", FieldType::String),
+            ("user_id", FieldType::String),
+            ("member_native_perms", FieldType::String),
+            ("member_kittycat_perms", FieldType::Array),
+        ],
+        optional_fields: &[("request_id", FieldType::String)],
+    },
+    KnownEvent {
+        name: CHECK_KITTYCAT_PERMISSIONS_EVENT,
+        fields: &[
+            ("perm", FieldType::String),
+            ("user_id", FieldType::String),
+            ("member_native_perms", FieldType::String),
+            ("member_kittycat_perms", FieldType::Array),
+        ],
+        optional_fields: &[("request_id", FieldType::String)],
+    },
+    KnownEvent {
+        name: ROLE_PERMS_CHANGED_EVENT,
+        fields: &[
+            ("action", FieldType::String),
+            ("role_ids", FieldType::Array),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: RETENTION_APPLIED_EVENT,
+        fields: &[
+            ("mode", FieldType::Any),
+            ("stings", FieldType::Number),
+            ("punishments", FieldType::Number),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: ROOT_OVERRIDE_USED_EVENT,
+        fields: &[("user_id", FieldType::String)],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: STING_CREATE_EVENT,
+        fields: &[
+            ("schema_version", FieldType::Number),
+            ("sting", FieldType::Object),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: STING_UPDATE_EVENT,
+        fields: &[
+            ("schema_version", FieldType::Number),
+            ("sting", FieldType::Object),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: STING_DELETE_EVENT,
+        fields: &[
+            ("schema_version", FieldType::Number),
+            ("sting", FieldType::Object),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: STING_CREATE_MANY_EVENT,
+        fields: &[
+            ("schema_version", FieldType::Number),
+            ("stings", FieldType::Array),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: STING_EXPIRE_EVENT,
+        fields: &[
+            ("schema_version", FieldType::Number),
+            ("sting", FieldType::Object),
+        ],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: PUNISHMENT_EXPIRE_EVENT,
+        fields: &[("punishment", FieldType::Object)],
+        optional_fields: &[],
+    },
+    KnownEvent {
+        name: BROKEN_REFERENCES_EVENT,
+        fields: &[("references", FieldType::Array)],
+        optional_fields: &[],
+    },
+];
+
+/// Builds a ``CustomEvent``, validating fields of known Anti-Raid events against their declared schema
+pub struct CustomEventBuilder {
+    name: String,
+    titlename: String,
+    schema: Option<&'static KnownEvent>,
+    data: serde_json::Map<String, serde_json::Value>,
+}
+
+impl CustomEventBuilder {
+    /// Creates a new builder
+    ///
+    /// ``AR/`` event names must be known events, all other event names must start with ``custom/``
+    pub fn new(name: &str, titlename: &str) -> Result<Self, crate::Error> {
+        let schema = if name.starts_with(AR_EVENT_PREFIX) {
+            Some(
+                KNOWN_EVENTS
+                    .iter()
+                    .find(|e| e.name == name)
+                    .ok_or_else(|| format!("Unknown Anti-Raid event: {}", name))?,
+            )
+        } else if name.starts_with(CUSTOM_EVENT_PREFIX) {
+            None
+        } else {
+            return Err(format!(
+                "Custom event names must start with {}: {}",
+                CUSTOM_EVENT_PREFIX, name
+            )
+            .into());
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            titlename: titlename.to_string(),
+            schema,
+            data: serde_json::Map::new(),
+        })
+    }
+
+    /// Sets a field, checking it against the schema for known events
+    pub fn field<T: serde::Serialize + ?Sized>(
+        mut self,
+        key: &str,
+        value: &T,
+    ) -> Result<Self, crate::Error> {
+        let value = serde_json::to_value(value)?;
+
+        if let Some(schema) = self.schema {
+            let Some((_, field_type)) = schema
+                .fields
+                .iter()
+                .chain(schema.optional_fields)
+                .find(|(k, _)| *k == key)
+            else {
+                return Err(format!("Unknown field {} for event {}", key, self.name).into());
+            };
+
+            if !field_type.matches(&value) {
+                return Err(format!(
+                    "Field {} of event {} must be of type {:?}",
+                    key, self.name, field_type
+                )
+                .into());
+            }
+        }
+
+        self.data.insert(key.to_string(), value);
+        Ok(self)
+    }
+
+    /// Sets all fields from a struct serializing to a JSON object
+    pub fn fields<T: serde::Serialize>(mut self, data: &T) -> Result<Self, crate::Error> {
+        let serde_json::Value::Object(map) = serde_json::to_value(data)? else {
+            return Err("Custom event data must serialize to an object".into());
+        };
+
+        for (key, value) in map {
+            self = self.field(&key, &value)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Builds the event, erroring if a declared (non-optional) field of a known event is missing
+    pub fn build(self) -> Result<AntiraidEvent, crate::Error> {
+        if let Some(schema) = self.schema {
+            for (key, _) in schema.fields {
+                if !self.data.contains_key(*key) {
+                    return Err(format!("Missing field {} for event {}", key, self.name).into());
+                }
+            }
+        }
+
+        Ok(AntiraidEvent::Custom(CustomEvent {
+            event_name: self.name,
+            event_titlename: self.titlename,
+            event_data: serde_json::Value::Object(self.data),
+        }))
+    }
+}
+
+/// Data of the ``AR/CheckCommand`` event
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct CheckCommandEventData {
+    pub command: String,
+    pub user_id: serenity::all::UserId,
+    pub member_native_perms: serenity::all::Permissions,
+    pub member_kittycat_perms: Vec<kittycat::perms::Permission>,
+    /// The id of the request (see ``RequestContext``) that triggered the check, if any
+    ///
+    /// Omitted from the payload when unset so the event data stays identical to before request ids existed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl CheckCommandEventData {
+    pub fn into_event(self) -> Result<AntiraidEvent, crate::Error> {
+        CustomEventBuilder::new(CHECK_COMMAND_EVENT, "(Anti-Raid) Check Command")?
+            .fields(&self)?
+            .build()
+    }
+}
+
+/// Data of the ``AR/CheckKittycatPermissions`` event
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct CheckKittycatPermissionsEventData {
+    pub perm: String,
+    pub user_id: serenity::all::UserId,
+    pub member_native_perms: serenity::all::Permissions,
+    pub member_kittycat_perms: Vec<kittycat::perms::Permission>,
+    /// The id of the request (see ``RequestContext``) that triggered the check, if any
+    ///
+    /// Omitted from the payload when unset so the event data stays identical to before request ids existed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl CheckKittycatPermissionsEventData {
+    pub fn into_event(self) -> Result<AntiraidEvent, crate::Error> {
+        CustomEventBuilder::new(
+            CHECK_KITTYCAT_PERMISSIONS_EVENT,
+            "(Anti-Raid) Check Kittycat Permissions",
+        )?
+        .fields(&self)?
+        .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_command(request_id: Option<&str>) -> serde_json::Value {
+        let event = CheckCommandEventData {
+            command: "ping".to_string(),
+            user_id: serenity::all::UserId::new(1),
+            member_native_perms: serenity::all::Permissions::empty(),
+            member_kittycat_perms: vec![],
+            request_id: request_id.map(|s| s.to_string()),
+        }
+        .into_event()
+        .unwrap();
+
+        into_custom(event).event_data
+    }
+
+    #[test]
+    fn omits_unset_request_id() {
+        let data = check_command(None);
+
+        let mut keys = data.as_object().unwrap().keys().collect::<Vec<_>>();
+        keys.sort();
+
+        assert_eq!(
+            keys,
+            [
+                "command",
+                "member_kittycat_perms",
+                "member_native_perms",
+                "user_id"
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_set_request_id() {
+        let data = check_command(Some("abc"));
+
+        assert_eq!(data["request_id"], "abc");
+    }
+
+    #[test]
+    fn expire_events_require_their_payload() {
+        let sting = CustomEventBuilder::new(STING_EXPIRE_EVENT, "Sting Expire")
+            .unwrap()
+            .field("schema_version", &1)
+            .unwrap()
+            .field("sting", &serde_json::json!({ "id": "a" }))
+            .unwrap();
+        assert!(sting.build().is_ok());
+
+        let punishment =
+            CustomEventBuilder::new(PUNISHMENT_EXPIRE_EVENT, "Punishment Expire").unwrap();
+        assert!(punishment.build().is_err());
+
+        assert!(
+            CustomEventBuilder::new(PUNISHMENT_EXPIRE_EVENT, "Punishment Expire")
+                .unwrap()
+                .field("punishment", "not an object")
+                .is_err()
+        );
+    }
+
+    fn into_custom(event: AntiraidEvent) -> CustomEvent {
+        let AntiraidEvent::Custom(event) = event else {
+            panic!("expected a custom event");
+        };
+
+        event
+    }
+
+    fn golden_perms() -> (
+        serenity::all::UserId,
+        serenity::all::Permissions,
+        Vec<kittycat::perms::Permission>,
+    ) {
+        (
+            serenity::all::UserId::new(1234),
+            serenity::all::Permissions::BAN_MEMBERS | serenity::all::Permissions::KICK_MEMBERS,
+            vec![
+                kittycat::perms::Permission::from_string("moderation.ban"),
+                kittycat::perms::Permission::from_string("~moderation.kick"),
+            ],
+        )
+    }
+
+    /// Asserts that ``event`` is byte-for-byte the event built with ``json!`` before the typed event data existed
+    fn assert_golden(
+        event: AntiraidEvent,
+        legacy_name: &str,
+        legacy_titlename: &str,
+        legacy_data: serde_json::Value,
+        golden: serde_json::Value,
+    ) {
+        let event = into_custom(event);
+
+        assert_eq!(event.event_name, legacy_name);
+        assert_eq!(event.event_titlename, legacy_titlename);
+        assert_eq!(
+            serde_json::to_string(&event.event_data).unwrap(),
+            serde_json::to_string(&legacy_data).unwrap()
+        );
+        assert_eq!(event.event_data, golden);
+    }
+
+    #[test]
+    fn check_command_matches_golden_payload() {
+        let (user_id, native_perms, kittycat_perms) = golden_perms();
+
+        let event = CheckCommandEventData {
+            command: "ban".to_string(),
+            user_id,
+            member_native_perms: native_perms,
+            member_kittycat_perms: kittycat_perms.clone(),
+            request_id: None,
+        }
+        .into_event()
+        .unwrap();
+
+        assert_golden(
+            event,
+            "AR/CheckCommand",
+            "(Anti-Raid) Check Command",
+            serde_json::json!({
+                "command": "ban",
+                "user_id": user_id,
+                "member_native_perms": native_perms,
+                "member_kittycat_perms": kittycat_perms,
+            }),
+            serde_json::json!({
+                "command": "ban",
+                "user_id": "1234",
+                "member_native_perms": "6",
+                "member_kittycat_perms": ["moderation.ban", "~moderation.kick"],
+            }),
+        );
+    }
+
+    #[test]
+    fn check_kittycat_permissions_matches_golden_payload() {
+        let (user_id, native_perms, kittycat_perms) = golden_perms();
+
+        let event = CheckKittycatPermissionsEventData {
+            perm: "moderation.ban".to_string(),
+            user_id,
+            member_native_perms: native_perms,
+            member_kittycat_perms: kittycat_perms.clone(),
+            request_id: None,
+        }
+        .into_event()
+        .unwrap();
+
+        assert_golden(
+            event,
+            "AR/CheckKittycatPermissions",
+            "(Anti-Raid) Check Kittycat Permissions",
+            serde_json::json!({
+                "perm": "moderation.ban",
+                "user_id": user_id,
+                "member_native_perms": native_perms,
+                "member_kittycat_perms": kittycat_perms,
+            }),
+            serde_json::json!({
+                "perm": "moderation.ban",
+                "user_id": "1234",
+                "member_native_perms": "6",
+                "member_kittycat_perms": ["moderation.ban", "~moderation.kick"],
+            }),
+        );
+    }
+
+    #[test]
+    fn event_names_are_validated() {
+        assert!(CustomEventBuilder::new("custom/my_event", "Mine").is_ok());
+        assert!(CustomEventBuilder::new("AR/NotAnEvent", "Unknown").is_err());
+        assert!(CustomEventBuilder::new("my_event", "No prefix").is_err());
+
+        // Fields of custom events are not checked against any schema
+        let event = CustomEventBuilder::new("custom/my_event", "Mine")
+            .unwrap()
+            .field("anything", &[1, 2])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            into_custom(event).event_data["anything"],
+            serde_json::json!([1, 2])
+        );
+
+        assert!(CustomEventBuilder::new(CHECK_COMMAND_EVENT, "Check")
+            .unwrap()
+            .field("typo_field", "x")
+            .is_err());
+        assert!(CustomEventBuilder::new(CHECK_COMMAND_EVENT, "Check")
+            .unwrap()
+            .field("command", &1)
+            .is_err());
+    }
+}
@@ -0,0 +1,387 @@
+use serenity::all::{GuildId, PartialGuild, RoleId};
+
+use crate::member_permission_calc::role_perms::{
+    ensure_unique_indexes, lock_guild_roles, validate_perm,
+};
+use crate::retention::{apply_retention, RetentionPolicy, RetentionReport};
+
+/// Defaults laid down by ``bootstrap_guild``
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct BootstrapOptions {
+    /// Modules which are enabled by default
+    pub default_modules: Vec<String>,
+    /// Kittycat permissions granted to roles with the ``ADMINISTRATOR`` permission
+    ///
+    /// Empty by default, ``global.*`` would also grant permissions of modules the guild never enabled. See
+    /// ``with_module_admin_perms`` to grant the default modules only
+    pub admin_perms: Vec<String>,
+    /// Version of the defaults, recorded in the bootstrap marker
+    pub version: i32,
+}
+
+impl Default for BootstrapOptions {
+    fn default() -> Self {
+        Self {
+            default_modules: Vec::new(),
+            admin_perms: Vec::new(),
+            version: 1,
+        }
+    }
+}
+
+impl BootstrapOptions {
+    /// Grants administrator roles ``<module>.*`` for each of the ``default_modules``
+    pub fn with_module_admin_perms(mut self) -> Self {
+        self.admin_perms = self
+            .default_modules
+            .iter()
+            .map(|module| format!("{}.*", module))
+            .collect();
+        self
+    }
+}
+
+/// What ``bootstrap_guild`` did
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct BootstrapReport {
+    /// The guild was already bootstrapped, nothing was changed
+    pub already_bootstrapped: bool,
+    pub modules_enabled: u64,
+    pub roles_granted: Vec<RoleId>,
+    pub lockdown_settings_created: bool,
+}
+
+/// Lays down the default configuration of a guild the bot just joined
+///
+/// Everything happens in one transaction and existing configuration is never overwritten. A bootstrap marker is
+/// recorded so calling this again (e.g. on every ``GUILD_CREATE``) is a no-op until ``unbootstrap_guild`` is called
+pub async fn bootstrap_guild(
+    db: &sqlx::PgPool,
+    guild: &PartialGuild,
+    opts: &BootstrapOptions,
+) -> Result<BootstrapReport, crate::Error> {
+    for perm in opts.admin_perms.iter() {
+        validate_perm(perm)?;
+    }
+
+    let mut tx = db.begin().await?;
+
+    let marked = sqlx::query(
+        "INSERT INTO guild_bootstraps (guild_id, version) VALUES ($1, $2) ON CONFLICT (guild_id) DO NOTHING",
+    )
+    .bind(guild.id.to_string())
+    .bind(opts.version)
+    .execute(&mut *tx)
+    .await?;
+
+    if marked.rows_affected() == 0 {
+        return Ok(BootstrapReport {
+            already_bootstrapped: true,
+            ..Default::default()
+        });
+    }
+
+    let modules_enabled = sqlx::query(
+        "INSERT INTO guild_module_configurations (guild_id, module, disabled) SELECT $1, module, false FROM unnest($2::text[]) AS module ON CONFLICT (guild_id, module) DO NOTHING",
+    )
+    .bind(guild.id.to_string())
+    .bind(&opts.default_modules)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    let mut roles_granted = Vec::new();
+
+    if !opts.admin_perms.is_empty() {
+        let mut admin_roles = guild
+            .roles
+            .iter()
+            .filter(|role| role.id != guild.id.everyone_role() && role.permissions.administrator())
+            .collect::<Vec<_>>();
+
+        // Highest role first so it gets the lowest (most powerful) of the new indexes
+        admin_roles.sort_by_key(|role| std::cmp::Reverse(role.position));
+
+        lock_guild_roles(&mut *tx, guild.id).await?;
+
+        let mut next_index: i32 = sqlx::query_scalar(
+            "SELECT COALESCE(MAX(index) + 1, 0) FROM guild_roles WHERE guild_id = $1",
+        )
+        .bind(guild.id.to_string())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for role in admin_roles {
+            let inserted = sqlx::query(
+                "INSERT INTO guild_roles (guild_id, role_id, perms, index) SELECT $1, $2, $3, $4 WHERE NOT EXISTS (SELECT 1 FROM guild_roles WHERE guild_id = $1 AND role_id = $2)",
+            )
+            .bind(guild.id.to_string())
+            .bind(role.id.to_string())
+            .bind(&opts.admin_perms)
+            .bind(next_index)
+            .execute(&mut *tx)
+            .await?;
+
+            if inserted.rows_affected() > 0 {
+                roles_granted.push(role.id);
+                next_index += 1;
+            }
+        }
+
+        ensure_unique_indexes(&mut *tx, guild.id).await?;
+    }
+
+    let lockdown_settings_created = sqlx::query(
+        "INSERT INTO lockdown__guilds (guild_id, member_roles, require_correct_layout) VALUES ($1, '{}', false) ON CONFLICT (guild_id) DO NOTHING",
+    )
+    .bind(guild.id.to_string())
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+
+    tx.commit().await?;
+
+    Ok(BootstrapReport {
+        already_bootstrapped: false,
+        modules_enabled,
+        roles_granted,
+        lockdown_settings_created,
+    })
+}
+
+/// What ``unbootstrap_guild`` did
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct UnbootstrapReport {
+    pub modules_removed: u64,
+    pub commands_removed: u64,
+    pub config_history_removed: u64,
+    pub roles_removed: u64,
+    pub channel_overrides_removed: u64,
+    pub event_sinks_removed: u64,
+    pub punishment_rules_removed: u64,
+    pub kv_constraints_removed: bool,
+    pub lockdown_settings_removed: bool,
+    /// Result of applying the guild's retention policy, None if the guild has none
+    pub retention: Option<RetentionReport>,
+}
+
+/// Deletes the configuration of a guild the bot was removed from
+///
+/// Stings and punishments are not deleted outright, instead the guild's retention policy (if any) is applied. The
+/// retention policy itself is kept so periodic retention runs keep enforcing it. Calling this again is a no-op
+pub async fn unbootstrap_guild(
+    db: &sqlx::PgPool,
+    guild_id: GuildId,
+) -> Result<UnbootstrapReport, crate::Error> {
+    let mut report = UnbootstrapReport::default();
+
+    let mut tx = db.begin().await?;
+
+    report.modules_removed =
+        sqlx::query("DELETE FROM guild_module_configurations WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+    report.commands_removed =
+        sqlx::query("DELETE FROM guild_command_configurations WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+    report.config_history_removed =
+        sqlx::query("DELETE FROM guild_config_history WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+    report.roles_removed = sqlx::query("DELETE FROM guild_roles WHERE guild_id = $1")
+        .bind(guild_id.to_string())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    report.channel_overrides_removed =
+        sqlx::query("DELETE FROM guild_channel_perm_overrides WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+    // Event sinks hold the webhook secrets of the guild
+    report.event_sinks_removed = sqlx::query("DELETE FROM guild_event_sinks WHERE guild_id = $1")
+        .bind(guild_id.to_string())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    report.punishment_rules_removed =
+        sqlx::query("DELETE FROM guild_punishment_rules WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+    report.kv_constraints_removed =
+        sqlx::query("DELETE FROM guild_kv_constraints WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+
+    report.lockdown_settings_removed =
+        sqlx::query("DELETE FROM lockdown__guilds WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+
+    sqlx::query("DELETE FROM guild_bootstraps WHERE guild_id = $1")
+        .bind(guild_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    if let Some(policy) = RetentionPolicy::get(db, guild_id).await? {
+        report.retention = Some(apply_retention(db, guild_id, &policy).await?);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::*;
+
+    const GUILD: GuildId = GuildId::new(1);
+    const OTHER_GUILD: GuildId = GuildId::new(2);
+
+    async fn setup(pool: &sqlx::PgPool) {
+        create_tables(
+            pool,
+            &[
+                GUILD_BOOTSTRAPS,
+                GUILD_MODULE_CONFIGURATIONS,
+                GUILD_COMMAND_CONFIGURATIONS,
+                GUILD_CONFIG_HISTORY,
+                GUILD_ROLES,
+                GUILD_CHANNEL_PERM_OVERRIDES,
+                GUILD_EVENT_SINKS,
+                GUILD_PUNISHMENT_RULES,
+                GUILD_KV_CONSTRAINTS,
+                LOCKDOWN_GUILDS,
+                GUILD_RETENTION_POLICIES,
+            ],
+        )
+        .await;
+    }
+
+    async fn seed(pool: &sqlx::PgPool, guild_id: GuildId) {
+        let statements = [
+            "INSERT INTO guild_bootstraps (guild_id, version) VALUES ($1, 1)",
+            "INSERT INTO guild_module_configurations (guild_id, module, disabled) VALUES ($1, 'moderation', false)",
+            "INSERT INTO guild_command_configurations (guild_id, command, disabled) VALUES ($1, 'ban', true)",
+            "INSERT INTO guild_config_history (guild_id, kind, key, author) VALUES ($1, 'command', 'ban', 'system')",
+            "INSERT INTO guild_roles (guild_id, role_id, perms, index) VALUES ($1, '3', '{moderation.ban}', 0)",
+            "INSERT INTO guild_channel_perm_overrides (guild_id, channel_id, target_type, target_id) VALUES ($1, '4', 'role', '3')",
+            "INSERT INTO guild_event_sinks (guild_id, url, secret_salt) VALUES ($1, 'https://example.com', gen_random_uuid())",
+            "INSERT INTO guild_punishment_rules (guild_id, threshold, punishment_kind) VALUES ($1, 5, 'ban')",
+            "INSERT INTO guild_kv_constraints (guild_id, max_keys) VALUES ($1, 10)",
+            "INSERT INTO lockdown__guilds (guild_id) VALUES ($1)",
+        ];
+
+        for statement in statements {
+            sqlx::query(statement)
+                .bind(guild_id.to_string())
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn rows_of(pool: &sqlx::PgPool, guild_id: GuildId) -> i64 {
+        let mut total = 0;
+
+        for table in [
+            "guild_bootstraps",
+            "guild_module_configurations",
+            "guild_command_configurations",
+            "guild_config_history",
+            "guild_roles",
+            "guild_channel_perm_overrides",
+            "guild_event_sinks",
+            "guild_punishment_rules",
+            "guild_kv_constraints",
+            "lockdown__guilds",
+        ] {
+            let count: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {} WHERE guild_id = $1",
+                table
+            ))
+            .bind(guild_id.to_string())
+            .fetch_one(pool)
+            .await
+            .unwrap();
+            total += count;
+        }
+
+        total
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn unbootstrap_removes_everything_and_is_idempotent(pool: sqlx::PgPool) {
+        setup(&pool).await;
+        seed(&pool, GUILD).await;
+        seed(&pool, OTHER_GUILD).await;
+
+        let report = unbootstrap_guild(&pool, GUILD).await.unwrap();
+        assert_eq!(report.modules_removed, 1);
+        assert_eq!(report.commands_removed, 1);
+        assert_eq!(report.config_history_removed, 1);
+        assert_eq!(report.roles_removed, 1);
+        assert_eq!(report.channel_overrides_removed, 1);
+        assert_eq!(report.event_sinks_removed, 1);
+        assert_eq!(report.punishment_rules_removed, 1);
+        assert!(report.kv_constraints_removed);
+        assert!(report.lockdown_settings_removed);
+        assert!(report.retention.is_none());
+
+        assert_eq!(rows_of(&pool, GUILD).await, 0);
+        assert_eq!(rows_of(&pool, OTHER_GUILD).await, 10);
+
+        let again = unbootstrap_guild(&pool, GUILD).await.unwrap();
+        assert_eq!(again.modules_removed, 0);
+        assert_eq!(again.commands_removed, 0);
+        assert_eq!(again.config_history_removed, 0);
+        assert_eq!(again.roles_removed, 0);
+        assert_eq!(again.channel_overrides_removed, 0);
+        assert_eq!(again.event_sinks_removed, 0);
+        assert_eq!(again.punishment_rules_removed, 0);
+        assert!(!again.kv_constraints_removed);
+        assert!(!again.lockdown_settings_removed);
+    }
+
+    #[test]
+    fn default_admin_perms_are_not_global() {
+        assert!(BootstrapOptions::default().admin_perms.is_empty());
+
+        let opts = BootstrapOptions {
+            default_modules: vec!["moderation".to_string(), "lockdowns".to_string()],
+            ..Default::default()
+        }
+        .with_module_admin_perms();
+
+        assert_eq!(opts.admin_perms, vec!["moderation.*", "lockdowns.*"]);
+        for perm in opts.admin_perms.iter() {
+            assert!(validate_perm(perm).is_ok());
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serenity::all::{ChannelId, GuildId, UserId};
+
+/// What a cooldown is shared between
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CooldownBucket {
+    Guild,
+    Channel,
+    User,
+}
+
+/// A cooldown of ``per`` between uses of a command within a bucket
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CooldownSpec {
+    pub per: Duration,
+    pub bucket: CooldownBucket,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum CooldownKey {
+    Guild,
+    Channel(ChannelId),
+    User(UserId),
+}
+
+impl CooldownKey {
+    fn new(bucket: CooldownBucket, channel_id: ChannelId, user_id: UserId) -> Self {
+        match bucket {
+            CooldownBucket::Guild => CooldownKey::Guild,
+            CooldownBucket::Channel => CooldownKey::Channel(channel_id),
+            CooldownBucket::User => CooldownKey::User(user_id),
+        }
+    }
+}
+
+/// Why ``CooldownTracker::try_charge`` did not charge a cooldown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CooldownError {
+    /// The cooldown is still active, the command can be used again after the given time
+    Active(Duration),
+    /// The cooldown's ``per`` is too large to be represented as a point in time
+    InvalidDuration(Duration),
+}
+
+impl std::fmt::Display for CooldownError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CooldownError::Active(retry_after) => write!(
+                f,
+                "This command is on cooldown, try again in {:.1} seconds",
+                retry_after.as_secs_f64()
+            ),
+            CooldownError::InvalidDuration(per) => {
+                write!(f, "Invalid cooldown duration: {:?}", per)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CooldownError {}
+
+/// Tracks command cooldowns per guild, command and bucket
+///
+/// Entries store when the cooldown ends, call ``prune`` periodically (e.g. through the ``TaskRegistry``)
+/// to drop expired entries
+#[derive(Default)]
+pub struct CooldownTracker {
+    entries: DashMap<(GuildId, String, CooldownKey), Instant>,
+}
+
+impl CooldownTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns how long until the command can be used again, without charging the cooldown
+    pub fn retry_after(
+        &self,
+        guild_id: GuildId,
+        command: &str,
+        spec: &CooldownSpec,
+        channel_id: ChannelId,
+        user_id: UserId,
+    ) -> Option<Duration> {
+        let key = (
+            guild_id,
+            command.to_string(),
+            CooldownKey::new(spec.bucket, channel_id, user_id),
+        );
+
+        let ends_at = *self.entries.get(&key)?;
+
+        ends_at.checked_duration_since(Instant::now())
+    }
+
+    /// Atomically checks and charges the cooldown, returning ``CooldownError::Active`` with the time left if it is
+    /// still active
+    ///
+    /// This should only be called once all other checks have passed so denied users don't burn the cooldown.
+    /// Concurrent callers racing on the same bucket are serialized, only one of them is charged
+    pub fn try_charge(
+        &self,
+        guild_id: GuildId,
+        command: &str,
+        spec: &CooldownSpec,
+        channel_id: ChannelId,
+        user_id: UserId,
+    ) -> Result<(), CooldownError> {
+        let key = (
+            guild_id,
+            command.to_string(),
+            CooldownKey::new(spec.bucket, channel_id, user_id),
+        );
+
+        let now = Instant::now();
+
+        let ends_at = now
+            .checked_add(spec.per)
+            .ok_or(CooldownError::InvalidDuration(spec.per))?;
+
+        let mut entry = self.entries.entry(key).or_insert(now);
+
+        if let Some(retry_after) = entry.checked_duration_since(now) {
+            if !retry_after.is_zero() {
+                return Err(CooldownError::Active(retry_after));
+            }
+        }
+
+        *entry = ends_at;
+
+        Ok(())
+    }
+
+    /// Removes all expired cooldowns, returning the number of entries removed
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let before = self.entries.len();
+
+        self.entries.retain(|_, ends_at| *ends_at > now);
+
+        before.saturating_sub(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    fn spec(per: Duration, bucket: CooldownBucket) -> CooldownSpec {
+        CooldownSpec { per, bucket }
+    }
+
+    fn charge(
+        tracker: &CooldownTracker,
+        spec: &CooldownSpec,
+        channel: u64,
+        user: u64,
+    ) -> Result<(), CooldownError> {
+        tracker.try_charge(
+            GUILD,
+            "ban",
+            spec,
+            ChannelId::new(channel),
+            UserId::new(user),
+        )
+    }
+
+    #[test]
+    fn second_charge_is_denied_until_the_cooldown_ends() {
+        let tracker = CooldownTracker::new();
+        let spec = spec(Duration::from_secs(60), CooldownBucket::User);
+
+        assert!(charge(&tracker, &spec, 1, 1).is_ok());
+
+        let Err(CooldownError::Active(retry_after)) = charge(&tracker, &spec, 1, 1) else {
+            panic!("expected the cooldown to be active");
+        };
+        assert!(retry_after <= Duration::from_secs(60));
+        assert!(tracker
+            .retry_after(GUILD, "ban", &spec, ChannelId::new(1), UserId::new(1))
+            .is_some());
+    }
+
+    #[test]
+    fn buckets_are_independent() {
+        let tracker = CooldownTracker::new();
+        let user = spec(Duration::from_secs(60), CooldownBucket::User);
+        let channel = spec(Duration::from_secs(60), CooldownBucket::Channel);
+        let guild = spec(Duration::from_secs(60), CooldownBucket::Guild);
+
+        assert!(charge(&tracker, &user, 1, 1).is_ok());
+        assert!(charge(&tracker, &user, 1, 2).is_ok());
+
+        assert!(charge(&tracker, &channel, 1, 1).is_ok());
+        assert!(charge(&tracker, &channel, 1, 2).is_err());
+        assert!(charge(&tracker, &channel, 2, 2).is_ok());
+
+        assert!(charge(&tracker, &guild, 1, 1).is_ok());
+        assert!(charge(&tracker, &guild, 2, 2).is_err());
+    }
+
+    #[test]
+    fn overflowing_duration_is_an_error() {
+        let tracker = CooldownTracker::new();
+        let spec = spec(Duration::MAX, CooldownBucket::User);
+
+        assert_eq!(
+            charge(&tracker, &spec, 1, 1),
+            Err(CooldownError::InvalidDuration(Duration::MAX))
+        );
+        assert!(tracker
+            .retry_after(GUILD, "ban", &spec, ChannelId::new(1), UserId::new(1))
+            .is_none());
+    }
+
+    #[test]
+    fn zero_cooldown_never_blocks_and_is_pruned() {
+        let tracker = CooldownTracker::new();
+        let spec = spec(Duration::ZERO, CooldownBucket::User);
+
+        assert!(charge(&tracker, &spec, 1, 1).is_ok());
+        assert!(charge(&tracker, &spec, 1, 1).is_ok());
+
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(tracker.prune(), 1);
+    }
+
+    #[test]
+    fn concurrent_charges_only_charge_once() {
+        const THREADS: usize = 16;
+
+        let tracker = Arc::new(CooldownTracker::new());
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let spec = spec(Duration::from_secs(60), CooldownBucket::Guild);
+
+        let handles = (0..THREADS as u64)
+            .map(|i| {
+                let tracker = tracker.clone();
+                let barrier = barrier.clone();
+
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    charge(&tracker, &spec, i + 1, i + 1).is_ok()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let charged = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|ok| *ok)
+            .count();
+
+        assert_eq!(charged, 1);
+    }
+}
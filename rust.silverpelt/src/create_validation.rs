@@ -0,0 +1,313 @@
+use std::time::Duration;
+
+use limits::embed_limits::EMBED_FIELD_VALUE_LIMIT;
+
+/// A field of a ``StingCreate`` or ``PunishmentCreate`` which failed validation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateValidationError {
+    /// Position of the entry within a batch, if created as part of one
+    pub row: Option<usize>,
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl CreateValidationError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            row: None,
+            field,
+            message: message.into(),
+        }
+    }
+
+    /// Records the position of the failing entry within a batch
+    pub fn at_row(mut self, row: usize) -> Self {
+        self.row = Some(row);
+        self
+    }
+}
+
+impl std::fmt::Display for CreateValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.row {
+            Some(row) => write!(f, "Entry {}: {}: {}", row, self.field, self.message),
+            None => write!(f, "{}: {}", self.field, self.message),
+        }
+    }
+}
+
+impl std::error::Error for CreateValidationError {}
+
+/// Limits checked before creating stings and punishments
+#[derive(Debug, Clone, Copy)]
+pub struct CreateLimits {
+    /// Maximum number of stings a single sting may carry
+    pub max_stings: i32,
+    /// Reasons are rendered in embed fields so must fit in one
+    pub max_reason_length: usize,
+    pub min_duration: Duration,
+    pub max_duration: Duration,
+}
+
+impl Default for CreateLimits {
+    fn default() -> Self {
+        Self {
+            max_stings: 1000,
+            max_reason_length: EMBED_FIELD_VALUE_LIMIT,
+            min_duration: Duration::from_secs(60),
+            max_duration: Duration::from_secs(60 * 60 * 24 * 365),
+        }
+    }
+}
+
+/// Normalization and validation run by every create path of stings and punishments
+///
+/// Kept separate from ``StingCreateOperations`` and ``PunishmentCreateOperations`` so implementing those does not
+/// require implementing validation
+pub trait CreateValidation {
+    /// Trims and lowercases fields in place, empty optional values are dropped
+    fn normalize(&mut self);
+
+    /// Validates the entry against ``limits``
+    fn validate_with(&self, limits: &CreateLimits) -> Result<(), CreateValidationError>;
+
+    /// Validates the entry against the default ``CreateLimits``
+    fn validate(&self) -> Result<(), CreateValidationError> {
+        self.validate_with(&CreateLimits::default())
+    }
+}
+
+/// Trims a reason, dropping it entirely if nothing is left
+pub(crate) fn normalize_reason(reason: &mut Option<String>) {
+    if let Some(r) = reason {
+        let trimmed = r.trim();
+
+        if trimmed.is_empty() {
+            *reason = None;
+        } else if trimmed.len() != r.len() {
+            *r = trimmed.to_string();
+        }
+    }
+}
+
+/// Trims and lowercases a src, dropping it entirely if nothing is left
+pub(crate) fn normalize_src(src: &mut Option<String>) {
+    if let Some(s) = src {
+        let normalized = s.trim().to_lowercase();
+
+        if normalized.is_empty() {
+            *src = None;
+        } else {
+            *s = normalized;
+        }
+    }
+}
+
+pub(crate) fn validate_reason(
+    field: &'static str,
+    reason: Option<&str>,
+    limits: &CreateLimits,
+) -> Result<(), CreateValidationError> {
+    let Some(reason) = reason else {
+        return Ok(());
+    };
+
+    let len = reason.chars().count();
+
+    if len > limits.max_reason_length {
+        return Err(CreateValidationError::new(
+            field,
+            format!(
+                "must be at most {} characters long, got {}",
+                limits.max_reason_length, len
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_duration(
+    duration: Option<Duration>,
+    limits: &CreateLimits,
+) -> Result<(), CreateValidationError> {
+    let Some(duration) = duration else {
+        return Ok(());
+    };
+
+    if duration < limits.min_duration {
+        return Err(CreateValidationError::new(
+            "duration",
+            format!("must be at least {} seconds", limits.min_duration.as_secs()),
+        ));
+    }
+
+    if duration > limits.max_duration {
+        return Err(CreateValidationError::new(
+            "duration",
+            format!("must be at most {} seconds", limits.max_duration.as_secs()),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use antiraid_types::punishments::{PunishmentCreate, PunishmentState, PunishmentTarget};
+    use antiraid_types::stings::{StingCreate, StingState, StingTarget};
+    use serenity::all::{GuildId, UserId};
+
+    fn sting() -> StingCreate {
+        StingCreate {
+            src: None,
+            stings: 1,
+            reason: Some("spam".to_string()),
+            void_reason: None,
+            guild_id: GuildId::new(1),
+            creator: StingTarget::System,
+            target: StingTarget::User(UserId::new(2)),
+            state: StingState::Active,
+            duration: None,
+            sting_data: None,
+        }
+    }
+
+    fn punishment() -> PunishmentCreate {
+        PunishmentCreate {
+            src: None,
+            guild_id: GuildId::new(1),
+            punishment: "ban".to_string(),
+            creator: PunishmentTarget::System,
+            target: PunishmentTarget::User(UserId::new(2)),
+            handle_log: serde_json::json!([]),
+            duration: None,
+            reason: "spam".to_string(),
+            data: None,
+            state: PunishmentState::Active,
+        }
+    }
+
+    fn failing_field<T: CreateValidation>(
+        entry: &T,
+        limits: &CreateLimits,
+    ) -> Option<&'static str> {
+        entry.validate_with(limits).err().map(|e| e.field)
+    }
+
+    #[test]
+    fn valid_entries_pass() {
+        assert_eq!(failing_field(&sting(), &CreateLimits::default()), None);
+        assert_eq!(failing_field(&punishment(), &CreateLimits::default()), None);
+    }
+
+    #[test]
+    fn sting_count_rules() {
+        let limits = CreateLimits::default();
+
+        let mut negative = sting();
+        negative.stings = -1;
+        assert_eq!(failing_field(&negative, &limits), Some("stings"));
+
+        let mut too_many = sting();
+        too_many.stings = limits.max_stings + 1;
+        assert_eq!(failing_field(&too_many, &limits), Some("stings"));
+
+        let mut at_max = sting();
+        at_max.stings = limits.max_stings;
+        assert_eq!(failing_field(&at_max, &limits), None);
+    }
+
+    #[test]
+    fn reason_rules() {
+        let limits = CreateLimits::default();
+
+        let mut no_reason = sting();
+        no_reason.reason = None;
+        assert_eq!(failing_field(&no_reason, &limits), Some("reason"));
+
+        no_reason.src = Some("automod".to_string());
+        assert_eq!(failing_field(&no_reason, &limits), None);
+
+        let mut long_reason = sting();
+        long_reason.reason = Some("a".repeat(limits.max_reason_length + 1));
+        assert_eq!(failing_field(&long_reason, &limits), Some("reason"));
+
+        let mut long_void_reason = sting();
+        long_void_reason.void_reason = Some("a".repeat(limits.max_reason_length + 1));
+        assert_eq!(
+            failing_field(&long_void_reason, &limits),
+            Some("void_reason")
+        );
+
+        let mut empty_reason = punishment();
+        empty_reason.reason = String::new();
+        assert_eq!(failing_field(&empty_reason, &limits), Some("reason"));
+
+        let mut empty_kind = punishment();
+        empty_kind.punishment = String::new();
+        assert_eq!(failing_field(&empty_kind, &limits), Some("punishment"));
+    }
+
+    #[test]
+    fn duration_rules_follow_limits() {
+        let limits = CreateLimits::default();
+
+        let mut short = sting();
+        short.duration = Some(limits.min_duration - Duration::from_secs(1));
+        assert_eq!(failing_field(&short, &limits), Some("duration"));
+
+        let mut long = punishment();
+        long.duration = Some(limits.max_duration + Duration::from_secs(1));
+        assert_eq!(failing_field(&long, &limits), Some("duration"));
+
+        let raised = CreateLimits {
+            max_duration: limits.max_duration * 2,
+            ..limits
+        };
+        assert_eq!(failing_field(&long, &raised), None);
+    }
+
+    #[test]
+    fn self_targeting_is_rejected() {
+        let limits = CreateLimits::default();
+
+        let mut sting = sting();
+        sting.creator = StingTarget::User(UserId::new(2));
+        assert_eq!(failing_field(&sting, &limits), Some("target"));
+
+        let mut punishment = punishment();
+        punishment.creator = PunishmentTarget::User(UserId::new(2));
+        assert_eq!(failing_field(&punishment, &limits), Some("target"));
+    }
+
+    #[test]
+    fn normalize_trims_and_drops_empty_values() {
+        let mut sting = sting();
+        sting.src = Some("  AutoMod ".to_string());
+        sting.reason = Some("   ".to_string());
+        sting.void_reason = Some(" voided ".to_string());
+        sting.normalize();
+
+        assert_eq!(sting.src.as_deref(), Some("automod"));
+        assert_eq!(sting.reason, None);
+        assert_eq!(sting.void_reason.as_deref(), Some("voided"));
+
+        let mut punishment = punishment();
+        punishment.punishment = " ban ".to_string();
+        punishment.src = Some(" ".to_string());
+        punishment.normalize();
+
+        assert_eq!(punishment.punishment, "ban");
+        assert_eq!(punishment.src, None);
+    }
+
+    #[test]
+    fn batch_rows_are_reported() {
+        let err = CreateValidationError::new("stings", "must not be negative").at_row(3);
+
+        assert_eq!(err.row, Some(3));
+        assert_eq!(err.to_string(), "Entry 3: stings: must not be negative");
+    }
+}
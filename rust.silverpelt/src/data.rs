@@ -1,4 +1,10 @@
+use crate::ar_event::DispatchOptions;
+use crate::cooldowns::CooldownTracker;
+use crate::extensions::{DuplicateExtension, Extensions};
+use crate::member_permission_calc::root_override::RootOverride;
 use crate::objectstore::ObjectStore;
+use crate::sandwich::SandwichStatus;
+use crate::tasks::TaskRegistry;
 use std::fmt::Debug;
 use std::sync::Arc;
 
@@ -8,6 +14,8 @@ pub struct Data {
     pub pool: sqlx::PgPool,
     pub reqwest: reqwest::Client,
     pub object_store: Arc<ObjectStore>,
+    /// Shared state of modules keyed by type, see ``insert_extension``
+    pub extensions: Arc<Extensions>,
 }
 
 impl Debug for Data {
@@ -16,6 +24,69 @@ impl Debug for Data {
             .field("pool", &"sqlx::PgPool")
             .field("reqwest", &"reqwest::Client")
             .field("object_store", &"Arc<ObjectStore>")
+            .field("extensions", &self.extensions.len())
             .finish()
     }
 }
+
+impl Data {
+    /// Creates the data with no extensions registered
+    pub fn new(
+        pool: sqlx::PgPool,
+        reqwest: reqwest::Client,
+        object_store: Arc<ObjectStore>,
+    ) -> Self {
+        Self {
+            pool,
+            reqwest,
+            object_store,
+            extensions: Arc::new(Extensions::default()),
+        }
+    }
+
+    /// Registers a module's shared state, keyed by its type
+    pub fn insert_extension<T: Send + Sync + 'static>(
+        &self,
+        value: Arc<T>,
+    ) -> Result<(), DuplicateExtension> {
+        self.extensions.insert(value)
+    }
+
+    /// Returns the shared state of type ``T``, if registered
+    pub fn get_extension<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.extensions.get::<T>()
+    }
+
+    /// Returns the shared sandwich health tracker, see ``SandwichStatus``
+    pub fn sandwich_status(&self) -> Arc<SandwichStatus> {
+        self.get_or_init_extension(SandwichStatus::default)
+    }
+
+    /// Returns the shared registry of background tasks
+    pub fn tasks(&self) -> Arc<TaskRegistry> {
+        self.get_or_init_extension(TaskRegistry::new)
+    }
+
+    /// Returns the shared command cooldown tracker
+    pub fn cooldowns(&self) -> Arc<CooldownTracker> {
+        self.get_or_init_extension(CooldownTracker::new)
+    }
+
+    /// Returns the event dispatch options registered with ``insert_extension``, or the defaults if there are none
+    pub fn dispatch_options(&self) -> Arc<DispatchOptions> {
+        self.get_or_init_extension(DispatchOptions::default)
+    }
+
+    /// Returns the root override, if the bot registered one with ``insert_extension``
+    pub fn root_override(&self) -> Option<Arc<RootOverride>> {
+        self.get_extension::<RootOverride>()
+    }
+
+    /// Returns the shared state of type ``T``, registering the result of ``init`` first if there is none
+    pub fn get_or_init_extension<T: Send + Sync + 'static>(
+        &self,
+        init: impl FnOnce() -> T,
+    ) -> Arc<T> {
+        self.extensions.get_or_init(init)
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A row that could not be decoded, returned by the ``_lossy`` list variants
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DecodeFailure {
+    /// ID of the offending row
+    pub id: String,
+    pub error: String,
+}
+
+/// Parses a snowflake (or other ``FromStr``) id stored as text, including the column, value and row id in the error
+pub fn parse_snowflake_column<T>(
+    value: &str,
+    column: &str,
+    row_id: impl Display,
+) -> Result<T, crate::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| {
+        format!(
+            "Failed to parse column ``{}`` of row {}: {:?} is not a valid id: {}",
+            column, row_id, value, e
+        )
+        .into()
+    })
+}
+
+/// Decodes all rows, skipping (and logging) rows which fail to decode instead of failing the whole list
+pub fn decode_lossy<R, T>(
+    rows: Vec<R>,
+    kind: &str,
+    id_of: impl Fn(&R) -> String,
+    decode: impl Fn(R) -> Result<T, crate::Error>,
+) -> (Vec<T>, Vec<DecodeFailure>) {
+    let mut items = Vec::with_capacity(rows.len());
+    let mut failures = Vec::new();
+
+    for row in rows {
+        let id = id_of(&row);
+
+        match decode(row) {
+            Ok(item) => items.push(item),
+            Err(e) => {
+                log::error!("Skipping undecodable {} row {}: {}", kind, id, e);
+
+                failures.push(DecodeFailure {
+                    id,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    (items, failures)
+}
@@ -0,0 +1,203 @@
+use std::any::{Any, TypeId};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+/// What ``Extensions::insert`` does if an extension of the same type is already registered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateExtensionPolicy {
+    /// Keep the existing extension and return ``Ok``
+    KeepExisting,
+    /// Keep the existing extension and return ``DuplicateExtension``
+    #[default]
+    Error,
+}
+
+/// Returned when an extension type is registered twice under ``DuplicateExtensionPolicy::Error``
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateExtension {
+    pub type_name: &'static str,
+}
+
+impl std::fmt::Display for DuplicateExtension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extension {} is already registered", self.type_name)
+    }
+}
+
+impl std::error::Error for DuplicateExtension {}
+
+/// A map of shared state keyed by type, so modules cannot collide on keys and lookups cannot fail to downcast
+#[derive(Default)]
+pub struct Extensions {
+    map: DashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+    on_duplicate: DuplicateExtensionPolicy,
+}
+
+impl Extensions {
+    pub fn new(on_duplicate: DuplicateExtensionPolicy) -> Self {
+        Self {
+            map: DashMap::new(),
+            on_duplicate,
+        }
+    }
+
+    /// Registers an extension, see ``DuplicateExtensionPolicy`` for what happens on double registration
+    pub fn insert<T: Send + Sync + 'static>(
+        &self,
+        value: Arc<T>,
+    ) -> Result<(), DuplicateExtension> {
+        match self.map.entry(TypeId::of::<T>()) {
+            dashmap::Entry::Occupied(_) => match self.on_duplicate {
+                DuplicateExtensionPolicy::KeepExisting => Ok(()),
+                DuplicateExtensionPolicy::Error => Err(DuplicateExtension {
+                    type_name: std::any::type_name::<T>(),
+                }),
+            },
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the extension of type ``T``, if registered
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self.map.get(&TypeId::of::<T>())?.value().clone();
+
+        value.downcast::<T>().ok()
+    }
+
+    /// Returns the extension of type ``T``, registering the result of ``init`` first if there is none
+    ///
+    /// ``init`` runs outside the map's locks so it may use the extensions itself. If several callers race to
+    /// initialize the same type, each may run ``init`` but only the first value registered is kept and returned to all
+    pub fn get_or_init<T: Send + Sync + 'static>(&self, init: impl FnOnce() -> T) -> Arc<T> {
+        if let Some(value) = self.get::<T>() {
+            return value;
+        }
+
+        let value: Arc<dyn Any + Send + Sync> = Arc::new(init());
+
+        let value = self
+            .map
+            .entry(TypeId::of::<T>())
+            .or_insert(value)
+            .value()
+            .clone();
+
+        value
+            .downcast::<T>()
+            .expect("extension is always stored under its own TypeId")
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(usize);
+
+    #[test]
+    fn insert_respects_duplicate_policy() {
+        let extensions = Extensions::new(DuplicateExtensionPolicy::Error);
+        extensions.insert(Arc::new(Counter(1))).unwrap();
+
+        assert_eq!(
+            extensions.insert(Arc::new(Counter(2))),
+            Err(DuplicateExtension {
+                type_name: std::any::type_name::<Counter>()
+            })
+        );
+        assert_eq!(extensions.get::<Counter>().unwrap().0, 1);
+
+        let extensions = Extensions::new(DuplicateExtensionPolicy::KeepExisting);
+        extensions.insert(Arc::new(Counter(1))).unwrap();
+        extensions.insert(Arc::new(Counter(2))).unwrap();
+
+        assert_eq!(extensions.get::<Counter>().unwrap().0, 1);
+    }
+
+    #[test]
+    fn types_do_not_collide() {
+        let extensions = Extensions::default();
+        extensions.insert(Arc::new(Counter(1))).unwrap();
+        extensions.insert(Arc::new(1_u64)).unwrap();
+
+        assert_eq!(extensions.get::<Counter>().unwrap().0, 1);
+        assert_eq!(*extensions.get::<u64>().unwrap(), 1);
+        assert!(extensions.get::<u32>().is_none());
+        assert_eq!(extensions.len(), 2);
+    }
+
+    #[test]
+    fn nested_get_or_init_does_not_deadlock() {
+        struct Outer(Arc<Counter>);
+
+        let extensions = Extensions::default();
+
+        let outer = extensions.get_or_init(|| Outer(extensions.get_or_init(|| Counter(1))));
+
+        assert!(Arc::ptr_eq(&outer.0, &extensions.get::<Counter>().unwrap()));
+        assert_eq!(extensions.len(), 2);
+    }
+
+    #[test]
+    fn first_registered_value_wins() {
+        let extensions = Extensions::default();
+
+        // The nested call registers first, so the outer value is discarded
+        let value = extensions.get_or_init(|| {
+            let inner = extensions.get_or_init(|| Counter(1));
+            Counter(inner.0 + 1)
+        });
+
+        assert_eq!(value.0, 1);
+        assert!(Arc::ptr_eq(&value, &extensions.get::<Counter>().unwrap()));
+        assert_eq!(extensions.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn racing_get_or_init_returns_one_value() {
+        let extensions = Arc::new(Extensions::default());
+        let inits = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(tokio::sync::Barrier::new(32));
+
+        let mut set = tokio::task::JoinSet::new();
+
+        for i in 0..32 {
+            let extensions = extensions.clone();
+            let inits = inits.clone();
+            let barrier = barrier.clone();
+
+            set.spawn(async move {
+                barrier.wait().await;
+
+                extensions.get_or_init(|| {
+                    inits.fetch_add(1, Ordering::SeqCst);
+                    Counter(i)
+                })
+            });
+        }
+
+        let mut values = Vec::new();
+        while let Some(value) = set.join_next().await {
+            values.push(value.unwrap());
+        }
+
+        let registered = extensions.get::<Counter>().unwrap();
+
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &registered)));
+        assert!(inits.load(Ordering::SeqCst) >= 1);
+        assert_eq!(extensions.len(), 1);
+    }
+}
@@ -0,0 +1,534 @@
+use std::collections::HashSet;
+
+use dashmap::DashMap;
+use sandwich_driver::SandwichConfigData;
+use serenity::all::{ChannelId, GuildId, RoleId};
+use sqlx::Row;
+
+use crate::ar_event::custom_events::BROKEN_REFERENCES_EVENT;
+use crate::ar_event::{AntiraidEventOperations, CustomEventBuilder, DispatchEventData};
+use crate::data::Data;
+
+/// The kind of Discord resource a settings column references
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReferenceKind {
+    Channel,
+    Role,
+}
+
+/// A settings column holding channel or role ids (either a single id or an array of them)
+#[derive(Clone, Debug)]
+pub struct ReferenceColumn {
+    /// The id of the setting the column belongs to
+    pub setting: String,
+    pub table: String,
+    pub column: String,
+    /// The primary key column used to identify the row in a ``BrokenReference``
+    pub primary_key: String,
+    pub kind: ReferenceKind,
+}
+
+/// Reverse index of all settings columns referencing channels or roles, built from the setting definitions
+///
+/// Every table is expected to have a ``guild_id`` column
+#[derive(Clone, Debug, Default)]
+pub struct ReferenceIndex {
+    columns: Vec<ReferenceColumn>,
+}
+
+/// Table and column names are interpolated into queries so only plain identifiers are allowed
+fn validate_identifier(ident: &str) -> Result<(), crate::Error> {
+    if ident.is_empty()
+        || !ident
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(format!("Invalid identifier in reference index: {:?}", ident).into());
+    }
+
+    Ok(())
+}
+
+impl ReferenceIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a column to the index
+    pub fn add(&mut self, column: ReferenceColumn) -> Result<(), crate::Error> {
+        validate_identifier(&column.table)?;
+        validate_identifier(&column.column)?;
+        validate_identifier(&column.primary_key)?;
+
+        if self
+            .columns
+            .iter()
+            .any(|c| c.table == column.table && c.column == column.column)
+        {
+            return Err(format!(
+                "Column {}.{} is already in the reference index",
+                column.table, column.column
+            )
+            .into());
+        }
+
+        self.columns.push(column);
+
+        Ok(())
+    }
+
+    pub fn columns(&self) -> &[ReferenceColumn] {
+        &self.columns
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+}
+
+/// A channel or role id referenced by a settings row
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Reference {
+    pub setting: String,
+    pub column: String,
+    pub row_pkey: String,
+    pub id: String,
+    pub kind: ReferenceKind,
+}
+
+/// A reference to a channel or role which no longer exists in the guild
+pub type BrokenReference = Reference;
+
+/// Returns every channel and role id referenced by the settings rows of a guild
+///
+/// Empty values are skipped, ids which fail to parse are returned as is and reported as broken by ``verify_references``
+pub async fn fingerprint(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    index: &ReferenceIndex,
+) -> Result<Vec<Reference>, crate::Error> {
+    let mut references = Vec::new();
+
+    for column in index.columns() {
+        let rows = sqlx::query(&format!(
+            "SELECT {}::text AS pkey, to_jsonb({}) AS value FROM {} WHERE guild_id = $1 AND {} IS NOT NULL",
+            column.primary_key, column.column, column.table, column.column
+        ))
+        .bind(guild_id.to_string())
+        .fetch_all(pool)
+        .await?;
+
+        for row in rows {
+            let row_pkey: String = row.try_get("pkey")?;
+            let value: serde_json::Value = row.try_get("value")?;
+
+            let ids = match value {
+                serde_json::Value::Array(values) => values,
+                value => vec![value],
+            };
+
+            for id in ids {
+                let id = match id {
+                    serde_json::Value::String(s) => s,
+                    serde_json::Value::Null => continue,
+                    id => id.to_string(),
+                };
+
+                if id.is_empty() {
+                    continue;
+                }
+
+                references.push(Reference {
+                    setting: column.setting.clone(),
+                    column: column.column.clone(),
+                    row_pkey: row_pkey.clone(),
+                    id,
+                    kind: column.kind,
+                });
+            }
+        }
+    }
+
+    Ok(references)
+}
+
+/// Returns the references of a guild's settings to channels or roles which no longer exist
+///
+/// Uses the serenity cache and sandwich, returning ``SandwichDegraded`` while sandwich is degraded unless the guild is cached
+pub async fn verify_references(
+    ctx: &serenity::all::Context,
+    guild_id: GuildId,
+    index: &ReferenceIndex,
+    sandwich_config: &SandwichConfigData,
+) -> Result<Vec<BrokenReference>, crate::Error> {
+    let data = ctx.data::<Data>();
+
+    let references = fingerprint(&data.pool, guild_id, index).await?;
+
+    if references.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let needs_roles = references.iter().any(|r| r.kind == ReferenceKind::Role);
+    let needs_channels = references.iter().any(|r| r.kind == ReferenceKind::Channel);
+
+    let role_ids: HashSet<RoleId> = if needs_roles {
+        crate::sandwich::guild(
+            &data.sandwich_status(),
+            true,
+            &ctx.cache,
+            &ctx.http,
+            &data.reqwest,
+            guild_id,
+            sandwich_config,
+        )
+        .await?
+        .roles
+        .iter()
+        .map(|role| role.id)
+        .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let channel_ids: HashSet<ChannelId> = if needs_channels {
+        crate::sandwich::guild_channels(
+            &data.sandwich_status(),
+            true,
+            &ctx.cache,
+            &ctx.http,
+            &data.reqwest,
+            guild_id,
+            sandwich_config,
+        )
+        .await?
+        .iter()
+        .map(|channel| channel.id)
+        .collect()
+    } else {
+        HashSet::new()
+    };
+
+    Ok(find_broken(references, &role_ids, &channel_ids))
+}
+
+/// Returns the references whose id is not one of the existing ``role_ids`` or ``channel_ids``
+///
+/// Ids which fail to parse are always broken
+pub fn find_broken(
+    references: Vec<Reference>,
+    role_ids: &HashSet<RoleId>,
+    channel_ids: &HashSet<ChannelId>,
+) -> Vec<BrokenReference> {
+    references
+        .into_iter()
+        .filter(|r| match r.kind {
+            ReferenceKind::Role => !r
+                .id
+                .parse::<RoleId>()
+                .is_ok_and(|role_id| role_ids.contains(&role_id)),
+            ReferenceKind::Channel => !r
+                .id
+                .parse::<ChannelId>()
+                .is_ok_and(|channel_id| channel_ids.contains(&channel_id)),
+        })
+        .collect()
+}
+
+/// Remembers the broken references already reported per guild so each breakage is only reported once
+///
+/// A reference which is fixed and later breaks again is reported again
+#[derive(Default)]
+pub struct GuildStateWatcher {
+    reported: DashMap<GuildId, HashSet<BrokenReference>>,
+}
+
+impl GuildStateWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the current broken references of a guild, returning those which were not reported before
+    pub fn new_breakages(
+        &self,
+        guild_id: GuildId,
+        broken: Vec<BrokenReference>,
+    ) -> Vec<BrokenReference> {
+        let current = broken.into_iter().collect::<HashSet<_>>();
+
+        if current.is_empty() {
+            self.reported.remove(&guild_id);
+            return Vec::new();
+        }
+
+        let previous = self.reported.insert(guild_id, current.clone());
+
+        current
+            .into_iter()
+            .filter(|r| previous.as_ref().is_none_or(|p| !p.contains(r)))
+            .collect()
+    }
+
+    /// Forgets the reported references of a guild, e.g. once the bot leaves it
+    pub fn forget(&self, guild_id: GuildId) {
+        self.reported.remove(&guild_id);
+    }
+
+    /// Forgets the reported references of every guild not in ``guilds``
+    pub fn retain_guilds(&self, guilds: &HashSet<GuildId>) {
+        self.reported
+            .retain(|guild_id, _| guilds.contains(guild_id));
+    }
+
+    /// Returns the number of guilds with reported references
+    pub fn len(&self) -> usize {
+        self.reported.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reported.is_empty()
+    }
+}
+
+/// Dispatches an ``AR/BrokenReferences`` event for newly broken references
+pub async fn dispatch_broken_references_event(
+    data: &Data,
+    guild_id: GuildId,
+    references: &[BrokenReference],
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    let event = CustomEventBuilder::new(BROKEN_REFERENCES_EVENT, "(Anti-Raid) Broken References")?
+        .field("references", references)?
+        .build()?;
+
+    event
+        .dispatch_to_template_worker_and_nowait(data, guild_id, dispatch_event_data)
+        .await
+}
+
+/// Verifies the references of all cached guilds, dispatching an event for every guild with new breakages
+///
+/// Returns the number of newly broken references. A failure in one guild is logged and does not stop the others,
+/// suited for the ``TaskRegistry``. Guilds which are no longer cached are forgotten by ``watcher``
+pub async fn run_guild_state_watch(
+    ctx: &serenity::all::Context,
+    watcher: &GuildStateWatcher,
+    index: &ReferenceIndex,
+    sandwich_config: &SandwichConfigData,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<usize, crate::Error> {
+    if index.is_empty() {
+        return Ok(0);
+    }
+
+    let data = ctx.data::<Data>();
+
+    let mut total = 0;
+    let guilds = ctx.cache.guilds();
+
+    watcher.retain_guilds(&guilds.iter().copied().collect());
+
+    for guild_id in guilds {
+        let broken = match verify_references(ctx, guild_id, index, sandwich_config).await {
+            Ok(broken) => broken,
+            Err(e) => {
+                log::error!(
+                    "Failed to verify settings references of guild {}: {}",
+                    guild_id,
+                    e
+                );
+                continue;
+            }
+        };
+
+        let new = watcher.new_breakages(guild_id, broken);
+
+        if new.is_empty() {
+            continue;
+        }
+
+        total += new.len();
+
+        if let Err(e) =
+            dispatch_broken_references_event(&data, guild_id, &new, dispatch_event_data).await
+        {
+            log::error!(
+                "Failed to dispatch broken references event for guild {}: {}",
+                guild_id,
+                e
+            );
+        }
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::create_tables;
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    fn reference(kind: ReferenceKind, id: &str) -> Reference {
+        Reference {
+            setting: "setting".to_string(),
+            column: "column".to_string(),
+            row_pkey: "1".to_string(),
+            id: id.to_string(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn find_broken_reports_missing_and_unparsable_ids() {
+        let roles = HashSet::from([RoleId::new(10)]);
+        let channels = HashSet::from([ChannelId::new(20)]);
+
+        let broken = find_broken(
+            vec![
+                reference(ReferenceKind::Role, "10"),
+                reference(ReferenceKind::Role, "11"),
+                reference(ReferenceKind::Channel, "20"),
+                reference(ReferenceKind::Channel, "21"),
+                reference(ReferenceKind::Channel, "not-an-id"),
+                // A role id referenced as a channel is still broken
+                reference(ReferenceKind::Channel, "10"),
+            ],
+            &roles,
+            &channels,
+        );
+
+        assert_eq!(
+            broken,
+            vec![
+                reference(ReferenceKind::Role, "11"),
+                reference(ReferenceKind::Channel, "21"),
+                reference(ReferenceKind::Channel, "not-an-id"),
+                reference(ReferenceKind::Channel, "10"),
+            ]
+        );
+    }
+
+    #[test]
+    fn breakages_are_reported_once() {
+        let watcher = GuildStateWatcher::new();
+        let a = reference(ReferenceKind::Role, "11");
+        let b = reference(ReferenceKind::Channel, "21");
+
+        assert_eq!(
+            watcher.new_breakages(GUILD, vec![a.clone()]),
+            vec![a.clone()]
+        );
+        assert!(watcher.new_breakages(GUILD, vec![a.clone()]).is_empty());
+        assert_eq!(
+            watcher.new_breakages(GUILD, vec![a.clone(), b.clone()]),
+            vec![b.clone()]
+        );
+    }
+
+    #[test]
+    fn fixed_breakages_are_reported_again() {
+        let watcher = GuildStateWatcher::new();
+        let a = reference(ReferenceKind::Role, "11");
+        let b = reference(ReferenceKind::Channel, "21");
+
+        watcher.new_breakages(GUILD, vec![a.clone(), b.clone()]);
+        assert!(watcher.new_breakages(GUILD, vec![b.clone()]).is_empty());
+        assert_eq!(
+            watcher.new_breakages(GUILD, vec![a.clone(), b]),
+            vec![a.clone()]
+        );
+
+        assert!(watcher.new_breakages(GUILD, Vec::new()).is_empty());
+        assert!(watcher.is_empty());
+        assert_eq!(watcher.new_breakages(GUILD, vec![a.clone()]), vec![a]);
+    }
+
+    #[test]
+    fn uncached_guilds_are_forgotten() {
+        let watcher = GuildStateWatcher::new();
+        let a = reference(ReferenceKind::Role, "11");
+
+        watcher.new_breakages(GuildId::new(1), vec![a.clone()]);
+        watcher.new_breakages(GuildId::new(2), vec![a.clone()]);
+        assert_eq!(watcher.len(), 2);
+
+        watcher.retain_guilds(&HashSet::from([GuildId::new(2)]));
+        assert_eq!(watcher.len(), 1);
+        assert_eq!(
+            watcher.new_breakages(GuildId::new(1), vec![a.clone()]),
+            vec![a.clone()]
+        );
+
+        watcher.forget(GuildId::new(1));
+        assert_eq!(watcher.len(), 1);
+    }
+
+    #[test]
+    fn index_rejects_bad_identifiers_and_duplicates() {
+        let column = |table: &str| ReferenceColumn {
+            setting: "setting".to_string(),
+            table: table.to_string(),
+            column: "channel".to_string(),
+            primary_key: "id".to_string(),
+            kind: ReferenceKind::Channel,
+        };
+
+        let mut index = ReferenceIndex::new();
+        assert!(index.add(column("settings; DROP TABLE x")).is_err());
+        assert!(index.add(column("settings")).is_ok());
+        assert!(index.add(column("settings")).is_err());
+        assert_eq!(index.columns().len(), 1);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn fingerprint_collects_single_and_array_columns(pool: sqlx::PgPool) {
+        create_tables(
+            &pool,
+            &["CREATE TABLE watch_settings (id INTEGER PRIMARY KEY, guild_id TEXT NOT NULL, channel TEXT, roles TEXT[])"],
+        )
+        .await;
+
+        sqlx::query(
+            "INSERT INTO watch_settings (id, guild_id, channel, roles) VALUES (1, '1', '20', ARRAY['10', '']), (2, '1', NULL, NULL), (3, '2', '30', NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut index = ReferenceIndex::new();
+        for (column, kind) in [
+            ("channel", ReferenceKind::Channel),
+            ("roles", ReferenceKind::Role),
+        ] {
+            index
+                .add(ReferenceColumn {
+                    setting: "watch".to_string(),
+                    table: "watch_settings".to_string(),
+                    column: column.to_string(),
+                    primary_key: "id".to_string(),
+                    kind,
+                })
+                .unwrap();
+        }
+
+        let references = fingerprint(&pool, GUILD, &index).await.unwrap();
+
+        assert_eq!(
+            references
+                .iter()
+                .map(|r| (
+                    r.column.as_str(),
+                    r.row_pkey.as_str(),
+                    r.id.as_str(),
+                    r.kind
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("channel", "1", "20", ReferenceKind::Channel),
+                ("roles", "1", "10", ReferenceKind::Role),
+            ]
+        );
+    }
+}
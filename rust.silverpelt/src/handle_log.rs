@@ -0,0 +1,338 @@
+/// A single entry of the ``handle_log`` of a sting or punishment
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct HandleLogEntry {
+    pub at: chrono::DateTime<chrono::Utc>,
+    /// Who performed the action, ``system`` for automated workflows or a user ID
+    pub actor: String,
+    /// What happened, e.g. ``expired`` or ``reversed``
+    pub action: String,
+    #[serde(default)]
+    pub details: serde_json::Value,
+}
+
+impl HandleLogEntry {
+    pub fn new(
+        actor: impl Into<String>,
+        action: impl Into<String>,
+        details: serde_json::Value,
+    ) -> Self {
+        Self {
+            at: chrono::Utc::now(),
+            actor: actor.into(),
+            action: action.into(),
+            details,
+        }
+    }
+
+    /// Creates an entry for an automated workflow
+    pub fn system(action: impl Into<String>, details: serde_json::Value) -> Self {
+        Self::new("system", action, details)
+    }
+}
+
+/// A handle log entry which could not be parsed, along with its position in the log
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InvalidHandleLogEntry {
+    pub index: usize,
+    pub error: String,
+    pub raw: serde_json::Value,
+}
+
+/// Parses a handle log, skipping entries which fail to parse instead of failing the whole log
+///
+/// Legacy non-array values are treated as a log with a single entry, ``null`` as an empty log
+pub fn parse_handle_log(
+    handle_log: &serde_json::Value,
+) -> (Vec<HandleLogEntry>, Vec<InvalidHandleLogEntry>) {
+    let raw_entries = match handle_log {
+        serde_json::Value::Array(entries) => entries.as_slice(),
+        serde_json::Value::Null => &[],
+        legacy => std::slice::from_ref(legacy),
+    };
+
+    let mut entries = Vec::with_capacity(raw_entries.len());
+    let mut invalid = Vec::new();
+
+    for (index, raw) in raw_entries.iter().enumerate() {
+        match serde_json::from_value::<HandleLogEntry>(raw.clone()) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => invalid.push(InvalidHandleLogEntry {
+                index,
+                error: e.to_string(),
+                raw: raw.clone(),
+            }),
+        }
+    }
+
+    (entries, invalid)
+}
+
+/// Appends an entry to a local copy of a handle log, converting legacy non-array values into an array first
+pub fn push_handle_log_entry(
+    handle_log: &mut serde_json::Value,
+    entry: &HandleLogEntry,
+) -> Result<(), crate::Error> {
+    let entry = serde_json::to_value(entry)?;
+
+    match &mut *handle_log {
+        serde_json::Value::Array(entries) => entries.push(entry),
+        serde_json::Value::Null => *handle_log = serde_json::Value::Array(vec![entry]),
+        legacy => {
+            let legacy = legacy.take();
+            *handle_log = serde_json::Value::Array(vec![legacy, entry]);
+        }
+    }
+
+    Ok(())
+}
+
+/// SQL expression appending the JSON entry bound as ``$1`` to the ``handle_log`` column in a single statement
+///
+/// Legacy non-array values are migrated into the array the same way as ``push_handle_log_entry``
+pub(crate) const HANDLE_LOG_APPEND_EXPR: &str = "CASE WHEN jsonb_typeof(handle_log) = 'array' THEN handle_log || jsonb_build_array($1::jsonb) WHEN handle_log IS NULL OR jsonb_typeof(handle_log) = 'null' THEN jsonb_build_array($1::jsonb) ELSE jsonb_build_array(handle_log, $1::jsonb) END";
+
+/// Atomically appends an entry to the handle log of a row of ``table`` (``stings`` or ``punishments``)
+///
+/// Returns false if the row does not exist
+pub(crate) async fn append_handle_log(
+    db: impl sqlx::PgExecutor<'_>,
+    table: &'static str,
+    guild_id: serenity::all::GuildId,
+    id: uuid::Uuid,
+    entry: &HandleLogEntry,
+) -> Result<bool, crate::Error> {
+    let res = sqlx::query(&format!(
+        "UPDATE {} SET handle_log = {} WHERE id = $2 AND guild_id = $3",
+        table, HANDLE_LOG_APPEND_EXPR
+    ))
+    .bind(serde_json::to_value(entry)?)
+    .bind(id)
+    .bind(guild_id.to_string())
+    .execute(db)
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::punishments::PunishmentOperations;
+    use crate::stings::StingOperations;
+    use crate::test_schema::{create_tables, PUNISHMENTS, STINGS};
+    use antiraid_types::punishments::{Punishment, PunishmentTarget};
+    use antiraid_types::stings::{Sting, StingTarget};
+    use serde_json::json;
+    use serenity::all::GuildId;
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    fn entry(action: &str) -> HandleLogEntry {
+        HandleLogEntry::system(action, json!({}))
+    }
+
+    #[test]
+    fn push_migrates_legacy_shapes() {
+        let legacy = json!({ "expired": true });
+
+        let mut handle_log = legacy.clone();
+        push_handle_log_entry(&mut handle_log, &entry("voided")).unwrap();
+
+        let (entries, invalid) = parse_handle_log(&handle_log);
+        assert_eq!(handle_log[0], legacy);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "voided");
+
+        let mut handle_log = serde_json::Value::Null;
+        push_handle_log_entry(&mut handle_log, &entry("voided")).unwrap();
+        assert_eq!(handle_log.as_array().unwrap().len(), 1);
+
+        let mut handle_log = json!([]);
+        push_handle_log_entry(&mut handle_log, &entry("voided")).unwrap();
+        push_handle_log_entry(&mut handle_log, &entry("unvoided")).unwrap();
+
+        let (entries, invalid) = parse_handle_log(&handle_log);
+        assert!(invalid.is_empty());
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| e.action.as_str())
+                .collect::<Vec<_>>(),
+            ["voided", "unvoided"]
+        );
+    }
+
+    #[test]
+    fn parse_recovers_from_invalid_entries() {
+        let valid = serde_json::to_value(entry("expired")).unwrap();
+
+        let (entries, invalid) = parse_handle_log(&json!([
+            valid,
+            { "note": "written by an old code path" },
+            "free text",
+            valid,
+        ]));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(invalid.iter().map(|e| e.index).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(invalid[1].raw, json!("free text"));
+
+        // Legacy non-array values are a single (here invalid) entry, null is an empty log
+        let (entries, invalid) = parse_handle_log(&json!({ "expired": true }));
+        assert!(entries.is_empty());
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].index, 0);
+
+        assert_eq!(parse_handle_log(&serde_json::Value::Null).0.len(), 0);
+        assert_eq!(parse_handle_log(&serde_json::Value::Null).1.len(), 0);
+    }
+
+    async fn insert_sting(pool: &sqlx::PgPool, handle_log: serde_json::Value) -> uuid::Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO stings (guild_id, creator, target, handle_log) VALUES ($1, $2, $2, $3) RETURNING id",
+        )
+        .bind(GUILD.to_string())
+        .bind(StingTarget::System.to_string())
+        .bind(handle_log)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn insert_punishment(pool: &sqlx::PgPool, handle_log: serde_json::Value) -> uuid::Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO punishments (guild_id, punishment, creator, target, handle_log) VALUES ($1, 'ban', $2, $2, $3) RETURNING id",
+        )
+        .bind(GUILD.to_string())
+        .bind(PunishmentTarget::System.to_string())
+        .bind(handle_log)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn insert(pool: &sqlx::PgPool, table: &str, handle_log: serde_json::Value) -> uuid::Uuid {
+        match table {
+            "stings" => insert_sting(pool, handle_log).await,
+            _ => insert_punishment(pool, handle_log).await,
+        }
+    }
+
+    async fn stored_handle_log(
+        pool: &sqlx::PgPool,
+        table: &str,
+        id: uuid::Uuid,
+    ) -> serde_json::Value {
+        sqlx::query_scalar(&format!("SELECT handle_log FROM {} WHERE id = $1", table))
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn append_migrates_legacy_shapes_in_the_database(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS, PUNISHMENTS]).await;
+
+        let legacy = json!({ "expired": true });
+
+        for table in ["stings", "punishments"] {
+            for (initial, expected_len) in [
+                (legacy.clone(), 2),
+                (json!("free text"), 2),
+                (serde_json::Value::Null, 1),
+                (json!([]), 1),
+            ] {
+                let id = insert(&pool, table, initial.clone()).await;
+
+                assert!(append_handle_log(&pool, table, GUILD, id, &entry("voided"))
+                    .await
+                    .unwrap());
+
+                let stored = stored_handle_log(&pool, table, id).await;
+                let stored = stored.as_array().unwrap();
+                assert_eq!(stored.len(), expected_len, "{} {}", table, initial);
+
+                // The legacy value is kept as the first entry, the new entry is appended after it
+                if expected_len == 2 {
+                    assert_eq!(stored[0], initial);
+                }
+
+                let last: HandleLogEntry =
+                    serde_json::from_value(stored[expected_len - 1].clone()).unwrap();
+                assert_eq!(last.action, "voided");
+            }
+
+            // Missing rows and rows of another guild are not touched
+            let id = insert(&pool, table, json!([])).await;
+            assert!(!append_handle_log(
+                &pool,
+                table,
+                GUILD,
+                uuid::Uuid::new_v4(),
+                &entry("voided")
+            )
+            .await
+            .unwrap());
+            assert!(
+                !append_handle_log(&pool, table, GuildId::new(2), id, &entry("voided"))
+                    .await
+                    .unwrap()
+            );
+        }
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn concurrent_appends_are_not_lost(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS, PUNISHMENTS]).await;
+
+        const APPENDS: usize = 50;
+
+        // Start from a legacy value so the migration races with the appends too
+        let sting_id = insert_sting(&pool, json!({ "expired": true })).await;
+        let punishment_id = insert_punishment(&pool, json!({ "expired": true })).await;
+
+        let mut handles = vec![];
+
+        for i in 0..APPENDS {
+            let pool = pool.clone();
+
+            handles.push(tokio::spawn(async move {
+                let entry = HandleLogEntry::system("note", json!({ "i": i }));
+
+                assert!(Sting::append_handle_log(&pool, sting_id, GUILD, &entry)
+                    .await
+                    .unwrap());
+                assert!(
+                    Punishment::append_handle_log(&pool, punishment_id, GUILD, &entry)
+                        .await
+                        .unwrap()
+                );
+            }));
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let sting = Sting::get(&pool, GUILD, sting_id).await.unwrap().unwrap();
+        let punishment = Punishment::get(&pool, GUILD, punishment_id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        for (entries, invalid) in [sting.parsed_handle_log(), punishment.parsed_handle_log()] {
+            // Only the legacy value fails to parse, every append made it in exactly once
+            assert_eq!(invalid.len(), 1);
+            assert_eq!(invalid[0].index, 0);
+
+            let mut seen = entries
+                .iter()
+                .map(|e| e.details["i"].as_u64().unwrap() as usize)
+                .collect::<Vec<_>>();
+            seen.sort_unstable();
+            assert_eq!(seen, (0..APPENDS).collect::<Vec<_>>());
+        }
+    }
+}
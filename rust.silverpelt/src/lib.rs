@@ -1,12 +1,31 @@
 pub mod ar_event;
+pub mod bootstrap;
+pub mod cooldowns;
+pub mod create_validation;
 pub mod data;
+pub mod decode;
+pub mod extensions;
+pub mod guild_state_watch;
+pub mod handle_log;
 pub mod lockdowns;
 pub mod member_permission_calc;
+pub mod metrics;
+pub mod module_config;
 pub mod objectstore;
+pub mod pagination;
 pub mod pginterval;
 pub mod punishments;
+pub mod request_context;
+pub mod retention;
+pub mod sandwich;
 pub mod stings;
+pub mod tasks;
 pub mod templates;
 pub mod userinfo;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>; // This is constant and should be copy pasted
+
+#[cfg(test)]
+mod mock_server;
+#[cfg(test)]
+mod test_schema;
@@ -7,6 +7,9 @@ use lockdowns::{
 use sandwich_driver::SandwichConfigData;
 use sqlx::Row;
 
+use crate::decode::{decode_lossy, parse_snowflake_column, DecodeFailure};
+use crate::sandwich::SandwichStatus;
+
 #[derive(Clone)]
 pub struct LockdownData {
     pub cache: Arc<serenity::all::Cache>,
@@ -14,6 +17,8 @@ pub struct LockdownData {
     pub pool: sqlx::PgPool,
     pub reqwest: reqwest::Client,
     pub sandwich_config: SandwichConfigData,
+    /// If set, sandwich calls are tracked and fall back to the cache while degraded, see ``with_sandwich_status``
+    sandwich_status: Option<Arc<SandwichStatus>>,
 }
 
 impl LockdownData {
@@ -30,8 +35,16 @@ impl LockdownData {
             pool,
             reqwest,
             sandwich_config,
+            sandwich_status: None,
         }
     }
+
+    /// Routes sandwich calls through ``crate::sandwich`` so they are tracked in ``status``, falling back to the
+    /// serenity cache while sandwich is degraded
+    pub fn with_sandwich_status(mut self, status: Arc<SandwichStatus>) -> Self {
+        self.sandwich_status = Some(status);
+        self
+    }
 }
 
 #[derive(sqlx::FromRow)]
@@ -43,6 +56,72 @@ struct LockdownRow {
     created_at: chrono::DateTime<chrono::Utc>,
 }
 
+impl LockdownRow {
+    fn into_lockdown(self) -> Result<Lockdown, crate::Error> {
+        let lockdown_mode = from_lockdown_mode_string(&self.r#type).map_err(|e| {
+            format!(
+                "Failed to parse column ``type`` of row {}: {:?} is not a valid lockdown mode: {}",
+                self.id, self.r#type, e
+            )
+        })?;
+
+        Ok(Lockdown {
+            id: self.id,
+            r#type: lockdown_mode,
+            data: self.data,
+            reason: self.reason,
+            created_at: self.created_at,
+        })
+    }
+}
+
+async fn fetch_lockdown_rows(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: serenity::all::GuildId,
+) -> Result<Vec<LockdownRow>, crate::Error> {
+    let rows = sqlx::query_as(
+        "SELECT id, type, data, reason, created_at FROM lockdown__guild_lockdowns WHERE guild_id = $1",
+    )
+    .bind(guild_id.to_string())
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows)
+}
+
+/// Like ``LockdownDataStore::get_lockdowns`` but skips rows which fail to decode, returning them separately
+pub async fn get_lockdowns_lossy(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: serenity::all::GuildId,
+) -> Result<(Vec<Lockdown>, Vec<DecodeFailure>), crate::Error> {
+    let rows = fetch_lockdown_rows(db, guild_id).await?;
+
+    Ok(decode_lossy(
+        rows,
+        "lockdown",
+        |row| row.id.to_string(),
+        LockdownRow::into_lockdown,
+    ))
+}
+
+/// Parses the ``member_roles`` of a guild's lockdown settings, ignoring empty entries
+fn parse_member_roles(
+    guild_id: serenity::all::GuildId,
+    member_roles: Vec<String>,
+) -> Result<HashSet<serenity::all::RoleId>, crate::Error> {
+    let mut roles = HashSet::with_capacity(member_roles.len());
+
+    for role in member_roles {
+        if role.is_empty() {
+            continue;
+        }
+
+        roles.insert(parse_snowflake_column(&role, "member_roles", guild_id)?);
+    }
+
+    Ok(roles)
+}
+
 impl LockdownDataStore for LockdownData {
     async fn get_guild_lockdown_settings(
         &self,
@@ -56,23 +135,7 @@ impl LockdownDataStore for LockdownData {
         .await?
         {
             Some(settings) => {
-                let member_roles = {
-                    let member_roles_vec = settings.try_get::<Vec<String>, _>("member_roles")?;
-
-                    let mut member_roles = HashSet::with_capacity(member_roles_vec.len());
-                    for role in member_roles_vec {
-                        if role.is_empty() {
-                            continue;
-                        }
-
-                        member_roles.insert(
-                            role.parse()
-                                .map_err(|_| format!("Failed to parse role ID: {}", role,))?,
-                        );
-                    }
-
-                    member_roles
-                };
+                let member_roles = parse_member_roles(guild_id, settings.try_get("member_roles")?)?;
 
                 let settings = GuildLockdownSettings {
                     member_roles,
@@ -89,28 +152,60 @@ impl LockdownDataStore for LockdownData {
         &self,
         guild_id: serenity::all::GuildId,
     ) -> Result<serenity::all::PartialGuild, lockdowns::Error> {
-        sandwich_driver::guild(
-            &self.cache,
-            &self.http,
-            &self.reqwest,
-            guild_id,
-            &self.sandwich_config,
-        )
-        .await
+        match self.sandwich_status {
+            Some(ref status) => {
+                crate::sandwich::guild(
+                    status,
+                    true,
+                    &self.cache,
+                    &self.http,
+                    &self.reqwest,
+                    guild_id,
+                    &self.sandwich_config,
+                )
+                .await
+            }
+            None => {
+                sandwich_driver::guild(
+                    &self.cache,
+                    &self.http,
+                    &self.reqwest,
+                    guild_id,
+                    &self.sandwich_config,
+                )
+                .await
+            }
+        }
     }
 
     async fn guild_channels(
         &self,
         guild_id: serenity::all::GuildId,
     ) -> Result<Vec<serenity::all::GuildChannel>, lockdowns::Error> {
-        sandwich_driver::guild_channels(
-            &self.cache,
-            &self.http,
-            &self.reqwest,
-            guild_id,
-            &self.sandwich_config,
-        )
-        .await
+        match self.sandwich_status {
+            Some(ref status) => {
+                crate::sandwich::guild_channels(
+                    status,
+                    true,
+                    &self.cache,
+                    &self.http,
+                    &self.reqwest,
+                    guild_id,
+                    &self.sandwich_config,
+                )
+                .await
+            }
+            None => {
+                sandwich_driver::guild_channels(
+                    &self.cache,
+                    &self.http,
+                    &self.reqwest,
+                    guild_id,
+                    &self.sandwich_config,
+                )
+                .await
+            }
+        }
     }
 
     fn cache(&self) -> Option<&serenity::all::Cache> {
@@ -125,33 +220,12 @@ impl LockdownDataStore for LockdownData {
         &self,
         guild_id: serenity::all::GuildId,
     ) -> Result<Vec<Lockdown>, lockdowns::Error> {
-        let data: Vec<LockdownRow> = sqlx::query_as(
-            "SELECT id, type, data, reason, created_at FROM lockdown__guild_lockdowns WHERE guild_id = $1",
-        )
-        .bind(guild_id.to_string())
-        .fetch_all(&self.pool)
-        .await?;
-
-        let mut lockdowns = Vec::new();
+        let rows = fetch_lockdown_rows(&self.pool, guild_id).await?;
 
-        for row in data {
-            let id = row.id;
-            let r#type = row.r#type;
-            let data = row.data;
-            let reason = row.reason;
-            let created_at = row.created_at;
+        let mut lockdowns = Vec::with_capacity(rows.len());
 
-            let lockdown_mode = from_lockdown_mode_string(&r#type)?;
-
-            let lockdown = Lockdown {
-                id,
-                r#type: lockdown_mode,
-                data,
-                reason,
-                created_at,
-            };
-
-            lockdowns.push(lockdown);
+        for row in rows {
+            lockdowns.push(row.into_lockdown()?);
         }
 
         Ok(lockdowns)
@@ -195,3 +269,177 @@ impl LockdownDataStore for LockdownData {
         Ok(())
     }
 }
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct LockdownSummaryEntry {
+    pub id: uuid::Uuid,
+    pub r#type: String,
+    pub reason: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Age of the lockdown in seconds
+    pub age: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct LockdownSummarySettings {
+    pub member_roles: Vec<serenity::all::RoleId>,
+    pub require_correct_layout: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct LayoutDeviation {
+    pub channel_id: serenity::all::ChannelId,
+    pub channel_name: String,
+    /// The roles whose overwrites explicitly allow sending messages in the channel
+    pub roles: Vec<serenity::all::RoleId>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LayoutCheck {
+    /// The guild does not require a correct layout
+    NotRequired,
+    /// All channels match the expected layout
+    Ok,
+    /// Some channels have overwrites that would bypass a lockdown
+    Deviations { channels: Vec<LayoutDeviation> },
+    /// The layout could not be checked (e.g. sandwich was unavailable)
+    Unknown { error: String },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct LockdownSummary {
+    pub guild_id: serenity::all::GuildId,
+    pub active_lockdowns: Vec<LockdownSummaryEntry>,
+    pub settings: LockdownSummarySettings,
+    pub layout: LayoutCheck,
+}
+
+/// Returns the channels where @everyone or a member role has an overwrite explicitly allowing sending messages
+///
+/// Such overwrites take precedence over role level permissions and hence would let members bypass a lockdown
+pub fn layout_deviations(
+    guild_id: serenity::all::GuildId,
+    member_roles: &HashSet<serenity::all::RoleId>,
+    channels: &[serenity::all::GuildChannel],
+) -> Vec<LayoutDeviation> {
+    let everyone_role = guild_id.everyone_role();
+    let mut deviations = Vec::new();
+
+    for channel in channels {
+        let mut roles = Vec::new();
+
+        for overwrite in channel.permission_overwrites.iter() {
+            let serenity::all::PermissionOverwriteType::Role(role_id) = overwrite.kind else {
+                continue;
+            };
+
+            if (role_id == everyone_role || member_roles.contains(&role_id))
+                && overwrite.allow.send_messages()
+            {
+                roles.push(role_id);
+            }
+        }
+
+        if !roles.is_empty() {
+            deviations.push(LayoutDeviation {
+                channel_id: channel.id,
+                channel_name: channel.name.to_string(),
+                roles,
+            });
+        }
+    }
+
+    deviations
+}
+
+/// Returns a high level summary of the current lockdown state of a guild
+///
+/// Failures while fetching channels for the layout check are reported as ``LayoutCheck::Unknown`` instead of erroring
+pub async fn summary(
+    guild_id: serenity::all::GuildId,
+    store: &impl LockdownDataStore,
+) -> Result<LockdownSummary, lockdowns::Error> {
+    let settings = store.get_guild_lockdown_settings(guild_id).await?;
+    let lockdowns = store.get_lockdowns(guild_id).await?;
+
+    let now = chrono::Utc::now();
+    let active_lockdowns = lockdowns
+        .into_iter()
+        .map(|lockdown| LockdownSummaryEntry {
+            id: lockdown.id,
+            r#type: lockdown.r#type.string_form(),
+            reason: lockdown.reason,
+            created_at: lockdown.created_at,
+            age: (now - lockdown.created_at).num_seconds(),
+        })
+        .collect();
+
+    let layout = if settings.require_correct_layout {
+        match store.guild_channels(guild_id).await {
+            Ok(channels) => {
+                let channels = layout_deviations(guild_id, &settings.member_roles, &channels);
+
+                if channels.is_empty() {
+                    LayoutCheck::Ok
+                } else {
+                    LayoutCheck::Deviations { channels }
+                }
+            }
+            Err(e) => LayoutCheck::Unknown {
+                error: e.to_string(),
+            },
+        }
+    } else {
+        LayoutCheck::NotRequired
+    };
+
+    Ok(LockdownSummary {
+        guild_id,
+        active_lockdowns,
+        settings: LockdownSummarySettings {
+            member_roles: settings.member_roles.into_iter().collect(),
+            require_correct_layout: settings.require_correct_layout,
+        },
+        layout,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, LOCKDOWN_GUILD_LOCKDOWNS};
+    use serenity::all::{GuildId, RoleId};
+
+    #[test]
+    fn member_roles_errors_name_the_bad_value() {
+        let guild = GuildId::new(1);
+
+        let roles = parse_member_roles(guild, vec!["2".to_string(), String::new()]).unwrap();
+        assert_eq!(roles, HashSet::from([RoleId::new(2)]));
+
+        let err = parse_member_roles(guild, vec!["2".to_string(), "abc".to_string()])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("member_roles"), "{}", err);
+        assert!(err.contains("\"abc\""), "{}", err);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn lossy_lockdowns_report_bad_rows(pool: sqlx::PgPool) {
+        create_tables(&pool, &[LOCKDOWN_GUILD_LOCKDOWNS]).await;
+
+        let bad: uuid::Uuid = sqlx::query_scalar(
+            "INSERT INTO lockdown__guild_lockdowns (guild_id, type, data, reason) VALUES ('1', 'not-a-mode', '{}', 'raid') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let (lockdowns, failures) = get_lockdowns_lossy(&pool, GuildId::new(1)).await.unwrap();
+        assert!(lockdowns.is_empty());
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].id, bad.to_string());
+        assert!(failures[0].error.contains("type"), "{}", failures[0].error);
+    }
+}
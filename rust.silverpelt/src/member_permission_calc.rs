@@ -1,3 +1,10 @@
+pub mod channel_overrides;
+pub mod hierarchy;
+pub mod role_perms;
+pub mod root_override;
+
+use std::collections::{HashMap, HashSet};
+
 use kittycat::perms::Permission;
 use serenity::all::{GuildId, RoleId, UserId};
 use sqlx::Row;
@@ -5,19 +12,25 @@ use sqlx::Row;
 /// ``create_roles_list_for_guild`` creates a list of roles for a guild including the everyone role as a string
 ///
 /// This is needed by other functions to rederive permissions such as ``rederive_perms_impl`` and ``get_user_positions_from_db``
+/// Duplicate roles (including an explicitly passed everyone role) are only listed once
 pub fn create_roles_list_for_guild(roles: &[RoleId], guild_id: GuildId) -> Vec<String> {
     let mut roles_str = Vec::new();
 
-    for role in roles {
-        roles_str.push(role.to_string());
-    }
+    for role in roles
+        .iter()
+        .chain(std::iter::once(&guild_id.everyone_role()))
+    {
+        let role = role.to_string();
 
-    roles_str.push(guild_id.everyone_role().to_string());
+        if !roles_str.contains(&role) {
+            roles_str.push(role);
+        }
+    }
 
     roles_str
 }
 
-/// Returns the user positions of the member. This can be useful for caching or to reduce DB calls
+/// Returns the user positions of the member ordered by index. This can be useful for caching or to reduce DB calls
 ///
 /// ``roles_str`` is the list of roles as strings. This can be obtained by calling ``create_roles_list_for_guild``
 pub async fn get_user_positions_from_db(
@@ -27,7 +40,7 @@ pub async fn get_user_positions_from_db(
 ) -> Result<Vec<kittycat::perms::PartialStaffPosition>, crate::Error> {
     // Rederive permissions for the new perms
     let role_perms = sqlx::query(
-        "SELECT role_id, perms, index FROM guild_roles WHERE guild_id = $1 AND role_id = ANY($2)",
+        "SELECT role_id, perms, index FROM guild_roles WHERE guild_id = $1 AND role_id = ANY($2) ORDER BY index ASC, role_id ASC",
     )
     .bind(guild_id.to_string())
     .bind(roles_str)
@@ -85,11 +98,139 @@ async fn rederive_perms(
     })
 }
 
+#[derive(Clone)]
 pub struct GetKittycatPermsConfigData {
     pub main_server_id: GuildId,
     pub root_users: &'static [UserId],
 }
 
+impl GetKittycatPermsConfigData {
+    pub fn new(main_server_id: GuildId, root_users: &'static [UserId]) -> Self {
+        Self {
+            main_server_id,
+            root_users,
+        }
+    }
+
+    /// Uses a runtime root override policy (usually ``Data::root_override``) instead of the static config
+    pub fn with_root_override(
+        self,
+        root_override: Option<std::sync::Arc<root_override::RootOverride>>,
+    ) -> GetKittycatPermsConfig {
+        GetKittycatPermsConfig::from(self).with_root_override(root_override)
+    }
+
+    /// Dispatches an ``AR/RootOverrideUsed`` event whenever the root override is applied
+    pub fn with_root_override_audit(
+        self,
+        audit: root_override::RootOverrideAudit,
+    ) -> GetKittycatPermsConfig {
+        GetKittycatPermsConfig::from(self).with_root_override_audit(audit)
+    }
+}
+
+/// ``GetKittycatPermsConfigData`` along with optional settings, all of which default to off
+///
+/// Created from ``GetKittycatPermsConfigData`` using ``From`` or its ``with_*`` methods
+#[derive(Clone)]
+pub struct GetKittycatPermsConfig {
+    pub data: GetKittycatPermsConfigData,
+    /// Runtime root override policy, if unset the override is derived from ``main_server_id`` and ``root_users``
+    root_override: Option<std::sync::Arc<root_override::RootOverride>>,
+    /// If set, an ``AR/RootOverrideUsed`` event is dispatched whenever the root override is applied
+    root_override_audit: Option<root_override::RootOverrideAudit>,
+}
+
+impl From<GetKittycatPermsConfigData> for GetKittycatPermsConfig {
+    fn from(data: GetKittycatPermsConfigData) -> Self {
+        Self {
+            data,
+            root_override: None,
+            root_override_audit: None,
+        }
+    }
+}
+
+impl GetKittycatPermsConfig {
+    /// Uses a runtime root override policy (usually ``Data::root_override``) instead of the static config
+    pub fn with_root_override(
+        mut self,
+        root_override: Option<std::sync::Arc<root_override::RootOverride>>,
+    ) -> Self {
+        self.root_override = root_override;
+        self
+    }
+
+    /// Dispatches an ``AR/RootOverrideUsed`` event whenever the root override is applied
+    pub fn with_root_override_audit(mut self, audit: root_override::RootOverrideAudit) -> Self {
+        self.root_override_audit = Some(audit);
+        self
+    }
+
+    /// Returns true if the root override applies to the user in the guild
+    pub fn root_override_applies(&self, guild_id: GuildId, user_id: UserId) -> bool {
+        match &self.root_override {
+            Some(root_override) => root_override.applies(guild_id, user_id),
+            None => root_override::RootOverridePolicy::from_config(
+                self.data.main_server_id,
+                self.data.root_users,
+            )
+            .applies(guild_id, user_id),
+        }
+    }
+}
+
+/// Metadata about how permissions were resolved
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResolutionMeta {
+    /// The user was granted ``global.*`` by the root override rather than their roles
+    pub root_override_applied: bool,
+}
+
+/// Returns the permissions of guild owners and root users, who are not subject to their roles
+fn owner_or_root_override(
+    guild_id: GuildId,
+    guild_owner_id: UserId,
+    user_id: UserId,
+    config: &GetKittycatPermsConfig,
+) -> Option<(kittycat::perms::StaffPermissions, ResolutionMeta)> {
+    // For now, owners have full permission, this may change in the future (maybe??)
+    if guild_owner_id == user_id {
+        return Some((
+            kittycat::perms::StaffPermissions {
+                user_positions: Vec::new(),
+                perm_overrides: vec!["global.*".into()],
+            },
+            ResolutionMeta::default(),
+        ));
+    }
+
+    // Root users get full control over the bot (by default only on the main server) to ensure control even under extreme circumstances
+    if config.root_override_applies(guild_id, user_id) {
+        log::warn!(
+            "Root override applied for user {} in guild {}",
+            user_id,
+            guild_id
+        );
+
+        if let Some(audit) = &config.root_override_audit {
+            audit.dispatch(guild_id, user_id);
+        }
+
+        return Some((
+            kittycat::perms::StaffPermissions {
+                user_positions: Vec::new(),
+                perm_overrides: vec!["global.*".into()],
+            },
+            ResolutionMeta {
+                root_override_applied: true,
+            },
+        ));
+    }
+
+    None
+}
+
 /// Returns the kittycat permissions of a user. This function also takes into account permission overrides etc.
 pub async fn get_kittycat_perms(
     pool: &sqlx::PgPool,
@@ -97,23 +238,421 @@ pub async fn get_kittycat_perms(
     guild_owner_id: UserId,
     user_id: UserId,
     roles: &[RoleId],
-    config: GetKittycatPermsConfigData,
+    config: impl Into<GetKittycatPermsConfig>,
 ) -> Result<kittycat::perms::StaffPermissions, crate::Error> {
-    // For now, owners have full permission, this may change in the future (maybe??)
-    if guild_owner_id == user_id {
-        return Ok(kittycat::perms::StaffPermissions {
-            user_positions: Vec::new(),
-            perm_overrides: vec!["global.*".into()],
-        });
+    get_kittycat_perms_with_meta(pool, guild_id, guild_owner_id, user_id, roles, config)
+        .await
+        .map(|(perms, _)| perms)
+}
+
+/// Like ``get_kittycat_perms`` but also returns how the permissions were resolved
+///
+/// If the root override is applied, ``AR/RootOverrideUsed`` is dispatched through the config's ``root_override_audit``
+pub async fn get_kittycat_perms_with_meta(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    guild_owner_id: UserId,
+    user_id: UserId,
+    roles: &[RoleId],
+    config: impl Into<GetKittycatPermsConfig>,
+) -> Result<(kittycat::perms::StaffPermissions, ResolutionMeta), crate::Error> {
+    let config = config.into();
+
+    if let Some(res) = owner_or_root_override(guild_id, guild_owner_id, user_id, &config) {
+        return Ok(res);
+    }
+
+    let start = std::time::Instant::now();
+    let res = rederive_perms(pool, guild_id, user_id, roles).await;
+
+    crate::metrics::record_duration(
+        "antiraid_kittycat_perms_resolve_duration_seconds",
+        &[],
+        start.elapsed(),
+    );
+
+    Ok((res?, ResolutionMeta::default()))
+}
+
+/// Like ``get_kittycat_perms`` but for many members at once, returning the resolved permissions of each member
+///
+/// ``guild_roles`` and ``guild_members`` are each queried once for all members instead of once per member
+pub async fn get_kittycat_perms_bulk(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    guild_owner_id: UserId,
+    members: &[(UserId, Vec<RoleId>)],
+    config: &GetKittycatPermsConfig,
+) -> Result<HashMap<UserId, Vec<Permission>>, crate::Error> {
+    let mut resolved = HashMap::with_capacity(members.len());
+    let mut to_rederive = Vec::with_capacity(members.len());
+
+    for (user_id, roles) in members {
+        match owner_or_root_override(guild_id, guild_owner_id, *user_id, config) {
+            Some((perms, _)) => {
+                resolved.insert(*user_id, perms.resolve());
+            }
+            None => to_rederive.push((*user_id, roles.as_slice())),
+        }
+    }
+
+    if to_rederive.is_empty() {
+        return Ok(resolved);
+    }
+
+    let start = std::time::Instant::now();
+
+    for (user_id, perms) in rederive_perms_bulk(pool, guild_id, &to_rederive).await? {
+        resolved.insert(user_id, perms.resolve());
+    }
+
+    crate::metrics::record_duration(
+        "antiraid_kittycat_perms_bulk_resolve_duration_seconds",
+        &[],
+        start.elapsed(),
+    );
+
+    Ok(resolved)
+}
+
+/// Bulk version of ``rederive_perms``, building the same ``StaffPermissions`` for each member
+async fn rederive_perms_bulk(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    members: &[(UserId, &[RoleId])],
+) -> Result<HashMap<UserId, kittycat::perms::StaffPermissions>, crate::Error> {
+    let mut roles_str = members
+        .iter()
+        .flat_map(|(_, roles)| roles.iter().map(|r| r.to_string()))
+        .collect::<HashSet<_>>();
+    roles_str.insert(guild_id.everyone_role().to_string());
+    let roles_str = roles_str.into_iter().collect::<Vec<_>>();
+
+    let positions = get_user_positions_from_db(pool, guild_id, &roles_str)
+        .await?
+        .into_iter()
+        .map(|p| (p.id.clone(), p))
+        .collect::<HashMap<_, _>>();
+
+    let user_ids = members
+        .iter()
+        .map(|(user_id, _)| user_id.to_string())
+        .collect::<Vec<_>>();
+
+    let override_rows = sqlx::query(
+        "SELECT user_id, perm_overrides FROM guild_members WHERE guild_id = $1 AND user_id = ANY($2)",
+    )
+    .bind(guild_id.to_string())
+    .bind(&user_ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut perm_overrides = HashMap::with_capacity(override_rows.len());
+
+    for row in override_rows {
+        perm_overrides.insert(
+            row.try_get::<String, _>("user_id")?,
+            row.try_get::<Vec<String>, _>("perm_overrides")?,
+        );
+    }
+
+    let mut rederived = HashMap::with_capacity(members.len());
+
+    for (user_id, roles) in members {
+        // Same order as ``get_user_positions_from_db`` so both paths build identical ``StaffPermissions``
+        let mut user_positions = create_roles_list_for_guild(roles, guild_id)
+            .iter()
+            .filter_map(|role_id| positions.get(role_id).cloned())
+            .collect::<Vec<_>>();
+        user_positions.sort_by(|a, b| a.index.cmp(&b.index).then_with(|| a.id.cmp(&b.id)));
+
+        rederived.insert(
+            *user_id,
+            kittycat::perms::StaffPermissions {
+                user_positions,
+                perm_overrides: perm_overrides
+                    .get(&user_id.to_string())
+                    .map(|overrides| {
+                        overrides
+                            .iter()
+                            .map(|x| Permission::from_string(x))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            },
+        );
+    }
+
+    Ok(rederived)
+}
+
+/// Returns the members out of ``members`` who have ``perm``, see ``get_kittycat_perms_bulk``
+pub async fn find_members_with_perm(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    guild_owner_id: UserId,
+    members: &[(UserId, Vec<RoleId>)],
+    perm: &Permission,
+    config: &GetKittycatPermsConfig,
+) -> Result<Vec<UserId>, crate::Error> {
+    let resolved = get_kittycat_perms_bulk(pool, guild_id, guild_owner_id, members, config).await?;
+
+    Ok(members
+        .iter()
+        .filter(|(user_id, _)| {
+            resolved
+                .get(user_id)
+                .is_some_and(|perms| kittycat::perms::has_perm(perms, perm))
+        })
+        .map(|(user_id, _)| *user_id)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use root_override::{RootOverride, RootOverridePolicy};
+    use std::sync::Arc;
+
+    static ROOT_USERS: &[UserId] = &[UserId::new(10)];
+
+    fn config() -> GetKittycatPermsConfigData {
+        GetKittycatPermsConfigData {
+            main_server_id: GuildId::new(1),
+            root_users: ROOT_USERS,
+        }
+    }
+
+    #[test]
+    fn static_config_applies_on_main_server_only() {
+        let config = GetKittycatPermsConfig::from(config());
+
+        assert!(config.root_override_applies(GuildId::new(1), UserId::new(10)));
+        assert!(!config.root_override_applies(GuildId::new(2), UserId::new(10)));
     }
 
-    // We hardcode root users for the main server to ensure root users have control over the bot even under extreme circumstances
-    if guild_id == config.main_server_id && config.root_users.contains(&user_id) {
-        return Ok(kittycat::perms::StaffPermissions {
-            user_positions: Vec::new(),
-            perm_overrides: vec!["global.*".into()],
-        });
+    #[test]
+    fn runtime_override_takes_precedence_over_static_config() {
+        let root_override = Arc::new(RootOverride::new(RootOverridePolicy::from_config(
+            GuildId::new(1),
+            ROOT_USERS,
+        )));
+        let config = config().with_root_override(Some(root_override.clone()));
+
+        assert!(config.root_override_applies(GuildId::new(1), UserId::new(10)));
+
+        root_override.set_enabled(false);
+        assert!(!config.root_override_applies(GuildId::new(1), UserId::new(10)));
+
+        root_override.set_enabled(true);
+        assert!(config.root_override_applies(GuildId::new(1), UserId::new(10)));
     }
 
-    rederive_perms(pool, guild_id, user_id, roles).await
+    #[test]
+    fn owner_is_not_reported_as_root_override() {
+        let (perms, meta) = owner_or_root_override(
+            GuildId::new(1),
+            UserId::new(10),
+            UserId::new(10),
+            &config().into(),
+        )
+        .unwrap();
+
+        assert!(kittycat::perms::has_perm(
+            &perms.resolve(),
+            &Permission::from_string("moderation.ban")
+        ));
+        assert!(!meta.root_override_applied);
+    }
+
+    #[test]
+    fn root_override_sets_meta_and_can_be_disabled() {
+        let root_override = Arc::new(RootOverride::new(RootOverridePolicy::from_config(
+            GuildId::new(1),
+            ROOT_USERS,
+        )));
+        let config = config().with_root_override(Some(root_override.clone()));
+
+        let (_, meta) =
+            owner_or_root_override(GuildId::new(1), UserId::new(20), UserId::new(10), &config)
+                .unwrap();
+        assert!(meta.root_override_applied);
+
+        root_override.set_enabled(false);
+        assert!(
+            owner_or_root_override(GuildId::new(1), UserId::new(20), UserId::new(10), &config)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn roles_list_is_deduped() {
+        let guild_id = GuildId::new(1);
+
+        assert_eq!(
+            create_roles_list_for_guild(
+                &[
+                    RoleId::new(3),
+                    RoleId::new(2),
+                    RoleId::new(3),
+                    RoleId::new(1)
+                ],
+                guild_id
+            ),
+            vec!["3", "2", "1"]
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn bulk_matches_single_member_path(pool: sqlx::PgPool) {
+        crate::test_schema::create_tables(
+            &pool,
+            &[
+                crate::test_schema::GUILD_ROLES,
+                crate::test_schema::GUILD_MEMBERS,
+            ],
+        )
+        .await;
+
+        let guild_id = GuildId::new(1);
+        let owner_id = UserId::new(2);
+        let perm_pool = [
+            "moderation.ban",
+            "~moderation.ban",
+            "moderation.*",
+            "~moderation.*",
+            "moderation.kick",
+            "~moderation.kick",
+            "lockdowns.create",
+            "~lockdowns.create",
+            "global.*",
+        ];
+
+        let state = std::cell::Cell::new(0x9E37_79B9_7F4A_7C15u64);
+        let next = |n: u64| {
+            let mut s = state.get();
+            s ^= s << 13;
+            s ^= s >> 7;
+            s ^= s << 17;
+            state.set(s);
+            s % n
+        };
+
+        let random_perms = |count: u64| {
+            (0..count)
+                .map(|_| perm_pool[next(perm_pool.len() as u64) as usize].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Role 1 is the everyone role, indexes are shuffled relative to role ids
+        for (i, index) in [5, 2, 7, 1, 4, 6, 3].into_iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO guild_roles (guild_id, role_id, perms, index) VALUES ('1', $1, $2, $3)",
+            )
+            .bind((i + 1).to_string())
+            .bind(random_perms(3))
+            .bind(index)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let mut members = Vec::new();
+
+        for user in 3..203u64 {
+            // Includes duplicates, the explicit everyone role and roles without kittycat perms (ids 8 and 9)
+            let roles = (0..next(6))
+                .map(|_| RoleId::new(1 + next(9)))
+                .collect::<Vec<_>>();
+
+            if next(2) == 0 {
+                sqlx::query(
+                    "INSERT INTO guild_members (guild_id, user_id, perm_overrides) VALUES ('1', $1, $2)",
+                )
+                .bind(user.to_string())
+                .bind(random_perms(next(3)))
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+
+            members.push((UserId::new(user), roles));
+        }
+
+        members.push((owner_id, vec![RoleId::new(2)]));
+
+        let config = GetKittycatPermsConfig::from(GetKittycatPermsConfigData::new(
+            GuildId::new(100),
+            ROOT_USERS,
+        ));
+        let bulk = get_kittycat_perms_bulk(&pool, guild_id, owner_id, &members, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(bulk.len(), members.len());
+
+        let rederive_members = members
+            .iter()
+            .map(|(user_id, roles)| (*user_id, roles.as_slice()))
+            .collect::<Vec<_>>();
+        let bulk_rederived = rederive_perms_bulk(&pool, guild_id, &rederive_members)
+            .await
+            .unwrap();
+
+        // Compares everything ``resolve`` depends on, including the order of positions
+        let summarize = |perms: &kittycat::perms::StaffPermissions| {
+            (
+                perms
+                    .user_positions
+                    .iter()
+                    .map(|p| {
+                        (
+                            p.id.clone(),
+                            p.index,
+                            p.perms.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+                perms
+                    .perm_overrides
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        for (user_id, roles) in members.iter() {
+            if *user_id != owner_id {
+                let single = rederive_perms(&pool, guild_id, *user_id, roles)
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    summarize(&bulk_rederived[user_id]),
+                    summarize(&single),
+                    "bulk and single member paths differ for {} with roles {:?}",
+                    user_id,
+                    roles
+                );
+            }
+
+            let single = get_kittycat_perms(
+                &pool,
+                guild_id,
+                owner_id,
+                *user_id,
+                roles,
+                GetKittycatPermsConfigData::new(GuildId::new(100), ROOT_USERS),
+            )
+            .await
+            .unwrap()
+            .resolve();
+
+            assert_eq!(
+                bulk[user_id], single,
+                "bulk and single member paths differ for {} with roles {:?}",
+                user_id, roles
+            );
+        }
+    }
 }
@@ -0,0 +1,421 @@
+use kittycat::perms::Permission;
+use serenity::all::{ChannelId, GuildId, RoleId, UserId};
+
+use super::role_perms::validate_perm;
+use super::{get_kittycat_perms_with_meta, GetKittycatPermsConfig, ResolutionMeta};
+
+/// Who a channel permission override applies to
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelOverrideTarget {
+    Role(RoleId),
+    User(UserId),
+}
+
+impl ChannelOverrideTarget {
+    fn to_columns(self) -> (&'static str, String) {
+        match self {
+            ChannelOverrideTarget::Role(role_id) => ("role", role_id.to_string()),
+            ChannelOverrideTarget::User(user_id) => ("user", user_id.to_string()),
+        }
+    }
+
+    fn from_columns(target_type: &str, target_id: &str) -> Result<Self, crate::Error> {
+        match target_type {
+            "role" => Ok(ChannelOverrideTarget::Role(target_id.parse()?)),
+            "user" => Ok(ChannelOverrideTarget::User(target_id.parse()?)),
+            _ => Err(format!("Invalid channel override target type: {}", target_type).into()),
+        }
+    }
+}
+
+/// Kittycat permissions granted (or with ``~``, removed) in a single channel
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ChannelPermOverride {
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub target: ChannelOverrideTarget,
+    pub perms: Vec<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ChannelPermOverrideRow {
+    guild_id: String,
+    channel_id: String,
+    target_type: String,
+    target_id: String,
+    perms: Vec<String>,
+}
+
+impl ChannelPermOverrideRow {
+    fn into_channel_perm_override(self) -> Result<ChannelPermOverride, crate::Error> {
+        Ok(ChannelPermOverride {
+            guild_id: self.guild_id.parse()?,
+            channel_id: self.channel_id.parse()?,
+            target: ChannelOverrideTarget::from_columns(&self.target_type, &self.target_id)?,
+            perms: self.perms,
+        })
+    }
+}
+
+/// Lists the overrides of a guild, optionally only those of one channel
+pub async fn list_channel_overrides(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    channel_id: Option<ChannelId>,
+) -> Result<Vec<ChannelPermOverride>, crate::Error> {
+    let recs: Vec<ChannelPermOverrideRow> = sqlx::query_as(
+        "SELECT guild_id, channel_id, target_type, target_id, perms FROM guild_channel_perm_overrides WHERE guild_id = $1 AND ($2::text IS NULL OR channel_id = $2) ORDER BY channel_id, target_type, target_id",
+    )
+    .bind(guild_id.to_string())
+    .bind(channel_id.map(|c| c.to_string()))
+    .fetch_all(db)
+    .await?;
+
+    let mut overrides = Vec::with_capacity(recs.len());
+
+    for rec in recs {
+        overrides.push(rec.into_channel_perm_override()?);
+    }
+
+    Ok(overrides)
+}
+
+/// Creates or replaces the override of a role or user in a channel
+pub async fn set_channel_override(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    target: ChannelOverrideTarget,
+    perms: Vec<String>,
+) -> Result<(), crate::Error> {
+    for perm in perms.iter() {
+        validate_perm(perm)?;
+    }
+
+    let (target_type, target_id) = target.to_columns();
+
+    sqlx::query(
+        "INSERT INTO guild_channel_perm_overrides (guild_id, channel_id, target_type, target_id, perms) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (guild_id, channel_id, target_type, target_id) DO UPDATE SET perms = EXCLUDED.perms",
+    )
+    .bind(guild_id.to_string())
+    .bind(channel_id.to_string())
+    .bind(target_type)
+    .bind(target_id)
+    .bind(&perms)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the override of a role or user in a channel
+pub async fn remove_channel_override(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    target: ChannelOverrideTarget,
+) -> Result<(), crate::Error> {
+    let (target_type, target_id) = target.to_columns();
+
+    sqlx::query(
+        "DELETE FROM guild_channel_perm_overrides WHERE guild_id = $1 AND channel_id = $2 AND target_type = $3 AND target_id = $4",
+    )
+    .bind(guild_id.to_string())
+    .bind(channel_id.to_string())
+    .bind(target_type)
+    .bind(target_id)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// The kittycat permissions of a member in a channel along with the overrides that were applied
+pub struct ChannelPermsResolution {
+    pub perms: kittycat::perms::StaffPermissions,
+    /// Channel overrides applied on top of the guild-level permissions, in the order they were applied
+    pub applied_overrides: Vec<ChannelPermOverride>,
+    pub meta: ResolutionMeta,
+}
+
+/// Returns the overrides out of ``overrides`` which apply to the member, in the order they must be applied (lowest
+/// precedence first)
+///
+/// The ``@everyone`` role override comes first, then the overrides of the member's other roles from the lowest to the
+/// highest ranked role (by kittycat index, roles without a kittycat position rank lowest), then the member's own override
+fn applicable_overrides(
+    overrides: Vec<ChannelPermOverride>,
+    guild_id: GuildId,
+    user_id: UserId,
+    roles: &[RoleId],
+    user_positions: &[kittycat::perms::PartialStaffPosition],
+) -> Vec<ChannelPermOverride> {
+    let everyone_role = guild_id.everyone_role();
+
+    let mut applicable = overrides
+        .into_iter()
+        .filter_map(|o| {
+            let rank = match o.target {
+                ChannelOverrideTarget::Role(role_id) if role_id == everyone_role => (0, 0),
+                ChannelOverrideTarget::Role(role_id) if roles.contains(&role_id) => {
+                    let role_id = role_id.to_string();
+
+                    // A lower kittycat index is a higher ranked role, so it must be applied later
+                    let index = user_positions
+                        .iter()
+                        .find(|p| p.id == role_id)
+                        .map_or(i64::MAX, |p| p.index.into());
+
+                    (1, -index)
+                }
+                ChannelOverrideTarget::User(id) if id == user_id => (2, 0),
+                _ => return None,
+            };
+
+            Some((rank, o))
+        })
+        .collect::<Vec<_>>();
+
+    // Overrides are listed ordered by target so ties (roles without a position) are still deterministic
+    applicable.sort_by_key(|(rank, _)| *rank);
+
+    applicable.into_iter().map(|(_, o)| o).collect()
+}
+
+/// Like ``get_kittycat_perms`` but also applies the overrides of ``channel_id``
+///
+/// Channel overrides are applied on top of all guild-level permissions, including the member's guild-level
+/// ``perm_overrides``, so a channel override always wins over them. Within the channel the ``@everyone`` override is
+/// applied first, then role overrides from the lowest to the highest ranked role, then the member's own override.
+///
+/// Guild owners and root users are not affected by channel overrides
+#[allow(clippy::too_many_arguments)]
+pub async fn get_kittycat_perms_in_channel(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    guild_owner_id: UserId,
+    user_id: UserId,
+    roles: &[RoleId],
+    channel_id: ChannelId,
+    config: impl Into<GetKittycatPermsConfig>,
+) -> Result<ChannelPermsResolution, crate::Error> {
+    let (mut perms, meta) =
+        get_kittycat_perms_with_meta(pool, guild_id, guild_owner_id, user_id, roles, config)
+            .await?;
+
+    if guild_owner_id == user_id || meta.root_override_applied {
+        return Ok(ChannelPermsResolution {
+            perms,
+            applied_overrides: Vec::new(),
+            meta,
+        });
+    }
+
+    let overrides = applicable_overrides(
+        list_channel_overrides(pool, guild_id, Some(channel_id)).await?,
+        guild_id,
+        user_id,
+        roles,
+        &perms.user_positions,
+    );
+
+    // kittycat applies ``perm_overrides`` after every position and in order, so appending after the member's
+    // guild-level overrides gives channel overrides the highest precedence
+    for o in overrides.iter() {
+        perms
+            .perm_overrides
+            .extend(o.perms.iter().map(|p| Permission::from_string(p)));
+    }
+
+    Ok(ChannelPermsResolution {
+        perms,
+        applied_overrides: overrides,
+        meta,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::member_permission_calc::GetKittycatPermsConfigData;
+    use crate::test_schema::{
+        create_tables, GUILD_CHANNEL_PERM_OVERRIDES, GUILD_MEMBERS, GUILD_ROLES,
+    };
+
+    const GUILD: GuildId = GuildId::new(1);
+    const OWNER: UserId = UserId::new(2);
+    const USER: UserId = UserId::new(3);
+    const ROOT: UserId = UserId::new(4);
+    const CHANNEL: ChannelId = ChannelId::new(5);
+    const OTHER_CHANNEL: ChannelId = ChannelId::new(6);
+    // Role ids are chosen so their string order is the reverse of their rank
+    const HELPER: RoleId = RoleId::new(30);
+    const MOD: RoleId = RoleId::new(20);
+
+    static ROOT_USERS: &[UserId] = &[ROOT];
+
+    fn config() -> GetKittycatPermsConfigData {
+        GetKittycatPermsConfigData::new(GUILD, ROOT_USERS)
+    }
+
+    fn role(role_id: RoleId) -> ChannelOverrideTarget {
+        ChannelOverrideTarget::Role(role_id)
+    }
+
+    async fn setup(pool: &sqlx::PgPool) {
+        create_tables(
+            pool,
+            &[GUILD_ROLES, GUILD_MEMBERS, GUILD_CHANNEL_PERM_OVERRIDES],
+        )
+        .await;
+
+        // Mod (index 1) ranks above helper (index 2)
+        sqlx::query(
+            "INSERT INTO guild_roles (guild_id, role_id, perms, index) VALUES ('1', '20', '{moderation.ban}', 1), ('1', '30', '{moderation.view}', 2)",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn set_member_overrides(pool: &sqlx::PgPool, user_id: UserId, perms: &[&str]) {
+        sqlx::query(
+            "INSERT INTO guild_members (guild_id, user_id, perm_overrides) VALUES ('1', $1, $2)",
+        )
+        .bind(user_id.to_string())
+        .bind(perms)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn set(pool: &sqlx::PgPool, target: ChannelOverrideTarget, perms: &[&str]) {
+        set_channel_override(
+            pool,
+            GUILD,
+            CHANNEL,
+            target,
+            perms.iter().map(|p| p.to_string()).collect(),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn has_perm_in(
+        pool: &sqlx::PgPool,
+        user_id: UserId,
+        roles: &[RoleId],
+        channel_id: ChannelId,
+        perm: &str,
+    ) -> bool {
+        let res =
+            get_kittycat_perms_in_channel(pool, GUILD, OWNER, user_id, roles, channel_id, config())
+                .await
+                .unwrap();
+
+        kittycat::perms::has_perm(&res.perms.resolve(), &Permission::from_string(perm))
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn channel_role_override_beats_role_perms(pool: sqlx::PgPool) {
+        setup(&pool).await;
+        set(&pool, role(HELPER), &["~moderation.view"]).await;
+
+        assert!(!has_perm_in(&pool, USER, &[HELPER], CHANNEL, "moderation.view").await);
+        assert!(has_perm_in(&pool, USER, &[HELPER], OTHER_CHANNEL, "moderation.view").await);
+
+        // Overrides of roles the member does not have are ignored
+        assert!(has_perm_in(&pool, USER, &[MOD], CHANNEL, "moderation.ban").await);
+        assert!(!has_perm_in(&pool, USER, &[MOD], CHANNEL, "moderation.view").await);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn channel_override_beats_guild_member_override(pool: sqlx::PgPool) {
+        setup(&pool).await;
+        set_member_overrides(&pool, USER, &["~moderation.view"]).await;
+        set(&pool, role(GUILD.everyone_role()), &["moderation.view"]).await;
+
+        assert!(has_perm_in(&pool, USER, &[HELPER], CHANNEL, "moderation.view").await);
+        assert!(!has_perm_in(&pool, USER, &[HELPER], OTHER_CHANNEL, "moderation.view").await);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn user_override_beats_role_overrides(pool: sqlx::PgPool) {
+        setup(&pool).await;
+        set(&pool, role(HELPER), &["moderation.kick"]).await;
+        set(
+            &pool,
+            ChannelOverrideTarget::User(USER),
+            &["~moderation.kick"],
+        )
+        .await;
+
+        assert!(!has_perm_in(&pool, USER, &[HELPER], CHANNEL, "moderation.kick").await);
+        assert!(has_perm_in(&pool, UserId::new(7), &[HELPER], CHANNEL, "moderation.kick").await);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn higher_ranked_role_override_wins(pool: sqlx::PgPool) {
+        setup(&pool).await;
+        set(&pool, role(GUILD.everyone_role()), &["moderation.kick"]).await;
+        set(&pool, role(MOD), &["~moderation.kick"]).await;
+        set(&pool, role(HELPER), &["moderation.kick"]).await;
+
+        assert!(!has_perm_in(&pool, USER, &[HELPER, MOD], CHANNEL, "moderation.kick").await);
+        assert!(!has_perm_in(&pool, USER, &[MOD, HELPER], CHANNEL, "moderation.kick").await);
+        assert!(has_perm_in(&pool, USER, &[HELPER], CHANNEL, "moderation.kick").await);
+
+        let res = get_kittycat_perms_in_channel(
+            &pool,
+            GUILD,
+            OWNER,
+            USER,
+            &[MOD, HELPER],
+            CHANNEL,
+            config(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            res.applied_overrides
+                .iter()
+                .map(|o| o.target)
+                .collect::<Vec<_>>(),
+            vec![role(GUILD.everyone_role()), role(HELPER), role(MOD)]
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn owner_and_root_ignore_channel_overrides(pool: sqlx::PgPool) {
+        setup(&pool).await;
+        set(&pool, role(GUILD.everyone_role()), &["~global.*"]).await;
+        set(
+            &pool,
+            ChannelOverrideTarget::User(OWNER),
+            &["~moderation.ban"],
+        )
+        .await;
+        set(
+            &pool,
+            ChannelOverrideTarget::User(ROOT),
+            &["~moderation.ban"],
+        )
+        .await;
+
+        for user_id in [OWNER, ROOT] {
+            let res =
+                get_kittycat_perms_in_channel(&pool, GUILD, OWNER, user_id, &[], CHANNEL, config())
+                    .await
+                    .unwrap();
+
+            assert!(res.applied_overrides.is_empty());
+            assert!(kittycat::perms::has_perm(
+                &res.perms.resolve(),
+                &Permission::from_string("moderation.ban")
+            ));
+        }
+
+        assert!(!has_perm_in(&pool, USER, &[MOD], CHANNEL, "moderation.ban").await);
+    }
+}
@@ -0,0 +1,210 @@
+use serenity::all::{Member, PartialGuild, Permissions, RoleId, UserId};
+
+/// The result of comparing the role hierarchy of two members
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum HierarchyResult {
+    /// The actor's top role is above the target's top role
+    ActorHigher,
+    /// The target's top role is above the actor's top role
+    TargetHigher,
+    /// Both top roles are at the same position
+    Equal,
+    /// The actor owns the guild and thus outranks everyone
+    ActorIsOwner,
+    /// The target owns the guild and thus cannot be acted on
+    TargetIsOwner,
+}
+
+impl HierarchyResult {
+    /// Returns true if the actor can act on the target
+    pub fn can_act(&self) -> bool {
+        matches!(
+            self,
+            HierarchyResult::ActorHigher | HierarchyResult::ActorIsOwner
+        )
+    }
+}
+
+/// Returns the highest role (by position) out of ``roles``, ignoring roles not in the guild
+///
+/// Returns None if no roles are given (meaning only the @everyone role)
+pub fn top_role(guild: &PartialGuild, roles: &[RoleId]) -> Option<(RoleId, i64)> {
+    roles
+        .iter()
+        .filter_map(|role_id| {
+            guild
+                .roles
+                .get(role_id)
+                .map(|role| (*role_id, i64::from(role.position)))
+        })
+        .max_by_key(|(_, position)| *position)
+}
+
+/// Compares the role hierarchy of an actor and a target based on their highest role positions
+///
+/// Members without any roles are treated as being at position 0 (the @everyone role)
+pub fn compare_members(
+    guild: &PartialGuild,
+    actor_roles: &[RoleId],
+    target_roles: &[RoleId],
+    actor_id: UserId,
+    target_id: UserId,
+    owner_id: UserId,
+) -> HierarchyResult {
+    compare_positions(
+        top_role(guild, actor_roles).map(|(_, position)| position),
+        top_role(guild, target_roles).map(|(_, position)| position),
+        actor_id,
+        target_id,
+        owner_id,
+    )
+}
+
+/// Compares an actor and a target given the positions of their top roles, see ``compare_members``
+///
+/// A position of None means the member has no roles and is treated as position 0
+pub fn compare_positions(
+    actor_position: Option<i64>,
+    target_position: Option<i64>,
+    actor_id: UserId,
+    target_id: UserId,
+    owner_id: UserId,
+) -> HierarchyResult {
+    if actor_id == owner_id {
+        return HierarchyResult::ActorIsOwner;
+    }
+
+    if target_id == owner_id {
+        return HierarchyResult::TargetIsOwner;
+    }
+
+    match actor_position
+        .unwrap_or(0)
+        .cmp(&target_position.unwrap_or(0))
+    {
+        std::cmp::Ordering::Greater => HierarchyResult::ActorHigher,
+        std::cmp::Ordering::Less => HierarchyResult::TargetHigher,
+        std::cmp::Ordering::Equal => HierarchyResult::Equal,
+    }
+}
+
+/// Why the bot cannot act on a member
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum BotActionError {
+    /// The bot is missing some of the required permissions
+    MissingPermissions { missing: Permissions },
+    /// The target is the owner or the bot's top role is not above the target's top role
+    Hierarchy { result: HierarchyResult },
+}
+
+impl std::fmt::Display for BotActionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotActionError::MissingPermissions { missing } => {
+                write!(f, "Bot is missing permissions: {}", missing)
+            }
+            BotActionError::Hierarchy { result } => {
+                write!(
+                    f,
+                    "Bot cannot act on this member due to role hierarchy: {:?}",
+                    result
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BotActionError {}
+
+/// Checks that the bot has ``required_perms`` and that its top role is above the target's top role
+pub fn bot_can_act_on(
+    guild: &PartialGuild,
+    bot_member: &Member,
+    target_member: &Member,
+    required_perms: Permissions,
+) -> Result<(), BotActionError> {
+    let bot_perms = botox::serenity_backports::user_permissions(
+        bot_member.user.id,
+        &bot_member.roles,
+        guild.id,
+        &guild.roles,
+        guild.owner_id,
+    );
+
+    if !bot_perms.contains(required_perms) {
+        return Err(BotActionError::MissingPermissions {
+            missing: required_perms - bot_perms,
+        });
+    }
+
+    let result = compare_members(
+        guild,
+        &bot_member.roles,
+        &target_member.roles,
+        bot_member.user.id,
+        target_member.user.id,
+        guild.owner_id,
+    );
+
+    if !result.can_act() {
+        return Err(BotActionError::Hierarchy { result });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: UserId = UserId::new(1);
+    const ACTOR: UserId = UserId::new(2);
+    const TARGET: UserId = UserId::new(3);
+
+    fn compare(actor_position: Option<i64>, target_position: Option<i64>) -> HierarchyResult {
+        compare_positions(actor_position, target_position, ACTOR, TARGET, OWNER)
+    }
+
+    #[test]
+    fn compares_top_role_positions() {
+        assert_eq!(compare(Some(5), Some(3)), HierarchyResult::ActorHigher);
+        assert_eq!(compare(Some(3), Some(5)), HierarchyResult::TargetHigher);
+        assert_eq!(compare(Some(1), None), HierarchyResult::ActorHigher);
+        assert_eq!(compare(None, Some(1)), HierarchyResult::TargetHigher);
+    }
+
+    #[test]
+    fn equal_positions_cannot_act() {
+        // Two different roles sharing a position, or two members with only @everyone
+        for (actor, target) in [(Some(4), Some(4)), (None, None), (Some(0), None)] {
+            let result = compare(actor, target);
+            assert_eq!(
+                result,
+                HierarchyResult::Equal,
+                "{:?} vs {:?}",
+                actor,
+                target
+            );
+            assert!(!result.can_act());
+        }
+    }
+
+    #[test]
+    fn owner_outranks_role_positions() {
+        // The owner can act on anyone, even without roles
+        let result = compare_positions(None, Some(100), OWNER, TARGET, OWNER);
+        assert_eq!(result, HierarchyResult::ActorIsOwner);
+        assert!(result.can_act());
+
+        // Nobody can act on the owner, even from a higher role
+        let result = compare_positions(Some(100), None, ACTOR, OWNER, OWNER);
+        assert_eq!(result, HierarchyResult::TargetIsOwner);
+        assert!(!result.can_act());
+
+        // Acting on yourself as the owner is still the owner acting
+        assert_eq!(
+            compare_positions(Some(1), Some(1), OWNER, OWNER, OWNER),
+            HierarchyResult::ActorIsOwner
+        );
+    }
+}
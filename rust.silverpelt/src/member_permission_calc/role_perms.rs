@@ -0,0 +1,528 @@
+use kittycat::perms::Permission;
+use serenity::all::{GuildId, RoleId};
+use sqlx::Row;
+
+use crate::ar_event::custom_events::ROLE_PERMS_CHANGED_EVENT;
+use crate::ar_event::{AntiraidEventOperations, CustomEventBuilder, DispatchEventData};
+use crate::data::Data;
+
+/// The kittycat permissions and position of a guild role
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RolePerms {
+    pub role_id: RoleId,
+    pub perms: Vec<String>,
+    pub index: i32,
+}
+
+/// Validates a kittycat permission string of form ``[~]namespace.perm``
+///
+/// The permission must parse with kittycat's ``Permission::from_string`` and format back to the same string, so
+/// shorthands such as ``namespace`` (parsed as ``namespace.*``) are rejected instead of being silently widened
+pub fn validate_perm(perm: &str) -> Result<(), crate::Error> {
+    let parsed = Permission::from_string(perm);
+
+    if parsed.to_string() != perm {
+        return Err(format!("Permission {:?} must be of form namespace.perm", perm).into());
+    }
+
+    if parsed.namespace.is_empty() || parsed.perm.is_empty() {
+        return Err(format!("Permission {:?} has an empty namespace or name", perm).into());
+    }
+
+    if [&parsed.namespace, &parsed.perm]
+        .iter()
+        .any(|part| part.chars().any(|c| c.is_whitespace() || c == '~'))
+    {
+        return Err(format!("Permission {:?} contains invalid characters", perm).into());
+    }
+
+    Ok(())
+}
+
+/// Serializes writers of a guild's roles until the end of the transaction
+///
+/// ``ensure_unique_indexes`` runs under READ COMMITTED, so two concurrent transactions would otherwise both pass the
+/// check before either commits. Every transaction writing ``guild_roles.index`` must take this lock first
+pub(crate) async fn lock_guild_roles(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+) -> Result<(), crate::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('guild_roles'), hashtext($1))")
+        .bind(guild_id.to_string())
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Errors if two roles of the guild share the same index
+pub(crate) async fn ensure_unique_indexes(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+) -> Result<(), crate::Error> {
+    let duplicate = sqlx::query(
+        "SELECT index FROM guild_roles WHERE guild_id = $1 GROUP BY index HAVING COUNT(*) > 1 LIMIT 1",
+    )
+    .bind(guild_id.to_string())
+    .fetch_optional(db)
+    .await?;
+
+    if let Some(row) = duplicate {
+        return Err(format!(
+            "Multiple roles share the index {}",
+            row.try_get::<i32, _>("index")?
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Lists the kittycat permissions of all roles of a guild, ordered by index
+pub async fn list_role_perms(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+) -> Result<Vec<RolePerms>, crate::Error> {
+    let rows = sqlx::query(
+        "SELECT role_id, perms, index FROM guild_roles WHERE guild_id = $1 ORDER BY index ASC",
+    )
+    .bind(guild_id.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    let mut roles = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        roles.push(RolePerms {
+            role_id: row.try_get::<String, _>("role_id")?.parse()?,
+            perms: row.try_get("perms")?,
+            index: row.try_get("index")?,
+        });
+    }
+
+    Ok(roles)
+}
+
+/// Sets the kittycat permissions and index of a role, creating it if needed
+///
+/// Errors if a permission is invalid or if another role already has the same index
+pub async fn set_role_perms_without_dispatch(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    role_id: RoleId,
+    perms: Vec<String>,
+    index: i32,
+) -> Result<(), crate::Error> {
+    for perm in perms.iter() {
+        validate_perm(perm)?;
+    }
+
+    let mut tx = pool.begin().await?;
+
+    lock_guild_roles(&mut *tx, guild_id).await?;
+
+    let updated = sqlx::query(
+        "UPDATE guild_roles SET perms = $1, index = $2 WHERE guild_id = $3 AND role_id = $4",
+    )
+    .bind(&perms)
+    .bind(index)
+    .bind(guild_id.to_string())
+    .bind(role_id.to_string())
+    .execute(&mut *tx)
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        sqlx::query(
+            "INSERT INTO guild_roles (guild_id, role_id, perms, index) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(guild_id.to_string())
+        .bind(role_id.to_string())
+        .bind(&perms)
+        .bind(index)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    ensure_unique_indexes(&mut *tx, guild_id).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Atomically sets the indexes of multiple roles, errors (changing nothing) if indexes would collide afterwards
+pub async fn reorder_roles_without_dispatch(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    order: &[(RoleId, i32)],
+) -> Result<(), crate::Error> {
+    let mut tx = pool.begin().await?;
+
+    lock_guild_roles(&mut *tx, guild_id).await?;
+
+    for (role_id, index) in order.iter().copied() {
+        let updated =
+            sqlx::query("UPDATE guild_roles SET index = $1 WHERE guild_id = $2 AND role_id = $3")
+                .bind(index)
+                .bind(guild_id.to_string())
+                .bind(role_id.to_string())
+                .execute(&mut *tx)
+                .await?;
+
+        if updated.rows_affected() == 0 {
+            return Err(format!("Role {} has no permissions set", role_id).into());
+        }
+    }
+
+    ensure_unique_indexes(&mut *tx, guild_id).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Removes the kittycat permissions of a role
+pub async fn remove_role_without_dispatch(
+    pool: &sqlx::PgPool,
+    guild_id: GuildId,
+    role_id: RoleId,
+) -> Result<(), crate::Error> {
+    sqlx::query("DELETE FROM guild_roles WHERE guild_id = $1 AND role_id = $2")
+        .bind(guild_id.to_string())
+        .bind(role_id.to_string())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Logs a failed ``AR/RolePermsChanged`` dispatch
+///
+/// Dispatch failures (e.g. while the template worker restarts) must not fail the role write that preceded them
+fn log_dispatch_failure(res: Result<(), crate::Error>, guild_id: GuildId) {
+    if let Err(e) = res {
+        log::error!(
+            "Failed to dispatch {} event for guild {}: {}",
+            ROLE_PERMS_CHANGED_EVENT,
+            guild_id,
+            e
+        );
+    }
+}
+
+/// Sets the kittycat permissions and index of a role and dispatches an ``AR/RolePermsChanged`` event
+///
+/// Dispatch failures are logged and do not fail the call as the role was already written
+pub async fn set_role_perms(
+    data: &Data,
+    guild_id: GuildId,
+    role_id: RoleId,
+    perms: Vec<String>,
+    index: i32,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    set_role_perms_without_dispatch(&data.pool, guild_id, role_id, perms, index).await?;
+
+    log_dispatch_failure(
+        dispatch_role_perms_changed_event(data, guild_id, &[role_id], "set", dispatch_event_data)
+            .await,
+        guild_id,
+    );
+
+    Ok(())
+}
+
+/// Reorders roles (see ``reorder_roles_without_dispatch``) and dispatches an ``AR/RolePermsChanged`` event
+///
+/// Dispatch failures are logged and do not fail the call as the roles were already reordered
+pub async fn reorder_roles(
+    data: &Data,
+    guild_id: GuildId,
+    order: Vec<(RoleId, i32)>,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    reorder_roles_without_dispatch(&data.pool, guild_id, &order).await?;
+
+    let role_ids = order
+        .into_iter()
+        .map(|(role_id, _)| role_id)
+        .collect::<Vec<_>>();
+
+    log_dispatch_failure(
+        dispatch_role_perms_changed_event(
+            data,
+            guild_id,
+            &role_ids,
+            "reorder",
+            dispatch_event_data,
+        )
+        .await,
+        guild_id,
+    );
+
+    Ok(())
+}
+
+/// Removes the kittycat permissions of a role and dispatches an ``AR/RolePermsChanged`` event
+///
+/// Dispatch failures are logged and do not fail the call as the role was already removed
+pub async fn remove_role(
+    data: &Data,
+    guild_id: GuildId,
+    role_id: RoleId,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    remove_role_without_dispatch(&data.pool, guild_id, role_id).await?;
+
+    log_dispatch_failure(
+        dispatch_role_perms_changed_event(
+            data,
+            guild_id,
+            &[role_id],
+            "remove",
+            dispatch_event_data,
+        )
+        .await,
+        guild_id,
+    );
+
+    Ok(())
+}
+
+/// Dispatches an ``AR/RolePermsChanged`` event, ``action`` is one of ``set``, ``reorder`` or ``remove``
+pub async fn dispatch_role_perms_changed_event(
+    data: &Data,
+    guild_id: GuildId,
+    role_ids: &[RoleId],
+    action: &str,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    let event = CustomEventBuilder::new(
+        ROLE_PERMS_CHANGED_EVENT,
+        "(Anti-Raid) Role Permissions Changed",
+    )?
+    .field("action", action)?
+    .field("role_ids", role_ids)?
+    .build()?;
+
+    event
+        .dispatch_to_template_worker_and_nowait(data, guild_id, dispatch_event_data)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::member_permission_calc::{get_kittycat_perms, GetKittycatPermsConfigData};
+    use crate::test_schema::{create_tables, GUILD_MEMBERS, GUILD_ROLES};
+    use serenity::all::UserId;
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    fn perms() -> Vec<String> {
+        vec!["moderation.ban".to_string()]
+    }
+
+    fn indexes(roles: &[RolePerms]) -> Vec<(RoleId, i32)> {
+        roles.iter().map(|r| (r.role_id, r.index)).collect()
+    }
+
+    /// Resolves the permissions of a (non-owner) member holding ``roles``
+    async fn resolve(pool: &sqlx::PgPool, guild_id: GuildId, roles: &[RoleId]) -> Vec<String> {
+        let mut perms = get_kittycat_perms(
+            pool,
+            guild_id,
+            UserId::new(1),
+            UserId::new(2),
+            roles,
+            GetKittycatPermsConfigData::new(GuildId::new(100), &[]),
+        )
+        .await
+        .unwrap()
+        .resolve()
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+
+        perms.sort();
+        perms
+    }
+
+    #[test]
+    fn validate_perm_accepts_kittycat_permissions() {
+        for perm in [
+            "moderation.ban",
+            "~moderation.ban",
+            "global.*",
+            "lockdowns.create.qsl",
+        ] {
+            assert!(validate_perm(perm).is_ok(), "{perm} should be valid");
+        }
+    }
+
+    #[test]
+    fn validate_perm_rejects_malformed_permissions() {
+        for perm in [
+            "",
+            "moderation",
+            ".ban",
+            "moderation.",
+            "~~moderation.ban",
+            "mod eration.ban",
+        ] {
+            assert!(validate_perm(perm).is_err(), "{perm:?} should be invalid");
+        }
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn reorder_swaps_indexes(pool: sqlx::PgPool) {
+        create_tables(&pool, &[GUILD_ROLES]).await;
+
+        set_role_perms_without_dispatch(&pool, GUILD, RoleId::new(10), perms(), 0)
+            .await
+            .unwrap();
+        set_role_perms_without_dispatch(&pool, GUILD, RoleId::new(11), perms(), 1)
+            .await
+            .unwrap();
+
+        reorder_roles_without_dispatch(&pool, GUILD, &[(RoleId::new(10), 1), (RoleId::new(11), 0)])
+            .await
+            .unwrap();
+
+        let roles = list_role_perms(&pool, GUILD).await.unwrap();
+        assert_eq!(
+            indexes(&roles),
+            vec![(RoleId::new(11), 0), (RoleId::new(10), 1)]
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn reorder_with_collision_changes_nothing(pool: sqlx::PgPool) {
+        create_tables(&pool, &[GUILD_ROLES]).await;
+
+        set_role_perms_without_dispatch(&pool, GUILD, RoleId::new(10), perms(), 0)
+            .await
+            .unwrap();
+        set_role_perms_without_dispatch(&pool, GUILD, RoleId::new(11), perms(), 1)
+            .await
+            .unwrap();
+
+        assert!(
+            reorder_roles_without_dispatch(&pool, GUILD, &[(RoleId::new(10), 1)])
+                .await
+                .is_err()
+        );
+
+        let roles = list_role_perms(&pool, GUILD).await.unwrap();
+        assert_eq!(
+            indexes(&roles),
+            vec![(RoleId::new(10), 0), (RoleId::new(11), 1)]
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn reorder_of_unknown_role_changes_nothing(pool: sqlx::PgPool) {
+        create_tables(&pool, &[GUILD_ROLES]).await;
+
+        set_role_perms_without_dispatch(&pool, GUILD, RoleId::new(10), perms(), 0)
+            .await
+            .unwrap();
+
+        assert!(reorder_roles_without_dispatch(
+            &pool,
+            GUILD,
+            &[(RoleId::new(10), 5), (RoleId::new(99), 6)]
+        )
+        .await
+        .is_err());
+
+        let roles = list_role_perms(&pool, GUILD).await.unwrap();
+        assert_eq!(indexes(&roles), vec![(RoleId::new(10), 0)]);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn reordering_mid_list_keeps_resolution_consistent(pool: sqlx::PgPool) {
+        create_tables(&pool, &[GUILD_ROLES, GUILD_MEMBERS]).await;
+
+        let roles: [(u64, &[&str]); 4] = [
+            (10, &["moderation.kick"]),
+            (11, &["~moderation.ban"]),
+            (12, &["moderation.ban", "lockdowns.create"]),
+            (13, &["~lockdowns.create"]),
+        ];
+        let member_roles = roles.map(|(role_id, _)| RoleId::new(role_id));
+
+        let configure = |guild_id: GuildId, order: [i32; 4]| {
+            let pool = pool.clone();
+
+            async move {
+                for ((role_id, perms), index) in roles.into_iter().zip(order) {
+                    set_role_perms_without_dispatch(
+                        &pool,
+                        guild_id,
+                        RoleId::new(role_id),
+                        perms.iter().map(|p| p.to_string()).collect(),
+                        index,
+                    )
+                    .await
+                    .unwrap();
+                }
+            }
+        };
+
+        configure(GUILD, [0, 10, 20, 30]).await;
+        let before = resolve(&pool, GUILD, &member_roles).await;
+
+        // Moving the middle roles without changing their relative order resolves the same
+        reorder_roles_without_dispatch(
+            &pool,
+            GUILD,
+            &[(RoleId::new(11), 15), (RoleId::new(12), 25)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolve(&pool, GUILD, &member_roles).await, before);
+
+        // Swapping them resolves like a guild which had the swapped order from the start
+        reorder_roles_without_dispatch(
+            &pool,
+            GUILD,
+            &[(RoleId::new(11), 25), (RoleId::new(12), 15)],
+        )
+        .await
+        .unwrap();
+
+        let fresh = GuildId::new(2);
+        configure(fresh, [0, 25, 15, 30]).await;
+
+        let swapped = resolve(&pool, GUILD, &member_roles).await;
+        assert_eq!(swapped, resolve(&pool, fresh, &member_roles).await);
+        assert_ne!(swapped, before);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn concurrent_writers_cannot_share_an_index(pool: sqlx::PgPool) {
+        create_tables(&pool, &[GUILD_ROLES]).await;
+
+        let mut handles = Vec::new();
+
+        for role_id in 10..20 {
+            let pool = pool.clone();
+            handles.push(tokio::spawn(async move {
+                set_role_perms_without_dispatch(&pool, GUILD, RoleId::new(role_id), perms(), 0)
+                    .await
+                    .is_ok()
+            }));
+        }
+
+        let mut succeeded = 0;
+        for handle in handles {
+            if handle.await.unwrap() {
+                succeeded += 1;
+            }
+        }
+
+        assert_eq!(succeeded, 1);
+        assert_eq!(list_role_perms(&pool, GUILD).await.unwrap().len(), 1);
+    }
+}
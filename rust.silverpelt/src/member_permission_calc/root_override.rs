@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use serenity::all::{GuildId, UserId};
+
+use crate::ar_event::custom_events::ROOT_OVERRIDE_USED_EVENT;
+use crate::ar_event::{AntiraidEventOperations, CustomEventBuilder, DispatchEventData};
+use crate::data::Data;
+
+/// Which users get ``global.*`` regardless of their roles, and in which guilds
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RootOverridePolicy {
+    pub enabled: bool,
+    pub users: HashSet<UserId>,
+    pub guilds: HashSet<GuildId>,
+}
+
+impl RootOverridePolicy {
+    /// Creates the policy from the static config, root users are only overridden on the main server
+    pub fn from_config(main_server_id: GuildId, root_users: &[UserId]) -> Self {
+        Self {
+            enabled: true,
+            users: root_users.iter().copied().collect(),
+            guilds: HashSet::from([main_server_id]),
+        }
+    }
+
+    /// Returns true if the override applies to the user in the guild
+    pub fn applies(&self, guild_id: GuildId, user_id: UserId) -> bool {
+        self.enabled && self.guilds.contains(&guild_id) && self.users.contains(&user_id)
+    }
+}
+
+/// Runtime-toggleable root override, registered by the bot as an extension of ``Data`` (see ``Data::root_override``)
+///
+/// Starts out with the policy read from config, owner-only commands can then disable or replace it without a restart
+#[derive(Debug, Default)]
+pub struct RootOverride {
+    policy: RwLock<RootOverridePolicy>,
+}
+
+impl RootOverride {
+    pub fn new(policy: RootOverridePolicy) -> Self {
+        Self {
+            policy: RwLock::new(policy),
+        }
+    }
+
+    /// Returns a copy of the current policy
+    pub fn policy(&self) -> RootOverridePolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Replaces the current policy
+    pub fn set_policy(&self, policy: RootOverridePolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    /// Enables or disables the override, keeping the allowed users and guilds
+    pub fn set_enabled(&self, enabled: bool) {
+        self.policy.write().unwrap().enabled = enabled;
+    }
+
+    pub fn applies(&self, guild_id: GuildId, user_id: UserId) -> bool {
+        self.policy.read().unwrap().applies(guild_id, user_id)
+    }
+}
+
+/// Dispatches an ``AR/RootOverrideUsed`` event so root override usage lands in the guild's audit logs
+pub async fn dispatch_root_override_used_event(
+    data: &Data,
+    guild_id: GuildId,
+    user_id: UserId,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    let event =
+        CustomEventBuilder::new(ROOT_OVERRIDE_USED_EVENT, "(Anti-Raid) Root Override Used")?
+            .field("user_id", &user_id)?
+            .build()?;
+
+    event
+        .dispatch_to_template_worker_and_nowait(data, guild_id, dispatch_event_data)
+        .await
+}
+
+/// Where ``AR/RootOverrideUsed`` events are sent when permission resolution applies the root override
+///
+/// Set on ``GetKittycatPermsConfig`` with ``with_root_override_audit``
+#[derive(Clone)]
+pub struct RootOverrideAudit {
+    pub data: Data,
+    pub dispatch_event_data: Arc<DispatchEventData>,
+}
+
+impl RootOverrideAudit {
+    pub fn new(data: Data, dispatch_event_data: Arc<DispatchEventData>) -> Self {
+        Self {
+            data,
+            dispatch_event_data,
+        }
+    }
+
+    /// Dispatches the event in the background so permission resolution is never held up by the template worker
+    pub fn dispatch(&self, guild_id: GuildId, user_id: UserId) {
+        let audit = self.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = dispatch_root_override_used_event(
+                &audit.data,
+                guild_id,
+                user_id,
+                &audit.dispatch_event_data,
+            )
+            .await
+            {
+                log::error!(
+                    "Failed to dispatch root override used event for user {} in guild {}: {}",
+                    user_id,
+                    guild_id,
+                    e
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RootOverridePolicy {
+        RootOverridePolicy::from_config(GuildId::new(1), &[UserId::new(10), UserId::new(11)])
+    }
+
+    #[test]
+    fn from_config_only_applies_on_main_server() {
+        let policy = policy();
+
+        assert!(policy.applies(GuildId::new(1), UserId::new(10)));
+        assert!(policy.applies(GuildId::new(1), UserId::new(11)));
+        assert!(!policy.applies(GuildId::new(2), UserId::new(10)));
+        assert!(!policy.applies(GuildId::new(1), UserId::new(12)));
+    }
+
+    #[test]
+    fn set_enabled_toggles_without_losing_users() {
+        let root_override = RootOverride::new(policy());
+
+        root_override.set_enabled(false);
+        assert!(!root_override.applies(GuildId::new(1), UserId::new(10)));
+        assert_eq!(root_override.policy().users.len(), 2);
+
+        root_override.set_enabled(true);
+        assert!(root_override.applies(GuildId::new(1), UserId::new(10)));
+    }
+
+    #[test]
+    fn set_policy_replaces_users_and_guilds() {
+        let root_override = RootOverride::new(policy());
+
+        root_override.set_policy(RootOverridePolicy {
+            enabled: true,
+            users: HashSet::from([UserId::new(12)]),
+            guilds: HashSet::from([GuildId::new(2)]),
+        });
+
+        assert!(!root_override.applies(GuildId::new(1), UserId::new(10)));
+        assert!(root_override.applies(GuildId::new(2), UserId::new(12)));
+    }
+
+    #[test]
+    fn default_never_applies() {
+        let root_override = RootOverride::default();
+
+        assert!(!root_override.applies(GuildId::new(1), UserId::new(10)));
+    }
+}
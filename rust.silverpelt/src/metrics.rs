@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+// Lightweight facade over the metrics crate, all functions are no-ops unless the ``metrics`` feature is enabled
+//
+// Labels must have a bounded set of values (no guild or user ids)
+
+/// Records a duration (in seconds) into a histogram
+#[allow(unused_variables)]
+pub fn record_duration(
+    name: &'static str,
+    labels: &[(&'static str, &'static str)],
+    duration: Duration,
+) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!(name, to_labels(labels)).record(duration.as_secs_f64());
+}
+
+/// Increments a counter by one
+#[allow(unused_variables)]
+pub fn increment_counter(name: &'static str, labels: &[(&'static str, &'static str)]) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(name, to_labels(labels)).increment(1);
+}
+
+#[cfg(feature = "metrics")]
+fn to_labels(labels: &[(&'static str, &'static str)]) -> Vec<metrics::Label> {
+    labels
+        .iter()
+        .map(|(k, v)| metrics::Label::new(*k, *v))
+        .collect()
+}
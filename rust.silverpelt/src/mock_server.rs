@@ -0,0 +1,133 @@
+//! A mock HTTP server recording every request, used to test code talking to the template worker and webhooks
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+
+/// A request received by the mock server
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: serde_json::Value,
+}
+
+/// What the mock server responds with
+pub struct MockResponse {
+    pub status: StatusCode,
+    pub body: serde_json::Value,
+    pub headers: Vec<(&'static str, String)>,
+    /// Waits this long before responding
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    pub fn ok(body: serde_json::Value) -> Self {
+        Self {
+            status: StatusCode::OK,
+            body,
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn status(status: StatusCode) -> Self {
+        Self {
+            status,
+            body: serde_json::Value::Null,
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+type Responder = dyn Fn(&RecordedRequest, usize) -> MockResponse + Send + Sync;
+
+pub struct MockServer {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockServer {
+    /// Starts a server on a random local port, ``responder`` is called with every request and the number of
+    /// requests received before it
+    pub async fn start(
+        responder: impl Fn(&RecordedRequest, usize) -> MockResponse + Send + Sync + 'static,
+    ) -> Self {
+        let requests: Arc<Mutex<Vec<RecordedRequest>>> = Arc::default();
+        let responder: Arc<Responder> = Arc::new(responder);
+
+        let recorded = requests.clone();
+        let app = axum::Router::new().fallback(
+            move |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| {
+                let recorded = recorded.clone();
+                let responder = responder.clone();
+
+                async move {
+                    let request = RecordedRequest {
+                        method,
+                        path: uri.path().to_string(),
+                        headers,
+                        body: serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+                    };
+
+                    let index = {
+                        let mut recorded = recorded.lock().unwrap();
+                        recorded.push(request.clone());
+                        recorded.len() - 1
+                    };
+
+                    let response = responder(&request, index);
+
+                    tokio::time::sleep(response.delay).await;
+
+                    let mut headers = HeaderMap::new();
+
+                    for (name, value) in response.headers {
+                        headers.insert(name, value.parse().unwrap());
+                    }
+
+                    (response.status, headers, axum::Json(response.body))
+                }
+            },
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Self { addr, requests }
+    }
+
+    /// Starts a server answering every request with ``200 {}``
+    pub async fn ok() -> Self {
+        Self::start(|_, _| MockResponse::ok(serde_json::json!({}))).await
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
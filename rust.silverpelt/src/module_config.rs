@@ -0,0 +1,789 @@
+use serenity::all::GuildId;
+use std::str::FromStr;
+
+use crate::pagination::{into_page, Cursor, ListOptions, ListPage};
+
+pub use antiraid_types::module_config::ModuleConfiguration;
+
+/// The kind of configuration a history entry records a change of
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigKind {
+    /// A row of ``guild_module_configurations``, keyed by module id
+    Module,
+    /// A row of ``guild_command_configurations``, keyed by command name
+    Command,
+}
+
+impl std::fmt::Display for ConfigKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigKind::Module => write!(f, "module"),
+            ConfigKind::Command => write!(f, "command"),
+        }
+    }
+}
+
+impl FromStr for ConfigKind {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "module" => Ok(ConfigKind::Module),
+            "command" => Ok(ConfigKind::Command),
+            _ => Err(format!("Invalid config kind: {}", s).into()),
+        }
+    }
+}
+
+/// The guild specific configuration of a command, unset fields fall back to the module defaults
+#[derive(serde::Serialize, serde::Deserialize, sqlx::FromRow, Clone, Debug, PartialEq)]
+pub struct CommandConfiguration {
+    pub disabled: Option<bool>,
+    pub perms: Option<serde_json::Value>,
+}
+
+/// A change to a module or command configuration
+///
+/// ``old_value`` and ``new_value`` are None if the configuration did not exist before or was deleted
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ConfigHistoryEntry {
+    pub id: uuid::Uuid,
+    pub guild_id: GuildId,
+    pub kind: ConfigKind,
+    /// The module id or command name
+    pub key: String,
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
+    /// Who made the change, ``system`` for automated workflows or a user ID
+    pub author: String,
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ConfigHistoryRow {
+    id: uuid::Uuid,
+    guild_id: String,
+    kind: String,
+    key: String,
+    old_value: Option<serde_json::Value>,
+    new_value: Option<serde_json::Value>,
+    author: String,
+    at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ConfigHistoryRow {
+    fn into_entry(self) -> Result<ConfigHistoryEntry, crate::Error> {
+        Ok(ConfigHistoryEntry {
+            id: self.id,
+            guild_id: self.guild_id.parse()?,
+            kind: self.kind.parse()?,
+            key: self.key,
+            old_value: self.old_value,
+            new_value: self.new_value,
+            author: self.author,
+            at: self.at,
+        })
+    }
+}
+
+const HISTORY_COLUMNS: &str = "id, guild_id, kind, key, old_value, new_value, author, at";
+
+/// Records a change, ``at`` uses ``clock_timestamp()`` so entries written within one transaction still sort in the order
+/// they were written
+async fn insert_history(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    kind: ConfigKind,
+    key: &str,
+    old_value: Option<serde_json::Value>,
+    new_value: Option<serde_json::Value>,
+    author: &str,
+) -> Result<ConfigHistoryEntry, crate::Error> {
+    let row: ConfigHistoryRow = sqlx::query_as(&format!(
+        "INSERT INTO guild_config_history (guild_id, kind, key, old_value, new_value, author, at) VALUES ($1, $2, $3, $4, $5, $6, clock_timestamp()) RETURNING {}",
+        HISTORY_COLUMNS
+    ))
+    .bind(guild_id.to_string())
+    .bind(kind.to_string())
+    .bind(key)
+    .bind(old_value)
+    .bind(new_value)
+    .bind(author)
+    .fetch_one(db)
+    .await?;
+
+    row.into_entry()
+}
+
+fn validate_key(kind: ConfigKind, key: &str) -> Result<(), crate::Error> {
+    if key.trim().is_empty() {
+        return Err(format!("The {} to configure must be set", kind).into());
+    }
+
+    Ok(())
+}
+
+/// A ``guild_module_configurations`` row as the JSON form of ``ModuleConfiguration``
+const MODULE_CONFIGURATION_JSON: &str =
+    "jsonb_build_object('disabled', disabled, 'default_perms', default_perms)";
+
+/// Sets (or with None, deletes) the configuration of a module, recording the change in the guild's config history
+///
+/// All writes to ``guild_module_configurations`` should go through this so the history stays complete
+pub async fn set_module_configuration(
+    db: &sqlx::PgPool,
+    guild_id: GuildId,
+    module: &str,
+    config: Option<&ModuleConfiguration>,
+    author: &str,
+) -> Result<ConfigHistoryEntry, crate::Error> {
+    validate_key(ConfigKind::Module, module)?;
+
+    let mut tx = db.begin().await?;
+
+    let old: Option<serde_json::Value> = sqlx::query_scalar(&format!(
+        "SELECT {} FROM guild_module_configurations WHERE guild_id = $1 AND module = $2 FOR UPDATE",
+        MODULE_CONFIGURATION_JSON
+    ))
+    .bind(guild_id.to_string())
+    .bind(module)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match config {
+        Some(config) => {
+            sqlx::query(
+                "INSERT INTO guild_module_configurations (guild_id, module, disabled, default_perms) VALUES ($1, $2, $3, $4) ON CONFLICT (guild_id, module) DO UPDATE SET disabled = EXCLUDED.disabled, default_perms = EXCLUDED.default_perms",
+            )
+            .bind(guild_id.to_string())
+            .bind(module)
+            .bind(config.disabled)
+            .bind(config.default_perms.as_ref().map(sqlx::types::Json))
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            sqlx::query(
+                "DELETE FROM guild_module_configurations WHERE guild_id = $1 AND module = $2",
+            )
+            .bind(guild_id.to_string())
+            .bind(module)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    let entry = insert_history(
+        &mut *tx,
+        guild_id,
+        ConfigKind::Module,
+        module,
+        old,
+        config.map(serde_json::to_value).transpose()?,
+        author,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(entry)
+}
+
+/// Sets (or with None, deletes) the configuration of a command, recording the change in the guild's config history
+///
+/// All writes to ``guild_command_configurations`` should go through this so the history stays complete
+pub async fn set_command_configuration(
+    db: &sqlx::PgPool,
+    guild_id: GuildId,
+    command: &str,
+    config: Option<&CommandConfiguration>,
+    author: &str,
+) -> Result<ConfigHistoryEntry, crate::Error> {
+    validate_key(ConfigKind::Command, command)?;
+
+    let mut tx = db.begin().await?;
+
+    let old: Option<CommandConfiguration> = sqlx::query_as(
+        "SELECT disabled, perms FROM guild_command_configurations WHERE guild_id = $1 AND command = $2 FOR UPDATE",
+    )
+    .bind(guild_id.to_string())
+    .bind(command)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match config {
+        Some(config) => {
+            sqlx::query(
+                "INSERT INTO guild_command_configurations (guild_id, command, disabled, perms) VALUES ($1, $2, $3, $4) ON CONFLICT (guild_id, command) DO UPDATE SET disabled = EXCLUDED.disabled, perms = EXCLUDED.perms",
+            )
+            .bind(guild_id.to_string())
+            .bind(command)
+            .bind(config.disabled)
+            .bind(&config.perms)
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            sqlx::query(
+                "DELETE FROM guild_command_configurations WHERE guild_id = $1 AND command = $2",
+            )
+            .bind(guild_id.to_string())
+            .bind(command)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    let entry = insert_history(
+        &mut *tx,
+        guild_id,
+        ConfigKind::Command,
+        command,
+        old.map(serde_json::to_value).transpose()?,
+        config.map(serde_json::to_value).transpose()?,
+        author,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(entry)
+}
+
+/// Returns a history entry of a guild
+pub async fn get_history_entry(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    history_id: uuid::Uuid,
+) -> Result<Option<ConfigHistoryEntry>, crate::Error> {
+    let row: Option<ConfigHistoryRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM guild_config_history WHERE id = $1 AND guild_id = $2",
+        HISTORY_COLUMNS
+    ))
+    .bind(history_id)
+    .bind(guild_id.to_string())
+    .fetch_optional(db)
+    .await?;
+
+    row.map(ConfigHistoryRow::into_entry).transpose()
+}
+
+/// The number of history entries per page of ``list_history``
+pub const HISTORY_PAGE_SIZE: i64 = 20;
+
+/// Lists the config history of a guild paginated based on page number (starting at 1), newest first
+///
+/// If ``kind`` is set, only changes to that kind of configuration are returned. See ``list_history_with_options`` for
+/// keyset pagination, which is stable while new changes are being recorded
+pub async fn list_history(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    kind: Option<ConfigKind>,
+    page: usize,
+) -> Result<Vec<ConfigHistoryEntry>, crate::Error> {
+    if page > i64::MAX as usize {
+        return Err("Page number too large".into());
+    }
+
+    let page = std::cmp::max(page, 1) as i64;
+
+    let rows: Vec<ConfigHistoryRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM guild_config_history WHERE guild_id = $1 AND ($2::text IS NULL OR kind = $2) ORDER BY at DESC, id DESC OFFSET $3 LIMIT $4",
+        HISTORY_COLUMNS
+    ))
+    .bind(guild_id.to_string())
+    .bind(kind.map(|k| k.to_string()))
+    .bind((page - 1).saturating_mul(HISTORY_PAGE_SIZE))
+    .bind(HISTORY_PAGE_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter().map(ConfigHistoryRow::into_entry).collect()
+}
+
+/// Lists the config history of a guild using keyset pagination, newest first
+///
+/// If ``kind`` is set, only changes to that kind of configuration are returned
+pub async fn list_history_with_options(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    kind: Option<ConfigKind>,
+    opts: &ListOptions,
+) -> Result<ListPage<ConfigHistoryEntry>, crate::Error> {
+    let (page_size, cursor) = opts.parse()?;
+
+    let rows: Vec<ConfigHistoryRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM guild_config_history WHERE guild_id = $1 AND ($2::text IS NULL OR kind = $2) AND ($3::timestamptz IS NULL OR (at, id) < ($3, $4)) ORDER BY at DESC, id DESC LIMIT $5",
+        HISTORY_COLUMNS
+    ))
+    .bind(guild_id.to_string())
+    .bind(kind.map(|k| k.to_string()))
+    .bind(cursor.map(|c| c.created_at))
+    .bind(cursor.map(|c| c.id))
+    .bind(page_size + 1)
+    .fetch_all(db)
+    .await?;
+
+    let mut entries = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        entries.push(row.into_entry()?);
+    }
+
+    Ok(into_page(entries, page_size, |entry| Cursor {
+        created_at: entry.at,
+        id: entry.id,
+    }))
+}
+
+/// Restores a configuration to its value before the given history entry
+///
+/// The old value is re-applied through ``set_module_configuration`` or ``set_command_configuration``, so the rollback
+/// is itself recorded in the history (and can be rolled back in turn). Returns the new history entry
+pub async fn rollback_to(
+    db: &sqlx::PgPool,
+    guild_id: GuildId,
+    history_id: uuid::Uuid,
+    author: &str,
+) -> Result<ConfigHistoryEntry, crate::Error> {
+    let Some(entry) = get_history_entry(db, guild_id, history_id).await? else {
+        return Err(format!("History entry {} not found", history_id).into());
+    };
+
+    match entry.kind {
+        ConfigKind::Module => {
+            let old: Option<ModuleConfiguration> =
+                entry.old_value.map(serde_json::from_value).transpose()?;
+
+            set_module_configuration(db, guild_id, &entry.key, old.as_ref(), author).await
+        }
+        ConfigKind::Command => {
+            let old: Option<CommandConfiguration> =
+                entry.old_value.map(serde_json::from_value).transpose()?;
+
+            set_command_configuration(db, guild_id, &entry.key, old.as_ref(), author).await
+        }
+    }
+}
+
+/// A ``guild_command_configurations`` row as the JSON form of ``CommandConfiguration``
+const COMMAND_CONFIGURATION_JSON: &str = "jsonb_build_object('disabled', disabled, 'perms', perms)";
+
+/// Returns the configuration of a module and the best configuration of a command in one round trip
+///
+/// ``command_permutations`` are the names the command can be configured under (e.g. ``ban``, ``ban user``), the most
+/// specific (longest) permutation with a configuration wins
+pub async fn get_module_and_command_config(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    command_permutations: &[String],
+    module: &str,
+) -> Result<(Option<ModuleConfiguration>, Option<CommandConfiguration>), crate::Error> {
+    let (module_config, command_config): (Option<serde_json::Value>, Option<serde_json::Value>) =
+        sqlx::query_as(&format!(
+            "SELECT (SELECT {} FROM guild_module_configurations WHERE guild_id = $1 AND module = $3), (SELECT {} FROM guild_command_configurations WHERE guild_id = $1 AND command = ANY($2::text[]) ORDER BY length(command) DESC LIMIT 1)",
+            MODULE_CONFIGURATION_JSON, COMMAND_CONFIGURATION_JSON
+        ))
+        .bind(guild_id.to_string())
+        .bind(command_permutations)
+        .bind(module)
+        .fetch_one(db)
+        .await?;
+
+    Ok((
+        module_config.map(serde_json::from_value).transpose()?,
+        command_config.map(serde_json::from_value).transpose()?,
+    ))
+}
+
+/// Returns the configuration of a module in a guild
+pub async fn get_module_configuration(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    module: &str,
+) -> Result<Option<ModuleConfiguration>, crate::Error> {
+    let (module_config, _) = get_module_and_command_config(db, guild_id, &[], module).await?;
+    Ok(module_config)
+}
+
+/// Returns the configuration of the most specific command permutation that is configured in a guild
+pub async fn get_best_command_configuration(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    command_permutations: &[String],
+) -> Result<Option<CommandConfiguration>, crate::Error> {
+    let (_, command_config) =
+        get_module_and_command_config(db, guild_id, command_permutations, "").await?;
+    Ok(command_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{
+        create_tables, GUILD_COMMAND_CONFIGURATIONS, GUILD_CONFIG_HISTORY,
+        GUILD_MODULE_CONFIGURATIONS,
+    };
+
+    const GUILD: GuildId = GuildId::new(1);
+
+    async fn setup(pool: &sqlx::PgPool) {
+        create_tables(
+            pool,
+            &[
+                GUILD_MODULE_CONFIGURATIONS,
+                GUILD_COMMAND_CONFIGURATIONS,
+                GUILD_CONFIG_HISTORY,
+            ],
+        )
+        .await;
+    }
+
+    fn module(disabled: bool) -> ModuleConfiguration {
+        ModuleConfiguration {
+            disabled: Some(disabled),
+            default_perms: None,
+        }
+    }
+
+    async fn current_module(pool: &sqlx::PgPool, module: &str) -> Option<ModuleConfiguration> {
+        let row: Option<serde_json::Value> = sqlx::query_scalar(&format!(
+            "SELECT {} FROM guild_module_configurations WHERE guild_id = $1 AND module = $2",
+            MODULE_CONFIGURATION_JSON
+        ))
+        .bind(GUILD.to_string())
+        .bind(module)
+        .fetch_optional(pool)
+        .await
+        .unwrap();
+
+        row.map(|row| serde_json::from_value(row).unwrap())
+    }
+
+    fn command(disabled: bool) -> CommandConfiguration {
+        CommandConfiguration {
+            disabled: Some(disabled),
+            perms: None,
+        }
+    }
+
+    fn permutations(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    /// Counts the statements sqlx logs while it is the thread's default subscriber
+    struct QueryCounter(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl tracing::Subscriber for QueryCounter {
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            metadata.target() == "sqlx::query"
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            if event.metadata().target() == "sqlx::query" {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn best_command_configuration_prefers_the_most_specific_permutation(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        set_command_configuration(&pool, GUILD, "ban", Some(&command(true)), "1")
+            .await
+            .unwrap();
+
+        let perms = permutations(&["ban", "ban user", "ban user add"]);
+        assert_eq!(
+            get_best_command_configuration(&pool, GUILD, &perms)
+                .await
+                .unwrap(),
+            Some(command(true))
+        );
+
+        set_command_configuration(&pool, GUILD, "ban user", Some(&command(false)), "1")
+            .await
+            .unwrap();
+        assert_eq!(
+            get_best_command_configuration(&pool, GUILD, &perms)
+                .await
+                .unwrap(),
+            Some(command(false))
+        );
+
+        // Order of the permutations does not matter
+        let reversed = perms.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            get_best_command_configuration(&pool, GUILD, &reversed)
+                .await
+                .unwrap(),
+            Some(command(false))
+        );
+
+        assert_eq!(
+            get_best_command_configuration(&pool, GUILD, &permutations(&["kick"]))
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            get_best_command_configuration(&pool, GuildId::new(2), &perms)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn module_and_command_config_are_fetched_in_one_query(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        set_module_configuration(&pool, GUILD, "moderation", Some(&module(true)), "1")
+            .await
+            .unwrap();
+        set_command_configuration(&pool, GUILD, "ban", Some(&command(false)), "1")
+            .await
+            .unwrap();
+
+        let perms = permutations(&["ban", "ban user"]);
+        let mut conn = pool.acquire().await.unwrap();
+
+        let queries = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let _guard = tracing::subscriber::set_default(QueryCounter(queries.clone()));
+        let count = || queries.swap(0, std::sync::atomic::Ordering::Relaxed);
+
+        let both = get_module_and_command_config(&mut *conn, GUILD, &perms, "moderation")
+            .await
+            .unwrap();
+        assert_eq!(both, (Some(module(true)), Some(command(false))));
+        assert_eq!(count(), 1);
+
+        let module_config = get_module_configuration(&mut *conn, GUILD, "moderation")
+            .await
+            .unwrap();
+        let command_config = get_best_command_configuration(&mut *conn, GUILD, &perms)
+            .await
+            .unwrap();
+        assert_eq!((module_config, command_config), both);
+        assert_eq!(count(), 2);
+
+        assert_eq!(
+            get_module_and_command_config(&mut *conn, GUILD, &perms, "fun")
+                .await
+                .unwrap(),
+            (None, Some(command(false)))
+        );
+        assert_eq!(count(), 1);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn rollback_of_rollback_restores_the_rolled_back_value(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        let created =
+            set_module_configuration(&pool, GUILD, "moderation", Some(&module(true)), "1")
+                .await
+                .unwrap();
+        let changed =
+            set_module_configuration(&pool, GUILD, "moderation", Some(&module(false)), "1")
+                .await
+                .unwrap();
+
+        let rollback = rollback_to(&pool, GUILD, changed.id, "2").await.unwrap();
+        assert_eq!(
+            current_module(&pool, "moderation").await,
+            Some(module(true))
+        );
+        assert_eq!(rollback.author, "2");
+        assert_eq!(rollback.old_value, changed.new_value);
+        assert_eq!(rollback.new_value, changed.old_value);
+
+        let undo = rollback_to(&pool, GUILD, rollback.id, "3").await.unwrap();
+        assert_eq!(
+            current_module(&pool, "moderation").await,
+            Some(module(false))
+        );
+        assert_eq!(undo.new_value, rollback.old_value);
+
+        // Rolling back the creation deletes the configuration again
+        let deleted = rollback_to(&pool, GUILD, created.id, "4").await.unwrap();
+        assert_eq!(current_module(&pool, "moderation").await, None);
+        assert_eq!(deleted.new_value, None);
+
+        assert_eq!(list_history(&pool, GUILD, None, 1).await.unwrap().len(), 5);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn rollback_of_unknown_entry_fails(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        let entry = set_module_configuration(&pool, GUILD, "moderation", Some(&module(true)), "1")
+            .await
+            .unwrap();
+
+        assert!(rollback_to(&pool, GUILD, uuid::Uuid::new_v4(), "1")
+            .await
+            .is_err());
+        assert!(rollback_to(&pool, GuildId::new(2), entry.id, "1")
+            .await
+            .is_err());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn history_is_paginated_newest_first(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        let total = HISTORY_PAGE_SIZE as usize * 2 + 5;
+        let mut ids = Vec::with_capacity(total);
+
+        for i in 0..total {
+            let entry = set_module_configuration(
+                &pool,
+                GUILD,
+                "moderation",
+                Some(&module(i % 2 == 0)),
+                "1",
+            )
+            .await
+            .unwrap();
+            ids.push(entry.id);
+        }
+
+        set_command_configuration(&pool, GUILD, "ban", Some(&command(true)), "1")
+            .await
+            .unwrap();
+
+        let mut listed = Vec::new();
+
+        for page in 1..=4 {
+            let entries = list_history(&pool, GUILD, Some(ConfigKind::Module), page)
+                .await
+                .unwrap();
+
+            let expected = match page {
+                1 | 2 => HISTORY_PAGE_SIZE as usize,
+                3 => 5,
+                _ => 0,
+            };
+            assert_eq!(entries.len(), expected, "page {}", page);
+
+            listed.extend(entries.into_iter().map(|e| e.id));
+        }
+
+        ids.reverse();
+        assert_eq!(listed, ids);
+
+        // Page 0 is treated as the first page
+        assert_eq!(
+            list_history(&pool, GUILD, Some(ConfigKind::Module), 0)
+                .await
+                .unwrap()
+                .first()
+                .map(|e| e.id),
+            ids.first().copied()
+        );
+
+        let commands = list_history(&pool, GUILD, Some(ConfigKind::Command), 1)
+            .await
+            .unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].key, "ban");
+
+        assert_eq!(
+            list_history(&pool, GUILD, None, 1).await.unwrap()[0].id,
+            commands[0].id
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn entries_of_one_transaction_keep_their_order(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        let mut tx = pool.begin().await.unwrap();
+        let mut ids = Vec::new();
+
+        for i in 0..10 {
+            let entry = insert_history(
+                &mut *tx,
+                GUILD,
+                ConfigKind::Module,
+                "moderation",
+                None,
+                Some(serde_json::json!(i)),
+                "1",
+            )
+            .await
+            .unwrap();
+            ids.push(entry.id);
+        }
+
+        tx.commit().await.unwrap();
+        ids.reverse();
+
+        let listed = list_history(&pool, GUILD, None, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(listed, ids);
+
+        let page = list_history_with_options(&pool, GUILD, None, &ListOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            page.items.into_iter().map(|e| e.id).collect::<Vec<_>>(),
+            ids
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn keyset_pages_cover_the_history(pool: sqlx::PgPool) {
+        setup(&pool).await;
+
+        for i in 0..7 {
+            set_module_configuration(&pool, GUILD, "moderation", Some(&module(i % 2 == 0)), "1")
+                .await
+                .unwrap();
+        }
+
+        let mut opts = ListOptions {
+            page_size: 3,
+            cursor: None,
+        };
+        let mut seen = Vec::new();
+
+        loop {
+            let page = list_history_with_options(&pool, GUILD, None, &opts)
+                .await
+                .unwrap();
+            seen.extend(page.items.into_iter().map(|e| e.id));
+
+            match page.next_cursor {
+                Some(cursor) => opts.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        let by_page = list_history(&pool, GUILD, None, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(seen, by_page);
+    }
+}
@@ -1,4 +1,6 @@
 use dashmap::DashMap;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 
 const CHUNK_SIZE: usize = 5 * 1024 * 1024;
 const MULTIPART_MIN_SIZE: usize = 50 * 1024 * 1024;
@@ -14,6 +16,15 @@ pub enum ObjectStore {
     Local {
         dir: String,
     },
+    /// In-memory store, mainly useful for tests
+    Memory {
+        objects: DashMap<(String, String), MemoryObject>,
+    },
+}
+
+pub struct MemoryObject {
+    pub data: Vec<u8>,
+    pub last_modified: chrono::DateTime<chrono::Utc>,
 }
 
 impl ObjectStore {
@@ -88,6 +99,12 @@ impl ObjectStore {
     pub fn new_local(dir: String) -> Self {
         ObjectStore::Local { dir }
     }
+
+    pub fn new_memory() -> Self {
+        ObjectStore::Memory {
+            objects: DashMap::new(),
+        }
+    }
 }
 
 pub struct ListObjectsResponse {
@@ -97,6 +114,139 @@ pub struct ListObjectsResponse {
     pub etag: Option<String>,
 }
 
+pub struct ListObjectsPage {
+    pub objects: Vec<ListObjectsResponse>,
+    /// Token to pass to the next ``list_files_page`` call, None when there are no more objects
+    pub next_continuation_token: Option<String>,
+}
+
+/// A byte range of an object, as in a HTTP ``Range`` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// From ``start`` up to and including ``end``, or to the end of the object if unset
+    Range { start: u64, end: Option<u64> },
+    /// The last ``n`` bytes of the object
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a ``Range`` header value, only a single range in bytes is supported
+    pub fn parse_header(value: &str) -> Result<Self, crate::Error> {
+        let Some(spec) = value.trim().strip_prefix("bytes=") else {
+            return Err(format!("Unsupported range unit: {}", value).into());
+        };
+
+        if spec.contains(',') {
+            return Err("Multiple ranges are not supported".into());
+        }
+
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Err(format!("Invalid range: {}", value).into());
+        };
+
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return Err(format!("Invalid range: {}", value).into()),
+            ("", suffix) => ByteRange::Suffix(suffix.parse()?),
+            (start, "") => ByteRange::Range {
+                start: start.parse()?,
+                end: None,
+            },
+            (start, end) => {
+                let (start, end) = (start.parse()?, end.parse()?);
+
+                if end < start {
+                    return Err(format!("Invalid range: {}", value).into());
+                }
+
+                ByteRange::Range {
+                    start,
+                    end: Some(end),
+                }
+            }
+        };
+
+        Ok(range)
+    }
+
+    /// Returns the inclusive start and end of the range within an object of ``size`` bytes,
+    /// None if the range is not satisfiable
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        if size == 0 {
+            return None;
+        }
+
+        match *self {
+            ByteRange::Range { start, end } => {
+                // ``parse_header`` rejects ends before the start, but a ``ByteRange`` can also be built directly
+                if start >= size || end.is_some_and(|end| end < start) {
+                    return None;
+                }
+
+                Some((start, end.unwrap_or(size - 1).min(size - 1)))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(n) => Some((size.saturating_sub(n), size - 1)),
+        }
+    }
+}
+
+/// Returned by ``ObjectStore::get`` if the requested range lies outside of the object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeNotSatisfiable {
+    /// Size of the object in bytes
+    pub size: u64,
+}
+
+impl std::fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Requested range is not satisfiable for an object of {} bytes",
+            self.size
+        )
+    }
+}
+
+impl std::error::Error for RangeNotSatisfiable {}
+
+/// An object (or part of one) being read from the object store
+pub struct GetObject {
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+    /// Size of the whole object in bytes
+    pub size: u64,
+    /// Inclusive start and end of the returned bytes if a range was requested
+    pub range: Option<(u64, u64)>,
+    pub content_type: Option<String>,
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl GetObject {
+    /// Number of bytes ``reader`` returns
+    pub fn content_length(&self) -> u64 {
+        match self.range {
+            Some((start, end)) => end - start + 1,
+            None => self.size,
+        }
+    }
+
+    /// Value of the ``Content-Range`` header for a ranged response
+    pub fn content_range(&self) -> Option<String> {
+        self.range
+            .map(|(start, end)| format!("bytes {}-{}/{}", start, end, self.size))
+    }
+}
+
+/// Resolves the requested range, erroring with ``RangeNotSatisfiable`` if it lies outside of the object
+fn resolve_range(range: Option<ByteRange>, size: u64) -> Result<Option<(u64, u64)>, crate::Error> {
+    match range {
+        Some(range) => match range.resolve(size) {
+            Some(resolved) => Ok(Some(resolved)),
+            None => Err(Box::new(RangeNotSatisfiable { size })),
+        },
+        None => Ok(None),
+    }
+}
+
 impl ObjectStore {
     /// Create a bucket with the given name
     pub async fn create_bucket(&self, name: &str) -> Result<(), crate::Error> {
@@ -117,6 +267,7 @@ impl ObjectStore {
 
                 Ok(())
             }
+            ObjectStore::Memory { .. } => Ok(()),
         }
     }
 
@@ -158,6 +309,7 @@ impl ObjectStore {
 
                 Ok(())
             }
+            ObjectStore::Memory { .. } => Ok(()),
         }
     }
 
@@ -186,6 +338,9 @@ impl ObjectStore {
                 let path = std::path::Path::new(dir).join(bucket).join(key);
                 Ok(path.exists())
             }
+            ObjectStore::Memory { objects } => {
+                Ok(objects.contains_key(&(bucket.to_string(), key.to_string())))
+            }
         }
     }
 
@@ -229,6 +384,7 @@ impl ObjectStore {
                 Ok(url)
             }
             ObjectStore::Local { dir } => Ok(format!("file://{}/{}/{}", dir, bucket, key)),
+            ObjectStore::Memory { .. } => Ok(format!("memory://{}/{}", bucket, key)),
         }
     }
 
@@ -343,6 +499,99 @@ impl ObjectStore {
 
                 Ok(files)
             }
+            ObjectStore::Memory { objects } => {
+                let prefix = key.unwrap_or_default();
+
+                Ok(objects
+                    .iter()
+                    .filter(|o| o.key().0 == bucket && o.key().1.starts_with(prefix))
+                    .map(|o| ListObjectsResponse {
+                        key: o.key().1.clone(),
+                        last_modified: Some(o.value().last_modified),
+                        size: o.value().data.len().try_into().unwrap_or(0),
+                        etag: None,
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Lists a single page of files in the object store with a given prefix
+    ///
+    /// ``continuation_token`` should be the ``next_continuation_token`` of the previous page
+    pub async fn list_files_page(
+        &self,
+        bucket: &str,
+        key: Option<&str>,
+        continuation_token: Option<String>,
+        max_keys: i32,
+    ) -> Result<ListObjectsPage, crate::Error> {
+        match self {
+            ObjectStore::S3 { client, .. } => {
+                let mut action = client.list_objects_v2().bucket(bucket).max_keys(max_keys);
+
+                if let Some(key) = key {
+                    action = action.prefix(key);
+                }
+
+                if let Some(continuation_token) = continuation_token {
+                    action = action.continuation_token(continuation_token);
+                }
+
+                let response = action
+                    .send()
+                    .await
+                    .map_err(|e| format!("Failed to list objects: {}", e))?;
+
+                let mut objects = vec![];
+
+                for object in response.contents.unwrap_or_default() {
+                    let Some(ref key) = object.key else {
+                        continue;
+                    };
+
+                    objects.push(ListObjectsResponse {
+                        key: key.to_string(),
+                        last_modified: match object.last_modified {
+                            Some(last_modified) => {
+                                chrono::DateTime::from_timestamp(last_modified.secs(), 0)
+                            }
+                            None => None,
+                        },
+                        size: object.size.unwrap_or(0),
+                        etag: object.e_tag().map(|etag| etag.to_string()),
+                    });
+                }
+
+                Ok(ListObjectsPage {
+                    objects,
+                    next_continuation_token: response.next_continuation_token,
+                })
+            }
+            ObjectStore::Local { .. } | ObjectStore::Memory { .. } => {
+                // Listing is cheap here, so page over the sorted full listing using the last key as the token
+                let mut objects = self.list_files(bucket, key).await?;
+                objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+                if let Some(continuation_token) = continuation_token {
+                    objects.retain(|o| o.key > continuation_token);
+                }
+
+                let max_keys = max_keys.max(1) as usize;
+                let has_more = objects.len() > max_keys;
+                objects.truncate(max_keys);
+
+                let next_continuation_token = if has_more {
+                    objects.last().map(|o| o.key.clone())
+                } else {
+                    None
+                };
+
+                Ok(ListObjectsPage {
+                    objects,
+                    next_continuation_token,
+                })
+            }
         }
     }
 
@@ -369,6 +618,132 @@ impl ObjectStore {
                 let path = std::path::Path::new(dir).join(bucket).join(key);
                 Ok(std::fs::read(path).map_err(|e| format!("Failed to read object: {}", e))?)
             }
+            ObjectStore::Memory { objects } => Ok(objects
+                .get(&(bucket.to_string(), key.to_string()))
+                .ok_or("Object not found")?
+                .data
+                .clone()),
+        }
+    }
+
+    /// Streams an object (or with ``range``, part of it) from the object store, None if it does not exist
+    ///
+    /// Errors with ``RangeNotSatisfiable`` if ``range`` lies outside of the object
+    pub async fn get(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<ByteRange>,
+    ) -> Result<Option<GetObject>, crate::Error> {
+        match self {
+            ObjectStore::S3 { client, .. } => {
+                // The size is needed to resolve the range, so fetch it first
+                let head = match client.head_object().bucket(bucket).key(key).send().await {
+                    Ok(head) => head,
+                    Err(e) => {
+                        let Some(e) = e.as_service_error() else {
+                            return Err(format!("Failed to get object: {}", e).into());
+                        };
+
+                        if e.is_not_found() {
+                            return Ok(None);
+                        }
+
+                        return Err(format!("Failed to get object: {}", e).into());
+                    }
+                };
+
+                let size: u64 = head.content_length.unwrap_or(0).try_into()?;
+                let range = resolve_range(range, size)?;
+
+                let mut action = client.get_object().bucket(bucket).key(key);
+
+                if let Some((start, end)) = range {
+                    action = action.range(format!("bytes={}-{}", start, end));
+                }
+
+                let resp = match action.send().await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        let Some(e) = e.as_service_error() else {
+                            return Err(format!("Failed to get object: {}", e).into());
+                        };
+
+                        if e.is_no_such_key() {
+                            return Ok(None);
+                        }
+
+                        return Err(format!("Failed to get object: {}", e).into());
+                    }
+                };
+
+                Ok(Some(GetObject {
+                    content_type: resp.content_type.clone(),
+                    last_modified: resp
+                        .last_modified
+                        .and_then(|lm| chrono::DateTime::from_timestamp(lm.secs(), 0)),
+                    reader: Box::pin(resp.body.into_async_read()),
+                    size,
+                    range,
+                }))
+            }
+            ObjectStore::Local { dir } => {
+                let path = std::path::Path::new(dir).join(bucket).join(key);
+
+                let mut file = match tokio::fs::File::open(&path).await {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(format!("Failed to read object: {}", e).into()),
+                };
+
+                let metadata = file
+                    .metadata()
+                    .await
+                    .map_err(|e| format!("Failed to get metadata: {}", e))?;
+
+                let size = metadata.len();
+                let range = resolve_range(range, size)?;
+
+                let reader: Pin<Box<dyn AsyncRead + Send>> = match range {
+                    Some((start, end)) => {
+                        file.seek(std::io::SeekFrom::Start(start))
+                            .await
+                            .map_err(|e| format!("Failed to read object: {}", e))?;
+
+                        Box::pin(file.take(end - start + 1))
+                    }
+                    None => Box::pin(file),
+                };
+
+                Ok(Some(GetObject {
+                    reader,
+                    size,
+                    range,
+                    content_type: None,
+                    last_modified: metadata.modified().ok().map(|m| m.into()),
+                }))
+            }
+            ObjectStore::Memory { objects } => {
+                let Some(object) = objects.get(&(bucket.to_string(), key.to_string())) else {
+                    return Ok(None);
+                };
+
+                let size = object.data.len() as u64;
+                let range = resolve_range(range, size)?;
+
+                let data = match range {
+                    Some((start, end)) => object.data[start as usize..=end as usize].to_vec(),
+                    None => object.data.clone(),
+                };
+
+                Ok(Some(GetObject {
+                    reader: Box::pin(std::io::Cursor::new(data)),
+                    size,
+                    range,
+                    content_type: None,
+                    last_modified: Some(object.last_modified),
+                }))
+            }
         }
     }
 
@@ -378,119 +753,175 @@ impl ObjectStore {
         bucket: &str,
         key: &str,
         data: Vec<u8>,
+    ) -> Result<(), crate::Error> {
+        self.put_streaming(bucket, key, data.as_slice(), None).await
+    }
+
+    /// Uploads a file to the object store from a reader without buffering it entirely in memory
+    ///
+    /// On S3, payloads larger than ``MULTIPART_MIN_SIZE`` are uploaded using a multipart upload
+    pub async fn put_streaming(
+        &self,
+        bucket: &str,
+        key: &str,
+        mut reader: impl AsyncRead + Unpin + Send,
+        content_type: Option<&str>,
     ) -> Result<(), crate::Error> {
         self.create_bucket_if_not_exists(bucket).await?;
 
         match self {
             ObjectStore::S3 { client, .. } => {
-                if data.len() > MULTIPART_MIN_SIZE {
-                    let cmuo = client
-                        .create_multipart_upload()
+                // Read just enough to know if a multipart upload is needed
+                let mut buf = Vec::new();
+                (&mut reader)
+                    .take(MULTIPART_MIN_SIZE as u64 + 1)
+                    .read_to_end(&mut buf)
+                    .await
+                    .map_err(|e| format!("Failed to read upload data: {}", e))?;
+
+                if buf.len() <= MULTIPART_MIN_SIZE {
+                    client
+                        .put_object()
                         .bucket(bucket)
                         .key(key)
+                        .set_content_type(content_type.map(|c| c.to_string()))
+                        .body(aws_smithy_types::byte_stream::ByteStream::from(buf))
                         .send()
                         .await?;
 
-                    let Some(upload_id) = cmuo.upload_id else {
-                        return Err("Failed to get upload id".into());
-                    };
+                    return Ok(());
+                }
 
-                    // Upload parts
-                    let mut error: Option<crate::Error> = None;
-                    let mut parts = vec![];
-                    loop {
-                        let mut action = client
-                            .upload_part()
-                            .bucket(bucket)
-                            .upload_id(upload_id.clone())
-                            .key(key)
-                            .part_number(match parts.len().try_into() {
-                                Ok(part_number) => part_number,
-                                Err(_) => {
-                                    error = Some("Failed to convert part number".into());
-                                    break;
-                                }
-                            });
+                let cmuo = client
+                    .create_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_content_type(content_type.map(|c| c.to_string()))
+                    .send()
+                    .await?;
 
-                        // Split into 5 mb parts
-                        let range = std::ops::Range {
-                            start: parts.len() * CHUNK_SIZE,
-                            end: std::cmp::min(data.len(), (parts.len() + 1) * CHUNK_SIZE),
-                        };
+                let Some(upload_id) = cmuo.upload_id else {
+                    return Err("Failed to get upload id".into());
+                };
 
-                        let send_data = &data[range.start..range.end];
+                let mut parts = vec![];
 
-                        action = action.body(aws_smithy_types::byte_stream::ByteStream::from(
-                            send_data.to_vec(),
-                        ));
+                let res = async {
+                    loop {
+                        // Fill the buffer up to one part
+                        while buf.len() < CHUNK_SIZE {
+                            let n = (&mut reader)
+                                .take((CHUNK_SIZE - buf.len()) as u64)
+                                .read_to_end(&mut buf)
+                                .await
+                                .map_err(|e| format!("Failed to read upload data: {}", e))?;
 
-                        let resp = match action.send().await {
-                            Ok(resp) => resp,
-                            Err(e) => {
-                                error = Some(format!("Failed to upload part: {}", e).into());
+                            if n == 0 {
                                 break;
                             }
+                        }
+
+                        if buf.is_empty() {
+                            break;
+                        }
+
+                        let part = if buf.len() > CHUNK_SIZE {
+                            buf.drain(..CHUNK_SIZE).collect::<Vec<_>>()
+                        } else {
+                            std::mem::take(&mut buf)
                         };
 
+                        // Part numbers start at 1
+                        let part_number: i32 = (parts.len() + 1).try_into()?;
+
+                        let resp = client
+                            .upload_part()
+                            .bucket(bucket)
+                            .upload_id(upload_id.clone())
+                            .key(key)
+                            .part_number(part_number)
+                            .body(aws_smithy_types::byte_stream::ByteStream::from(part))
+                            .send()
+                            .await
+                            .map_err(|e| format!("Failed to upload part: {}", e))?;
+
                         let Some(e_tag) = resp.e_tag else {
-                            error = Some("Failed to get e_tag".into());
-                            break;
+                            return Err("Failed to get e_tag".into());
                         };
 
                         parts.push(
                             aws_sdk_s3::types::CompletedPart::builder()
                                 .e_tag(e_tag)
-                                .part_number(parts.len().try_into()?)
+                                .part_number(part_number)
                                 .build(),
                         );
-
-                        if range.end == data.len() {
-                            break;
-                        }
-                    }
-
-                    if let Some(error) = error {
-                        client
-                            .abort_multipart_upload()
-                            .bucket(bucket)
-                            .key(key)
-                            .upload_id(upload_id)
-                            .send()
-                            .await?;
-
-                        return Err(error);
                     }
 
-                    let completed_multipart_upload =
-                        aws_sdk_s3::types::CompletedMultipartUpload::builder()
-                            .set_parts(Some(parts))
-                            .build();
+                    Ok::<(), crate::Error>(())
+                }
+                .await;
 
+                if let Err(error) = res {
                     client
-                        .complete_multipart_upload()
+                        .abort_multipart_upload()
                         .bucket(bucket)
                         .key(key)
                         .upload_id(upload_id)
-                        .multipart_upload(completed_multipart_upload)
-                        .send()
-                        .await?;
-
-                    Ok(())
-                } else {
-                    client
-                        .put_object()
-                        .bucket(bucket)
-                        .key(key)
-                        .body(aws_smithy_types::byte_stream::ByteStream::from(data))
                         .send()
                         .await?;
 
-                    Ok(())
+                    return Err(error);
                 }
+
+                let completed_multipart_upload =
+                    aws_sdk_s3::types::CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build();
+
+                client
+                    .complete_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .multipart_upload(completed_multipart_upload)
+                    .send()
+                    .await?;
+
+                Ok(())
             }
             ObjectStore::Local { dir } => {
                 let path = std::path::Path::new(dir).join(bucket).join(key);
-                std::fs::write(path, data).map_err(|e| format!("Failed to write object: {}", e))?;
+
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .map_err(|e| format!("Failed to create directory: {}", e))?;
+                }
+
+                let mut file = tokio::fs::File::create(path)
+                    .await
+                    .map_err(|e| format!("Failed to write object: {}", e))?;
+
+                tokio::io::copy(&mut reader, &mut file)
+                    .await
+                    .map_err(|e| format!("Failed to write object: {}", e))?;
+
+                Ok(())
+            }
+            ObjectStore::Memory { objects } => {
+                let mut data = Vec::new();
+                reader
+                    .read_to_end(&mut data)
+                    .await
+                    .map_err(|e| format!("Failed to read upload data: {}", e))?;
+
+                objects.insert(
+                    (bucket.to_string(), key.to_string()),
+                    MemoryObject {
+                        data,
+                        last_modified: chrono::Utc::now(),
+                    },
+                );
 
                 Ok(())
             }
@@ -514,6 +945,11 @@ impl ObjectStore {
                 std::fs::remove_file(path)
                     .map_err(|e| format!("Failed to delete object: {}", e))?;
 
+                Ok(())
+            }
+            ObjectStore::Memory { objects } => {
+                objects.remove(&(bucket.to_string(), key.to_string()));
+
                 Ok(())
             }
         }
@@ -524,3 +960,408 @@ impl ObjectStore {
 pub fn guild_bucket(guild_id: serenity::all::GuildId) -> String {
     format!("antiraid.guild.{}", guild_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn put(store: &ObjectStore, bucket: &str, key: &str, data: &[u8]) {
+        store.put_streaming(bucket, key, data, None).await.unwrap();
+    }
+
+    /// Lists every key under ``prefix`` by walking ``list_files_page``
+    async fn walk_pages(
+        store: &ObjectStore,
+        bucket: &str,
+        prefix: &str,
+        max_keys: i32,
+    ) -> Vec<String> {
+        let mut keys = vec![];
+        let mut continuation_token = None;
+
+        loop {
+            let page = store
+                .list_files_page(bucket, Some(prefix), continuation_token, max_keys)
+                .await
+                .unwrap();
+
+            assert!(page.objects.len() <= max_keys as usize);
+            keys.extend(page.objects.into_iter().map(|o| o.key));
+
+            match page.next_continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => break,
+            }
+        }
+
+        keys
+    }
+
+    #[tokio::test]
+    async fn memory_put_streaming_roundtrips() {
+        let store = ObjectStore::new_memory();
+
+        // Larger than a single read of the underlying reader
+        let data = (0..CHUNK_SIZE + 17).map(|i| i as u8).collect::<Vec<_>>();
+        put(&store, "bucket", "jobs/1/backup.tar", &data).await;
+
+        assert!(store.exists("bucket", "jobs/1/backup.tar").await.unwrap());
+        assert!(!store.exists("bucket", "jobs/1/other.tar").await.unwrap());
+        assert!(!store.exists("other", "jobs/1/backup.tar").await.unwrap());
+
+        assert_eq!(
+            store
+                .download_file("bucket", "jobs/1/backup.tar")
+                .await
+                .unwrap(),
+            data
+        );
+
+        // Uploading again replaces the object
+        put(&store, "bucket", "jobs/1/backup.tar", b"new").await;
+        assert_eq!(
+            store
+                .download_file("bucket", "jobs/1/backup.tar")
+                .await
+                .unwrap(),
+            b"new"
+        );
+
+        store.delete("bucket", "jobs/1/backup.tar").await.unwrap();
+        assert!(!store.exists("bucket", "jobs/1/backup.tar").await.unwrap());
+        assert!(store
+            .download_file("bucket", "jobs/1/backup.tar")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_list_files_filters_by_bucket_and_prefix() {
+        let store = ObjectStore::new_memory();
+
+        put(&store, "bucket", "jobs/1/a", b"a").await;
+        put(&store, "bucket", "jobs/2/bb", b"bb").await;
+        put(&store, "bucket", "other/c", b"c").await;
+        put(&store, "other", "jobs/3/d", b"d").await;
+
+        let mut files = store.list_files("bucket", Some("jobs/")).await.unwrap();
+        files.sort_by(|a, b| a.key.cmp(&b.key));
+
+        assert_eq!(
+            files
+                .iter()
+                .map(|f| (f.key.as_str(), f.size))
+                .collect::<Vec<_>>(),
+            vec![("jobs/1/a", 1), ("jobs/2/bb", 2)]
+        );
+        assert!(files.iter().all(|f| f.last_modified.is_some()));
+
+        assert_eq!(store.list_files("bucket", None).await.unwrap().len(), 3);
+        assert!(store.list_files("missing", None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_list_files_page_walks_every_key_once() {
+        let store = ObjectStore::new_memory();
+
+        let mut expected = (0..25)
+            .map(|i| format!("jobs/{}/output", i))
+            .collect::<Vec<_>>();
+
+        for key in expected.iter() {
+            put(&store, "bucket", key, b"x").await;
+        }
+
+        put(&store, "bucket", "unrelated", b"x").await;
+
+        expected.sort();
+
+        for max_keys in [1, 7, 25, 100] {
+            assert_eq!(
+                walk_pages(&store, "bucket", "jobs/", max_keys).await,
+                expected,
+                "max_keys = {}",
+                max_keys
+            );
+        }
+
+        let last = store
+            .list_files_page("bucket", Some("jobs/"), None, 25)
+            .await
+            .unwrap();
+        assert_eq!(last.objects.len(), 25);
+        assert!(last.next_continuation_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn local_put_streaming_creates_parent_directories() {
+        let dir = std::env::temp_dir().join(format!("objectstore-test-{}", uuid::Uuid::new_v4()));
+        let store = ObjectStore::new_local(dir.to_string_lossy().to_string());
+
+        put(&store, "bucket", "jobs/1/backup.tar", b"backup").await;
+
+        assert!(store.exists("bucket", "jobs/1/backup.tar").await.unwrap());
+        assert_eq!(
+            store
+                .download_file("bucket", "jobs/1/backup.tar")
+                .await
+                .unwrap(),
+            b"backup"
+        );
+
+        store.delete("bucket", "jobs/1/backup.tar").await.unwrap();
+        assert!(!store.exists("bucket", "jobs/1/backup.tar").await.unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    async fn read_all(mut object: GetObject) -> Vec<u8> {
+        let mut data = vec![];
+        object.reader.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    #[test]
+    fn parses_range_headers() {
+        assert_eq!(
+            ByteRange::parse_header("bytes=0-99").unwrap(),
+            ByteRange::Range {
+                start: 0,
+                end: Some(99)
+            }
+        );
+        assert_eq!(
+            ByteRange::parse_header("bytes=100-").unwrap(),
+            ByteRange::Range {
+                start: 100,
+                end: None
+            }
+        );
+        assert_eq!(
+            ByteRange::parse_header("bytes=-50").unwrap(),
+            ByteRange::Suffix(50)
+        );
+
+        for invalid in [
+            "bytes=-",
+            "bytes=5-2",
+            "bytes=0-1,3-4",
+            "items=0-1",
+            "bytes=a-b",
+        ] {
+            assert!(ByteRange::parse_header(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn resolves_ranges_within_the_object() {
+        let range = |start, end| ByteRange::Range { start, end };
+
+        assert_eq!(range(0, Some(9)).resolve(100), Some((0, 9)));
+        assert_eq!(range(90, None).resolve(100), Some((90, 99)));
+        // Ends past the object are clamped, starts past it are not satisfiable
+        assert_eq!(range(90, Some(500)).resolve(100), Some((90, 99)));
+        assert_eq!(range(100, None).resolve(100), None);
+        assert_eq!(ByteRange::Suffix(10).resolve(100), Some((90, 99)));
+        assert_eq!(ByteRange::Suffix(500).resolve(100), Some((0, 99)));
+        assert_eq!(ByteRange::Suffix(0).resolve(100), None);
+        assert_eq!(range(0, None).resolve(0), None);
+        // Ends before the start are not satisfiable
+        assert_eq!(range(500, Some(100)).resolve(1000), None);
+        assert_eq!(range(5, Some(4)).resolve(100), None);
+        assert_eq!(range(5, Some(5)).resolve(100), Some((5, 5)));
+    }
+
+    #[tokio::test]
+    async fn memory_get_streams_ranges() {
+        let store = ObjectStore::new_memory();
+        let data = (0..=255u8).collect::<Vec<_>>();
+        put(&store, "bucket", "jobs/1/backup.tar", &data).await;
+
+        let whole = store
+            .get("bucket", "jobs/1/backup.tar", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(whole.size, 256);
+        assert_eq!(whole.content_length(), 256);
+        assert!(whole.content_range().is_none());
+        assert!(whole.last_modified.is_some());
+        assert_eq!(read_all(whole).await, data);
+
+        let part = store
+            .get(
+                "bucket",
+                "jobs/1/backup.tar",
+                Some(ByteRange::parse_header("bytes=10-19").unwrap()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(part.content_length(), 10);
+        assert_eq!(part.content_range().as_deref(), Some("bytes 10-19/256"));
+        assert_eq!(read_all(part).await, data[10..20]);
+
+        let tail = store
+            .get("bucket", "jobs/1/backup.tar", Some(ByteRange::Suffix(6)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tail.content_range().as_deref(), Some("bytes 250-255/256"));
+        assert_eq!(read_all(tail).await, data[250..]);
+    }
+
+    #[tokio::test]
+    async fn memory_get_missing_and_unsatisfiable() {
+        let store = ObjectStore::new_memory();
+        put(&store, "bucket", "jobs/1/backup.tar", b"backup").await;
+
+        assert!(store
+            .get("bucket", "jobs/1/missing.tar", None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get("other", "jobs/1/backup.tar", None)
+            .await
+            .unwrap()
+            .is_none());
+
+        let err = store
+            .get(
+                "bucket",
+                "jobs/1/backup.tar",
+                Some(ByteRange::Range {
+                    start: 6,
+                    end: None,
+                }),
+            )
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.downcast_ref::<RangeNotSatisfiable>(),
+            Some(&RangeNotSatisfiable { size: 6 })
+        );
+
+        let err = store
+            .get(
+                "bucket",
+                "jobs/1/backup.tar",
+                Some(ByteRange::Range {
+                    start: 5,
+                    end: Some(1),
+                }),
+            )
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.downcast_ref::<RangeNotSatisfiable>(),
+            Some(&RangeNotSatisfiable { size: 6 })
+        );
+    }
+
+    #[tokio::test]
+    async fn local_get_streams_ranges() {
+        let dir = std::env::temp_dir().join(format!("objectstore-test-{}", uuid::Uuid::new_v4()));
+        let store = ObjectStore::new_local(dir.to_string_lossy().to_string());
+
+        put(&store, "bucket", "jobs/1/backup.tar", b"0123456789").await;
+
+        let part = store
+            .get(
+                "bucket",
+                "jobs/1/backup.tar",
+                Some(ByteRange::Range {
+                    start: 3,
+                    end: Some(5),
+                }),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(part.size, 10);
+        assert_eq!(read_all(part).await, b"345");
+
+        assert!(store
+            .get("bucket", "jobs/1/missing.tar", None)
+            .await
+            .unwrap()
+            .is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Runs against a real S3-compatible store (e.g. MinIO) if ``OBJECTSTORE_TEST_S3_ENDPOINT``,
+    /// ``OBJECTSTORE_TEST_S3_KEY`` and ``OBJECTSTORE_TEST_S3_SECRET`` are set, and is skipped otherwise
+    #[tokio::test]
+    async fn s3_multipart_upload_and_list() {
+        let (Ok(endpoint), Ok(key), Ok(secret)) = (
+            std::env::var("OBJECTSTORE_TEST_S3_ENDPOINT"),
+            std::env::var("OBJECTSTORE_TEST_S3_KEY"),
+            std::env::var("OBJECTSTORE_TEST_S3_SECRET"),
+        ) else {
+            return;
+        };
+
+        let store = ObjectStore::new_s3(
+            "silverpelt-test".to_string(),
+            endpoint.clone(),
+            endpoint,
+            key,
+            secret,
+        )
+        .unwrap();
+
+        let bucket = format!("silverpelt-test-{}", uuid::Uuid::new_v4());
+
+        // Big enough to need a multipart upload with a partial last part
+        let data = (0..MULTIPART_MIN_SIZE + CHUNK_SIZE / 2)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        put(&store, &bucket, "jobs/1/large", &data).await;
+        put(&store, &bucket, "jobs/2/small", b"small").await;
+
+        assert!(store.exists(&bucket, "jobs/1/large").await.unwrap());
+        assert!(!store.exists(&bucket, "jobs/3/missing").await.unwrap());
+        assert_eq!(
+            store.download_file(&bucket, "jobs/1/large").await.unwrap(),
+            data
+        );
+        assert_eq!(
+            walk_pages(&store, &bucket, "jobs/", 1).await,
+            vec!["jobs/1/large", "jobs/2/small"]
+        );
+
+        let part = store
+            .get(
+                &bucket,
+                "jobs/1/large",
+                Some(ByteRange::Range {
+                    start: 10,
+                    end: Some(19),
+                }),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(part.size, data.len() as u64);
+        assert_eq!(read_all(part).await, data[10..20]);
+        assert!(store
+            .get(&bucket, "jobs/3/missing", None)
+            .await
+            .unwrap()
+            .is_none());
+
+        let files = store.list_files(&bucket, Some("jobs/1/")).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].size, data.len() as i64);
+
+        store.delete(&bucket, "jobs/1/large").await.unwrap();
+        store.delete(&bucket, "jobs/2/small").await.unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+/// The maximum page size for list operations
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// Options for keyset-paginated list operations
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ListOptions {
+    /// Number of items per page, must be between 1 and ``MAX_PAGE_SIZE``
+    pub page_size: i64,
+    /// The cursor returned by the previous page, None for the first page
+    pub cursor: Option<String>,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        ListOptions {
+            page_size: 20,
+            cursor: None,
+        }
+    }
+}
+
+/// A page of results along with the cursor to fetch the next page
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ListPage<T> {
+    pub items: Vec<T>,
+    /// None when there are no more items
+    pub next_cursor: Option<String>,
+}
+
+/// A decoded (created_at, id) keyset cursor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub id: uuid::Uuid,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.created_at.timestamp_micros(), self.id)
+    }
+
+    pub fn decode(s: &str) -> Result<Self, crate::Error> {
+        let (ts, id) = s.split_once('_').ok_or("Invalid cursor")?;
+
+        let created_at = chrono::DateTime::from_timestamp_micros(
+            ts.parse().map_err(|_| "Invalid cursor timestamp")?,
+        )
+        .ok_or("Invalid cursor timestamp")?;
+
+        let id = id.parse().map_err(|_| "Invalid cursor id")?;
+
+        Ok(Cursor { created_at, id })
+    }
+}
+
+impl ListOptions {
+    /// Validates the page size and decodes the cursor
+    pub fn parse(&self) -> Result<(i64, Option<Cursor>), crate::Error> {
+        if self.page_size < 1 || self.page_size > MAX_PAGE_SIZE {
+            return Err(format!("Page size must be between 1 and {}", MAX_PAGE_SIZE).into());
+        }
+
+        let cursor = match self.cursor {
+            Some(ref c) => Some(Cursor::decode(c)?),
+            None => None,
+        };
+
+        Ok((self.page_size, cursor))
+    }
+}
+
+/// Builds a page from ``page_size + 1`` fetched items, using the extra item to detect if more exist
+pub fn into_page<T>(
+    mut items: Vec<T>,
+    page_size: i64,
+    cursor_of: impl Fn(&T) -> Cursor,
+) -> ListPage<T> {
+    let has_more = items.len() as i64 > page_size;
+
+    if has_more {
+        items.truncate(page_size as usize);
+    }
+
+    let next_cursor = if has_more {
+        items.last().map(|item| cursor_of(item).encode())
+    } else {
+        None
+    };
+
+    ListPage { items, next_cursor }
+}
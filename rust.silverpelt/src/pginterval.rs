@@ -1,50 +1,328 @@
 use sqlx::postgres::types::PgInterval;
 
-pub fn pg_interval_to_secs(i: PgInterval) -> i64 {
-    i.microseconds / 1000000 + ((i.days * 86400) as i64) + ((i.months * 2628000) as i64)
+const MICROS_PER_SEC: i64 = 1_000_000;
+const SECS_PER_DAY: i64 = 86400;
+const MICROS_PER_DAY: i64 = SECS_PER_DAY * MICROS_PER_SEC;
+
+/// Number of days in a month when months are approximated, this matches what postgres itself uses
+pub const DAYS_PER_APPROX_MONTH: i64 = 30;
+
+/// Number of seconds in a month used by the deprecated conversions (an average month of 30.4 days)
+const LEGACY_SECS_PER_MONTH: i64 = 2_628_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntervalError {
+    /// The interval is negative but the target type cannot represent negative durations
+    Negative,
+    /// The interval does not fit in the target type
+    Overflow,
+    /// The interval contains months and ``approx_months`` was not set
+    MonthsNotExact,
 }
 
-pub fn pg_interval_to_chrono_duration(i: PgInterval) -> chrono::Duration {
-    let secs = pg_interval_to_secs(i);
+impl std::fmt::Display for IntervalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntervalError::Negative => write!(f, "Interval cannot be negative"),
+            IntervalError::Overflow => write!(f, "Interval is out of range"),
+            IntervalError::MonthsNotExact => write!(
+                f,
+                "Interval contains months which cannot be converted to an exact duration"
+            ),
+        }
+    }
+}
 
-    chrono::Duration::from_std(std::time::Duration::from_secs(
-        secs.try_into().unwrap_or_default(),
-    ))
-    .unwrap_or_default()
+impl std::error::Error for IntervalError {}
+
+/// Returns the total number of microseconds in the interval
+///
+/// If ``approx_months`` is set, months are treated as ``DAYS_PER_APPROX_MONTH`` days, otherwise intervals containing months are rejected
+pub fn try_pg_interval_to_micros(
+    i: &PgInterval,
+    approx_months: bool,
+) -> Result<i64, IntervalError> {
+    if i.months != 0 && !approx_months {
+        return Err(IntervalError::MonthsNotExact);
+    }
+
+    let days = (i.months as i64)
+        .checked_mul(DAYS_PER_APPROX_MONTH)
+        .and_then(|d| d.checked_add(i.days as i64))
+        .ok_or(IntervalError::Overflow)?;
+
+    days.checked_mul(MICROS_PER_DAY)
+        .and_then(|m| m.checked_add(i.microseconds))
+        .ok_or(IntervalError::Overflow)
 }
 
-pub fn secs_to_pg_interval(secs: i64) -> PgInterval {
+/// Returns the total number of whole seconds in the interval
+pub fn try_pg_interval_to_secs(i: &PgInterval, approx_months: bool) -> Result<i64, IntervalError> {
+    Ok(try_pg_interval_to_micros(i, approx_months)? / MICROS_PER_SEC)
+}
+
+/// Converts the interval to a ``std::time::Duration``, erroring on negative intervals
+pub fn try_pg_interval_to_std_duration(
+    i: &PgInterval,
+    approx_months: bool,
+) -> Result<std::time::Duration, IntervalError> {
+    let micros = try_pg_interval_to_micros(i, approx_months)?;
+
+    if micros < 0 {
+        return Err(IntervalError::Negative);
+    }
+
+    Ok(std::time::Duration::from_micros(micros as u64))
+}
+
+/// Converts the interval to a ``chrono::Duration``, negative intervals are preserved
+pub fn try_pg_interval_to_chrono_duration(
+    i: &PgInterval,
+    approx_months: bool,
+) -> Result<chrono::Duration, IntervalError> {
+    Ok(chrono::Duration::microseconds(try_pg_interval_to_micros(
+        i,
+        approx_months,
+    )?))
+}
+
+/// Converts a number of microseconds to a normalized interval (no months, whole days split out)
+pub fn micros_to_pg_interval(micros: i64) -> PgInterval {
+    // i64::MAX microseconds is ~106 million days so this always fits in an i32
     PgInterval {
-        microseconds: secs * 1000000,
-        days: (secs / 86400) as i32,
-        months: (secs / 2628000) as i32,
+        months: 0,
+        days: (micros / MICROS_PER_DAY) as i32,
+        microseconds: micros % MICROS_PER_DAY,
     }
 }
 
-pub fn chrono_duration_to_pg_interval(d: chrono::Duration) -> PgInterval {
-    let secs = d.num_seconds();
+/// Converts a number of seconds to a normalized interval
+pub fn try_secs_to_pg_interval(secs: i64) -> Result<PgInterval, IntervalError> {
+    let days: i32 = (secs / SECS_PER_DAY)
+        .try_into()
+        .map_err(|_| IntervalError::Overflow)?;
+
+    Ok(PgInterval {
+        months: 0,
+        days,
+        microseconds: (secs % SECS_PER_DAY) * MICROS_PER_SEC,
+    })
+}
+
+/// Converts a ``std::time::Duration`` to a normalized interval
+pub fn try_std_duration_to_pg_interval(
+    d: std::time::Duration,
+) -> Result<PgInterval, IntervalError> {
+    let micros: i64 = d
+        .as_micros()
+        .try_into()
+        .map_err(|_| IntervalError::Overflow)?;
+
+    Ok(micros_to_pg_interval(micros))
+}
+
+/// Converts a ``chrono::Duration`` to a normalized interval
+pub fn try_chrono_duration_to_pg_interval(
+    d: chrono::Duration,
+) -> Result<PgInterval, IntervalError> {
+    Ok(micros_to_pg_interval(
+        d.num_microseconds().ok_or(IntervalError::Overflow)?,
+    ))
+}
+
+/// Legacy conversion kept for the deprecated functions
+///
+/// Every term is at most ~2^53 in magnitude so the sum cannot overflow an i64
+fn legacy_pg_interval_to_secs(i: &PgInterval) -> i64 {
+    i.microseconds / MICROS_PER_SEC
+        + i.days as i64 * SECS_PER_DAY
+        + i.months as i64 * LEGACY_SECS_PER_MONTH
+}
+
+#[deprecated(note = "use try_pg_interval_to_secs instead")]
+pub fn pg_interval_to_secs(i: PgInterval) -> i64 {
+    legacy_pg_interval_to_secs(&i)
+}
+
+#[deprecated(note = "use try_pg_interval_to_chrono_duration instead")]
+pub fn pg_interval_to_chrono_duration(i: PgInterval) -> chrono::Duration {
+    let secs = legacy_pg_interval_to_secs(&i).clamp(0, chrono::Duration::MAX.num_seconds());
+    chrono::Duration::seconds(secs)
+}
 
-    secs_to_pg_interval(secs)
+#[deprecated(note = "use try_secs_to_pg_interval instead")]
+pub fn secs_to_pg_interval(secs: i64) -> PgInterval {
+    try_secs_to_pg_interval(secs).unwrap_or(PgInterval {
+        months: 0,
+        days: if secs < 0 { i32::MIN } else { i32::MAX },
+        microseconds: 0,
+    })
 }
 
+#[deprecated(note = "use try_chrono_duration_to_pg_interval instead")]
+pub fn chrono_duration_to_pg_interval(d: chrono::Duration) -> PgInterval {
+    #[allow(deprecated)]
+    secs_to_pg_interval(d.num_seconds())
+}
+
+#[deprecated(note = "use try_secs_to_pg_interval instead")]
 pub fn secs_to_pg_interval_u64(secs: u64) -> PgInterval {
-    // Check if the value is too large to fit in an i64
-    if secs > i64::MAX as u64 {
-        // If it is, return the maximum value
-        return PgInterval {
-            microseconds: i64::MAX,
-            days: i32::MAX,
+    #[allow(deprecated)]
+    secs_to_pg_interval(secs.try_into().unwrap_or(i64::MAX))
+}
+
+pub fn parse_pg_interval(i: PgInterval) -> String {
+    let secs = legacy_pg_interval_to_secs(&i)
+        .try_into()
+        .unwrap_or_default();
+    format!("{:?}", std::time::Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic spread of values covering small, large, negative and boundary inputs
+    fn sample_values(max: i64) -> Vec<i64> {
+        let mut values = vec![0, 1, -1, 59, 60, 86399, 86400, 86401, max, -max];
+
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        for _ in 0..2000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            // Vary the magnitude so small values are sampled as often as large ones
+            let magnitude = 1 + (state % 63) as u32;
+            let v = (state >> 1) as i64 & ((1i64 << magnitude.min(62)) - 1);
+            let v = if v > max { v % max } else { v };
+            values.push(if state & 1 == 0 { v } else { -v });
+        }
+
+        values
+    }
+
+    #[test]
+    fn secs_round_trip() {
+        let max = i64::MAX / MICROS_PER_SEC;
+        for secs in sample_values(max) {
+            let interval = try_secs_to_pg_interval(secs).unwrap();
+            assert_eq!(interval.months, 0);
+            assert_eq!(
+                try_pg_interval_to_secs(&interval, false),
+                Ok(secs),
+                "{}",
+                secs
+            );
+        }
+    }
+
+    #[test]
+    fn micros_round_trip() {
+        for micros in sample_values(i64::MAX) {
+            let interval = micros_to_pg_interval(micros);
+            assert_eq!(interval.months, 0);
+            assert!(interval.microseconds.abs() < MICROS_PER_DAY);
+            assert_eq!(try_pg_interval_to_micros(&interval, false), Ok(micros));
+        }
+    }
+
+    #[test]
+    fn chrono_round_trip() {
+        for micros in sample_values(i64::MAX) {
+            let d = chrono::Duration::microseconds(micros);
+            let interval = try_chrono_duration_to_pg_interval(d).unwrap();
+            assert_eq!(try_pg_interval_to_chrono_duration(&interval, false), Ok(d));
+        }
+    }
+
+    #[test]
+    fn std_round_trip() {
+        for micros in sample_values(i64::MAX) {
+            let d = std::time::Duration::from_micros(micros.unsigned_abs().min(i64::MAX as u64));
+            let interval = try_std_duration_to_pg_interval(d).unwrap();
+            assert_eq!(try_pg_interval_to_std_duration(&interval, false), Ok(d));
+        }
+    }
+
+    #[test]
+    fn rejects_unrepresentable_intervals() {
+        let months = PgInterval {
+            months: 1,
+            days: 0,
+            microseconds: 0,
+        };
+        assert_eq!(
+            try_pg_interval_to_secs(&months, false),
+            Err(IntervalError::MonthsNotExact)
+        );
+        assert_eq!(
+            try_pg_interval_to_secs(&months, true),
+            Ok(DAYS_PER_APPROX_MONTH * SECS_PER_DAY)
+        );
+
+        let huge = PgInterval {
             months: i32::MAX,
+            days: i32::MAX,
+            microseconds: i64::MAX,
         };
+        assert_eq!(
+            try_pg_interval_to_micros(&huge, true),
+            Err(IntervalError::Overflow)
+        );
+
+        let negative = micros_to_pg_interval(-1);
+        assert_eq!(
+            try_pg_interval_to_std_duration(&negative, false),
+            Err(IntervalError::Negative)
+        );
+
+        assert_eq!(
+            try_secs_to_pg_interval(i64::MAX),
+            Err(IntervalError::Overflow)
+        );
+        assert_eq!(
+            try_std_duration_to_pg_interval(std::time::Duration::MAX),
+            Err(IntervalError::Overflow)
+        );
     }
 
-    secs_to_pg_interval(secs as i64)
-}
+    #[test]
+    #[allow(deprecated)]
+    fn legacy_conversions_keep_average_months() {
+        let interval = PgInterval {
+            months: 2,
+            days: 3,
+            microseconds: 4_500_000,
+        };
 
-pub fn parse_pg_interval(i: PgInterval) -> String {
-    let seconds = pg_interval_to_secs(i);
+        let secs = 4 + 3 * SECS_PER_DAY + 2 * LEGACY_SECS_PER_MONTH;
+        assert_eq!(pg_interval_to_secs(interval), secs);
+        assert_eq!(
+            pg_interval_to_chrono_duration(interval),
+            chrono::Duration::seconds(secs)
+        );
+        assert_eq!(
+            parse_pg_interval(interval),
+            format!("{:?}", std::time::Duration::from_secs(secs as u64))
+        );
 
-    let dur = std::time::Duration::from_secs(seconds.try_into().unwrap_or_default());
+        // Negative intervals are clamped to zero as before
+        assert_eq!(
+            pg_interval_to_chrono_duration(micros_to_pg_interval(-MICROS_PER_DAY)),
+            chrono::Duration::zero()
+        );
 
-    format!("{:?}", dur)
+        let huge = PgInterval {
+            months: i32::MAX,
+            days: i32::MAX,
+            microseconds: i64::MAX,
+        };
+        assert_eq!(
+            pg_interval_to_secs(huge),
+            i64::MAX / MICROS_PER_SEC
+                + i32::MAX as i64 * SECS_PER_DAY
+                + i32::MAX as i64 * LEGACY_SECS_PER_MONTH
+        );
+    }
 }
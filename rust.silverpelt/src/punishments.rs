@@ -1,11 +1,27 @@
+pub mod reversal;
+pub mod rules;
+
 use antiraid_types::punishments::{
     Punishment, PunishmentCreate, PunishmentState, PunishmentTarget,
 };
 use std::str::FromStr;
 
 use crate::{
-    ar_event::DispatchEventData,
-    pginterval::pg_interval_to_secs,
+    ar_event::custom_events::PUNISHMENT_EXPIRE_EVENT,
+    ar_event::{AntiraidEventOperations, CustomEventBuilder, DispatchEventData},
+    create_validation::{
+        normalize_src, validate_duration, validate_reason, CreateLimits, CreateValidation,
+        CreateValidationError,
+    },
+    data::Data,
+    decode::{decode_lossy, parse_snowflake_column, DecodeFailure},
+    handle_log::{
+        append_handle_log, parse_handle_log, push_handle_log_entry, HandleLogEntry,
+        InvalidHandleLogEntry,
+    },
+    pagination::{into_page, Cursor, ListOptions, ListPage},
+    pginterval::try_pg_interval_to_std_duration,
+    stings::{ExpiryAction, EXPIRY_BATCH_SIZE},
 };
 use sqlx::{postgres::types::PgInterval, Row};
 
@@ -18,13 +34,38 @@ pub trait PunishmentOperations: Send + Sync {
         id: sqlx::types::Uuid,
     ) -> Result<Option<Punishment>, crate::Error>;
 
-    /// Lists punishments for a guild paginated based on page number
+    /// Lists punishments for a guild paginated based on page number (20 punishments per page)
+    ///
+    /// Pages share the order of ``list_with_options`` and ``list_lossy``
     async fn list(
         db: impl sqlx::PgExecutor<'_>,
         guild_id: serenity::all::GuildId,
         page: usize,
     ) -> Result<Vec<Punishment>, crate::Error>;
 
+    /// Like ``list`` but skips rows which fail to decode, returning them separately instead of failing the whole page
+    async fn list_lossy(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        page: usize,
+    ) -> Result<(Vec<Punishment>, Vec<DecodeFailure>), crate::Error> {
+        let rec = fetch_page_rows(db, guild_id, page).await?;
+
+        Ok(decode_lossy(
+            rec,
+            "punishment",
+            |row| row.id.to_string(),
+            PunishmentRow::into_punishment,
+        ))
+    }
+
+    /// Lists punishments for a guild using keyset pagination, newest first
+    async fn list_with_options(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        opts: &ListOptions,
+    ) -> Result<ListPage<Punishment>, crate::Error>;
+
     /// Get all expired punishments
     async fn get_expired(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<Punishment>, crate::Error>;
 
@@ -34,6 +75,17 @@ pub trait PunishmentOperations: Send + Sync {
         ctx: serenity::all::Context,
         dispatch_event_data: &DispatchEventData,
     ) -> Result<(), crate::Error>;
+
+    /// Atomically appends an entry to the handle log of a punishment, returning false if the punishment does not exist
+    async fn append_handle_log(
+        db: impl sqlx::PgExecutor<'_>,
+        id: sqlx::types::Uuid,
+        guild_id: serenity::all::GuildId,
+        entry: &HandleLogEntry,
+    ) -> Result<bool, crate::Error>;
+
+    /// Returns the parsed handle log entries along with the entries which failed to parse
+    fn parsed_handle_log(&self) -> (Vec<HandleLogEntry>, Vec<InvalidHandleLogEntry>);
 }
 
 #[derive(sqlx::FromRow)]
@@ -57,18 +109,15 @@ impl PunishmentRow {
         Ok(Punishment {
             id: self.id,
             src: self.src,
-            guild_id: self.guild_id.parse()?,
+            guild_id: parse_snowflake_column(&self.guild_id, "guild_id", self.id)?,
             punishment: self.punishment,
-            creator: PunishmentTarget::from_str(&self.creator)?,
-            target: PunishmentTarget::from_str(&self.target)?,
+            creator: parse_snowflake_column(&self.creator, "creator", self.id)?,
+            target: parse_snowflake_column(&self.target, "target", self.id)?,
             state: PunishmentState::from_str(&self.state)?,
             handle_log: self.handle_log,
             created_at: self.created_at,
             duration: match self.duration {
-                Some(d) => {
-                    let secs = pg_interval_to_secs(d);
-                    Some(std::time::Duration::from_secs(secs.try_into()?))
-                }
+                Some(d) => Some(try_pg_interval_to_std_duration(&d, true)?),
                 None => None,
             },
             reason: self.reason,
@@ -77,6 +126,34 @@ impl PunishmentRow {
     }
 }
 
+/// Fetches a page (20 punishments per page) of raw rows for a guild
+async fn fetch_page_rows(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: serenity::all::GuildId,
+    page: usize,
+) -> Result<Vec<PunishmentRow>, crate::Error> {
+    const PAGE_SIZE: i64 = 20; // 20 punishments per page
+
+    // Page 0 is treated as the first page, pages past the end of the table are empty
+    let Some(offset) = i64::try_from(page.saturating_sub(1))
+        .ok()
+        .and_then(|page| page.checked_mul(PAGE_SIZE))
+    else {
+        return Ok(Vec::new());
+    };
+
+    let rec: Vec<PunishmentRow> = sqlx::query_as(
+        "SELECT id, src, guild_id, punishment, creator, target, state, handle_log, created_at, duration, reason, data FROM punishments WHERE guild_id = $1 ORDER BY created_at DESC, id DESC OFFSET $2 LIMIT $3",
+    )
+    .bind(guild_id.to_string())
+    .bind(offset)
+    .bind(PAGE_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    Ok(rec)
+}
+
 impl PunishmentOperations for Punishment {
     /// Returns a punishment by ID
     async fn get(
@@ -102,48 +179,53 @@ impl PunishmentOperations for Punishment {
         }
     }
 
-    /// Lists punishments for a guild paginated based on page number
+    /// Lists punishments for a guild paginated based on page number (20 punishments per page)
+    ///
+    /// Pages share the order of ``list_with_options`` and ``list_lossy``
     async fn list(
         db: impl sqlx::PgExecutor<'_>,
         guild_id: serenity::all::GuildId,
         page: usize,
     ) -> Result<Vec<Punishment>, crate::Error> {
-        const PAGE_SIZE: i64 = 20; // 20 punishments per page
+        fetch_page_rows(db, guild_id, page)
+            .await?
+            .into_iter()
+            .map(PunishmentRow::into_punishment)
+            .collect()
+    }
+
+    /// Lists punishments for a guild using keyset pagination, newest first
+    async fn list_with_options(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        opts: &ListOptions,
+    ) -> Result<ListPage<Punishment>, crate::Error> {
+        let (page_size, cursor) = opts.parse()?;
 
         let rec: Vec<PunishmentRow> = sqlx::query_as(
-            "SELECT id, src, guild_id, punishment, creator, target, state, handle_log, created_at, duration, reason, data FROM punishments WHERE guild_id = $1 ORDER BY created_at DESC OFFSET $2 LIMIT $3",
+            "SELECT id, src, guild_id, punishment, creator, target, state, handle_log, created_at, duration, reason, data FROM punishments WHERE guild_id = $1 AND ($2::timestamptz IS NULL OR (created_at, id) < ($2, $3)) ORDER BY created_at DESC, id DESC LIMIT $4",
         )
         .bind(guild_id.to_string())
-        .bind((page as i64 - 1) * PAGE_SIZE)
-        .bind(PAGE_SIZE)
+        .bind(cursor.map(|c| c.created_at))
+        .bind(cursor.map(|c| c.id))
+        .bind(page_size + 1)
         .fetch_all(db)
         .await?;
 
-        let mut punishments = Vec::new();
+        let mut punishments = Vec::with_capacity(rec.len());
 
         for row in rec {
-            let punishment = row.into_punishment()?;
-            punishments.push(punishment);
+            punishments.push(row.into_punishment()?);
         }
 
-        Ok(punishments)
+        Ok(into_page(punishments, page_size, |item| Cursor {
+            created_at: item.created_at,
+            id: item.id,
+        }))
     }
 
     async fn get_expired(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<Punishment>, crate::Error> {
-        let rec: Vec<PunishmentRow> = sqlx::query_as(
-            "SELECT id, src, guild_id, punishment, creator, target, state, handle_log, created_at, duration, reason, data FROM punishments WHERE duration IS NOT NULL AND state = 'active' AND (created_at + duration) < NOW()",
-        )
-        .fetch_all(db)
-        .await?;
-
-        let mut punishments = Vec::new();
-
-        for row in rec {
-            let punishment = row.into_punishment()?;
-            punishments.push(punishment);
-        }
-
-        Ok(punishments)
+        select_expired(db, &[], None).await
     }
 
     /// Dispatch a PunishmentCreate event
@@ -154,6 +236,21 @@ impl PunishmentOperations for Punishment {
     ) -> Result<(), crate::Error> {
         Ok(()) // disabled as builtins+stings are being rewritten in luau
     }
+
+    /// Atomically appends an entry to the handle log of a punishment, returning false if the punishment does not exist
+    async fn append_handle_log(
+        db: impl sqlx::PgExecutor<'_>,
+        id: sqlx::types::Uuid,
+        guild_id: serenity::all::GuildId,
+        entry: &HandleLogEntry,
+    ) -> Result<bool, crate::Error> {
+        append_handle_log(db, "punishments", guild_id, id, entry).await
+    }
+
+    /// Returns the parsed handle log entries along with the entries which failed to parse
+    fn parsed_handle_log(&self) -> (Vec<HandleLogEntry>, Vec<InvalidHandleLogEntry>) {
+        parse_handle_log(&self.handle_log)
+    }
 }
 
 #[allow(async_fn_in_trait)]
@@ -181,32 +278,99 @@ pub trait PunishmentCreateOperations: Send + Sync {
     ) -> Result<sqlx::types::Uuid, crate::Error>;
 }
 
+impl CreateValidation for PunishmentCreate {
+    /// Trims the reason and lowercases the src, an empty src is dropped
+    fn normalize(&mut self) {
+        normalize_src(&mut self.src);
+
+        let reason = self.reason.trim();
+        if reason.len() != self.reason.len() {
+            self.reason = reason.to_string();
+        }
+
+        let punishment = self.punishment.trim();
+        if punishment.len() != self.punishment.len() {
+            self.punishment = punishment.to_string();
+        }
+    }
+
+    /// Validates the punishment against ``limits``
+    fn validate_with(&self, limits: &CreateLimits) -> Result<(), CreateValidationError> {
+        if self.punishment.is_empty() {
+            return Err(CreateValidationError::new(
+                "punishment",
+                "must not be empty",
+            ));
+        }
+
+        if self.reason.is_empty() && self.src.is_none() {
+            return Err(CreateValidationError::new(
+                "reason",
+                "must be set if the punishment has no src",
+            ));
+        }
+
+        validate_reason("reason", Some(&self.reason), limits)?;
+        validate_duration(self.duration, limits)?;
+
+        if let (PunishmentTarget::User(creator), PunishmentTarget::User(target)) =
+            (&self.creator, &self.target)
+        {
+            if creator == target {
+                return Err(CreateValidationError::new(
+                    "target",
+                    "must not be the same user as the creator",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a new Punishment without dispatching it as an event, validating it against ``limits``
+///
+/// The punishment is normalized first, ``PunishmentCreateOperations::create_without_dispatch`` uses the default limits
+pub async fn create_punishment_with_limits(
+    db: impl sqlx::PgExecutor<'_>,
+    mut punishment: PunishmentCreate,
+    limits: &CreateLimits,
+) -> Result<Punishment, crate::Error> {
+    punishment.normalize();
+    punishment.validate_with(limits)?;
+
+    let ret_data = sqlx::query(
+        r#"
+        INSERT INTO punishments (src, guild_id, punishment, creator, target, handle_log, duration, reason, data, state)
+        VALUES ($1, $2, $3, $4, $5, $6, make_interval(secs => $7), $8, $9, $10) RETURNING id, created_at
+        "#,
+    )
+    .bind(&punishment.src)
+    .bind(punishment.guild_id.to_string())
+    .bind(&punishment.punishment)
+    .bind(punishment.creator.to_string())
+    .bind(punishment.target.to_string())
+    .bind(&punishment.handle_log)
+    .bind(punishment.duration.map(|d| d.as_secs() as f64))
+    .bind(&punishment.reason)
+    .bind(&punishment.data)
+    .bind(punishment.state.to_string())
+    .fetch_one(db)
+    .await?;
+
+    Ok(punishment.to_punishment(ret_data.try_get("id")?, ret_data.try_get("created_at")?))
+}
+
 impl PunishmentCreateOperations for PunishmentCreate {
     /// Creates a new Punishment without dispatching it as an event
+    ///
+    /// The punishment is normalized and validated against the default ``CreateLimits``, see
+    /// ``create_punishment_with_limits``
     async fn create_without_dispatch(
         self,
         db: impl sqlx::PgExecutor<'_>,
     ) -> Result<Punishment, crate::Error> {
-        let ret_data = sqlx::query(
-            r#"
-            INSERT INTO punishments (src, guild_id, punishment, creator, target, handle_log, duration, reason, data, state)
-            VALUES ($1, $2, $3, $4, $5, $6, make_interval(secs => $7), $8, $9, $10) RETURNING id, created_at
-            "#,
-        )
-        .bind(&self.src)
-        .bind(self.guild_id.to_string())
-        .bind(&self.punishment)
-        .bind(self.creator.to_string())
-        .bind(self.target.to_string())
-        .bind(&self.handle_log)
-        .bind(self.duration.map(|d| d.as_secs() as f64))
-        .bind(&self.reason)
-        .bind(&self.data)
-        .bind(self.state.to_string())
-        .fetch_one(db)
-        .await?;
-
-        Ok(self.to_punishment(ret_data.try_get("id")?, ret_data.try_get("created_at")?))
+        create_punishment_with_limits(db, self, &CreateLimits::default()).await
     }
 
     /// Creates a new Punishment and dispatches it as an event in one go
@@ -238,3 +402,251 @@ impl PunishmentCreateOperations for PunishmentCreate {
         Ok(sid)
     }
 }
+
+/// Selects the expired, still active punishments oldest first, skipping kinds in ``exclude_kinds``
+///
+/// With ``lock_batch`` at most that many punishments are returned, locked with ``FOR UPDATE SKIP LOCKED``
+async fn select_expired(
+    db: impl sqlx::PgExecutor<'_>,
+    exclude_kinds: &[&str],
+    lock_batch: Option<i64>,
+) -> Result<Vec<Punishment>, crate::Error> {
+    let mut sql = String::from(
+        "SELECT id, src, guild_id, punishment, creator, target, state, handle_log, created_at, duration, reason, data FROM punishments WHERE duration IS NOT NULL AND state = 'active' AND (created_at + duration) < NOW() AND NOT (punishment = ANY($1)) ORDER BY created_at ASC",
+    );
+
+    if lock_batch.is_some() {
+        sql.push_str(" LIMIT $2 FOR UPDATE SKIP LOCKED");
+    }
+
+    let mut query = sqlx::query_as::<_, PunishmentRow>(&sql).bind(exclude_kinds);
+
+    if let Some(batch) = lock_batch {
+        query = query.bind(batch);
+    }
+
+    let rec = query.fetch_all(db).await?;
+
+    let mut punishments = Vec::with_capacity(rec.len());
+
+    for row in rec {
+        punishments.push(row.into_punishment()?);
+    }
+
+    Ok(punishments)
+}
+
+/// Handles all expired punishments in batches, returning the punishments handled
+///
+/// Punishments are selected with ``FOR UPDATE SKIP LOCKED`` so this can safely run from multiple processes at once.
+/// Kinds in ``reversal::REVERSIBLE_KINDS`` are skipped, they are handled by ``reversal::mark_reversed`` once undone
+pub async fn run_expiry_cycle_without_dispatch(
+    pool: &sqlx::PgPool,
+    action: ExpiryAction,
+) -> Result<Vec<Punishment>, crate::Error> {
+    let mut handled = Vec::new();
+
+    loop {
+        let mut tx = pool.begin().await?;
+
+        let expired = select_expired(
+            &mut *tx,
+            reversal::REVERSIBLE_KINDS,
+            Some(EXPIRY_BATCH_SIZE),
+        )
+        .await?;
+
+        let batch_len = expired.len();
+
+        for mut punishment in expired {
+            punishment.state = match action {
+                ExpiryAction::Handle => PunishmentState::Handled,
+                ExpiryAction::Void => PunishmentState::Voided,
+            };
+
+            push_handle_log_entry(
+                &mut punishment.handle_log,
+                &HandleLogEntry::system(
+                    "expired",
+                    serde_json::json!({ "new_state": punishment.state.to_string() }),
+                ),
+            )?;
+
+            sqlx::query(
+                "UPDATE punishments SET state = $1, handle_log = $2 WHERE id = $3 AND guild_id = $4",
+            )
+            .bind(punishment.state.to_string())
+            .bind(&punishment.handle_log)
+            .bind(punishment.id)
+            .bind(punishment.guild_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+
+            handled.push(punishment);
+        }
+
+        tx.commit().await?;
+
+        if batch_len < EXPIRY_BATCH_SIZE as usize {
+            break;
+        }
+    }
+
+    Ok(handled)
+}
+
+/// Handles all expired punishments (see ``run_expiry_cycle_without_dispatch``) and dispatches an
+/// ``AR/PunishmentExpire`` event for each, returning the number of punishments handled
+///
+/// Dispatch failures are logged and do not fail the cycle as the punishments were already updated
+pub async fn run_expiry_cycle(
+    pool: &sqlx::PgPool,
+    ctx: serenity::all::Context,
+    dispatch_event_data: &DispatchEventData,
+    action: ExpiryAction,
+) -> Result<usize, crate::Error> {
+    let handled = run_expiry_cycle_without_dispatch(pool, action).await?;
+    let data = ctx.data::<Data>();
+
+    for punishment in handled.iter() {
+        if let Err(e) = dispatch_expire_event(&data, punishment, dispatch_event_data).await {
+            log::error!(
+                "Failed to dispatch {} event for guild {}: {}",
+                PUNISHMENT_EXPIRE_EVENT,
+                punishment.guild_id,
+                e
+            );
+        }
+    }
+
+    Ok(handled.len())
+}
+
+/// Dispatches an ``AR/PunishmentExpire`` event for a punishment
+pub async fn dispatch_expire_event(
+    data: &Data,
+    punishment: &Punishment,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    let event = CustomEventBuilder::new(PUNISHMENT_EXPIRE_EVENT, "(Anti-Raid) Punishment Expire")?
+        .field("punishment", punishment)?
+        .build()?;
+
+    event
+        .dispatch_to_template_worker_and_nowait(data, punishment.guild_id, dispatch_event_data)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, PUNISHMENTS};
+    use serenity::all::{GuildId, UserId};
+
+    #[sqlx::test(migrations = false)]
+    async fn list_page_zero_is_the_first_page(pool: sqlx::PgPool) {
+        create_tables(&pool, &[PUNISHMENTS]).await;
+
+        sqlx::query(
+            "INSERT INTO punishments (guild_id, punishment, creator, target, created_at) SELECT '1', 'warn', $1, $2, NOW() - make_interval(secs => g) FROM generate_series(1, 25) g",
+        )
+        .bind(PunishmentTarget::System.to_string())
+        .bind(PunishmentTarget::User(UserId::new(2)).to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let guild = GuildId::new(1);
+        let ids = |punishments: Vec<Punishment>| {
+            punishments.into_iter().map(|p| p.id).collect::<Vec<_>>()
+        };
+
+        let page_0 = ids(Punishment::list(&pool, guild, 0).await.unwrap());
+        let page_1 = ids(Punishment::list(&pool, guild, 1).await.unwrap());
+        assert_eq!(page_0.len(), 20);
+        assert_eq!(page_0, page_1);
+
+        let page_2 = ids(Punishment::list(&pool, guild, 2).await.unwrap());
+        assert_eq!(page_2.len(), 5);
+        assert!(page_2.iter().all(|id| !page_1.contains(id)));
+
+        assert!(Punishment::list(&pool, guild, 3).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn list_pages_break_created_at_ties_by_id(pool: sqlx::PgPool) {
+        create_tables(&pool, &[PUNISHMENTS]).await;
+
+        sqlx::query(
+            "INSERT INTO punishments (guild_id, punishment, creator, target, created_at) SELECT '1', 'warn', $1, $2, '2024-01-01T00:00:00Z' FROM generate_series(1, 25)",
+        )
+        .bind(PunishmentTarget::System.to_string())
+        .bind(PunishmentTarget::User(UserId::new(2)).to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let guild = GuildId::new(1);
+        let ids = |punishments: &[Punishment]| {
+            punishments.iter().map(|p| p.id).collect::<Vec<_>>()
+        };
+
+        // Transactions are not ``Copy`` and must be usable as the executor
+        let mut tx = pool.begin().await.unwrap();
+
+        let mut listed = Vec::new();
+        for page in 1..=2 {
+            let strict = Punishment::list(&mut *tx, guild, page).await.unwrap();
+            let (lossy, failures) = Punishment::list_lossy(&mut *tx, guild, page).await.unwrap();
+            assert!(failures.is_empty());
+            assert_eq!(ids(&strict), ids(&lossy));
+
+            listed.extend(ids(&strict));
+        }
+
+        let mut sorted = listed.clone();
+        sorted.sort();
+        sorted.reverse();
+        sorted.dedup();
+        assert_eq!(listed.len(), 25);
+        assert_eq!(listed, sorted);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn list_lossy_skips_bad_rows(pool: sqlx::PgPool) {
+        create_tables(&pool, &[PUNISHMENTS]).await;
+
+        let guild = GuildId::new(1);
+
+        let bad: uuid::Uuid = sqlx::query_scalar(
+            "INSERT INTO punishments (guild_id, punishment, creator, target) VALUES ('1', 'warn', $1, 'garbage') RETURNING id",
+        )
+        .bind(PunishmentTarget::System.to_string())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        for _ in 0..3 {
+            sqlx::query(
+                "INSERT INTO punishments (guild_id, punishment, creator, target) VALUES ('1', 'warn', $1, $2)",
+            )
+            .bind(PunishmentTarget::System.to_string())
+            .bind(PunishmentTarget::User(UserId::new(2)).to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        assert!(Punishment::list(&pool, guild, 1).await.is_err());
+
+        let (punishments, failures) = Punishment::list_lossy(&pool, guild, 1).await.unwrap();
+        assert_eq!(punishments.len(), 3);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].id, bad.to_string());
+        assert!(
+            failures[0].error.contains("target"),
+            "{}",
+            failures[0].error
+        );
+    }
+}
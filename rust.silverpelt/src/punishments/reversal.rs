@@ -0,0 +1,365 @@
+use antiraid_types::punishments::{Punishment, PunishmentState};
+use serenity::all::{GuildId, RoleId};
+
+use super::PunishmentRow;
+use crate::decode::{decode_lossy, DecodeFailure};
+use crate::handle_log::{HandleLogEntry, HANDLE_LOG_APPEND_EXPR};
+use crate::stings::EXPIRY_BATCH_SIZE;
+
+/// What needs to be done on Discord to undo an expired punishment
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReversalAction {
+    Unban,
+    RemoveTimeout,
+    RemoveRole(RoleId),
+    /// Nothing to undo (e.g. kicks)
+    None,
+}
+
+/// Why the reversal action of a punishment could not be determined
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ReversalError {
+    /// The punishment kind is not known
+    UnknownPunishment(String),
+    /// The punishment needs a field in its ``data`` which is missing or invalid
+    InvalidData { field: String, reason: String },
+}
+
+impl std::fmt::Display for ReversalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReversalError::UnknownPunishment(kind) => {
+                write!(f, "Unknown punishment kind: {}", kind)
+            }
+            ReversalError::InvalidData { field, reason } => {
+                write!(f, "Invalid punishment data field {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReversalError {}
+
+/// Punishment kinds that have something to undo on Discord once they expire
+///
+/// ``punishments::run_expiry_cycle`` leaves these to the reversal flow (``plan_reversals`` then ``mark_reversed``),
+/// otherwise it would mark them handled before they were ever reversed
+pub const REVERSIBLE_KINDS: &[&str] = &["ban", "tempban", "timeout", "mute", "role", "add_role"];
+
+pub trait PunishmentReversalOperations {
+    /// Returns the action needed to undo the punishment, derived from its kind and ``data``
+    fn reversal_action(&self) -> Result<ReversalAction, ReversalError>;
+}
+
+impl PunishmentReversalOperations for Punishment {
+    fn reversal_action(&self) -> Result<ReversalAction, ReversalError> {
+        match self.punishment.as_str() {
+            "ban" | "tempban" => Ok(ReversalAction::Unban),
+            "timeout" | "mute" => Ok(ReversalAction::RemoveTimeout),
+            "kick" | "warn" => Ok(ReversalAction::None),
+            "role" | "add_role" => {
+                let Some(role_id) = self.data.as_ref().and_then(|d| d.get("role_id")) else {
+                    return Err(ReversalError::InvalidData {
+                        field: "role_id".to_string(),
+                        reason: "missing".to_string(),
+                    });
+                };
+
+                let role_id = match role_id {
+                    serde_json::Value::String(s) => s.parse::<RoleId>().ok(),
+                    serde_json::Value::Number(n) => n.as_u64().filter(|n| *n != 0).map(RoleId::new),
+                    _ => None,
+                };
+
+                match role_id {
+                    Some(role_id) => Ok(ReversalAction::RemoveRole(role_id)),
+                    None => Err(ReversalError::InvalidData {
+                        field: "role_id".to_string(),
+                        reason: "not a valid role ID".to_string(),
+                    }),
+                }
+            }
+            kind => Err(ReversalError::UnknownPunishment(kind.to_string())),
+        }
+    }
+}
+
+/// The reversals needed for all expired punishments
+pub struct ReversalPlan {
+    pub entries: Vec<(Punishment, Result<ReversalAction, ReversalError>)>,
+    /// Expired punishments whose rows could not be decoded at all
+    pub decode_failures: Vec<DecodeFailure>,
+}
+
+/// Fetches the expired, still active punishments of the ``REVERSIBLE_KINDS`` in batches and computes their reversal
+/// actions
+///
+/// Punishments whose action cannot be determined get an error entry instead of failing the whole plan
+pub async fn plan_reversals(db: &sqlx::PgPool) -> Result<ReversalPlan, crate::Error> {
+    let mut plan = ReversalPlan {
+        entries: Vec::new(),
+        decode_failures: Vec::new(),
+    };
+
+    let mut cursor: Option<(chrono::DateTime<chrono::Utc>, uuid::Uuid)> = None;
+
+    loop {
+        let recs: Vec<PunishmentRow> = sqlx::query_as(
+            "SELECT id, src, guild_id, punishment, creator, target, state, handle_log, created_at, duration, reason, data FROM punishments WHERE duration IS NOT NULL AND state = 'active' AND (created_at + duration) < NOW() AND punishment = ANY($4) AND ($1::timestamptz IS NULL OR (created_at, id) > ($1, $2)) ORDER BY created_at ASC, id ASC LIMIT $3",
+        )
+        .bind(cursor.map(|(created_at, _)| created_at))
+        .bind(cursor.map(|(_, id)| id))
+        .bind(EXPIRY_BATCH_SIZE)
+        .bind(REVERSIBLE_KINDS)
+        .fetch_all(db)
+        .await?;
+
+        let batch_len = recs.len();
+        cursor = recs.last().map(|rec| (rec.created_at, rec.id));
+
+        let (punishments, failures) = decode_lossy(
+            recs,
+            "punishment",
+            |rec| rec.id.to_string(),
+            PunishmentRow::into_punishment,
+        );
+
+        plan.decode_failures.extend(failures);
+
+        for punishment in punishments {
+            let action = punishment.reversal_action();
+            plan.entries.push((punishment, action));
+        }
+
+        if batch_len < EXPIRY_BATCH_SIZE as usize {
+            break;
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Marks an active punishment as handled once its reversal was carried out, appending ``entry`` to its handle log
+///
+/// Both happen in a single statement. Returns false if the punishment does not exist or is no longer active
+pub async fn mark_reversed(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: GuildId,
+    id: uuid::Uuid,
+    entry: &HandleLogEntry,
+) -> Result<bool, crate::Error> {
+    let res = sqlx::query(&format!(
+        "UPDATE punishments SET state = $4, handle_log = {} WHERE id = $2 AND guild_id = $3 AND state = $5",
+        HANDLE_LOG_APPEND_EXPR
+    ))
+    .bind(serde_json::to_value(entry)?)
+    .bind(id)
+    .bind(guild_id.to_string())
+    .bind(PunishmentState::Handled.to_string())
+    .bind(PunishmentState::Active.to_string())
+    .execute(db)
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::punishments::{run_expiry_cycle_without_dispatch, PunishmentOperations};
+    use crate::stings::ExpiryAction;
+    use crate::test_schema::{create_tables, PUNISHMENTS};
+    use antiraid_types::punishments::PunishmentTarget;
+    use serenity::all::UserId;
+
+    fn punishment(kind: &str, data: Option<serde_json::Value>) -> Punishment {
+        Punishment {
+            id: uuid::Uuid::new_v4(),
+            src: None,
+            guild_id: GuildId::new(1),
+            punishment: kind.to_string(),
+            creator: PunishmentTarget::System,
+            target: PunishmentTarget::User(UserId::new(2)),
+            state: PunishmentState::Active,
+            handle_log: serde_json::json!([]),
+            created_at: chrono::Utc::now(),
+            duration: None,
+            reason: String::new(),
+            data,
+        }
+    }
+
+    #[test]
+    fn reversal_action_of_each_kind() {
+        let role = Some(serde_json::json!({ "role_id": "3" }));
+
+        for (kind, data, action) in [
+            ("ban", None, ReversalAction::Unban),
+            ("tempban", None, ReversalAction::Unban),
+            ("timeout", None, ReversalAction::RemoveTimeout),
+            ("mute", None, ReversalAction::RemoveTimeout),
+            ("kick", None, ReversalAction::None),
+            ("warn", None, ReversalAction::None),
+            (
+                "role",
+                role.clone(),
+                ReversalAction::RemoveRole(RoleId::new(3)),
+            ),
+            ("add_role", role, ReversalAction::RemoveRole(RoleId::new(3))),
+            (
+                "role",
+                Some(serde_json::json!({ "role_id": 3 })),
+                ReversalAction::RemoveRole(RoleId::new(3)),
+            ),
+        ] {
+            assert_eq!(
+                punishment(kind, data).reversal_action(),
+                Ok(action),
+                "{}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_role_data_is_an_error() {
+        for data in [
+            None,
+            Some(serde_json::json!({})),
+            Some(serde_json::json!({ "role_id": 0 })),
+            Some(serde_json::json!({ "role_id": "abc" })),
+            Some(serde_json::json!({ "role_id": true })),
+        ] {
+            assert!(matches!(
+                punishment("role", data).reversal_action(),
+                Err(ReversalError::InvalidData { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_are_an_error() {
+        assert_eq!(
+            punishment("banish", None).reversal_action(),
+            Err(ReversalError::UnknownPunishment("banish".to_string()))
+        );
+    }
+
+    #[test]
+    fn reversible_kinds_match_reversal_actions() {
+        let data = Some(serde_json::json!({ "role_id": "3" }));
+
+        for kind in REVERSIBLE_KINDS {
+            assert_ne!(
+                punishment(kind, data.clone()).reversal_action(),
+                Ok(ReversalAction::None),
+                "{}",
+                kind
+            );
+        }
+
+        for kind in ["kick", "warn"] {
+            assert!(!REVERSIBLE_KINDS.contains(&kind));
+        }
+    }
+
+    async fn insert_expired(pool: &sqlx::PgPool, kind: &str) -> uuid::Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO punishments (guild_id, punishment, creator, target, created_at, duration, data) VALUES ('1', $1, $2, $3, NOW() - INTERVAL '2 hours', INTERVAL '1 hour', $4) RETURNING id",
+        )
+        .bind(kind)
+        .bind(PunishmentTarget::System.to_string())
+        .bind(PunishmentTarget::User(UserId::new(2)).to_string())
+        .bind(serde_json::json!({ "role_id": "3" }))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn state_of(pool: &sqlx::PgPool, id: uuid::Uuid) -> String {
+        sqlx::query_scalar("SELECT state FROM punishments WHERE id = $1")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn expiry_cycle_leaves_reversible_kinds_to_reversal(pool: sqlx::PgPool) {
+        create_tables(&pool, &[PUNISHMENTS]).await;
+
+        let mut ids = Vec::new();
+
+        for kind in [
+            "ban", "tempban", "timeout", "mute", "role", "add_role", "kick", "warn",
+        ] {
+            ids.push((kind, insert_expired(&pool, kind).await));
+        }
+
+        assert_eq!(Punishment::get_expired(&pool).await.unwrap().len(), 8);
+
+        let mut handled = run_expiry_cycle_without_dispatch(&pool, ExpiryAction::Handle)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.punishment, p.state.to_string()))
+            .collect::<Vec<_>>();
+        handled.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(
+            handled,
+            vec![
+                ("kick".to_string(), "handled".to_string()),
+                ("warn".to_string(), "handled".to_string()),
+            ]
+        );
+
+        let plan = plan_reversals(&pool).await.unwrap();
+        assert!(plan.decode_failures.is_empty());
+
+        let mut planned = plan
+            .entries
+            .iter()
+            .map(|(p, action)| (p.punishment.as_str(), action.clone().unwrap()))
+            .collect::<Vec<_>>();
+        planned.sort_by_key(|(kind, _)| *kind);
+
+        assert_eq!(
+            planned,
+            vec![
+                ("add_role", ReversalAction::RemoveRole(RoleId::new(3))),
+                ("ban", ReversalAction::Unban),
+                ("mute", ReversalAction::RemoveTimeout),
+                ("role", ReversalAction::RemoveRole(RoleId::new(3))),
+                ("tempban", ReversalAction::Unban),
+                ("timeout", ReversalAction::RemoveTimeout),
+            ]
+        );
+
+        for (kind, id) in &ids {
+            let expected = if REVERSIBLE_KINDS.contains(kind) {
+                "active"
+            } else {
+                "handled"
+            };
+
+            assert_eq!(state_of(&pool, *id).await, expected, "{}", kind);
+        }
+
+        let entry = HandleLogEntry::system("reversed", serde_json::json!({}));
+
+        for (punishment, _) in &plan.entries {
+            assert!(
+                mark_reversed(&pool, punishment.guild_id, punishment.id, &entry)
+                    .await
+                    .unwrap()
+            );
+        }
+
+        assert!(plan_reversals(&pool).await.unwrap().entries.is_empty());
+
+        for (_, id) in &ids {
+            assert_eq!(state_of(&pool, *id).await, "handled");
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use antiraid_types::punishments::{PunishmentCreate, PunishmentState, PunishmentTarget};
+use antiraid_types::stings::{StingAggregate, StingTarget};
+use sqlx::postgres::types::PgInterval;
+
+use crate::pginterval::try_pg_interval_to_std_duration;
+use crate::stings::user_sting_weights;
+
+/// Source set on punishments created from a punishment rule
+pub const PUNISHMENT_RULE_SRC: &str = "punishment_rules";
+
+/// Applies ``punishment_kind`` once a user has at least ``threshold`` active stings
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct PunishmentRule {
+    pub id: uuid::Uuid,
+    pub guild_id: serenity::all::GuildId,
+    pub threshold: i64,
+    /// The punishment to create, e.g. ``timeout``, ``kick`` or ``ban``
+    pub punishment_kind: String,
+    pub duration: Option<Duration>,
+    /// Only count stings with this source, all stings are counted if unset
+    pub src_filter: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PunishmentRuleRow {
+    id: uuid::Uuid,
+    guild_id: String,
+    threshold: i64,
+    punishment_kind: String,
+    duration: Option<PgInterval>,
+    src_filter: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl PunishmentRuleRow {
+    fn into_punishment_rule(self) -> Result<PunishmentRule, crate::Error> {
+        Ok(PunishmentRule {
+            id: self.id,
+            guild_id: self.guild_id.parse()?,
+            threshold: self.threshold,
+            punishment_kind: self.punishment_kind,
+            duration: match self.duration {
+                Some(d) => Some(try_pg_interval_to_std_duration(&d, true)?),
+                None => None,
+            },
+            src_filter: self.src_filter,
+            created_at: self.created_at,
+        })
+    }
+}
+
+pub struct PunishmentRuleCreate {
+    pub guild_id: serenity::all::GuildId,
+    pub threshold: i64,
+    pub punishment_kind: String,
+    pub duration: Option<Duration>,
+    pub src_filter: Option<String>,
+}
+
+impl PunishmentRule {
+    /// Returns all rules of a guild, ordered by threshold
+    pub async fn list(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+    ) -> Result<Vec<PunishmentRule>, crate::Error> {
+        let recs: Vec<PunishmentRuleRow> = sqlx::query_as(
+            "SELECT id, guild_id, threshold, punishment_kind, duration, src_filter, created_at FROM guild_punishment_rules WHERE guild_id = $1 ORDER BY threshold ASC, created_at ASC",
+        )
+        .bind(guild_id.to_string())
+        .fetch_all(db)
+        .await?;
+
+        let mut rules = Vec::with_capacity(recs.len());
+
+        for rec in recs {
+            rules.push(rec.into_punishment_rule()?);
+        }
+
+        Ok(rules)
+    }
+
+    /// Creates a new rule
+    pub async fn create(
+        db: impl sqlx::PgExecutor<'_>,
+        rule: PunishmentRuleCreate,
+    ) -> Result<PunishmentRule, crate::Error> {
+        if rule.threshold <= 0 {
+            return Err("Punishment rule thresholds must be greater than 0".into());
+        }
+
+        if rule.punishment_kind.is_empty() {
+            return Err("Punishment rules must have a punishment kind".into());
+        }
+
+        let rec: PunishmentRuleRow = sqlx::query_as(
+            r#"
+            INSERT INTO guild_punishment_rules (guild_id, threshold, punishment_kind, duration, src_filter)
+            VALUES ($1, $2, $3, make_interval(secs => $4), $5)
+            RETURNING id, guild_id, threshold, punishment_kind, duration, src_filter, created_at
+            "#,
+        )
+        .bind(rule.guild_id.to_string())
+        .bind(rule.threshold)
+        .bind(&rule.punishment_kind)
+        .bind(rule.duration.map(|d| d.as_secs() as f64))
+        .bind(&rule.src_filter)
+        .fetch_one(db)
+        .await?;
+
+        rec.into_punishment_rule()
+    }
+
+    /// Deletes a rule, returning whether it existed
+    pub async fn delete(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        id: uuid::Uuid,
+    ) -> Result<bool, crate::Error> {
+        let res = sqlx::query("DELETE FROM guild_punishment_rules WHERE id = $1 AND guild_id = $2")
+            .bind(id)
+            .bind(guild_id.to_string())
+            .execute(db)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Returns the weighted number of stings counted towards this rule
+    ///
+    /// Only stings targeting a user are counted, guild-wide ``system`` stings never count towards a user
+    pub fn count_stings(&self, aggregates: &[StingAggregate]) -> i64 {
+        aggregates
+            .iter()
+            .filter(|a| matches!(a.target, StingTarget::User(_)))
+            .filter(|a| match self.src_filter {
+                Some(ref src) => a.src.as_deref() == Some(src.as_str()),
+                None => true,
+            })
+            .map(|a| a.total_stings)
+            .sum()
+    }
+}
+
+/// Picks the rule with the highest crossed threshold whose punishment kind is not already active
+pub fn select_rule<'a>(
+    rules: &'a [PunishmentRule],
+    aggregates: &[StingAggregate],
+    active_kinds: &HashSet<String>,
+) -> Option<(&'a PunishmentRule, i64)> {
+    rules
+        .iter()
+        .filter(|rule| !active_kinds.contains(&rule.punishment_kind))
+        .filter_map(|rule| {
+            let total = rule.count_stings(aggregates);
+            (total >= rule.threshold).then_some((rule, total))
+        })
+        .max_by_key(|(rule, _)| rule.threshold)
+}
+
+/// Evaluates the rules of a guild against the active stings of a user, weighted by their ``stings`` count
+///
+/// Returns the punishment to apply (created by ``PunishmentTarget::System``), if any. The punishment is
+/// not created, callers should use ``PunishmentCreateOperations`` for that
+pub async fn evaluate(
+    db: &sqlx::PgPool,
+    guild_id: serenity::all::GuildId,
+    target: serenity::all::UserId,
+) -> Result<Option<PunishmentCreate>, crate::Error> {
+    let rules = PunishmentRule::list(db, guild_id).await?;
+
+    if rules.is_empty() {
+        return Ok(None);
+    }
+
+    let aggregates = user_sting_weights(db, guild_id, target).await?;
+
+    let active_kinds: HashSet<String> = sqlx::query_scalar(
+        "SELECT DISTINCT punishment FROM punishments WHERE guild_id = $1 AND target = $2 AND state = 'active'",
+    )
+    .bind(guild_id.to_string())
+    .bind(PunishmentTarget::User(target).to_string())
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .collect();
+
+    let Some((rule, total)) = select_rule(&rules, &aggregates, &active_kinds) else {
+        return Ok(None);
+    };
+
+    Ok(Some(PunishmentCreate {
+        src: Some(PUNISHMENT_RULE_SRC.to_string()),
+        guild_id,
+        punishment: rule.punishment_kind.clone(),
+        creator: PunishmentTarget::System,
+        target: PunishmentTarget::User(target),
+        handle_log: serde_json::Value::Array(vec![]),
+        duration: rule.duration,
+        reason: format!(
+            "Reached {} stings (rule threshold: {})",
+            total, rule.threshold
+        ),
+        data: Some(serde_json::json!({
+            "rule_id": rule.id,
+            "total_stings": total,
+        })),
+        state: PunishmentState::Active,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::all::{GuildId, UserId};
+
+    fn rule(threshold: i64, kind: &str, src_filter: Option<&str>) -> PunishmentRule {
+        PunishmentRule {
+            id: uuid::Uuid::new_v4(),
+            guild_id: GuildId::new(1),
+            threshold,
+            punishment_kind: kind.to_string(),
+            duration: None,
+            src_filter: src_filter.map(|s| s.to_string()),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn user_stings(src: Option<&str>, total_stings: i64) -> StingAggregate {
+        StingAggregate {
+            src: src.map(|s| s.to_string()),
+            target: StingTarget::User(UserId::new(2)),
+            total_stings,
+        }
+    }
+
+    fn system_stings(total_stings: i64) -> StingAggregate {
+        StingAggregate {
+            src: None,
+            target: StingTarget::System,
+            total_stings,
+        }
+    }
+
+    #[test]
+    fn below_threshold_selects_nothing() {
+        let rules = vec![rule(5, "timeout", None)];
+
+        assert!(select_rule(&rules, &[user_stings(None, 4)], &HashSet::new()).is_none());
+    }
+
+    #[test]
+    fn threshold_is_inclusive_and_weighted() {
+        let rules = vec![rule(5, "timeout", None)];
+        let aggregates = vec![user_stings(Some("automod"), 3), user_stings(None, 2)];
+
+        let (selected, total) = select_rule(&rules, &aggregates, &HashSet::new()).unwrap();
+
+        assert_eq!(selected.punishment_kind, "timeout");
+        assert_eq!(total, 5);
+    }
+
+    #[test]
+    fn highest_crossed_threshold_wins() {
+        let rules = vec![
+            rule(3, "timeout", None),
+            rule(5, "kick", None),
+            rule(10, "ban", None),
+        ];
+
+        let (selected, _) = select_rule(&rules, &[user_stings(None, 7)], &HashSet::new()).unwrap();
+
+        assert_eq!(selected.punishment_kind, "kick");
+    }
+
+    #[test]
+    fn active_kinds_are_deduped() {
+        let rules = vec![rule(3, "timeout", None), rule(5, "kick", None)];
+        let active = HashSet::from(["kick".to_string()]);
+
+        let (selected, _) = select_rule(&rules, &[user_stings(None, 7)], &active).unwrap();
+        assert_eq!(selected.punishment_kind, "timeout");
+
+        let active = HashSet::from(["kick".to_string(), "timeout".to_string()]);
+        assert!(select_rule(&rules, &[user_stings(None, 7)], &active).is_none());
+    }
+
+    #[test]
+    fn system_stings_are_not_counted() {
+        let rules = vec![rule(3, "timeout", None)];
+
+        assert!(select_rule(&rules, &[system_stings(100)], &HashSet::new()).is_none());
+        assert_eq!(
+            rules[0].count_stings(&[system_stings(100), user_stings(None, 1)]),
+            1
+        );
+    }
+
+    #[test]
+    fn src_filter_only_counts_matching_stings() {
+        let rules = vec![rule(3, "timeout", Some("automod"))];
+        let aggregates = vec![
+            user_stings(Some("automod"), 2),
+            user_stings(Some("manual"), 5),
+        ];
+
+        assert_eq!(rules[0].count_stings(&aggregates), 2);
+        assert!(select_rule(&rules, &aggregates, &HashSet::new()).is_none());
+    }
+}
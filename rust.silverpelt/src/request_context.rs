@@ -0,0 +1,91 @@
+pub use request_id::REQUEST_ID_HEADER;
+
+/// Correlates the logs of a single operation across the bot, RPC server, template worker and database layers
+///
+/// HTTP entry points should reuse the id assigned by the RPC server, other entry points (such as commands) create
+/// a new one with ``RequestContext::new``
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RequestContext {
+    pub request_id: String,
+}
+
+tokio::task_local! {
+    static CURRENT: RequestContext;
+}
+
+impl RequestContext {
+    /// Creates a context with a freshly generated request id
+    pub fn new() -> Self {
+        Self {
+            request_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Creates a context from an existing request id (e.g. the ``x-request-id`` header of an incoming request)
+    pub fn from_request_id(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+        }
+    }
+
+    /// Runs ``fut`` with this as the current context, events dispatched within it forward the request id to the
+    /// template worker
+    pub async fn scope<F: std::future::Future>(self, fut: F) -> F::Output {
+        CURRENT.scope(self, fut).await
+    }
+
+    /// Returns the context set by the enclosing ``scope``, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|ctx| ctx.clone()).ok()
+    }
+}
+
+impl From<&request_id::RequestId> for RequestContext {
+    fn from(request_id: &request_id::RequestId) -> Self {
+        Self::from_request_id(request_id.as_str())
+    }
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request_id::RequestId;
+
+    #[test]
+    fn reuses_rpc_request_id() {
+        let request_id = RequestId::generate();
+        let ctx = RequestContext::from(&request_id);
+
+        assert_eq!(ctx.request_id, request_id.as_str());
+        assert_eq!(ctx.to_string(), request_id.to_string());
+    }
+
+    #[test]
+    fn generates_distinct_ids() {
+        assert_ne!(RequestContext::new(), RequestContext::new());
+    }
+
+    #[tokio::test]
+    async fn current_is_set_within_scope() {
+        let ctx = RequestContext::from_request_id("abc");
+
+        assert_eq!(RequestContext::current(), None);
+        assert_eq!(
+            ctx.clone().scope(async { RequestContext::current() }).await,
+            Some(ctx)
+        );
+        assert_eq!(RequestContext::current(), None);
+    }
+}
@@ -0,0 +1,486 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use antiraid_types::punishments::{PunishmentState, PunishmentTarget};
+use antiraid_types::stings::{StingState, StingTarget};
+use serenity::all::GuildId;
+use sqlx::postgres::types::PgInterval;
+
+use crate::ar_event::custom_events::RETENTION_APPLIED_EVENT;
+use crate::ar_event::{AntiraidEventOperations, CustomEventBuilder, DispatchEventData};
+use crate::data::Data;
+use crate::pginterval::try_pg_interval_to_std_duration;
+
+/// Number of rows deleted or anonymized per query
+pub const RETENTION_BATCH_SIZE: i64 = 500;
+
+/// ``sting_data`` of anonymized stings
+pub const ANONYMIZED_STING_DATA: &str = r#"{"anonymized":true}"#;
+
+/// What happens to rows older than the retention period
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetentionMode {
+    /// Delete the rows
+    Delete,
+    /// Replace creator and target with ``system`` and strip the reason and data, keeping the row
+    ///
+    /// Only non-active rows are anonymized so they are never counted by the (active only) sting aggregates or
+    /// punishment rules, anonymized stings are marked with ``ANONYMIZED_STING_DATA``
+    Anonymize,
+}
+
+impl std::fmt::Display for RetentionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetentionMode::Delete => write!(f, "delete"),
+            RetentionMode::Anonymize => write!(f, "anonymize"),
+        }
+    }
+}
+
+impl FromStr for RetentionMode {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delete" => Ok(RetentionMode::Delete),
+            "anonymize" => Ok(RetentionMode::Anonymize),
+            _ => Err(format!("Invalid retention mode: {}", s).into()),
+        }
+    }
+}
+
+/// Retention policy of a guild
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RetentionPolicy {
+    pub guild_id: GuildId,
+    /// Stings older than this are removed, stings are kept forever if unset
+    pub sting_retention: Option<Duration>,
+    /// Punishments older than this are removed, punishments are kept forever if unset
+    pub punishment_retention: Option<Duration>,
+    /// Only remove voided stings and punishments
+    pub voided_only: bool,
+    pub mode: RetentionMode,
+}
+
+#[derive(sqlx::FromRow)]
+struct RetentionPolicyRow {
+    guild_id: String,
+    sting_retention: Option<PgInterval>,
+    punishment_retention: Option<PgInterval>,
+    voided_only: bool,
+    mode: String,
+}
+
+impl RetentionPolicyRow {
+    fn into_retention_policy(self) -> Result<RetentionPolicy, crate::Error> {
+        Ok(RetentionPolicy {
+            guild_id: self.guild_id.parse()?,
+            sting_retention: self
+                .sting_retention
+                .map(|d| try_pg_interval_to_std_duration(&d, true))
+                .transpose()?,
+            punishment_retention: self
+                .punishment_retention
+                .map(|d| try_pg_interval_to_std_duration(&d, true))
+                .transpose()?,
+            voided_only: self.voided_only,
+            mode: self.mode.parse()?,
+        })
+    }
+}
+
+/// Number of rows removed by ``apply_retention``
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default)]
+pub struct RetentionReport {
+    pub stings: u64,
+    pub punishments: u64,
+    pub mode: Option<RetentionMode>,
+}
+
+impl RetentionReport {
+    pub fn is_empty(&self) -> bool {
+        self.stings == 0 && self.punishments == 0
+    }
+}
+
+impl RetentionPolicy {
+    /// Returns the retention policy of a guild, if any
+    pub async fn get(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: GuildId,
+    ) -> Result<Option<RetentionPolicy>, crate::Error> {
+        let rec: Option<RetentionPolicyRow> = sqlx::query_as(
+            "SELECT guild_id, sting_retention, punishment_retention, voided_only, mode FROM guild_retention_policies WHERE guild_id = $1",
+        )
+        .bind(guild_id.to_string())
+        .fetch_optional(db)
+        .await?;
+
+        rec.map(|r| r.into_retention_policy()).transpose()
+    }
+
+    /// Returns the retention policies of all guilds
+    pub async fn list_all(
+        db: impl sqlx::PgExecutor<'_>,
+    ) -> Result<Vec<RetentionPolicy>, crate::Error> {
+        let recs: Vec<RetentionPolicyRow> = sqlx::query_as(
+            "SELECT guild_id, sting_retention, punishment_retention, voided_only, mode FROM guild_retention_policies",
+        )
+        .fetch_all(db)
+        .await?;
+
+        let mut policies = Vec::with_capacity(recs.len());
+
+        for rec in recs {
+            policies.push(rec.into_retention_policy()?);
+        }
+
+        Ok(policies)
+    }
+
+    /// Creates or replaces the retention policy of the guild
+    pub async fn set(&self, db: impl sqlx::PgExecutor<'_>) -> Result<(), crate::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO guild_retention_policies (guild_id, sting_retention, punishment_retention, voided_only, mode)
+            VALUES ($1, make_interval(secs => $2), make_interval(secs => $3), $4, $5)
+            ON CONFLICT (guild_id) DO UPDATE SET sting_retention = EXCLUDED.sting_retention, punishment_retention = EXCLUDED.punishment_retention, voided_only = EXCLUDED.voided_only, mode = EXCLUDED.mode
+            "#,
+        )
+        .bind(self.guild_id.to_string())
+        .bind(self.sting_retention.map(|d| d.as_secs() as f64))
+        .bind(self.punishment_retention.map(|d| d.as_secs() as f64))
+        .bind(self.voided_only)
+        .bind(self.mode.to_string())
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes the retention policy of a guild, keeping data forever again
+    pub async fn delete(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: GuildId,
+    ) -> Result<(), crate::Error> {
+        sqlx::query("DELETE FROM guild_retention_policies WHERE guild_id = $1")
+            .bind(guild_id.to_string())
+            .execute(db)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Runs ``query`` until it affects less than a full batch of rows
+///
+/// The guild id, retention (in seconds), state filter and batch size are bound as $1-$4, ``extra_binds`` follow from $5
+async fn run_batched(
+    db: &sqlx::PgPool,
+    query: &str,
+    guild_id: GuildId,
+    retention: Duration,
+    state: Option<String>,
+    extra_binds: &[String],
+) -> Result<u64, crate::Error> {
+    let mut total = 0;
+
+    loop {
+        let mut q = sqlx::query(query)
+            .bind(guild_id.to_string())
+            .bind(retention.as_secs() as f64)
+            .bind(&state)
+            .bind(RETENTION_BATCH_SIZE);
+
+        for bind in extra_binds {
+            q = q.bind(bind);
+        }
+
+        let affected = q.execute(db).await?.rows_affected();
+
+        total += affected;
+
+        if affected < RETENTION_BATCH_SIZE as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Deletes or anonymizes the stings and punishments of a guild older than the policy's retention periods
+///
+/// Active rows are deleted but never anonymized, see ``RetentionMode::Anonymize``
+pub async fn apply_retention(
+    db: &sqlx::PgPool,
+    guild_id: GuildId,
+    policy: &RetentionPolicy,
+) -> Result<RetentionReport, crate::Error> {
+    let mut report = RetentionReport {
+        mode: Some(policy.mode),
+        ..Default::default()
+    };
+
+    if let Some(retention) = policy.sting_retention {
+        let state = policy.voided_only.then(|| StingState::Voided.to_string());
+
+        report.stings = match policy.mode {
+            RetentionMode::Delete => {
+                run_batched(
+                    db,
+                    "DELETE FROM stings WHERE id IN (SELECT id FROM stings WHERE guild_id = $1 AND created_at < NOW() - make_interval(secs => $2) AND ($3::text IS NULL OR state = $3) LIMIT $4)",
+                    guild_id,
+                    retention,
+                    state,
+                    &[],
+                )
+                .await?
+            }
+            RetentionMode::Anonymize => {
+                let system = StingTarget::System.to_string();
+
+                run_batched(
+                    db,
+                    "UPDATE stings SET creator = $5, target = $5, reason = NULL, void_reason = NULL, sting_data = $7::jsonb WHERE id IN (SELECT id FROM stings WHERE guild_id = $1 AND created_at < NOW() - make_interval(secs => $2) AND ($3::text IS NULL OR state = $3) AND state <> $6 AND sting_data IS DISTINCT FROM $7::jsonb LIMIT $4)",
+                    guild_id,
+                    retention,
+                    state,
+                    &[
+                        system,
+                        StingState::Active.to_string(),
+                        ANONYMIZED_STING_DATA.to_string(),
+                    ],
+                )
+                .await?
+            }
+        };
+    }
+
+    if let Some(retention) = policy.punishment_retention {
+        let state = policy
+            .voided_only
+            .then(|| PunishmentState::Voided.to_string());
+
+        report.punishments = match policy.mode {
+            RetentionMode::Delete => {
+                run_batched(
+                    db,
+                    "DELETE FROM punishments WHERE id IN (SELECT id FROM punishments WHERE guild_id = $1 AND created_at < NOW() - make_interval(secs => $2) AND ($3::text IS NULL OR state = $3) LIMIT $4)",
+                    guild_id,
+                    retention,
+                    state,
+                    &[],
+                )
+                .await?
+            }
+            RetentionMode::Anonymize => {
+                let system = PunishmentTarget::System.to_string();
+
+                run_batched(
+                    db,
+                    "UPDATE punishments SET creator = $5, target = $5, reason = '', data = NULL WHERE id IN (SELECT id FROM punishments WHERE guild_id = $1 AND created_at < NOW() - make_interval(secs => $2) AND ($3::text IS NULL OR state = $3) AND state <> $6 AND NOT (creator = $5 AND target = $5 AND reason = '' AND data IS NULL) LIMIT $4)",
+                    guild_id,
+                    retention,
+                    state,
+                    &[system, PunishmentState::Active.to_string()],
+                )
+                .await?
+            }
+        };
+    }
+
+    Ok(report)
+}
+
+/// Dispatches a single ``AR/RetentionApplied`` summary event for a retention run
+pub async fn dispatch_retention_event(
+    data: &Data,
+    guild_id: GuildId,
+    report: &RetentionReport,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    let event = CustomEventBuilder::new(RETENTION_APPLIED_EVENT, "(Anti-Raid) Retention Applied")?
+        .field("mode", &report.mode.map(|m| m.to_string()))?
+        .field("stings", &report.stings)?
+        .field("punishments", &report.punishments)?
+        .build()?;
+
+    event
+        .dispatch_to_template_worker_and_nowait(data, guild_id, dispatch_event_data)
+        .await
+}
+
+/// Applies the retention policies of all guilds, returning the total number of rows removed
+///
+/// A failure in one guild is logged and does not stop the others, suited for the ``TaskRegistry``
+pub async fn run_retention_for_all_guilds(
+    data: &Data,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<u64, crate::Error> {
+    let policies = RetentionPolicy::list_all(&data.pool).await?;
+
+    let mut total = 0;
+
+    for policy in policies {
+        let report = match apply_retention(&data.pool, policy.guild_id, &policy).await {
+            Ok(report) => report,
+            Err(e) => {
+                log::error!(
+                    "Failed to apply retention policy of guild {}: {}",
+                    policy.guild_id,
+                    e
+                );
+                continue;
+            }
+        };
+
+        if report.is_empty() {
+            continue;
+        }
+
+        total += report.stings + report.punishments;
+
+        if let Err(e) =
+            dispatch_retention_event(data, policy.guild_id, &report, dispatch_event_data).await
+        {
+            log::error!(
+                "Failed to dispatch retention event for guild {}: {}",
+                policy.guild_id,
+                e
+            );
+        }
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stings::{user_sting_weights, StingAggregateOperations};
+    use crate::test_schema::{create_tables, PUNISHMENTS, STINGS};
+    use antiraid_types::stings::StingAggregate;
+    use serenity::all::UserId;
+
+    const GUILD: GuildId = GuildId::new(1);
+    const USER: UserId = UserId::new(2);
+
+    async fn insert_sting(
+        pool: &sqlx::PgPool,
+        target: &str,
+        state: &str,
+        age_days: i32,
+    ) -> uuid::Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO stings (guild_id, creator, target, state, stings, reason, created_at) VALUES ($1, $2, $3, $4, 2, 'reason', NOW() - make_interval(days => $5::int)) RETURNING id",
+        )
+        .bind(GUILD.to_string())
+        .bind(StingTarget::System.to_string())
+        .bind(target)
+        .bind(state)
+        .bind(age_days)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn sting_row(pool: &sqlx::PgPool, id: uuid::Uuid) -> Option<(String, Option<String>)> {
+        sqlx::query_as("SELECT target, reason FROM stings WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    fn policy(mode: RetentionMode, voided_only: bool) -> RetentionPolicy {
+        RetentionPolicy {
+            guild_id: GUILD,
+            sting_retention: Some(Duration::from_secs(60 * 60 * 24)),
+            punishment_retention: None,
+            voided_only,
+            mode,
+        }
+    }
+
+    fn totals(aggregates: &[StingAggregate]) -> i64 {
+        aggregates.iter().map(|a| a.total_stings).sum()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn voided_only_removes_only_voided_stings(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS, PUNISHMENTS]).await;
+
+        let user = StingTarget::User(USER).to_string();
+        let voided = insert_sting(&pool, &user, "voided", 10).await;
+        let handled = insert_sting(&pool, &user, "handled", 10).await;
+        let recent_voided = insert_sting(&pool, &user, "voided", 0).await;
+
+        let report = apply_retention(&pool, GUILD, &policy(RetentionMode::Delete, true))
+            .await
+            .unwrap();
+
+        assert_eq!(report.stings, 1);
+        assert!(sting_row(&pool, voided).await.is_none());
+        assert!(sting_row(&pool, handled).await.is_some());
+        assert!(sting_row(&pool, recent_voided).await.is_some());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn anonymize_preserves_counts(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS, PUNISHMENTS]).await;
+
+        let user = StingTarget::User(USER).to_string();
+        let active = insert_sting(&pool, &user, "active", 10).await;
+        let handled = insert_sting(&pool, &user, "handled", 10).await;
+
+        let guild_before = totals(&StingAggregate::guild(&pool, GUILD).await.unwrap());
+        let user_before = totals(
+            &StingAggregate::guild_user(&pool, GUILD, USER)
+                .await
+                .unwrap(),
+        );
+        let weights_before = totals(&user_sting_weights(&pool, GUILD, USER).await.unwrap());
+
+        let report = apply_retention(&pool, GUILD, &policy(RetentionMode::Anonymize, false))
+            .await
+            .unwrap();
+
+        assert_eq!(report.stings, 1);
+        assert_eq!(
+            sting_row(&pool, active).await,
+            Some((user, Some("reason".to_string())))
+        );
+        assert_eq!(
+            sting_row(&pool, handled).await,
+            Some((StingTarget::System.to_string(), None))
+        );
+
+        assert_eq!(
+            totals(&StingAggregate::guild(&pool, GUILD).await.unwrap()),
+            guild_before
+        );
+        assert_eq!(
+            totals(
+                &StingAggregate::guild_user(&pool, GUILD, USER)
+                    .await
+                    .unwrap()
+            ),
+            user_before
+        );
+        assert_eq!(
+            totals(&user_sting_weights(&pool, GUILD, USER).await.unwrap()),
+            weights_before
+        );
+
+        let total_rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM stings")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(total_rows, 2);
+
+        // Already anonymized rows are not touched again
+        let report = apply_retention(&pool, GUILD, &policy(RetentionMode::Anonymize, false))
+            .await
+            .unwrap();
+        assert_eq!(report.stings, 0);
+    }
+}
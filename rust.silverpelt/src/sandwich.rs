@@ -0,0 +1,405 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sandwich_driver::SandwichConfigData;
+use serenity::all::{Cache, ChannelId, GuildId, Http, UserId};
+
+/// Returned instead of calling sandwich while it is degraded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandwichDegraded;
+
+impl std::fmt::Display for SandwichDegraded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Discord data is temporarily unavailable, please try again in a few minutes"
+        )
+    }
+}
+
+impl std::error::Error for SandwichDegraded {}
+
+/// Controls when sandwich is considered degraded
+#[derive(Clone, Copy, Debug)]
+pub struct SandwichStatusOptions {
+    /// Calls older than this are not taken into account
+    pub window: Duration,
+    /// Error rate (between 0 and 1) at or above which sandwich is degraded
+    pub error_rate_threshold: f64,
+    /// Minimum number of calls within the window before sandwich can be considered degraded
+    pub min_samples: usize,
+}
+
+impl Default for SandwichStatusOptions {
+    fn default() -> Self {
+        SandwichStatusOptions {
+            window: Duration::from_secs(60),
+            error_rate_threshold: 0.5,
+            min_samples: 10,
+        }
+    }
+}
+
+/// Health of sandwich as returned by ``SandwichStatus::snapshot``
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SandwichStatusSnapshot {
+    pub degraded: bool,
+    /// Number of calls within the window
+    pub calls: usize,
+    /// Number of failed calls within the window
+    pub failures: usize,
+    pub last_success: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_failure: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Number of buckets the window is split into
+const STATUS_BUCKETS: usize = 12;
+
+#[derive(Clone, Copy, Default)]
+struct StatusBucket {
+    /// Index of the time slice (since ``SandwichStatusInner::start``) the counts belong to
+    slice: u64,
+    calls: usize,
+    failures: usize,
+}
+
+struct SandwichStatusInner {
+    start: Instant,
+    buckets: [StatusBucket; STATUS_BUCKETS],
+    last_success: Option<chrono::DateTime<chrono::Utc>>,
+    last_failure: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl SandwichStatusInner {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            buckets: [StatusBucket::default(); STATUS_BUCKETS],
+            last_success: None,
+            last_failure: None,
+        }
+    }
+
+    fn slice(&self, window: Duration, now: Instant) -> u64 {
+        let width = (window.as_nanos() / STATUS_BUCKETS as u128).max(1);
+
+        (now.saturating_duration_since(self.start).as_nanos() / width) as u64
+    }
+
+    fn record(&mut self, window: Duration, now: Instant, success: bool) {
+        let slice = self.slice(window, now);
+        let bucket = &mut self.buckets[(slice % STATUS_BUCKETS as u64) as usize];
+
+        if bucket.slice != slice {
+            *bucket = StatusBucket {
+                slice,
+                ..Default::default()
+            };
+        }
+
+        bucket.calls += 1;
+
+        if !success {
+            bucket.failures += 1;
+        }
+    }
+
+    /// Returns the number of calls and failures within the window
+    fn totals(&self, window: Duration, now: Instant) -> (usize, usize) {
+        let slice = self.slice(window, now);
+
+        self.buckets
+            .iter()
+            .filter(|b| b.slice <= slice && slice - b.slice < STATUS_BUCKETS as u64)
+            .fold((0, 0), |(calls, failures), b| {
+                (calls + b.calls, failures + b.failures)
+            })
+    }
+}
+
+/// Tracks the error rate of sandwich calls over a sliding window
+///
+/// Calls are counted in fixed time buckets, so memory use does not depend on the call rate. Calls are not made
+/// (and thus not recorded) while degraded, so sandwich is retried once the failures have left the window
+pub struct SandwichStatus {
+    opts: SandwichStatusOptions,
+    inner: Mutex<SandwichStatusInner>,
+}
+
+impl Default for SandwichStatus {
+    fn default() -> Self {
+        Self::new(SandwichStatusOptions::default())
+    }
+}
+
+impl SandwichStatus {
+    pub fn new(opts: SandwichStatusOptions) -> Self {
+        Self {
+            opts,
+            inner: Mutex::new(SandwichStatusInner::new(Instant::now())),
+        }
+    }
+
+    /// Records the outcome of a sandwich call
+    pub fn record(&self, success: bool) {
+        self.record_at(Instant::now(), success)
+    }
+
+    fn record_at(&self, now: Instant, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.record(self.opts.window, now, success);
+
+        if success {
+            inner.last_success = Some(chrono::Utc::now());
+        } else {
+            inner.last_failure = Some(chrono::Utc::now());
+        }
+    }
+
+    /// Returns true if the error rate within the window is at or above the threshold
+    pub fn is_degraded(&self) -> bool {
+        self.is_degraded_at(Instant::now())
+    }
+
+    fn is_degraded_at(&self, now: Instant) -> bool {
+        let (calls, failures) = self.inner.lock().unwrap().totals(self.opts.window, now);
+
+        if calls == 0 || calls < self.opts.min_samples {
+            return false;
+        }
+
+        failures as f64 / calls as f64 >= self.opts.error_rate_threshold
+    }
+
+    pub fn snapshot(&self) -> SandwichStatusSnapshot {
+        let now = Instant::now();
+        let degraded = self.is_degraded_at(now);
+        let inner = self.inner.lock().unwrap();
+        let (calls, failures) = inner.totals(self.opts.window, now);
+
+        SandwichStatusSnapshot {
+            degraded,
+            calls,
+            failures,
+            last_success: inner.last_success,
+            last_failure: inner.last_failure,
+        }
+    }
+
+    fn track<T>(&self, res: Result<T, crate::Error>) -> Result<T, crate::Error> {
+        self.record(res.is_ok());
+        res
+    }
+}
+
+/// Wraps ``sandwich_driver::member_in_guild``
+///
+/// While degraded, the serenity cache is used if ``allow_cache_only`` is set, otherwise ``SandwichDegraded`` is returned
+#[allow(clippy::too_many_arguments)]
+pub async fn member_in_guild(
+    status: &SandwichStatus,
+    allow_cache_only: bool,
+    cache: &Cache,
+    http: &Http,
+    reqwest: &reqwest::Client,
+    guild_id: GuildId,
+    user_id: UserId,
+    config: &SandwichConfigData,
+) -> Result<Option<serenity::all::Member>, crate::Error> {
+    if status.is_degraded() {
+        if allow_cache_only {
+            if let Some(member) = cache
+                .guild(guild_id)
+                .and_then(|g| g.members.get(&user_id).cloned())
+            {
+                return Ok(Some(member));
+            }
+        }
+
+        return Err(Box::new(SandwichDegraded));
+    }
+
+    status.track(
+        sandwich_driver::member_in_guild(cache, http, reqwest, guild_id, user_id, config).await,
+    )
+}
+
+/// Wraps ``sandwich_driver::guild``, see ``member_in_guild`` for the degraded behaviour
+pub async fn guild(
+    status: &SandwichStatus,
+    allow_cache_only: bool,
+    cache: &Cache,
+    http: &Http,
+    reqwest: &reqwest::Client,
+    guild_id: GuildId,
+    config: &SandwichConfigData,
+) -> Result<serenity::all::PartialGuild, crate::Error> {
+    if status.is_degraded() {
+        if allow_cache_only {
+            if let Some(guild) = cache.guild(guild_id) {
+                return Ok(guild.clone().into());
+            }
+        }
+
+        return Err(Box::new(SandwichDegraded));
+    }
+
+    status.track(sandwich_driver::guild(cache, http, reqwest, guild_id, config).await)
+}
+
+/// Wraps ``sandwich_driver::guild_channels``, see ``member_in_guild`` for the degraded behaviour
+pub async fn guild_channels(
+    status: &SandwichStatus,
+    allow_cache_only: bool,
+    cache: &Cache,
+    http: &Http,
+    reqwest: &reqwest::Client,
+    guild_id: GuildId,
+    config: &SandwichConfigData,
+) -> Result<Vec<serenity::all::GuildChannel>, crate::Error> {
+    if status.is_degraded() {
+        if allow_cache_only {
+            if let Some(guild) = cache.guild(guild_id) {
+                return Ok(guild.channels.clone().into_iter().collect());
+            }
+        }
+
+        return Err(Box::new(SandwichDegraded));
+    }
+
+    status.track(sandwich_driver::guild_channels(cache, http, reqwest, guild_id, config).await)
+}
+
+/// Wraps ``sandwich_driver::channel``, see ``member_in_guild`` for the degraded behaviour
+#[allow(clippy::too_many_arguments)]
+pub async fn channel(
+    status: &SandwichStatus,
+    allow_cache_only: bool,
+    cache: &Cache,
+    http: &Http,
+    reqwest: &reqwest::Client,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    config: &SandwichConfigData,
+) -> Result<Option<serenity::all::Channel>, crate::Error> {
+    if status.is_degraded() {
+        if allow_cache_only {
+            if let Some(channel) = guild_id
+                .and_then(|guild_id| cache.guild(guild_id))
+                .and_then(|g| g.channels.get(&channel_id).cloned())
+            {
+                return Ok(Some(serenity::all::Channel::Guild(channel)));
+            }
+        }
+
+        return Err(Box::new(SandwichDegraded));
+    }
+
+    status.track(sandwich_driver::channel(cache, http, reqwest, guild_id, channel_id, config).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> SandwichStatus {
+        SandwichStatus::new(SandwichStatusOptions {
+            window: Duration::from_secs(60),
+            error_rate_threshold: 0.5,
+            min_samples: 4,
+        })
+    }
+
+    #[test]
+    fn degraded_at_threshold() {
+        let status = status();
+        let now = Instant::now();
+
+        status.record_at(now, true);
+        status.record_at(now, true);
+        status.record_at(now, false);
+        assert!(!status.is_degraded_at(now), "below min_samples");
+
+        status.record_at(now, false);
+        assert!(status.is_degraded_at(now));
+    }
+
+    #[test]
+    fn below_threshold_is_not_degraded() {
+        let status = status();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            status.record_at(now, true);
+        }
+
+        status.record_at(now, false);
+
+        assert!(!status.is_degraded_at(now));
+    }
+
+    #[test]
+    fn failures_leave_the_window() {
+        let status = status();
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            status.record_at(now, false);
+        }
+
+        assert!(status.is_degraded_at(now + Duration::from_secs(30)));
+        assert!(!status.is_degraded_at(now + Duration::from_secs(61)));
+
+        let inner = status.inner.lock().unwrap();
+        assert_eq!(
+            inner.totals(status.opts.window, now + Duration::from_secs(61)),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn reused_buckets_are_reset() {
+        let status = status();
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            status.record_at(now, false);
+        }
+
+        // Lands in the same bucket slot one full window later
+        let later = now + Duration::from_secs(60);
+
+        for _ in 0..4 {
+            status.record_at(later, true);
+        }
+
+        assert!(!status.is_degraded_at(later));
+        assert_eq!(
+            status
+                .inner
+                .lock()
+                .unwrap()
+                .totals(status.opts.window, later),
+            (4, 0)
+        );
+    }
+
+    #[test]
+    fn memory_does_not_grow_with_calls() {
+        let status = status();
+        let now = Instant::now();
+
+        for i in 0..100_000u64 {
+            status.record_at(now + Duration::from_millis(i), i % 2 == 0);
+        }
+
+        let end = now + Duration::from_millis(99_999);
+        let (calls, failures) = status.inner.lock().unwrap().totals(status.opts.window, end);
+
+        // Only (roughly) the last window of calls is counted
+        assert!(calls <= 65_000, "{calls}");
+        assert!(calls >= 55_000, "{calls}");
+        assert!(failures * 2 >= calls - 1 && failures * 2 <= calls + 1);
+    }
+}
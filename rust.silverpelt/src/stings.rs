@@ -1,11 +1,29 @@
+pub mod import;
+
+use antiraid_types::ar_event::AntiraidEvent;
 use antiraid_types::stings::{Sting, StingAggregate, StingCreate, StingState, StingTarget};
 use sqlx::postgres::types::PgInterval;
 use sqlx::Row;
 use std::str::FromStr;
 
 use crate::{
-    ar_event::DispatchEventData,
-    pginterval::pg_interval_to_secs,
+    ar_event::custom_events::{
+        STING_CREATE_EVENT, STING_CREATE_MANY_EVENT, STING_DELETE_EVENT, STING_EXPIRE_EVENT,
+        STING_UPDATE_EVENT,
+    },
+    ar_event::{AntiraidEventOperations, CustomEventBuilder, DispatchEventData},
+    create_validation::{
+        normalize_reason, normalize_src, validate_duration, validate_reason, CreateLimits,
+        CreateValidation, CreateValidationError,
+    },
+    data::Data,
+    decode::{decode_lossy, parse_snowflake_column, DecodeFailure},
+    handle_log::{
+        append_handle_log, parse_handle_log, push_handle_log_entry, HandleLogEntry,
+        InvalidHandleLogEntry,
+    },
+    pagination::{into_page, Cursor, ListOptions, ListPage},
+    pginterval::try_pg_interval_to_std_duration,
 };
 
 #[allow(async_fn_in_trait)]
@@ -17,13 +35,38 @@ pub trait StingOperations: Send + Sync {
         id: sqlx::types::Uuid,
     ) -> Result<Option<Sting>, crate::Error>;
 
-    /// Lists stings for a guild paginated based on page number
+    /// Lists stings for a guild paginated based on page number (20 stings per page)
+    ///
+    /// Pages share the order of ``list_with_options`` and ``list_lossy``
     async fn list(
         db: impl sqlx::PgExecutor<'_>,
         guild_id: serenity::all::GuildId,
         page: usize,
     ) -> Result<Vec<Sting>, crate::Error>;
 
+    /// Like ``list`` but skips rows which fail to decode, returning them separately instead of failing the whole page
+    async fn list_lossy(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        page: usize,
+    ) -> Result<(Vec<Sting>, Vec<DecodeFailure>), crate::Error> {
+        let rec = fetch_page_rows(db, guild_id, page).await?;
+
+        Ok(decode_lossy(
+            rec,
+            "sting",
+            |row| row.id.to_string(),
+            StingRow::into_sting,
+        ))
+    }
+
+    /// Lists stings for a guild using keyset pagination, newest first
+    async fn list_with_options(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        opts: &ListOptions,
+    ) -> Result<ListPage<Sting>, crate::Error>;
+
     /// Returns the expired stings
     async fn get_expired(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<Sting>, crate::Error>;
 
@@ -81,6 +124,17 @@ pub trait StingOperations: Send + Sync {
         ctx: serenity::all::Context,
         dispatch_event_data: &DispatchEventData,
     ) -> Result<(), crate::Error>;
+
+    /// Atomically appends an entry to the handle log of a sting, returning false if the sting does not exist
+    async fn append_handle_log(
+        db: impl sqlx::PgExecutor<'_>,
+        id: sqlx::types::Uuid,
+        guild_id: serenity::all::GuildId,
+        entry: &HandleLogEntry,
+    ) -> Result<bool, crate::Error>;
+
+    /// Returns the parsed handle log entries along with the entries which failed to parse
+    fn parsed_handle_log(&self) -> (Vec<HandleLogEntry>, Vec<InvalidHandleLogEntry>);
 }
 
 #[derive(sqlx::FromRow)]
@@ -108,17 +162,14 @@ impl StingRow {
             stings: self.stings,
             reason: self.reason,
             void_reason: self.void_reason,
-            guild_id: self.guild_id.parse()?,
-            creator: StingTarget::from_str(&self.creator)?,
-            target: StingTarget::from_str(&self.target)?,
+            guild_id: parse_snowflake_column(&self.guild_id, "guild_id", self.id)?,
+            creator: parse_snowflake_column(&self.creator, "creator", self.id)?,
+            target: parse_snowflake_column(&self.target, "target", self.id)?,
             state: StingState::from_str(&self.state)?,
             sting_data: self.sting_data,
             created_at: self.created_at,
             duration: match self.duration {
-                Some(d) => {
-                    let secs = pg_interval_to_secs(d);
-                    Some(std::time::Duration::from_secs(secs.try_into()?))
-                }
+                Some(d) => Some(try_pg_interval_to_std_duration(&d, true)?),
                 None => None,
             },
             handle_log: self.handle_log,
@@ -126,6 +177,34 @@ impl StingRow {
     }
 }
 
+/// Fetches a page (20 stings per page) of raw rows for a guild
+async fn fetch_page_rows(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: serenity::all::GuildId,
+    page: usize,
+) -> Result<Vec<StingRow>, crate::Error> {
+    const PAGE_SIZE: i64 = 20; // 20 stings per page
+
+    // Page 0 is treated as the first page, pages past the end of the table are empty
+    let Some(offset) = i64::try_from(page.saturating_sub(1))
+        .ok()
+        .and_then(|page| page.checked_mul(PAGE_SIZE))
+    else {
+        return Ok(Vec::new());
+    };
+
+    let rec: Vec<StingRow> = sqlx::query_as(
+        "SELECT id, src, stings, reason, void_reason, guild_id, creator, target, state, sting_data, created_at, duration, handle_log FROM stings WHERE guild_id = $1 ORDER BY created_at DESC, id DESC OFFSET $2 LIMIT $3",
+    )
+    .bind(guild_id.to_string())
+    .bind(offset)
+    .bind(PAGE_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    Ok(rec)
+}
+
 impl StingOperations for Sting {
     /// Returns a sting by ID
     async fn get(
@@ -147,36 +226,49 @@ impl StingOperations for Sting {
         }
     }
 
-    /// Lists stings for a guild paginated based on page number
+    /// Lists stings for a guild paginated based on page number (20 stings per page)
+    ///
+    /// Pages share the order of ``list_with_options`` and ``list_lossy``
     async fn list(
         db: impl sqlx::PgExecutor<'_>,
         guild_id: serenity::all::GuildId,
         page: usize,
     ) -> Result<Vec<Sting>, crate::Error> {
-        const PAGE_SIZE: i64 = 20; // 20 stings per page
-
-        if page > i64::MAX as usize {
-            return Err("Page number too large".into());
-        }
+        fetch_page_rows(db, guild_id, page)
+            .await?
+            .into_iter()
+            .map(StingRow::into_sting)
+            .collect()
+    }
 
-        let page = std::cmp::max(page, 1) as i64; // Avoid negative pages
+    /// Lists stings for a guild using keyset pagination, newest first
+    async fn list_with_options(
+        db: impl sqlx::PgExecutor<'_>,
+        guild_id: serenity::all::GuildId,
+        opts: &ListOptions,
+    ) -> Result<ListPage<Sting>, crate::Error> {
+        let (page_size, cursor) = opts.parse()?;
 
         let rec: Vec<StingRow> = sqlx::query_as(
-            "SELECT id, src, stings, reason, void_reason, guild_id, creator, target, state, sting_data, created_at, duration, handle_log FROM stings WHERE guild_id = $1 ORDER BY created_at DESC OFFSET $2 LIMIT $3",
+            "SELECT id, src, stings, reason, void_reason, guild_id, creator, target, state, sting_data, created_at, duration, handle_log FROM stings WHERE guild_id = $1 AND ($2::timestamptz IS NULL OR (created_at, id) < ($2, $3)) ORDER BY created_at DESC, id DESC LIMIT $4",
         )
         .bind(guild_id.to_string())
-        .bind((page - 1) * PAGE_SIZE)
-        .bind(PAGE_SIZE)
+        .bind(cursor.map(|c| c.created_at))
+        .bind(cursor.map(|c| c.id))
+        .bind(page_size + 1)
         .fetch_all(db)
         .await?;
 
-        let mut stings = Vec::new();
+        let mut stings = Vec::with_capacity(rec.len());
 
         for row in rec {
             stings.push(row.into_sting()?);
         }
 
-        Ok(stings)
+        Ok(into_page(stings, page_size, |item| Cursor {
+            created_at: item.created_at,
+            id: item.id,
+        }))
     }
 
     async fn get_expired(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<Sting>, crate::Error> {
@@ -198,28 +290,34 @@ impl StingOperations for Sting {
     /// Dispatch a StingCreate event
     async fn dispatch_create_event(
         self,
-        _ctx: serenity::all::Context,
-        _dispatch_event_data: &DispatchEventData,
+        ctx: serenity::all::Context,
+        dispatch_event_data: &DispatchEventData,
     ) -> Result<(), crate::Error> {
-        Ok(()) // disabled as builtins+stings are being rewritten in luau
+        let event = sting_event(STING_CREATE_EVENT, "(Anti-Raid) Sting Create", &self)?;
+
+        dispatch_sting_event(&ctx, self.guild_id, event, dispatch_event_data).await
     }
 
     /// Dispatch a StingUpdate event
     async fn dispatch_update_event(
         self,
-        _ctx: serenity::all::Context,
-        _dispatch_event_data: &DispatchEventData,
+        ctx: serenity::all::Context,
+        dispatch_event_data: &DispatchEventData,
     ) -> Result<(), crate::Error> {
-        Ok(()) // disabled as builtins+stings are being rewritten in luau
+        let event = sting_event(STING_UPDATE_EVENT, "(Anti-Raid) Sting Update", &self)?;
+
+        dispatch_sting_event(&ctx, self.guild_id, event, dispatch_event_data).await
     }
 
     /// Dispatch a StingDelete event
     async fn dispatch_delete_event(
         self,
-        _ctx: serenity::all::Context,
-        _dispatch_event_data: &DispatchEventData,
+        ctx: serenity::all::Context,
+        dispatch_event_data: &DispatchEventData,
     ) -> Result<(), crate::Error> {
-        Ok(()) // disabled as builtins+stings are being rewritten in luau
+        let event = sting_event(STING_DELETE_EVENT, "(Anti-Raid) Sting Delete", &self)?;
+
+        dispatch_sting_event(&ctx, self.guild_id, event, dispatch_event_data).await
     }
 
     /// Returns the guild ID associated with a sting
@@ -272,7 +370,12 @@ impl StingOperations for Sting {
     ) -> Result<(), crate::Error> {
         self.update_without_dispatch(db).await?;
 
-        self.dispatch_update_event(ctx, dispatch_event_data).await?;
+        let guild_id = self.guild_id;
+        log_dispatch_failure(
+            self.dispatch_update_event(ctx, dispatch_event_data).await,
+            STING_UPDATE_EVENT,
+            guild_id,
+        );
 
         Ok(())
     }
@@ -301,10 +404,30 @@ impl StingOperations for Sting {
     ) -> Result<(), crate::Error> {
         Self::delete_without_dispatch(db, self.guild_id, self.id).await?;
 
-        self.dispatch_delete_event(ctx, dispatch_event_data).await?;
+        let guild_id = self.guild_id;
+        log_dispatch_failure(
+            self.dispatch_delete_event(ctx, dispatch_event_data).await,
+            STING_DELETE_EVENT,
+            guild_id,
+        );
 
         Ok(())
     }
+
+    /// Atomically appends an entry to the handle log of a sting, returning false if the sting does not exist
+    async fn append_handle_log(
+        db: impl sqlx::PgExecutor<'_>,
+        id: sqlx::types::Uuid,
+        guild_id: serenity::all::GuildId,
+        entry: &HandleLogEntry,
+    ) -> Result<bool, crate::Error> {
+        append_handle_log(db, "stings", guild_id, id, entry).await
+    }
+
+    /// Returns the parsed handle log entries along with the entries which failed to parse
+    fn parsed_handle_log(&self) -> (Vec<HandleLogEntry>, Vec<InvalidHandleLogEntry>) {
+        parse_handle_log(&self.handle_log)
+    }
 }
 
 #[allow(async_fn_in_trait)]
@@ -330,34 +453,297 @@ pub trait StingCreateOperations: Send + Sync {
         db: impl sqlx::PgExecutor<'_>,
         dispatch_event_data: &DispatchEventData,
     ) -> Result<sqlx::types::Uuid, crate::Error>;
+
+    /// Creates many Stings in a single INSERT without dispatching them as events
+    ///
+    /// The returned Stings are in the same order as the input. All entries must share the same guild_id
+    async fn create_many_without_dispatch(
+        db: impl sqlx::PgExecutor<'_>,
+        stings: Vec<StingCreate>,
+    ) -> Result<Vec<Sting>, crate::Error>;
+
+    /// Creates many Stings in a single INSERT and dispatches one aggregate event for all of them
+    async fn create_many_and_dispatch(
+        ctx: serenity::all::Context,
+        db: impl sqlx::PgExecutor<'_>,
+        stings: Vec<StingCreate>,
+        dispatch_event_data: &DispatchEventData,
+    ) -> Result<Vec<Sting>, crate::Error>;
+}
+
+/// The maximum number of stings that can be created in one batch
+pub const MAX_STING_BATCH_SIZE: usize = 1000;
+
+/// Version of the payload of the ``AR/Sting*`` events, bumped whenever its shape changes incompatibly
+pub const STING_EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// Builds an ``AR/StingCreate``, ``AR/StingUpdate`` or ``AR/StingDelete`` event
+///
+/// The payload is ``{"schema_version": STING_EVENT_SCHEMA_VERSION, "sting": <the full sting>}``
+pub fn sting_event(
+    name: &str,
+    titlename: &str,
+    sting: &Sting,
+) -> Result<AntiraidEvent, crate::Error> {
+    CustomEventBuilder::new(name, titlename)?
+        .field("schema_version", &STING_EVENT_SCHEMA_VERSION)?
+        .field("sting", sting)?
+        .build()
+}
+
+/// Dispatches a sting event to the template worker, a no-op if the ``sting_events`` feature is disabled
+async fn dispatch_sting_event(
+    ctx: &serenity::all::Context,
+    guild_id: serenity::all::GuildId,
+    event: AntiraidEvent,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    if !cfg!(feature = "sting_events") {
+        return Ok(());
+    }
+
+    let data = ctx.data::<Data>();
+
+    event
+        .dispatch_to_template_worker_and_nowait(&data, guild_id, dispatch_event_data)
+        .await
+}
+
+/// Logs a failed sting event dispatch
+///
+/// Dispatch failures (e.g. while the template worker restarts) must not fail the database write that preceded them
+fn log_dispatch_failure(
+    res: Result<(), crate::Error>,
+    event: &str,
+    guild_id: serenity::all::GuildId,
+) {
+    if let Err(e) = res {
+        log::error!(
+            "Failed to dispatch {} event for guild {}: {}",
+            event,
+            guild_id,
+            e
+        );
+    }
+}
+
+/// Dispatch a single aggregate event for a batch of created stings
+async fn dispatch_create_many_event(
+    stings: &[Sting],
+    ctx: serenity::all::Context,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    let Some(first) = stings.first() else {
+        return Ok(());
+    };
+
+    let event = sting_create_many_event(stings)?;
+
+    dispatch_sting_event(&ctx, first.guild_id, event, dispatch_event_data).await
+}
+
+/// Builds the ``AR/StingCreateMany`` event for a batch of created stings
+///
+/// The payload is ``{"schema_version": STING_EVENT_SCHEMA_VERSION, "stings": [<the full stings>]}``
+pub fn sting_create_many_event(stings: &[Sting]) -> Result<AntiraidEvent, crate::Error> {
+    CustomEventBuilder::new(STING_CREATE_MANY_EVENT, "(Anti-Raid) Sting Create Many")?
+        .field("schema_version", &STING_EVENT_SCHEMA_VERSION)?
+        .field("stings", stings)?
+        .build()
+}
+
+impl CreateValidation for StingCreate {
+    /// Trims the reasons and lowercases the src, empty values are dropped
+    fn normalize(&mut self) {
+        normalize_src(&mut self.src);
+        normalize_reason(&mut self.reason);
+        normalize_reason(&mut self.void_reason);
+    }
+
+    /// Validates the sting against ``limits``
+    fn validate_with(&self, limits: &CreateLimits) -> Result<(), CreateValidationError> {
+        if self.stings < 0 {
+            return Err(CreateValidationError::new("stings", "must not be negative"));
+        }
+
+        if self.stings > limits.max_stings {
+            return Err(CreateValidationError::new(
+                "stings",
+                format!("must be at most {}", limits.max_stings),
+            ));
+        }
+
+        if self.reason.is_none() && self.src.is_none() {
+            return Err(CreateValidationError::new(
+                "reason",
+                "must be set if the sting has no src",
+            ));
+        }
+
+        validate_reason("reason", self.reason.as_deref(), limits)?;
+        validate_reason("void_reason", self.void_reason.as_deref(), limits)?;
+        validate_duration(self.duration, limits)?;
+
+        if let (StingTarget::User(creator), StingTarget::User(target)) =
+            (&self.creator, &self.target)
+        {
+            if creator == target {
+                return Err(CreateValidationError::new(
+                    "target",
+                    "must not be the same user as the creator",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a new Sting without dispatching it as an event, validating it against ``limits``
+///
+/// The sting is normalized first, ``StingCreateOperations::create_without_dispatch`` uses the default limits
+pub async fn create_sting_with_limits(
+    db: impl sqlx::PgExecutor<'_>,
+    mut sting: StingCreate,
+    limits: &CreateLimits,
+) -> Result<Sting, crate::Error> {
+    sting.normalize();
+    sting.validate_with(limits)?;
+
+    let ret_data = sqlx::query(
+        r#"
+        INSERT INTO stings (src, stings, reason, void_reason, guild_id, target, creator, state, duration, sting_data)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, make_interval(secs => $9), $10) RETURNING id, created_at
+        "#,
+    )
+    .bind(&sting.src)
+    .bind(sting.stings)
+    .bind(&sting.reason)
+    .bind(&sting.void_reason)
+    .bind(sting.guild_id.to_string())
+    .bind(sting.target.to_string())
+    .bind(sting.creator.to_string())
+    .bind(sting.state.to_string())
+    .bind(sting.duration.map(|d| d.as_secs() as f64))
+    .bind(&sting.sting_data)
+    .fetch_one(db)
+    .await?;
+
+    Ok(sting.to_sting(ret_data.try_get("id")?, ret_data.try_get("created_at")?))
+}
+
+/// Creates many Stings in a single INSERT without dispatching them as events, validating them against ``limits``
+///
+/// See ``StingCreateOperations::create_many_without_dispatch``, which uses the default limits
+pub async fn create_many_stings_with_limits(
+    db: impl sqlx::PgExecutor<'_>,
+    mut stings: Vec<StingCreate>,
+    limits: &CreateLimits,
+) -> Result<Vec<Sting>, crate::Error> {
+    if stings.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if stings.len() > MAX_STING_BATCH_SIZE {
+        return Err(format!(
+            "Too many stings in one batch: {} > {}",
+            stings.len(),
+            MAX_STING_BATCH_SIZE
+        )
+        .into());
+    }
+
+    let guild_id = stings[0].guild_id;
+
+    if stings.iter().any(|s| s.guild_id != guild_id) {
+        return Err("All stings in a batch must share the same guild_id".into());
+    }
+
+    for (i, sting) in stings.iter_mut().enumerate() {
+        sting.normalize();
+        sting.validate_with(limits).map_err(|e| e.at_row(i))?;
+    }
+
+    // IDs are generated here so the returned rows can be matched back to the input order
+    let mut ids = Vec::with_capacity(stings.len());
+    let mut srcs = Vec::with_capacity(stings.len());
+    let mut counts = Vec::with_capacity(stings.len());
+    let mut reasons = Vec::with_capacity(stings.len());
+    let mut void_reasons = Vec::with_capacity(stings.len());
+    let mut guild_ids = Vec::with_capacity(stings.len());
+    let mut targets = Vec::with_capacity(stings.len());
+    let mut creators = Vec::with_capacity(stings.len());
+    let mut states = Vec::with_capacity(stings.len());
+    let mut durations = Vec::with_capacity(stings.len());
+    let mut sting_datas = Vec::with_capacity(stings.len());
+
+    for sting in stings.iter() {
+        ids.push(sqlx::types::Uuid::new_v4());
+        srcs.push(sting.src.clone());
+        counts.push(sting.stings);
+        reasons.push(sting.reason.clone());
+        void_reasons.push(sting.void_reason.clone());
+        guild_ids.push(sting.guild_id.to_string());
+        targets.push(sting.target.to_string());
+        creators.push(sting.creator.to_string());
+        states.push(sting.state.to_string());
+        durations.push(sting.duration.map(|d| d.as_secs() as f64));
+        sting_datas.push(sting.sting_data.clone());
+    }
+
+    let rows = sqlx::query(
+        r#"
+        INSERT INTO stings (id, src, stings, reason, void_reason, guild_id, target, creator, state, duration, sting_data)
+        SELECT id, src, stings, reason, void_reason, guild_id, target, creator, state, make_interval(secs => duration), sting_data
+        FROM UNNEST($1::uuid[], $2::text[], $3::integer[], $4::text[], $5::text[], $6::text[], $7::text[], $8::text[], $9::text[], $10::float8[], $11::jsonb[])
+        AS t(id, src, stings, reason, void_reason, guild_id, target, creator, state, duration, sting_data)
+        RETURNING id, created_at
+        "#,
+    )
+    .bind(&ids)
+    .bind(&srcs)
+    .bind(&counts)
+    .bind(&reasons)
+    .bind(&void_reasons)
+    .bind(&guild_ids)
+    .bind(&targets)
+    .bind(&creators)
+    .bind(&states)
+    .bind(&durations)
+    .bind(&sting_datas)
+    .fetch_all(db)
+    .await?;
+
+    let mut created_ats = std::collections::HashMap::with_capacity(rows.len());
+
+    for row in rows {
+        let id: sqlx::types::Uuid = row.try_get("id")?;
+        let created_at: chrono::DateTime<chrono::Utc> = row.try_get("created_at")?;
+        created_ats.insert(id, created_at);
+    }
+
+    let mut created = Vec::with_capacity(stings.len());
+
+    for (sting, id) in stings.into_iter().zip(ids) {
+        let Some(created_at) = created_ats.remove(&id) else {
+            return Err(format!("Sting {} was not returned by the batch insert", id).into());
+        };
+
+        created.push(sting.to_sting(id, created_at));
+    }
+
+    Ok(created)
 }
 
 impl StingCreateOperations for StingCreate {
     /// Creates a new Sting without dispatching it as an event
+    ///
+    /// The sting is normalized and validated against the default ``CreateLimits``, see ``create_sting_with_limits``
     async fn create_without_dispatch(
         self,
         db: impl sqlx::PgExecutor<'_>,
     ) -> Result<Sting, crate::Error> {
-        let ret_data = sqlx::query(
-            r#"
-            INSERT INTO stings (src, stings, reason, void_reason, guild_id, target, creator, state, duration, sting_data)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, make_interval(secs => $9), $10) RETURNING id, created_at
-            "#,
-        )
-        .bind(&self.src)
-        .bind(self.stings)
-        .bind(&self.reason)
-        .bind(&self.void_reason)
-        .bind(self.guild_id.to_string())
-        .bind(self.target.to_string())
-        .bind(self.creator.to_string())
-        .bind(self.state.to_string())
-        .bind(self.duration.map(|d| d.as_secs() as f64))
-        .bind(&self.sting_data)
-        .fetch_one(db)
-        .await?;
-
-        Ok(self.to_sting(ret_data.try_get("id")?, ret_data.try_get("created_at")?))
+        create_sting_with_limits(db, self, &CreateLimits::default()).await
     }
 
     /// Creates a new Sting and dispatches it as an event in one go
@@ -369,9 +755,12 @@ impl StingCreateOperations for StingCreate {
     ) -> Result<(), crate::Error> {
         let sting = self.create_without_dispatch(db).await?;
 
-        sting
-            .dispatch_create_event(ctx, dispatch_event_data)
-            .await?;
+        let guild_id = sting.guild_id;
+        log_dispatch_failure(
+            sting.dispatch_create_event(ctx, dispatch_event_data).await,
+            STING_CREATE_EVENT,
+            guild_id,
+        );
 
         Ok(())
     }
@@ -386,12 +775,47 @@ impl StingCreateOperations for StingCreate {
         let sting = self.create_without_dispatch(db).await?;
         let sid = sting.id;
 
-        sting
-            .dispatch_create_event(ctx, dispatch_event_data)
-            .await?;
+        let guild_id = sting.guild_id;
+        log_dispatch_failure(
+            sting.dispatch_create_event(ctx, dispatch_event_data).await,
+            STING_CREATE_EVENT,
+            guild_id,
+        );
 
         Ok(sid)
     }
+
+    /// Creates many Stings in a single INSERT without dispatching them as events
+    ///
+    /// The returned Stings are in the same order as the input. All entries must share the same guild_id.
+    /// Every entry is normalized and validated against the default ``CreateLimits`` first, nothing is inserted if any
+    /// entry is invalid
+    async fn create_many_without_dispatch(
+        db: impl sqlx::PgExecutor<'_>,
+        stings: Vec<StingCreate>,
+    ) -> Result<Vec<Sting>, crate::Error> {
+        create_many_stings_with_limits(db, stings, &CreateLimits::default()).await
+    }
+
+    /// Creates many Stings in a single INSERT and dispatches one aggregate event for all of them
+    async fn create_many_and_dispatch(
+        ctx: serenity::all::Context,
+        db: impl sqlx::PgExecutor<'_>,
+        stings: Vec<StingCreate>,
+        dispatch_event_data: &DispatchEventData,
+    ) -> Result<Vec<Sting>, crate::Error> {
+        let stings = Self::create_many_without_dispatch(db, stings).await?;
+
+        if let Some(first) = stings.first() {
+            log_dispatch_failure(
+                dispatch_create_many_event(&stings, ctx, dispatch_event_data).await,
+                STING_CREATE_MANY_EVENT,
+                first.guild_id,
+            );
+        }
+
+        Ok(stings)
+    }
 }
 
 #[derive(sqlx::FromRow)]
@@ -470,3 +894,583 @@ impl StingAggregateOperations for StingAggregate {
         Ok(stings)
     }
 }
+
+/// Returns the weighted (``SUM(stings)``) active stings targeting the user directly, grouped by source
+///
+/// Unlike ``StingAggregateOperations::guild_user``, guild-wide ``system`` stings are not included
+pub async fn user_sting_weights(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: serenity::all::GuildId,
+    target: serenity::all::UserId,
+) -> Result<Vec<StingAggregate>, crate::Error> {
+    let rec: Vec<StingAggregateRow> = sqlx::query_as(
+        "SELECT SUM(stings) AS total_stings, src, target FROM stings WHERE guild_id = $1 AND state = 'active' AND target = $2 GROUP BY src, target",
+    )
+    .bind(guild_id.to_string())
+    .bind(StingTarget::User(target).to_string())
+    .fetch_all(db)
+    .await?;
+
+    let mut stings = Vec::with_capacity(rec.len());
+
+    for row in rec {
+        stings.push(row.into_sting_aggregate()?);
+    }
+
+    Ok(stings)
+}
+
+/// What to do with a sting once it has expired
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpiryAction {
+    /// Mark the sting as handled
+    Handle,
+    /// Void the sting
+    Void,
+}
+
+/// The number of expired stings/punishments handled per transaction
+pub const EXPIRY_BATCH_SIZE: i64 = 100;
+
+/// Handles all expired stings in batches, returning the stings handled
+///
+/// Stings are selected with ``FOR UPDATE SKIP LOCKED`` so this can safely run from multiple processes at once. Rows
+/// which fail to decode are logged and skipped for the rest of the cycle instead of aborting it
+pub async fn run_expiry_cycle_without_dispatch(
+    pool: &sqlx::PgPool,
+    action: ExpiryAction,
+) -> Result<Vec<Sting>, crate::Error> {
+    let mut handled = Vec::new();
+    let mut skipped = Vec::new();
+
+    loop {
+        let mut tx = pool.begin().await?;
+
+        let rec: Vec<StingRow> = sqlx::query_as(
+            "SELECT id, src, stings, reason, void_reason, guild_id, creator, target, state, sting_data, created_at, duration, handle_log FROM stings WHERE duration IS NOT NULL AND state = 'active' AND (created_at + duration) < NOW() AND id <> ALL($2) ORDER BY created_at ASC LIMIT $1 FOR UPDATE SKIP LOCKED",
+        )
+        .bind(EXPIRY_BATCH_SIZE)
+        .bind(&skipped)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let batch_len = rec.len();
+
+        for row in rec {
+            let id = row.id;
+
+            let mut sting = match row.into_sting() {
+                Ok(sting) => sting,
+                Err(e) => {
+                    log::error!("Skipping undecodable sting row {}: {}", id, e);
+                    skipped.push(id);
+                    continue;
+                }
+            };
+
+            match action {
+                ExpiryAction::Handle => sting.state = StingState::Handled,
+                ExpiryAction::Void => {
+                    sting.state = StingState::Voided;
+                    sting.void_reason = Some("Sting expired".to_string());
+                }
+            }
+
+            push_handle_log_entry(
+                &mut sting.handle_log,
+                &HandleLogEntry::system(
+                    "expired",
+                    serde_json::json!({ "new_state": sting.state.to_string() }),
+                ),
+            )?;
+
+            sting.update_without_dispatch(&mut *tx).await?;
+            handled.push(sting);
+        }
+
+        tx.commit().await?;
+
+        if batch_len < EXPIRY_BATCH_SIZE as usize {
+            break;
+        }
+    }
+
+    Ok(handled)
+}
+
+/// Handles all expired stings (see ``run_expiry_cycle_without_dispatch``) and dispatches an ``AR/StingExpire`` event
+/// for each, returning the number of stings handled
+///
+/// Dispatch failures are logged and do not fail the cycle as the stings were already updated
+pub async fn run_expiry_cycle(
+    pool: &sqlx::PgPool,
+    ctx: serenity::all::Context,
+    dispatch_event_data: &DispatchEventData,
+    action: ExpiryAction,
+) -> Result<usize, crate::Error> {
+    let handled = run_expiry_cycle_without_dispatch(pool, action).await?;
+    let data = ctx.data::<Data>();
+
+    for sting in handled.iter() {
+        log_dispatch_failure(
+            dispatch_expire_event(&data, sting, dispatch_event_data).await,
+            STING_EXPIRE_EVENT,
+            sting.guild_id,
+        );
+    }
+
+    Ok(handled.len())
+}
+
+/// Dispatches an ``AR/StingExpire`` event for a sting, a no-op if the ``sting_events`` feature is disabled
+pub async fn dispatch_expire_event(
+    data: &Data,
+    sting: &Sting,
+    dispatch_event_data: &DispatchEventData,
+) -> Result<(), crate::Error> {
+    if !cfg!(feature = "sting_events") {
+        return Ok(());
+    }
+
+    sting_event(STING_EXPIRE_EVENT, "(Anti-Raid) Sting Expire", sting)?
+        .dispatch_to_template_worker_and_nowait(data, sting.guild_id, dispatch_event_data)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, STINGS};
+    use serenity::all::{GuildId, UserId};
+    use std::time::Duration;
+
+    fn sting(stings: i32) -> StingCreate {
+        StingCreate {
+            src: None,
+            stings,
+            reason: Some("spam".to_string()),
+            void_reason: None,
+            guild_id: GuildId::new(1),
+            creator: StingTarget::System,
+            target: StingTarget::User(UserId::new(2)),
+            state: StingState::Active,
+            duration: None,
+            sting_data: None,
+        }
+    }
+
+    async fn count(pool: &sqlx::PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM stings")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn invalid_batch_entry_reports_its_row_and_inserts_nothing(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        let err = StingCreate::create_many_without_dispatch(
+            &pool,
+            vec![sting(1), sting(2), sting(-1), sting(3)],
+        )
+        .await
+        .unwrap_err();
+
+        let err = err.downcast_ref::<CreateValidationError>().unwrap();
+        assert_eq!(err.row, Some(2));
+        assert_eq!(err.field, "stings");
+        assert_eq!(count(&pool).await, 0);
+    }
+
+    fn golden_sting(n: u128, stings: i32) -> Sting {
+        sting(stings).to_sting(
+            uuid::Uuid::from_u128(n),
+            chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        )
+    }
+
+    fn event_data(event: AntiraidEvent, name: &str, titlename: &str) -> serde_json::Value {
+        let AntiraidEvent::Custom(event) = event else {
+            panic!("expected a custom event");
+        };
+
+        assert_eq!(event.event_name, name);
+        assert_eq!(event.event_titlename, titlename);
+
+        event.event_data
+    }
+
+    /// Fields templates read from the ``sting`` payload, these must not change without bumping the schema version
+    fn assert_golden_sting(payload: &serde_json::Value, id: &str, stings: i32) {
+        assert_eq!(payload["id"], id);
+        assert_eq!(payload["stings"], stings);
+        assert_eq!(payload["reason"], "spam");
+        assert_eq!(payload["void_reason"], serde_json::Value::Null);
+        assert_eq!(payload["guild_id"], "1");
+        assert_eq!(payload["created_at"], "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn sting_events_match_golden_payload() {
+        assert_eq!(STING_EVENT_SCHEMA_VERSION, 1);
+
+        let sting = golden_sting(1, 3);
+
+        for (name, titlename) in [
+            (STING_CREATE_EVENT, "(Anti-Raid) Sting Create"),
+            (STING_UPDATE_EVENT, "(Anti-Raid) Sting Update"),
+            (STING_DELETE_EVENT, "(Anti-Raid) Sting Delete"),
+        ] {
+            let data = event_data(
+                sting_event(name, titlename, &sting).unwrap(),
+                name,
+                titlename,
+            );
+
+            // Exactly the versioned envelope around the full sting
+            assert_eq!(
+                data,
+                serde_json::json!({
+                    "schema_version": 1,
+                    "sting": serde_json::to_value(&sting).unwrap(),
+                })
+            );
+
+            assert_golden_sting(&data["sting"], "00000000-0000-0000-0000-000000000001", 3);
+        }
+    }
+
+    #[test]
+    fn sting_create_many_event_matches_golden_payload() {
+        let stings = vec![golden_sting(1, 3), golden_sting(2, 5)];
+
+        let data = event_data(
+            sting_create_many_event(&stings).unwrap(),
+            STING_CREATE_MANY_EVENT,
+            "(Anti-Raid) Sting Create Many",
+        );
+
+        assert_eq!(
+            data,
+            serde_json::json!({
+                "schema_version": 1,
+                "stings": serde_json::to_value(&stings).unwrap(),
+            })
+        );
+
+        // One aggregate event with the stings in creation order
+        assert_golden_sting(
+            &data["stings"][0],
+            "00000000-0000-0000-0000-000000000001",
+            3,
+        );
+        assert_golden_sting(
+            &data["stings"][1],
+            "00000000-0000-0000-0000-000000000002",
+            5,
+        );
+        assert_eq!(data["stings"].as_array().unwrap().len(), 2);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn create_many_preserves_input_order(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        // Distinct targets and counts so a reordered row can't compare equal to its neighbour
+        let input = (0..200u64)
+            .map(|i| {
+                let mut s = sting((i % 7) as i32);
+                s.target = StingTarget::User(UserId::new(1000 + (i * 7919) % 1009));
+                s.reason = Some(format!("raid member {}", i));
+                s
+            })
+            .collect::<Vec<_>>();
+
+        let created = StingCreate::create_many_without_dispatch(&pool, input.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(created.len(), input.len());
+
+        for (i, (sting, expected)) in created.iter().zip(&input).enumerate() {
+            assert_eq!(
+                sting.target.to_string(),
+                expected.target.to_string(),
+                "row {}",
+                i
+            );
+            assert_eq!(sting.stings, expected.stings, "row {}", i);
+            assert_eq!(sting.reason, expected.reason, "row {}", i);
+
+            // The returned id and created_at must belong to the row built from this input
+            let stored = Sting::get(&pool, GuildId::new(1), sting.id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                stored.target.to_string(),
+                expected.target.to_string(),
+                "row {}",
+                i
+            );
+            assert_eq!(stored.reason, expected.reason, "row {}", i);
+            assert_eq!(stored.created_at, sting.created_at, "row {}", i);
+        }
+
+        assert_eq!(count(&pool).await, 200);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn create_many_rejects_invalid_batches(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        assert!(StingCreate::create_many_without_dispatch(&pool, Vec::new())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let too_many = vec![sting(1); MAX_STING_BATCH_SIZE + 1];
+        assert!(StingCreate::create_many_without_dispatch(&pool, too_many)
+            .await
+            .is_err());
+
+        let mut other_guild = sting(1);
+        other_guild.guild_id = GuildId::new(3);
+        assert!(
+            StingCreate::create_many_without_dispatch(&pool, vec![sting(1), other_guild])
+                .await
+                .is_err()
+        );
+
+        assert_eq!(count(&pool).await, 0);
+
+        let max = vec![sting(1); MAX_STING_BATCH_SIZE];
+        assert_eq!(
+            StingCreate::create_many_without_dispatch(&pool, max)
+                .await
+                .unwrap()
+                .len(),
+            MAX_STING_BATCH_SIZE
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn limits_are_threaded_through_create(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        let defaults = CreateLimits::default();
+        let mut long = sting(1);
+        long.duration = Some(defaults.max_duration + Duration::from_secs(60));
+
+        assert!(create_sting_with_limits(&pool, long.clone(), &defaults)
+            .await
+            .is_err());
+
+        let raised = CreateLimits {
+            max_duration: defaults.max_duration * 2,
+            ..defaults
+        };
+
+        let created = create_sting_with_limits(&pool, long.clone(), &raised)
+            .await
+            .unwrap();
+        assert_eq!(created.duration, long.duration);
+
+        assert!(
+            create_many_stings_with_limits(&pool, vec![long.clone()], &defaults)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            create_many_stings_with_limits(&pool, vec![long], &raised)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(count(&pool).await, 2);
+    }
+
+    async fn insert_aged(pool: &sqlx::PgPool, n: i32) {
+        sqlx::query(
+            "INSERT INTO stings (guild_id, creator, target, created_at) SELECT '1', $1, $2, NOW() - make_interval(secs => g) FROM generate_series(1, $3) g",
+        )
+        .bind(StingTarget::System.to_string())
+        .bind(StingTarget::User(UserId::new(2)).to_string())
+        .bind(n)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn list_pages_match_list_with_options(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+        insert_aged(&pool, 25).await;
+
+        let guild = GuildId::new(1);
+
+        let first = Sting::list_with_options(&pool, guild, &ListOptions::default())
+            .await
+            .unwrap();
+        let second = Sting::list_with_options(
+            &pool,
+            guild,
+            &ListOptions {
+                cursor: first.next_cursor.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let ids = |stings: &[Sting]| stings.iter().map(|s| s.id).collect::<Vec<_>>();
+
+        // Page 0 used to be a separate code path, it must behave like page 1
+        let page_0 = Sting::list(&pool, guild, 0).await.unwrap();
+        let page_1 = Sting::list(&pool, guild, 1).await.unwrap();
+        assert_eq!(page_0.len(), 20);
+        assert_eq!(ids(&page_0), ids(&page_1));
+        assert_eq!(ids(&page_1), ids(&first.items));
+
+        let page_2 = Sting::list(&pool, guild, 2).await.unwrap();
+        assert_eq!(ids(&page_2), ids(&second.items));
+        assert_eq!(page_2.len(), 5);
+
+        assert!(Sting::list(&pool, guild, 3).await.unwrap().is_empty());
+        assert!(Sting::list(&pool, guild, usize::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn list_pages_break_created_at_ties_by_id(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        sqlx::query(
+            "INSERT INTO stings (guild_id, creator, target, created_at) SELECT '1', $1, $2, '2024-01-01T00:00:00Z' FROM generate_series(1, 25)",
+        )
+        .bind(StingTarget::System.to_string())
+        .bind(StingTarget::User(UserId::new(2)).to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let guild = GuildId::new(1);
+        let ids = |stings: &[Sting]| stings.iter().map(|s| s.id).collect::<Vec<_>>();
+
+        // Transactions are not ``Copy`` and must be usable as the executor
+        let mut tx = pool.begin().await.unwrap();
+
+        let mut listed = Vec::new();
+        let mut options = ListOptions::default();
+        for page in 1..=2 {
+            let strict = Sting::list(&mut *tx, guild, page).await.unwrap();
+            let (lossy, failures) = Sting::list_lossy(&mut *tx, guild, page).await.unwrap();
+            assert!(failures.is_empty());
+            assert_eq!(ids(&strict), ids(&lossy));
+
+            let keyset = Sting::list_with_options(&mut *tx, guild, &options)
+                .await
+                .unwrap();
+            assert_eq!(ids(&strict), ids(&keyset.items));
+            options.cursor = keyset.next_cursor;
+
+            listed.extend(ids(&strict));
+        }
+
+        let mut unique = listed.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(listed.len(), 25);
+        assert_eq!(unique.len(), 25);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn list_lossy_skips_bad_rows(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+        insert_aged(&pool, 3).await;
+
+        let bad: uuid::Uuid = sqlx::query_scalar(
+            "INSERT INTO stings (guild_id, creator, target) VALUES ('1', 'garbage', $1) RETURNING id",
+        )
+        .bind(StingTarget::User(UserId::new(2)).to_string())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let guild = GuildId::new(1);
+
+        assert!(Sting::list(&pool, guild, 1).await.is_err());
+
+        let (stings, failures) = Sting::list_lossy(&pool, guild, 1).await.unwrap();
+        assert_eq!(stings.len(), 3);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].id, bad.to_string());
+        assert!(
+            failures[0].error.contains("creator"),
+            "{}",
+            failures[0].error
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn expiry_cycle_skips_undecodable_rows(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        // A whole batch of undecodable rows ahead of the valid ones must not stall the cycle
+        sqlx::query(
+            "INSERT INTO stings (guild_id, creator, target, duration, created_at) SELECT 'not-a-guild', 'system', 'user:2', interval '1 second', NOW() - interval '1 day' - make_interval(secs => g) FROM generate_series(1, $1) g",
+        )
+        .bind(EXPIRY_BATCH_SIZE as i32)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut expired = Vec::new();
+        for _ in 0..3 {
+            let created = StingCreate {
+                duration: Some(Duration::from_secs(60)),
+                ..sting(1)
+            }
+            .create_without_dispatch(&pool)
+            .await
+            .unwrap();
+            expired.push(created.id);
+        }
+
+        let active = StingCreate {
+            duration: Some(Duration::from_secs(3600)),
+            ..sting(1)
+        }
+        .create_without_dispatch(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query("UPDATE stings SET created_at = NOW() - interval '1 hour' WHERE id = ANY($1)")
+            .bind(&expired)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let handled = run_expiry_cycle_without_dispatch(&pool, ExpiryAction::Void)
+            .await
+            .unwrap();
+
+        let mut ids = handled.iter().map(|s| s.id).collect::<Vec<_>>();
+        ids.sort();
+        expired.sort();
+        assert_eq!(ids, expired);
+        assert!(handled.iter().all(|s| s.state == StingState::Voided));
+
+        let states: Vec<(uuid::Uuid, String)> =
+            sqlx::query_as("SELECT id, state FROM stings WHERE state = 'active'")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(states.len(), EXPIRY_BATCH_SIZE as usize + 1);
+        assert!(states.iter().any(|(id, _)| *id == active.id));
+    }
+}
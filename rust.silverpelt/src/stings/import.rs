@@ -0,0 +1,429 @@
+use std::collections::HashSet;
+
+use antiraid_types::stings::{StingCreate, StingState, StingTarget};
+use serde::Deserialize;
+use sqlx::Row;
+
+use super::MAX_STING_BATCH_SIZE;
+use crate::create_validation::{normalize_src, CreateValidation};
+
+/// A warning/sting exported from another moderation bot
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ImportedSting {
+    /// The ID of the entry in the external bot, used to deduplicate re-imports
+    #[serde(alias = "id", deserialize_with = "string_or_number")]
+    pub external_id: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// The moderator who issued the warning, if known
+    #[serde(
+        default,
+        alias = "moderator",
+        alias = "mod_id",
+        deserialize_with = "opt_string_or_number"
+    )]
+    pub moderator_id: Option<String>,
+    /// The user who was warned
+    #[serde(
+        alias = "user_id",
+        alias = "user",
+        deserialize_with = "string_or_number"
+    )]
+    pub target_id: String,
+    /// When the warning was originally issued
+    #[serde(alias = "created_at", alias = "date")]
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// The number of stings the warning is worth
+    #[serde(default = "default_weight")]
+    pub weight: i32,
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        v => Err(serde::de::Error::custom(format!(
+            "expected string or number, got {}",
+            v
+        ))),
+    }
+}
+
+fn opt_string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(s) => Ok(Some(s)),
+        serde_json::Value::Number(n) => Ok(Some(n.to_string())),
+        v => Err(serde::de::Error::custom(format!(
+            "expected string, number or null, got {}",
+            v
+        ))),
+    }
+}
+
+/// Parses an export that is a JSON array of warnings
+pub fn parse_json_export(data: &str) -> Result<Vec<ImportedSting>, crate::Error> {
+    Ok(serde_json::from_str::<Vec<ImportedSting>>(data)?)
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ImportError {
+    pub external_id: String,
+    pub error: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct ImportReport {
+    pub created: usize,
+    pub skipped_duplicates: usize,
+    pub errors: Vec<ImportError>,
+}
+
+/// Returns the ``src`` used for stings imported under the given label, normalized like ``StingCreate::normalize``
+pub fn import_src(src_label: &str) -> String {
+    let mut src = Some(format!("import:{}", src_label));
+    normalize_src(&mut src);
+    src.unwrap_or_default()
+}
+
+/// Maps an imported entry onto a normalized and validated StingCreate
+fn to_sting_create(
+    entry: &ImportedSting,
+    guild_id: serenity::all::GuildId,
+    src_label: &str,
+) -> Result<StingCreate, crate::Error> {
+    let target = entry
+        .target_id
+        .parse::<serenity::all::UserId>()
+        .map_err(|e| format!("Invalid target id {}: {}", entry.target_id, e))?;
+
+    let creator = match entry
+        .moderator_id
+        .as_ref()
+        .and_then(|m| m.parse::<serenity::all::UserId>().ok())
+    {
+        Some(moderator) => StingTarget::User(moderator),
+        None => StingTarget::System,
+    };
+
+    let mut sting = StingCreate {
+        src: Some(import_src(src_label)),
+        stings: entry.weight,
+        reason: entry.reason.clone(),
+        void_reason: None,
+        guild_id,
+        creator,
+        target: StingTarget::User(target),
+        state: StingState::Active,
+        duration: None,
+        sting_data: Some(serde_json::json!({
+            "import": {
+                "source": src_label,
+                "external_id": entry.external_id,
+            }
+        })),
+    };
+
+    sting.normalize();
+    sting.validate()?;
+
+    Ok(sting)
+}
+
+/// Serializes imports of the same label into a guild so concurrent imports cannot both miss each other's rows
+async fn lock_import(
+    db: impl sqlx::PgExecutor<'_>,
+    guild_id: serenity::all::GuildId,
+    src_label: &str,
+) -> Result<(), crate::Error> {
+    sqlx::query(
+        "SELECT pg_advisory_xact_lock(hashtext('sting_import'), hashtext($1 || ':' || $2))",
+    )
+    .bind(guild_id.to_string())
+    .bind(src_label)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Imports stings from another bot, preserving their original timestamps
+///
+/// Entries whose external ID was already imported under the same label are skipped. The import runs in a single
+/// transaction, so either every valid entry is inserted or none are
+pub async fn import_stings(
+    pool: &sqlx::PgPool,
+    guild_id: serenity::all::GuildId,
+    entries: Vec<ImportedSting>,
+    src_label: &str,
+) -> Result<ImportReport, crate::Error> {
+    let mut report = ImportReport::default();
+
+    let mut tx = pool.begin().await?;
+
+    lock_import(&mut *tx, guild_id, src_label).await?;
+
+    let external_ids = entries
+        .iter()
+        .map(|e| e.external_id.clone())
+        .collect::<Vec<_>>();
+
+    let mut seen = HashSet::new();
+
+    for row in sqlx::query(
+        "SELECT sting_data->'import'->>'external_id' AS external_id FROM stings WHERE guild_id = $1 AND src = $2 AND sting_data->'import'->>'external_id' = ANY($3)",
+    )
+    .bind(guild_id.to_string())
+    .bind(import_src(src_label))
+    .bind(&external_ids)
+    .fetch_all(&mut *tx)
+    .await?
+    {
+        if let Some(id) = row.try_get::<Option<String>, _>("external_id")? {
+            seen.insert(id);
+        }
+    }
+
+    let mut to_insert = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        // Also catches duplicates within the same import
+        if !seen.insert(entry.external_id.clone()) {
+            report.skipped_duplicates += 1;
+            continue;
+        }
+
+        match to_sting_create(&entry, guild_id, src_label) {
+            Ok(sting) => to_insert.push((sting, entry.timestamp)),
+            Err(e) => report.errors.push(ImportError {
+                external_id: entry.external_id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    for chunk in to_insert.chunks(MAX_STING_BATCH_SIZE) {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO stings (src, stings, reason, void_reason, guild_id, target, creator, state, sting_data, created_at)
+            SELECT * FROM UNNEST($1::text[], $2::integer[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[], $8::text[], $9::jsonb[], $10::timestamptz[])
+            "#,
+        )
+        .bind(chunk.iter().map(|(s, _)| s.src.clone()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.stings).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.reason.clone()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.void_reason.clone()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.guild_id.to_string()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.target.to_string()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.creator.to_string()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.state.to_string()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(s, _)| s.sting_data.clone()).collect::<Vec<_>>())
+        .bind(chunk.iter().map(|(_, ts)| *ts).collect::<Vec<_>>())
+        .execute(&mut *tx)
+        .await?;
+
+        report.created += res.rows_affected() as usize;
+    }
+
+    tx.commit().await?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_schema::{create_tables, STINGS};
+    use serenity::all::GuildId;
+
+    const EXPORT: &str = r#"[
+        {"id": 17, "reason": "spam", "moderator": "3", "user_id": 2, "date": "2021-05-01T12:00:00Z"},
+        {"external_id": "a", "mod_id": null, "target_id": "2", "timestamp": "2022-01-01T00:00:00Z", "weight": 3}
+    ]"#;
+
+    #[test]
+    fn parses_json_export_with_aliases() {
+        let entries = parse_json_export(EXPORT).unwrap();
+
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].external_id, "17");
+        assert_eq!(entries[0].reason.as_deref(), Some("spam"));
+        assert_eq!(entries[0].moderator_id.as_deref(), Some("3"));
+        assert_eq!(entries[0].target_id, "2");
+        assert_eq!(
+            entries[0].timestamp.to_rfc3339(),
+            "2021-05-01T12:00:00+00:00"
+        );
+        assert_eq!(entries[0].weight, 1);
+
+        assert_eq!(entries[1].external_id, "a");
+        assert_eq!(entries[1].reason, None);
+        assert_eq!(entries[1].moderator_id, None);
+        assert_eq!(entries[1].weight, 3);
+    }
+
+    #[test]
+    fn rejects_malformed_exports() {
+        // Not an array
+        assert!(parse_json_export(r#"{"id": 1}"#).is_err());
+        // Missing target
+        assert!(parse_json_export(r#"[{"id": 1, "timestamp": "2021-05-01T12:00:00Z"}]"#).is_err());
+        // Object ids are not accepted
+        assert!(parse_json_export(
+            r#"[{"id": {}, "user_id": 2, "timestamp": "2021-05-01T12:00:00Z"}]"#
+        )
+        .is_err());
+        // Bad timestamp
+        assert!(
+            parse_json_export(r#"[{"id": 1, "user_id": 2, "timestamp": "yesterday"}]"#).is_err()
+        );
+    }
+
+    #[test]
+    fn maps_creator_and_src() {
+        let mut entries = parse_json_export(EXPORT).unwrap();
+
+        let sting = to_sting_create(&entries[0], GuildId::new(1), "dyno").unwrap();
+        assert_eq!(sting.src.as_deref(), Some("import:dyno"));
+        assert_eq!(
+            sting.creator.to_string(),
+            StingTarget::User(serenity::all::UserId::new(3)).to_string()
+        );
+
+        let sting = to_sting_create(&entries[1], GuildId::new(1), "dyno").unwrap();
+        assert_eq!(sting.creator.to_string(), StingTarget::System.to_string());
+        assert_eq!(sting.stings, 3);
+
+        entries[1].target_id = "not a user".to_string();
+        assert!(to_sting_create(&entries[1], GuildId::new(1), "dyno").is_err());
+    }
+
+    #[test]
+    fn normalizes_and_validates_entries() {
+        let entries = parse_json_export(
+            r#"[
+                {"id": 1, "reason": "  spam  ", "user_id": 2, "date": "2021-05-01T12:00:00Z"},
+                {"id": 2, "reason": "spam", "user_id": 2, "weight": -1, "date": "2021-05-01T12:00:00Z"},
+                {"id": 3, "reason": "spam", "user_id": 2, "weight": 100000, "date": "2021-05-01T12:00:00Z"},
+                {"id": 4, "reason": "spam", "moderator": 2, "user_id": 2, "date": "2021-05-01T12:00:00Z"}
+            ]"#,
+        )
+        .unwrap();
+
+        let sting = to_sting_create(&entries[0], GuildId::new(1), "Dyno").unwrap();
+        assert_eq!(sting.reason.as_deref(), Some("spam"));
+        assert_eq!(sting.src.as_deref(), Some("import:dyno"));
+        assert_eq!(import_src("Dyno"), "import:dyno");
+
+        for (entry, field) in entries[1..].iter().zip(["stings", "stings", "target"]) {
+            let err = to_sting_create(entry, GuildId::new(1), "dyno").unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<crate::create_validation::CreateValidationError>()
+                    .unwrap()
+                    .field,
+                field
+            );
+        }
+    }
+
+    async fn created_ats(pool: &sqlx::PgPool) -> Vec<chrono::DateTime<chrono::Utc>> {
+        sqlx::query_scalar("SELECT created_at FROM stings ORDER BY created_at")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn import_preserves_timestamps_and_dedups(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        let entries = parse_json_export(EXPORT).unwrap();
+        let guild = GuildId::new(1);
+
+        let report = import_stings(&pool, guild, entries.clone(), "dyno")
+            .await
+            .unwrap();
+        assert_eq!(report.created, 2);
+        assert_eq!(report.skipped_duplicates, 0);
+
+        assert_eq!(
+            created_ats(&pool).await,
+            entries.iter().map(|e| e.timestamp).collect::<Vec<_>>()
+        );
+
+        let report = import_stings(&pool, guild, entries.clone(), "dyno")
+            .await
+            .unwrap();
+        assert_eq!(report.created, 0);
+        assert_eq!(report.skipped_duplicates, 2);
+
+        // The same external ids under another label are separate imports
+        let report = import_stings(&pool, guild, entries, "carl").await.unwrap();
+        assert_eq!(report.created, 2);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn invalid_entries_are_reported(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        let mut entries = parse_json_export(EXPORT).unwrap();
+        entries[1].weight = -1;
+
+        let report = import_stings(&pool, GuildId::new(1), entries, "Dyno")
+            .await
+            .unwrap();
+        assert_eq!(report.created, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].external_id, "a");
+        assert!(
+            report.errors[0].error.starts_with("stings:"),
+            "{}",
+            report.errors[0].error
+        );
+
+        // Labels differing only in case are the same import
+        let report = import_stings(
+            &pool,
+            GuildId::new(1),
+            parse_json_export(EXPORT).unwrap(),
+            "dyno",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.created, 1);
+        assert_eq!(report.skipped_duplicates, 1);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn concurrent_imports_do_not_duplicate(pool: sqlx::PgPool) {
+        create_tables(&pool, &[STINGS]).await;
+
+        let entries = parse_json_export(EXPORT).unwrap();
+        let guild = GuildId::new(1);
+
+        let imports = (0..8).map(|_| {
+            let pool = pool.clone();
+            let entries = entries.clone();
+            tokio::spawn(async move { import_stings(&pool, guild, entries, "dyno").await })
+        });
+
+        let mut created = 0;
+        for import in imports.collect::<Vec<_>>() {
+            created += import.await.unwrap().unwrap().created;
+        }
+
+        assert_eq!(created, 2);
+        assert_eq!(created_ats(&pool).await.len(), 2);
+    }
+}
@@ -0,0 +1,283 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio_util::sync::CancellationToken;
+
+/// Backoff before restarting a task whose iteration panicked, doubled on every consecutive panic
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long a running iteration may take to stop after shutdown before it is aborted
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Health of a background task
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub name: String,
+    /// Interval between runs in seconds
+    pub interval: u64,
+    pub running: bool,
+    pub run_count: u64,
+    pub failure_count: u64,
+    /// Number of times an iteration panicked and the task was restarted
+    pub restarts: u64,
+    pub last_run: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_success: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+    pub stopped: bool,
+}
+
+/// Registry of supervised background tasks (sting expiry, job reaper etc.)
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: DashMap<String, Arc<Mutex<TaskStatus>>>,
+    shutdown: CancellationToken,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers and spawns a task which runs ``fut_factory`` every ``interval``
+    ///
+    /// Every iteration runs in its own tokio task, so a panicking iteration is recorded and the task
+    /// restarted with backoff instead of the loop dying silently. The factory is given a ``CancellationToken``
+    /// which is cancelled on ``shutdown``, iterations should stop at the next safe point once it is
+    pub fn register<F, Fut>(
+        &self,
+        name: &str,
+        interval: Duration,
+        fut_factory: F,
+    ) -> Result<(), crate::Error>
+    where
+        F: Fn(CancellationToken) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), crate::Error>> + Send + 'static,
+    {
+        if interval.is_zero() {
+            return Err(format!("Task {} must have a non-zero interval", name).into());
+        }
+
+        let status = Arc::new(Mutex::new(TaskStatus {
+            name: name.to_string(),
+            interval: interval.as_secs(),
+            running: false,
+            run_count: 0,
+            failure_count: 0,
+            restarts: 0,
+            last_run: None,
+            last_success: None,
+            last_error: None,
+            stopped: false,
+        }));
+
+        match self.tasks.entry(name.to_string()) {
+            dashmap::mapref::entry::Entry::Occupied(_) => {
+                return Err(format!("Task {} is already registered", name).into());
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(status.clone());
+            }
+        }
+
+        let shutdown = self.shutdown.child_token();
+        let name = name.to_string();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut restart_backoff = INITIAL_RESTART_BACKOFF;
+
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+
+                {
+                    let mut status = status.lock().unwrap();
+                    status.running = true;
+                    status.last_run = Some(chrono::Utc::now());
+                }
+
+                let mut handle = tokio::spawn(fut_factory(shutdown.clone()));
+
+                let res = tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        // Give the iteration a chance to stop cooperatively before aborting it
+                        if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, &mut handle).await.is_err() {
+                            log::warn!("Background task {} did not stop in time, aborting it", name);
+                            handle.abort();
+                        }
+
+                        break;
+                    }
+                    res = &mut handle => res,
+                };
+
+                let panicked = {
+                    let mut status = status.lock().unwrap();
+                    status.running = false;
+                    status.run_count += 1;
+
+                    match res {
+                        Ok(Ok(())) => {
+                            status.last_success = Some(chrono::Utc::now());
+                            status.last_error = None;
+                            false
+                        }
+                        Ok(Err(e)) => {
+                            log::error!("Background task {} failed: {}", name, e);
+                            status.failure_count += 1;
+                            status.last_error = Some(e.to_string());
+                            false
+                        }
+                        Err(e) => {
+                            log::error!("Background task {} panicked: {}", name, e);
+                            status.failure_count += 1;
+                            status.restarts += 1;
+                            status.last_error = Some(format!("Panicked: {}", e));
+                            true
+                        }
+                    }
+                };
+
+                if panicked {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = tokio::time::sleep(restart_backoff) => {}
+                    }
+
+                    restart_backoff = (restart_backoff * 2).min(MAX_RESTART_BACKOFF);
+                } else {
+                    restart_backoff = INITIAL_RESTART_BACKOFF;
+                }
+            }
+
+            let mut status = status.lock().unwrap();
+            status.running = false;
+            status.stopped = true;
+        });
+
+        Ok(())
+    }
+
+    /// Returns the health of all registered tasks, sorted by name
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        let mut statuses = self
+            .tasks
+            .iter()
+            .map(|t| t.value().lock().unwrap().clone())
+            .collect::<Vec<_>>();
+
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        statuses
+    }
+
+    /// Cooperatively stops all tasks
+    ///
+    /// Running iterations see their ``CancellationToken`` cancelled and are aborted if they do not finish within
+    /// ``SHUTDOWN_GRACE_PERIOD``
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    async fn wait_for(registry: &TaskRegistry, cond: impl Fn(&TaskStatus) -> bool) -> TaskStatus {
+        for _ in 0..1000 {
+            if let Some(status) = registry.statuses().into_iter().find(|s| cond(s)) {
+                return status;
+            }
+
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        panic!("condition was never met");
+    }
+
+    #[tokio::test]
+    async fn rejects_zero_interval_and_duplicates() {
+        let registry = TaskRegistry::new();
+
+        assert!(registry
+            .register("zero", Duration::ZERO, |_| async { Ok(()) })
+            .is_err());
+        assert!(registry.statuses().is_empty());
+
+        assert!(registry
+            .register("task", Duration::from_secs(60), |_| async { Ok(()) })
+            .is_ok());
+        assert!(registry
+            .register("task", Duration::from_secs(60), |_| async { Ok(()) })
+            .is_err());
+        assert_eq!(registry.statuses().len(), 1);
+
+        registry.shutdown();
+    }
+
+    #[tokio::test]
+    async fn records_runs_and_failures() {
+        let registry = TaskRegistry::new();
+        let runs = Arc::new(AtomicU64::new(0));
+
+        let counter = runs.clone();
+        registry
+            .register("flaky", Duration::from_millis(10), move |_| {
+                let run = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if run.is_multiple_of(2) {
+                        Err("failed".into())
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .unwrap();
+
+        let status = wait_for(&registry, |s| s.run_count >= 4).await;
+        assert!(status.failure_count >= 2);
+        assert!(status.last_success.is_some());
+
+        registry.shutdown();
+        wait_for(&registry, |s| s.stopped).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_running_iterations_finish() {
+        let registry = TaskRegistry::new();
+        let started = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let (s, f) = (started.clone(), finished.clone());
+        registry
+            .register("slow", Duration::from_millis(10), move |token| {
+                let (started, finished) = (s.clone(), f.clone());
+                async move {
+                    started.store(true, Ordering::SeqCst);
+                    token.cancelled().await;
+                    // Cleanup after cancellation still runs
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    finished.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .unwrap();
+
+        while !started.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        registry.shutdown();
+        wait_for(&registry, |s| s.stopped).await;
+
+        assert!(finished.load(Ordering::SeqCst));
+    }
+}
@@ -1,6 +1,142 @@
 use crate::Error;
+use std::str::FromStr;
+
+pub use semver::{Version, VersionReq};
+
+/// Prefix of shop template references
+pub const SHOP_TEMPLATE_PREFIX: &str = "$shop/";
+
+/// Maximum length of a shop template name
+pub const SHOP_TEMPLATE_NAME_MAX_LENGTH: usize = 64;
+
+/// The version part of a shop template reference
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShopTemplateVersion {
+    /// The latest published (non pre-release) version
+    Latest,
+    /// Exactly this version
+    Exact(Version),
+    /// The newest version matching a semver requirement such as ``^1.2``
+    ///
+    /// ``raw`` is the requirement as written, semver normalizes e.g. ``1.2`` to ``^1.2`` when displaying a ``VersionReq``
+    Req { req: VersionReq, raw: String },
+}
+
+impl std::fmt::Display for ShopTemplateVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShopTemplateVersion::Latest => write!(f, "latest"),
+            ShopTemplateVersion::Exact(v) => write!(f, "{}", v),
+            ShopTemplateVersion::Req { raw, .. } => write!(f, "{}", raw),
+        }
+    }
+}
+
+impl FromStr for ShopTemplateVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "latest" {
+            return Ok(ShopTemplateVersion::Latest);
+        }
+
+        if let Ok(v) = Version::parse(s) {
+            return Ok(ShopTemplateVersion::Exact(v));
+        }
+
+        match VersionReq::parse(s) {
+            Ok(req) => Ok(ShopTemplateVersion::Req {
+                req,
+                raw: s.to_string(),
+            }),
+            Err(e) => Err(format!("Invalid shop template version {:?}: {}", s, e).into()),
+        }
+    }
+}
+
+/// A reference to a shop template of form ``$shop/template_name#version``
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShopTemplateRef {
+    pub name: String,
+    pub version: ShopTemplateVersion,
+}
+
+/// Validates a shop template name, names must be lowercase and may only contain letters, digits, dots and dashes
+pub fn validate_shop_template_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > SHOP_TEMPLATE_NAME_MAX_LENGTH {
+        return Err(format!(
+            "Shop template name must be between 1 and {} characters",
+            SHOP_TEMPLATE_NAME_MAX_LENGTH
+        )
+        .into());
+    }
+
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        return Err(format!(
+            "Shop template name {:?} may only contain lowercase letters, digits, dots and dashes",
+            name
+        )
+        .into());
+    }
+
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(format!(
+            "Shop template name {:?} must start with a letter or digit",
+            name
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+impl ShopTemplateRef {
+    /// Picks the best matching version out of the published versions of the template
+    pub fn resolve(&self, available: &[Version]) -> Option<Version> {
+        match &self.version {
+            ShopTemplateVersion::Latest => {
+                available.iter().filter(|v| v.pre.is_empty()).max().cloned()
+            }
+            ShopTemplateVersion::Exact(v) => available.iter().find(|a| *a == v).cloned(),
+            ShopTemplateVersion::Req { req, .. } => {
+                available.iter().filter(|v| req.matches(v)).max().cloned()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ShopTemplateRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}#{}", SHOP_TEMPLATE_PREFIX, self.name, self.version)
+    }
+}
+
+impl FromStr for ShopTemplateRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(s) = s.strip_prefix(SHOP_TEMPLATE_PREFIX) else {
+            return Err(format!("Shop templates must start with {}", SHOP_TEMPLATE_PREFIX).into());
+        };
+
+        let Some((name, version)) = s.split_once('#') else {
+            return Err("Shop templates must be of form $shop/template_name#version".into());
+        };
+
+        validate_shop_template_name(name)?;
+
+        Ok(ShopTemplateRef {
+            name: name.to_string(),
+            version: version.parse()?,
+        })
+    }
+}
 
 /// Parses a shop template of form template_name#version
+#[deprecated(note = "use ShopTemplateRef::from_str instead")]
 pub fn parse_shop_template(s: &str) -> Result<(String, String), Error> {
     let s = s.trim_start_matches("$shop/");
     let (template, version) = match s.split_once('#') {
@@ -12,11 +148,12 @@ pub fn parse_shop_template(s: &str) -> Result<(String, String), Error> {
 }
 
 /// Creates a shop template string given name and version
+#[deprecated(note = "use ShopTemplateRef's Display impl instead")]
 pub fn create_shop_template(template: &str, version: &str) -> String {
     format!("$shop/{}#{}", template, version)
 }
 
-#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct LuaKVConstraints {
     /// Maximum length of a key
     pub max_key_length: usize,
@@ -36,7 +173,479 @@ impl Default for LuaKVConstraints {
             max_value_bytes: 256 * 1024,
             max_object_storage_path_length: 2048,
             // 512kb max per value
-            max_object_storage_bytes: 512 * 1024
+            max_object_storage_bytes: 512 * 1024,
+        }
+    }
+}
+
+/// A KV write exceeding one of the ``LuaKVConstraints``
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KVLimitError {
+    /// Name of the exceeded limit, e.g. ``max_key_length``
+    pub limit: &'static str,
+    pub max: usize,
+    pub observed: usize,
+}
+
+impl std::fmt::Display for KVLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} exceeded: {} is greater than the limit of {}",
+            self.limit, self.observed, self.max
+        )
+    }
+}
+
+impl std::error::Error for KVLimitError {}
+
+fn check_limit(limit: &'static str, max: usize, observed: usize) -> Result<(), KVLimitError> {
+    if observed > max {
+        return Err(KVLimitError {
+            limit,
+            max,
+            observed,
+        });
+    }
+
+    Ok(())
+}
+
+impl LuaKVConstraints {
+    /// Checks the length of a key
+    pub fn check_key(&self, key: &str) -> Result<(), KVLimitError> {
+        check_limit("max_key_length", self.max_key_length, key.len())
+    }
+
+    /// Checks the size of a value in bytes
+    pub fn check_value(&self, bytes: usize) -> Result<(), KVLimitError> {
+        check_limit("max_value_bytes", self.max_value_bytes, bytes)
+    }
+
+    /// Checks the length of an object storage path
+    pub fn check_object_storage_path(&self, path: &str) -> Result<(), KVLimitError> {
+        check_limit(
+            "max_object_storage_path_length",
+            self.max_object_storage_path_length,
+            path.len(),
+        )
+    }
+
+    /// Checks the size of object storage data in bytes
+    pub fn check_object_storage_bytes(&self, bytes: usize) -> Result<(), KVLimitError> {
+        check_limit(
+            "max_object_storage_bytes",
+            self.max_object_storage_bytes,
+            bytes,
+        )
+    }
+}
+
+/// The KV constraints of a guild, as returned by a ``KVConstraintsProvider``
+///
+/// ``max_keys`` lives here rather than on ``LuaKVConstraints`` so existing ``LuaKVConstraints`` literals keep compiling
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct GuildKVConstraints {
+    #[serde(flatten)]
+    pub kv: LuaKVConstraints,
+    /// Maximum number of keys a guild may store
+    pub max_keys: usize,
+}
+
+impl Default for GuildKVConstraints {
+    fn default() -> Self {
+        GuildKVConstraints {
+            kv: LuaKVConstraints::default(),
+            max_keys: 1024,
+        }
+    }
+}
+
+impl GuildKVConstraints {
+    /// Checks that a new key can be added when ``current_count`` keys already exist
+    pub fn check_total_keys(&self, current_count: usize) -> Result<(), KVLimitError> {
+        check_limit("max_keys", self.max_keys, current_count.saturating_add(1))
+    }
+}
+
+/// Provides the KV constraints of a guild
+pub trait KVConstraintsProvider: Send + Sync {
+    fn constraints(
+        &self,
+        guild_id: serenity::all::GuildId,
+    ) -> impl std::future::Future<Output = Result<GuildKVConstraints, Error>> + Send;
+}
+
+/// Returns the default constraints for every guild
+#[derive(Clone, Copy, Default)]
+pub struct StaticKVConstraints;
+
+impl KVConstraintsProvider for StaticKVConstraints {
+    async fn constraints(
+        &self,
+        _guild_id: serenity::all::GuildId,
+    ) -> Result<GuildKVConstraints, Error> {
+        Ok(GuildKVConstraints::default())
+    }
+}
+
+/// Reads per-guild overrides (e.g. for premium guilds) from the ``guild_kv_constraints`` table
+///
+/// Limits which are not overridden (null) fall back to the defaults
+#[derive(Clone)]
+pub struct DbKVConstraints {
+    pub pool: sqlx::PgPool,
+}
+
+#[derive(sqlx::FromRow)]
+struct KVConstraintsOverrideRow {
+    max_key_length: Option<i64>,
+    max_value_bytes: Option<i64>,
+    max_object_storage_path_length: Option<i64>,
+    max_object_storage_bytes: Option<i64>,
+    max_keys: Option<i64>,
+}
+
+impl KVConstraintsProvider for DbKVConstraints {
+    async fn constraints(
+        &self,
+        guild_id: serenity::all::GuildId,
+    ) -> Result<GuildKVConstraints, Error> {
+        let mut constraints = GuildKVConstraints::default();
+
+        let rec: Option<KVConstraintsOverrideRow> = sqlx::query_as(
+            "SELECT max_key_length, max_value_bytes, max_object_storage_path_length, max_object_storage_bytes, max_keys FROM guild_kv_constraints WHERE guild_id = $1",
+        )
+        .bind(guild_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(rec) = rec else {
+            return Ok(constraints);
+        };
+
+        let fields = [
+            (rec.max_key_length, &mut constraints.kv.max_key_length),
+            (rec.max_value_bytes, &mut constraints.kv.max_value_bytes),
+            (
+                rec.max_object_storage_path_length,
+                &mut constraints.kv.max_object_storage_path_length,
+            ),
+            (
+                rec.max_object_storage_bytes,
+                &mut constraints.kv.max_object_storage_bytes,
+            ),
+            (rec.max_keys, &mut constraints.max_keys),
+        ];
+
+        for (value, field) in fields {
+            if let Some(value) = value {
+                *field = value.try_into()?;
+            }
+        }
+
+        Ok(constraints)
+    }
+}
+
+/// Path of the RPC route serving the KV constraints of a guild, see ``kv_constraints_router``
+#[cfg(feature = "rpc")]
+pub const KV_CONSTRAINTS_ROUTE: &str = "/kv-constraints/:guild_id";
+
+#[cfg(feature = "rpc")]
+async fn get_kv_constraints<P: KVConstraintsProvider + 'static>(
+    axum::extract::State(provider): axum::extract::State<std::sync::Arc<P>>,
+    axum::extract::Path(guild_id): axum::extract::Path<String>,
+) -> Result<axum::Json<GuildKVConstraints>, (axum::http::StatusCode, String)> {
+    let guild_id: serenity::all::GuildId = guild_id.parse().map_err(|_| {
+        (
+            axum::http::StatusCode::BAD_REQUEST,
+            format!("Invalid guild id: {}", guild_id),
+        )
+    })?;
+
+    match provider.constraints(guild_id).await {
+        Ok(constraints) => Ok(axum::Json(constraints)),
+        Err(e) => Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Returns a router serving ``GET /kv-constraints/:guild_id`` from ``provider``
+///
+/// The bot merges this into its RPC router so the template worker and the dashboard read the same limits
+#[cfg(feature = "rpc")]
+pub fn kv_constraints_router<P: KVConstraintsProvider + 'static>(provider: P) -> axum::Router {
+    axum::Router::new()
+        .route(
+            KV_CONSTRAINTS_ROUTE,
+            axum::routing::get(get_kv_constraints::<P>),
+        )
+        .with_state(std::sync::Arc::new(provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(vs: &[&str]) -> Vec<Version> {
+        vs.iter().map(|v| Version::parse(v).unwrap()).collect()
+    }
+
+    #[test]
+    fn shop_refs_round_trip() {
+        for s in [
+            "$shop/anti-spam#latest",
+            "$shop/anti-spam#1.2.3",
+            "$shop/anti-spam#1.2.3-beta.1+build.5",
+            "$shop/anti-spam#1.2",
+            "$shop/anti-spam#^1.2",
+            "$shop/anti-spam#~1",
+            "$shop/a.b-c#>=1.0.0, <2.0.0",
+            "$shop/x#*",
+        ] {
+            let parsed = s.parse::<ShopTemplateRef>().unwrap();
+            assert_eq!(parsed.to_string(), s);
+            assert_eq!(
+                parsed.to_string().parse::<ShopTemplateRef>().unwrap(),
+                parsed
+            );
+        }
+    }
+
+    #[test]
+    fn shop_ref_versions() {
+        let parse = |s: &str| s.parse::<ShopTemplateRef>().unwrap().version;
+
+        assert_eq!(parse("$shop/t#latest"), ShopTemplateVersion::Latest);
+        assert_eq!(
+            parse("$shop/t#1.2.3"),
+            ShopTemplateVersion::Exact(Version::new(1, 2, 3))
+        );
+        assert!(matches!(
+            parse("$shop/t#1.2"),
+            ShopTemplateVersion::Req { .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_shop_refs() {
+        let long_name = format!(
+            "$shop/{}#latest",
+            "a".repeat(SHOP_TEMPLATE_NAME_MAX_LENGTH + 1)
+        );
+
+        for s in [
+            "",
+            "$shop/",
+            "$shop/#latest",
+            "$shop/name",
+            "$shop/name#",
+            "shop/name#latest",
+            "name#latest",
+            "$shop/Name#latest",
+            "$shop/na me#latest",
+            "$shop/na_me#latest",
+            "$shop/-name#latest",
+            "$shop/.name#latest",
+            "$shop/naïve#latest",
+            "$shop/name#Latest",
+            "$shop/name#1.2.3.4",
+            "$shop/name#v1",
+            "$shop/name#1.2#3",
+            "$shop/name#>>1",
+            "$shop/name#^",
+            long_name.as_str(),
+        ] {
+            assert!(s.parse::<ShopTemplateRef>().is_err(), "{:?} parsed", s);
         }
+
+        let max_name = format!("$shop/{}#latest", "a".repeat(SHOP_TEMPLATE_NAME_MAX_LENGTH));
+        assert!(max_name.parse::<ShopTemplateRef>().is_ok());
+    }
+
+    #[test]
+    fn random_inputs_never_panic_and_round_trip() {
+        const ALPHABET: &[u8] = b"$shop/#abz09.-_^~<>=*, latestAZ";
+
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..20_000 {
+            let len = (next() % 24) as usize;
+            let mut s = if next() % 2 == 0 {
+                SHOP_TEMPLATE_PREFIX.to_string()
+            } else {
+                String::new()
+            };
+
+            for _ in 0..len {
+                s.push(ALPHABET[(next() % ALPHABET.len() as u64) as usize] as char);
+            }
+
+            if let Ok(parsed) = s.parse::<ShopTemplateRef>() {
+                assert_eq!(parsed.to_string(), s);
+                assert_eq!(s.parse::<ShopTemplateRef>().unwrap(), parsed);
+            }
+        }
+    }
+
+    #[test]
+    fn resolves_best_version() {
+        let available = versions(&["1.0.0", "1.2.0", "1.2.5", "1.3.0-beta.1", "2.0.0"]);
+        let resolve = |s: &str| {
+            s.parse::<ShopTemplateRef>()
+                .unwrap()
+                .resolve(&available)
+                .map(|v| v.to_string())
+        };
+
+        assert_eq!(resolve("$shop/t#latest").as_deref(), Some("2.0.0"));
+        assert_eq!(resolve("$shop/t#1.2.0").as_deref(), Some("1.2.0"));
+        assert_eq!(resolve("$shop/t#1.2.1"), None);
+        assert_eq!(resolve("$shop/t#1.2").as_deref(), Some("1.2.5"));
+        assert_eq!(resolve("$shop/t#^1").as_deref(), Some("1.2.5"));
+        assert_eq!(resolve("$shop/t#~1.0").as_deref(), Some("1.0.0"));
+        assert_eq!(resolve("$shop/t#>=3"), None);
+
+        assert_eq!(
+            "$shop/t#latest"
+                .parse::<ShopTemplateRef>()
+                .unwrap()
+                .resolve(&versions(&["1.0.0-alpha"])),
+            None
+        );
+    }
+
+    #[test]
+    fn kv_limits_are_inclusive() {
+        let c = LuaKVConstraints::default();
+
+        assert!(c.check_key(&"k".repeat(c.max_key_length)).is_ok());
+        assert_eq!(
+            c.check_key(&"k".repeat(c.max_key_length + 1)),
+            Err(KVLimitError {
+                limit: "max_key_length",
+                max: c.max_key_length,
+                observed: c.max_key_length + 1,
+            })
+        );
+
+        assert!(c.check_value(c.max_value_bytes).is_ok());
+        assert_eq!(
+            c.check_value(c.max_value_bytes + 1).unwrap_err().limit,
+            "max_value_bytes"
+        );
+
+        let path = "p".repeat(c.max_object_storage_path_length);
+        assert!(c.check_object_storage_path(&path).is_ok());
+        assert_eq!(
+            c.check_object_storage_path(&format!("{}p", path))
+                .unwrap_err()
+                .limit,
+            "max_object_storage_path_length"
+        );
+
+        assert!(c
+            .check_object_storage_bytes(c.max_object_storage_bytes)
+            .is_ok());
+        assert!(c
+            .check_object_storage_bytes(c.max_object_storage_bytes + 1)
+            .is_err());
+        assert!(c.check_value(0).is_ok());
+    }
+
+    #[test]
+    fn total_keys_counts_the_new_key() {
+        let c = GuildKVConstraints::default();
+
+        assert!(c.check_total_keys(c.max_keys - 1).is_ok());
+
+        let err = c.check_total_keys(c.max_keys).unwrap_err();
+        assert_eq!(err.limit, "max_keys");
+        assert_eq!(err.observed, c.max_keys + 1);
+
+        assert!(c.check_total_keys(usize::MAX).is_err());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn db_overrides_fall_back_to_defaults(pool: sqlx::PgPool) {
+        crate::test_schema::create_tables(&pool, &[crate::test_schema::GUILD_KV_CONSTRAINTS]).await;
+
+        sqlx::query(
+            "INSERT INTO guild_kv_constraints (guild_id, max_value_bytes, max_keys) VALUES ('1', 1048576, 4096), ('2', -1, NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let provider = DbKVConstraints { pool };
+
+        let premium = provider
+            .constraints(serenity::all::GuildId::new(1))
+            .await
+            .unwrap();
+        assert_eq!(premium.kv.max_value_bytes, 1048576);
+        assert_eq!(premium.max_keys, 4096);
+        assert_eq!(
+            premium.kv.max_key_length,
+            LuaKVConstraints::default().max_key_length
+        );
+
+        assert_eq!(
+            provider
+                .constraints(serenity::all::GuildId::new(3))
+                .await
+                .unwrap(),
+            GuildKVConstraints::default()
+        );
+
+        // Negative overrides are rejected rather than wrapping around
+        assert!(provider
+            .constraints(serenity::all::GuildId::new(2))
+            .await
+            .is_err());
+    }
+
+    #[cfg(feature = "rpc")]
+    #[tokio::test]
+    async fn kv_constraints_route() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            axum::serve(listener, kv_constraints_router(StaticKVConstraints))
+                .await
+                .unwrap()
+        });
+
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(format!("http://{}/kv-constraints/1", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let defaults = GuildKVConstraints::default();
+        assert_eq!(body["max_keys"], defaults.max_keys);
+        assert_eq!(body["max_key_length"], defaults.kv.max_key_length);
+        assert_eq!(
+            serde_json::from_value::<GuildKVConstraints>(body).unwrap(),
+            defaults
+        );
+
+        let resp = client
+            .get(format!("http://{}/kv-constraints/abc", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
     }
 }
@@ -0,0 +1,188 @@
+//! Minimal versions of the tables used by the database tests
+//!
+//! The real schema is managed outside this crate, these only contain the columns silverpelt reads and writes. Tests
+//! use ``#[sqlx::test(migrations = false)]`` (which needs ``DATABASE_URL``) and create the tables they need
+
+pub const STINGS: &str = r#"
+CREATE TABLE stings (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    src TEXT,
+    stings INTEGER NOT NULL DEFAULT 1,
+    reason TEXT,
+    void_reason TEXT,
+    guild_id TEXT NOT NULL,
+    creator TEXT NOT NULL,
+    target TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'active',
+    sting_data JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    duration INTERVAL,
+    handle_log JSONB NOT NULL DEFAULT '[]'
+);
+"#;
+
+pub const PUNISHMENTS: &str = r#"
+CREATE TABLE punishments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    src TEXT,
+    guild_id TEXT NOT NULL,
+    punishment TEXT NOT NULL,
+    creator TEXT NOT NULL,
+    target TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'active',
+    handle_log JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    duration INTERVAL,
+    reason TEXT NOT NULL DEFAULT '',
+    data JSONB
+);
+"#;
+
+pub const GUILD_MODULE_CONFIGURATIONS: &str = r#"
+CREATE TABLE guild_module_configurations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    module TEXT NOT NULL,
+    disabled BOOLEAN,
+    default_perms JSONB,
+    UNIQUE (guild_id, module)
+);
+"#;
+
+pub const GUILD_COMMAND_CONFIGURATIONS: &str = r#"
+CREATE TABLE guild_command_configurations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    command TEXT NOT NULL,
+    disabled BOOLEAN,
+    perms JSONB,
+    UNIQUE (guild_id, command)
+);
+"#;
+
+pub const GUILD_CONFIG_HISTORY: &str = r#"
+CREATE TABLE guild_config_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL,
+    old_value JSONB,
+    new_value JSONB,
+    author TEXT NOT NULL,
+    at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+);
+"#;
+
+pub const GUILD_EVENT_SINKS: &str = r#"
+CREATE TABLE guild_event_sinks (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    secret_salt UUID NOT NULL,
+    event_filter TEXT[] NOT NULL DEFAULT '{}',
+    disabled BOOLEAN NOT NULL DEFAULT false,
+    consecutive_failures INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+"#;
+
+pub const GUILD_ROLES: &str = r#"
+CREATE TABLE guild_roles (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    role_id TEXT NOT NULL,
+    perms TEXT[] NOT NULL DEFAULT '{}',
+    index INTEGER NOT NULL,
+    UNIQUE (guild_id, role_id)
+);
+"#;
+
+pub const GUILD_MEMBERS: &str = r#"
+CREATE TABLE guild_members (
+    guild_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    perm_overrides TEXT[] NOT NULL DEFAULT '{}',
+    UNIQUE (guild_id, user_id)
+);
+"#;
+
+pub const GUILD_BOOTSTRAPS: &str = r#"
+CREATE TABLE guild_bootstraps (
+    guild_id TEXT PRIMARY KEY,
+    version INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+"#;
+
+pub const GUILD_CHANNEL_PERM_OVERRIDES: &str = r#"
+CREATE TABLE guild_channel_perm_overrides (
+    guild_id TEXT NOT NULL,
+    channel_id TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    perms TEXT[] NOT NULL DEFAULT '{}',
+    UNIQUE (guild_id, channel_id, target_type, target_id)
+);
+"#;
+
+pub const LOCKDOWN_GUILDS: &str = r#"
+CREATE TABLE lockdown__guilds (
+    guild_id TEXT PRIMARY KEY,
+    member_roles TEXT[] NOT NULL DEFAULT '{}',
+    require_correct_layout BOOLEAN NOT NULL DEFAULT false
+);
+"#;
+
+pub const LOCKDOWN_GUILD_LOCKDOWNS: &str = r#"
+CREATE TABLE lockdown__guild_lockdowns (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    type TEXT NOT NULL,
+    data JSONB NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+"#;
+
+pub const GUILD_RETENTION_POLICIES: &str = r#"
+CREATE TABLE guild_retention_policies (
+    guild_id TEXT PRIMARY KEY,
+    sting_retention INTERVAL,
+    punishment_retention INTERVAL,
+    voided_only BOOLEAN NOT NULL DEFAULT false,
+    mode TEXT NOT NULL
+);
+"#;
+
+pub const GUILD_PUNISHMENT_RULES: &str = r#"
+CREATE TABLE guild_punishment_rules (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    guild_id TEXT NOT NULL,
+    threshold BIGINT NOT NULL,
+    punishment_kind TEXT NOT NULL,
+    duration INTERVAL,
+    src_filter TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+"#;
+
+pub const GUILD_KV_CONSTRAINTS: &str = r#"
+CREATE TABLE guild_kv_constraints (
+    guild_id TEXT PRIMARY KEY,
+    max_key_length BIGINT,
+    max_value_bytes BIGINT,
+    max_object_storage_path_length BIGINT,
+    max_object_storage_bytes BIGINT,
+    max_keys BIGINT
+);
+"#;
+
+/// Creates the given tables
+pub async fn create_tables(pool: &sqlx::PgPool, tables: &[&str]) {
+    for table in tables {
+        sqlx::raw_sql(table)
+            .execute(pool)
+            .await
+            .expect("failed to create test table");
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::HashMap;
+
 use antiraid_types::userinfo::UserInfo;
 use sandwich_driver::SandwichConfigData;
+use serenity::all::{ChannelId, Permissions};
 
-use crate::member_permission_calc::GetKittycatPermsConfigData;
+use crate::data::Data;
+use crate::member_permission_calc::GetKittycatPermsConfig;
 
 pub struct NoMember {}
 
@@ -21,10 +25,34 @@ pub trait UserInfoOperations: Send + Sync {
         pool: &sqlx::PgPool,
         serenity_context: &serenity::all::Context,
         reqwest: &reqwest::Client,
-        config: GetKittycatPermsConfigData,
+        config: impl Into<GetKittycatPermsConfig>,
         sandwich_config: &SandwichConfigData,
         member_opt: Option<impl AsRef<serenity::all::Member>>,
     ) -> Result<UserInfo, crate::Error>;
+
+    #[allow(clippy::too_many_arguments)]
+    /// Like ``get`` but also resolves the permissions of the user in the given channels
+    async fn get_with_channels(
+        guild_id: serenity::all::GuildId,
+        user_id: serenity::all::UserId,
+        channel_ids: &[ChannelId],
+        pool: &sqlx::PgPool,
+        serenity_context: &serenity::all::Context,
+        reqwest: &reqwest::Client,
+        config: impl Into<GetKittycatPermsConfig>,
+        sandwich_config: &SandwichConfigData,
+        member_opt: Option<impl AsRef<serenity::all::Member>>,
+    ) -> Result<UserInfoWithChannels, crate::Error>;
+}
+
+/// A UserInfo along with the permissions of the user in a set of channels
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UserInfoWithChannels {
+    pub user_info: UserInfo,
+    /// The resolved permissions of the user per channel
+    pub channel_permissions: HashMap<ChannelId, Permissions>,
+    /// Channels that do not exist or are not part of the guild
+    pub unresolved_channels: Vec<ChannelId>,
 }
 
 impl UserInfoOperations for UserInfo {
@@ -35,11 +63,13 @@ impl UserInfoOperations for UserInfo {
         pool: &sqlx::PgPool,
         serenity_context: &serenity::all::Context,
         reqwest: &reqwest::Client,
-        config: GetKittycatPermsConfigData,
+        config: impl Into<GetKittycatPermsConfig>,
         sandwich_config: &SandwichConfigData,
         // In some cases, we *do* have the member object, so we can pass it here
         member_opt: Option<impl AsRef<serenity::all::Member>>,
     ) -> Result<Self, crate::Error> {
+        let sandwich_status = serenity_context.data::<Data>().sandwich_status();
+
         let cached_data = {
             if let Some(cached_guild) = guild_id.to_guild_cached(&serenity_context.cache) {
                 if let Some(ref member) = member_opt {
@@ -68,7 +98,9 @@ impl UserInfoOperations for UserInfo {
             let member_roles = match member_roles {
                 Some(member_roles) => member_roles,
                 None => {
-                    let member = sandwich_driver::member_in_guild(
+                    let member = crate::sandwich::member_in_guild(
+                        &sandwich_status,
+                        false,
                         &serenity_context.cache,
                         &serenity_context.http,
                         reqwest,
@@ -145,7 +177,9 @@ impl UserInfoOperations for UserInfo {
         }
 
         let member = {
-            let member = sandwich_driver::member_in_guild(
+            let member = crate::sandwich::member_in_guild(
+                &sandwich_status,
+                false,
                 &serenity_context.cache,
                 &serenity_context.http,
                 reqwest,
@@ -187,4 +221,109 @@ impl UserInfoOperations for UserInfo {
             member_roles: member.roles.to_vec(),
         })
     }
+
+    /// Like ``get`` but also resolves the permissions of the user in the given channels
+    async fn get_with_channels(
+        guild_id: serenity::all::GuildId,
+        user_id: serenity::all::UserId,
+        channel_ids: &[ChannelId],
+        pool: &sqlx::PgPool,
+        serenity_context: &serenity::all::Context,
+        reqwest: &reqwest::Client,
+        config: impl Into<GetKittycatPermsConfig>,
+        sandwich_config: &SandwichConfigData,
+        member_opt: Option<impl AsRef<serenity::all::Member>>,
+    ) -> Result<UserInfoWithChannels, crate::Error> {
+        // Channel permissions need the full member (for timeouts) so keep it if the caller has it
+        let member = member_opt.as_ref().map(|m| m.as_ref().clone());
+
+        let user_info = Self::get(
+            guild_id,
+            user_id,
+            pool,
+            serenity_context,
+            reqwest,
+            config,
+            sandwich_config,
+            member_opt,
+        )
+        .await?;
+
+        let sandwich_status = serenity_context.data::<Data>().sandwich_status();
+
+        let member = match member {
+            Some(member) => member,
+            None => {
+                let member = crate::sandwich::member_in_guild(
+                    &sandwich_status,
+                    false,
+                    &serenity_context.cache,
+                    &serenity_context.http,
+                    reqwest,
+                    guild_id,
+                    user_id,
+                    sandwich_config,
+                )
+                .await?;
+
+                let Some(member) = member else {
+                    return Err("Member could not fetched".into());
+                };
+
+                member
+            }
+        };
+
+        let channels = crate::sandwich::guild_channels(
+            &sandwich_status,
+            false,
+            &serenity_context.cache,
+            &serenity_context.http,
+            reqwest,
+            guild_id,
+            sandwich_config,
+        )
+        .await?;
+
+        let mut unresolved_channels = Vec::new();
+        let mut resolved_channels = Vec::with_capacity(channel_ids.len());
+
+        for channel_id in channel_ids {
+            match channels
+                .iter()
+                .find(|c| c.id == *channel_id && c.guild_id == guild_id)
+            {
+                Some(channel) => resolved_channels.push(channel),
+                None => unresolved_channels.push(*channel_id),
+            }
+        }
+
+        // Use serenity's calculation so implicit permissions and timeouts are accounted for
+        let cached_permissions = guild_id
+            .to_guild_cached(&serenity_context.cache)
+            .map(|guild| {
+                resolved_channels
+                    .iter()
+                    .map(|channel| (channel.id, guild.user_permissions_in(channel, &member)))
+                    .collect::<HashMap<_, _>>()
+            });
+
+        let channel_permissions = match cached_permissions {
+            Some(channel_permissions) => channel_permissions,
+            None => {
+                let guild = guild_id.to_partial_guild(&serenity_context).await?;
+
+                resolved_channels
+                    .iter()
+                    .map(|channel| (channel.id, guild.user_permissions_in(channel, &member)))
+                    .collect()
+            }
+        };
+
+        Ok(UserInfoWithChannels {
+            user_info,
+            channel_permissions,
+            unresolved_channels,
+        })
+    }
 }